@@ -0,0 +1,2160 @@
+//! Body of the main loop. Everything the loop needs from the chip goes
+//! through `Board` and the bus traits, so a tick can be driven on the host.
+use alloc::vec::Vec;
+use defmt::{debug, info, warn};
+use fugit::{HertzU32, MicrosDurationU64, RateExtU32};
+use rp2040_hal::timer::Instant;
+
+use crate::{
+    config::{Config, MAX_POLL_INTERVAL_MS, MAX_STARTUP_DELAY_MS, MIN_POLL_INTERVAL_MS},
+    downstream::{
+        spi_downstream::{slot_for_id, DownstreamDelay, SpiDownstream, EMPTY_SLOT_PROBE_INTERVAL},
+        spi_protocol::{
+            set_trace_slot, validate_spi_freq, NegiconProtocol, SpiClock, MAX_CS_SETTLE_US,
+        },
+    },
+    heartbeat::Heartbeat,
+    idle::{IdleTracker, PowerState},
+    negicon_event::{
+        ConfigKey, DiagnosticField, NegiconEvent, NegiconEventType, QueryKey, RebootKind, SlotStat,
+    },
+    upstream::{ringbuf::RingBuffer, upstream::Upstream},
+};
+
+/// Poll interval while idle. Any input brings back `Config::poll_interval`.
+/// USB is serviced on every iteration of the main loop regardless of the
+/// interval, so host traffic is not held back by downstream polling.
+pub(crate) const IDLE_POLL_INTERVAL: MicrosDurationU64 =
+    MicrosDurationU64::millis(MAX_POLL_INTERVAL_MS as u64);
+
+/// Share of the active poll interval, in percent, downstream polling may take
+/// per tick. Slots not reached are polled first on the next tick, so a slow
+/// round can't make the tick timer fire again right away and starve USB.
+pub(crate) const POLL_BUDGET_PERCENT: u32 = 75;
+
+/// Host events handled per upstream and tick. A burst beyond this waits for
+/// the next tick, so it can't hold up downstream polling.
+pub(crate) const MAX_HOST_EVENTS_PER_TICK: usize = 8;
+
+/// Memory writes kept for downstreams that aren't there yet. Beyond this the
+/// oldest one is dropped.
+pub(crate) const MAX_DEFERRED_WRITES: usize = 16;
+
+/// Polling rounds a deferred write waits for a downstream with its id. They
+/// only count while no downstream is still reading its id, and detecting a
+/// device on an empty slot takes up to two of its probes, so a device that
+/// comes up late still gets the write.
+pub(crate) const DEFERRED_WRITE_ROUNDS: u16 = 2 * EMPTY_SLOT_PROBE_INTERVAL as u16 + 1;
+
+/// Polling rounds in a row in which downstream transfers failed and none
+/// got through, after which the SPI peripheral is taken to be stuck and gets
+/// reset. Rounds without any transfer, e.g. between empty slot probes, don't
+/// count either way. A single round can fail when all slots happen to be
+/// empty and floating.
+pub(crate) const SPI_RESET_ROUNDS: u16 = 2;
+
+/// A `MemWrite` or `MemWriteBatch` event waiting for its downstream
+pub(crate) struct DeferredWrite {
+    pub(crate) event: NegiconEvent,
+    /// Polling rounds left before it is given up, see
+    /// `DEFERRED_WRITE_ROUNDS`
+    rounds_left: u16,
+}
+
+/// Main loop state that outlives a single tick
+pub(crate) struct LoopState {
+    pub(crate) idle: IdleTracker,
+    pub(crate) heartbeat: Heartbeat,
+    /// The downstreams aren't polled before this, see `hold_off_polling`
+    polling_from: Instant,
+    /// Slot the next polling round starts at
+    pub(crate) next_slot: usize,
+    /// Failed rounds in a row, see `SPI_RESET_ROUNDS`
+    failed_rounds: u16,
+    /// Whether a transfer failed in the current round
+    round_failed: bool,
+    /// Whether the failed transfer count dropped to zero in the current
+    /// round, i.e. the bus isn't failing throughout
+    round_got_through: bool,
+    /// Last downstream events and errors for post-mortem debugging, see
+    /// `QueryKey::Replay`. `RawAlpha` events are left out, as they would
+    /// crowd out everything else while enabled.
+    pub(crate) replay: RingBuffer<NegiconEvent>,
+    /// `MemWrite` and `MemWriteBatch` events for ids no downstream reports
+    /// yet, e.g. sent right after power-on. Applied in order once a
+    /// downstream with the id shows up, or dropped after
+    /// `DEFERRED_WRITE_ROUNDS` if none does.
+    pub(crate) deferred_writes: Vec<DeferredWrite>,
+    /// Live settings, written to flash on `ConfigKey::Save`
+    pub(crate) config: Config,
+}
+
+impl LoopState {
+    pub(crate) fn new(config: Config) -> Self {
+        Self {
+            idle: IdleTracker::new(config.idle_timeout()),
+            heartbeat: Heartbeat::new(config.heartbeat_interval()),
+            polling_from: Instant::from_ticks(0),
+            next_slot: 0,
+            failed_rounds: 0,
+            round_failed: false,
+            round_got_through: false,
+            replay: RingBuffer::new(),
+            deferred_writes: Vec::new(),
+            config,
+        }
+    }
+
+    /// Leaves the downstreams alone for the configured startup delay from
+    /// `now` on, while the upstreams are serviced as usual
+    pub(crate) fn hold_off_polling(&mut self, now: Instant) {
+        self.polling_from = now + self.config.startup_delay();
+    }
+
+    /// Notes the failed transfer count before and after polling one slot
+    fn record_transfers(&mut self, before: u16, after: u16) {
+        if after == 0 {
+            self.round_got_through = true;
+        } else if after > before {
+            self.round_failed = true;
+        }
+    }
+
+    /// Closes the current polling round
+    fn finish_round(&mut self) {
+        if self.round_got_through {
+            self.failed_rounds = 0;
+        } else if self.round_failed {
+            self.failed_rounds = self.failed_rounds.saturating_add(1);
+        }
+        self.round_failed = false;
+        self.round_got_through = false;
+    }
+
+    /// Returns true once `SPI_RESET_ROUNDS` rounds failed in a row, and
+    /// starts counting afresh for the reset peripheral
+    pub(crate) fn take_spi_stuck(&mut self) -> bool {
+        let stuck = self.failed_rounds >= SPI_RESET_ROUNDS;
+        if stuck {
+            self.failed_rounds = 0;
+        }
+        stuck
+    }
+}
+
+/// Chip services used by the main loop
+pub(crate) trait Board {
+    /// Current time of the free running timer
+    fn now(&self) -> Instant;
+    /// Returns true once the interval passed to the last `schedule_poll` has
+    /// elapsed, the downstreams are only polled then.
+    fn poll_due(&mut self) -> bool;
+    /// Starts the next poll interval
+    fn schedule_poll(&mut self, interval: MicrosDurationU64);
+    /// Clock feeding the SPI peripherals
+    fn peripheral_freq(&self) -> HertzU32;
+    /// Resets the chip. Never returns on hardware.
+    fn reboot(&mut self, kind: RebootKind);
+    /// Persists `config` so it is loaded on the next boot
+    fn store_config(&mut self, config: &Config);
+}
+
+pub(crate) fn broadcast(upstreams: &mut [Upstream], event: NegiconEvent) {
+    for up in upstreams.iter_mut() {
+        match up.enqueue(event) {
+            Ok(_) => {}
+            Err(e) => {
+                warn!("Error while enqueueing event for upstream: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Hands the downstream part of `config` to every slot
+pub(crate) fn apply_downstream_settings<S: NegiconProtocol>(
+    downstreams: &mut [SpiDownstream<'_, S>],
+    config: &Config,
+) {
+    let settings = config.downstream_settings();
+    for ds in downstreams.iter_mut() {
+        ds.apply_settings(&settings);
+    }
+}
+
+fn apply_write<S: NegiconProtocol>(ds: &mut SpiDownstream<'_, S>, event: &NegiconEvent) {
+    match event.event_type() {
+        NegiconEventType::MemWriteBatch => ds.stage_write(event),
+        _ => ds.write_memory(event),
+    }
+}
+
+/// Keeps a write for a downstream that hasn't been detected or read its id
+/// yet, instead of dropping it. Without device state no downstream ever
+/// takes it, so it is dropped rather than held on the heap.
+fn defer_write<S: NegiconProtocol>(
+    state: &mut LoopState,
+    downstreams: &[SpiDownstream<'_, S>],
+    event: NegiconEvent,
+) {
+    if !downstreams.iter().any(|ds| ds.keeps_device_state()) {
+        warn!("No downstream with id {}", event.id());
+        return;
+    }
+    if state.deferred_writes.len() >= MAX_DEFERRED_WRITES {
+        let dropped = state.deferred_writes.remove(0);
+        warn!("Too many deferred writes, dropping {}", dropped.event);
+    }
+    info!("No downstream with id {} yet, deferring write", event.id());
+    state.deferred_writes.push(DeferredWrite {
+        event,
+        rounds_left: DEFERRED_WRITE_ROUNDS,
+    });
+}
+
+/// Hands deferred writes to the downstreams that have shown up since.
+fn apply_deferred_writes<S: NegiconProtocol>(
+    state: &mut LoopState,
+    downstreams: &mut [SpiDownstream<'_, S>],
+) {
+    state
+        .deferred_writes
+        .retain(|write| match slot_for_id(downstreams, write.event.id()) {
+            Some(slot) => {
+                apply_write(&mut downstreams[slot], &write.event);
+                false
+            }
+            None => true,
+        });
+}
+
+/// Counts a finished polling round against the deferred writes, unless a
+/// downstream is still reading its id and may turn out to be the one they
+/// wait for. Writes out of rounds are dropped, their downstream isn't there.
+fn age_deferred_writes<S: NegiconProtocol>(
+    state: &mut LoopState,
+    downstreams: &[SpiDownstream<'_, S>],
+) {
+    if downstreams.iter().any(|ds| ds.is_initializing()) {
+        return;
+    }
+    state.deferred_writes.retain_mut(|write| {
+        write.rounds_left = write.rounds_left.saturating_sub(1);
+        if write.rounds_left == 0 {
+            warn!("No downstream with id {} showed up", write.event.id());
+        }
+        write.rounds_left > 0
+    });
+}
+
+/// Handles a single event received from the host on `origin`.
+pub(crate) fn dispatch<S>(
+    event: NegiconEvent,
+    origin: &mut Upstream,
+    state: &mut LoopState,
+    downstreams: &mut [SpiDownstream<'_, S>],
+    spi: &mut S,
+    board: &mut impl Board,
+) where
+    S: NegiconProtocol + SpiClock,
+{
+    debug!("Received event from upstream {}", event);
+    match event.event_type() {
+        NegiconEventType::Input | NegiconEventType::NarrowInput | NegiconEventType::Output => {
+            warn!("Unsupported event from upstream {}", event)
+        }
+        NegiconEventType::MemWrite | NegiconEventType::MemWriteBatch => {
+            // Writes queued earlier for the same id have to go first
+            let queued = state
+                .deferred_writes
+                .iter()
+                .any(|write| write.event.id() == event.id());
+            match slot_for_id(downstreams, event.id()) {
+                Some(slot) if !queued => apply_write(&mut downstreams[slot], &event),
+                _ => defer_write(state, downstreams, event),
+            }
+        }
+        NegiconEventType::Reinit => match slot_for_id(downstreams, event.id()) {
+            Some(slot) => downstreams[slot].reinit(),
+            None => warn!("No downstream with id {}", event.id()),
+        },
+        NegiconEventType::SlotEnable => {
+            let slot = event.id() as usize;
+            match downstreams.get_mut(slot) {
+                Some(ds) => {
+                    let enabled = event.value() != 0;
+                    ds.set_enabled(enabled);
+                    state.config.set_slot_enabled(slot, enabled);
+                    info!("Slot {} enabled: {}", slot, enabled);
+                }
+                None => warn!("No downstream slot {}", slot),
+            }
+        }
+        NegiconEventType::DiagnosticDetails => match downstreams.get_mut(event.id() as usize) {
+            Some(ds) => ds.request_diagnostic_details(),
+            None => warn!("No downstream slot {}", event.id()),
+        },
+        NegiconEventType::FieldReading => match downstreams.get_mut(event.id() as usize) {
+            Some(ds) => ds.request_field_reading(),
+            None => warn!("No downstream slot {}", event.id()),
+        },
+        NegiconEventType::Reboot => board.reboot(RebootKind::from_value(event.value())),
+        NegiconEventType::Config => match ConfigKey::from_id(event.id()) {
+            Some(ConfigKey::DownstreamSpiClock) => {
+                let peripheral = board.peripheral_freq();
+                match validate_spi_freq(event.value().max(0) as u32 * 1000, peripheral.to_Hz()) {
+                    Some(freq) => {
+                        let actual = spi.set_clock(peripheral, freq.Hz());
+                        state.config.spi_clock_khz = (freq / 1000) as u16;
+                        info!("Downstream SPI clock set to {} Hz", actual.to_Hz());
+                    }
+                    None => warn!("Invalid downstream SPI clock {} kHz", event.value()),
+                }
+            }
+            Some(ConfigKey::IdleTimeout) => {
+                state.config.idle_timeout_s = event.value().max(0) as u16;
+                state.idle.set_timeout(state.config.idle_timeout());
+                info!("Idle timeout set to {} s", state.config.idle_timeout_s);
+            }
+            Some(ConfigKey::HeartbeatInterval) => {
+                state.config.heartbeat_interval_ms = event.value().max(0) as u16;
+                state
+                    .heartbeat
+                    .set_interval(state.config.heartbeat_interval());
+                info!(
+                    "Heartbeat interval set to {} ms",
+                    state.config.heartbeat_interval_ms
+                );
+            }
+            Some(ConfigKey::PrimeReadings) => {
+                state.config.prime_readings = event.value().max(0) as u16;
+                apply_downstream_settings(downstreams, &state.config);
+                info!("Prime readings set to {}", state.config.prime_readings);
+            }
+            Some(ConfigKey::FlushOnDisconnect) => {
+                state.config.flush_on_disconnect = event.value() != 0;
+                info!("Flush on disconnect: {}", state.config.flush_on_disconnect);
+            }
+            Some(ConfigKey::RawAlpha) => {
+                state.config.raw_alpha = event.value() != 0;
+                apply_downstream_settings(downstreams, &state.config);
+                info!("Raw alpha reporting: {}", state.config.raw_alpha);
+            }
+            Some(ConfigKey::BoardId) => {
+                state.config.board_id = event.value().clamp(0, u8::MAX as i16) as u8;
+                info!("Board id set to {}", state.config.board_id);
+            }
+            Some(ConfigKey::CsSettle) => {
+                state.config.cs_settle_us = event.value().clamp(0, MAX_CS_SETTLE_US as i16) as u16;
+                spi.set_cs_settle(state.config.cs_settle());
+                info!("CS settle time set to {} us", state.config.cs_settle_us);
+            }
+            Some(ConfigKey::ReportPeriod) => {
+                state.config.report_period = event.value().max(0) as u16;
+                apply_downstream_settings(downstreams, &state.config);
+                info!("Report mode set to {}", state.config.report_mode());
+            }
+            Some(ConfigKey::MinRelativeStep) => {
+                state.config.min_relative_step = event.value().max(0) as u16;
+                apply_downstream_settings(downstreams, &state.config);
+                info!(
+                    "Minimum relative step set to {}",
+                    state.config.min_relative_step
+                );
+            }
+            // Takes effect when the next poll is scheduled, after the current
+            // polling round
+            Some(ConfigKey::PollInterval) => {
+                state.config.poll_interval_ms =
+                    (event.value().max(0) as u16).clamp(MIN_POLL_INTERVAL_MS, MAX_POLL_INTERVAL_MS);
+                info!("Poll interval set to {} ms", state.config.poll_interval_ms);
+            }
+            Some(ConfigKey::DiagnosticFailLimit) => {
+                state.config.diagnostic_fail_limit = event.value().max(0) as u16;
+                apply_downstream_settings(downstreams, &state.config);
+                info!(
+                    "Diagnostic fail limit set to {}",
+                    state.config.diagnostic_fail_limit
+                );
+            }
+            Some(ConfigKey::InitAttempts) => {
+                state.config.init_attempts = event.value().max(0) as u16;
+                apply_downstream_settings(downstreams, &state.config);
+                info!("Init attempts set to {}", state.config.init_attempts);
+            }
+            Some(
+                key @ (ConfigKey::ButtonPressValue
+                | ConfigKey::ButtonReleaseValue
+                | ConfigKey::ButtonIdOffset),
+            ) => {
+                let mapping = &mut state.config.button_mapping;
+                match key {
+                    ConfigKey::ButtonPressValue => mapping.press = event.value(),
+                    ConfigKey::ButtonReleaseValue => mapping.release = event.value(),
+                    _ => mapping.id_offset = event.value() as u16,
+                }
+                apply_downstream_settings(downstreams, &state.config);
+                info!("Button mapping set to {}", state.config.button_mapping);
+            }
+            Some(ConfigKey::BlockedEvents) => {
+                state.config.blocked_events = event.value() as u16;
+                info!("Blocked event types {:b}", state.config.blocked_events);
+            }
+            Some(ConfigKey::StartupDelay) => {
+                state.config.startup_delay_ms =
+                    event.value().clamp(0, MAX_STARTUP_DELAY_MS as i16) as u16;
+                info!("Startup delay set to {} ms", state.config.startup_delay_ms);
+            }
+            Some(ConfigKey::FrameIds) => {
+                state.config.frame_ids = event.value() != 0;
+                apply_downstream_settings(downstreams, &state.config);
+                info!("Frame ids: {}", state.config.frame_ids);
+            }
+            Some(ConfigKey::Save) => board.store_config(&state.config),
+            None => warn!("Unknown config key {}", event.id()),
+        },
+        NegiconEventType::Error
+        | NegiconEventType::Heartbeat
+        | NegiconEventType::RawAlpha
+        | NegiconEventType::Downstream
+        | NegiconEventType::Replay
+        | NegiconEventType::WriteChallenge => {
+            warn!("Ignoring {} event from upstream", event.event_type())
+        }
+        NegiconEventType::Query => {
+            let up = origin;
+            let answer = match QueryKey::from_id(event.id()) {
+                Some(QueryKey::DroppedEvents) => {
+                    let dropped = up.dropped_count().min(i16::MAX as u32) as i16;
+                    if event.value() == 1 {
+                        up.clear_dropped();
+                    }
+                    dropped
+                }
+                Some(QueryKey::CorruptFrames) => {
+                    let corrupt = up.corrupt_frames().min(i16::MAX as u32) as i16;
+                    if event.value() == 1 {
+                        up.clear_corrupt_frames();
+                    }
+                    corrupt
+                }
+                Some(QueryKey::Enumerate) => {
+                    let mut detected = 0;
+                    for (slot, ds) in downstreams.iter().enumerate() {
+                        if let Some(kind) = ds.kind() {
+                            detected += 1;
+                            let entry = NegiconEvent::new(
+                                NegiconEventType::Downstream,
+                                ds.id().unwrap_or(0xFFFF),
+                                slot as i16,
+                                0,
+                                kind.opcode(),
+                            );
+                            if let Err(e) = up.enqueue(entry) {
+                                warn!("Error while enqueueing downstream entry: {:?}", e);
+                            }
+                        }
+                    }
+                    detected
+                }
+                Some(QueryKey::Replay) => {
+                    // One slot is kept for the answer
+                    let room = up.free_slots().saturating_sub(1);
+                    let mut sent = 0;
+                    for past in state.replay.iter().take(room) {
+                        if let Err(e) = up.enqueue(past.replay()) {
+                            warn!("Error while enqueueing replayed event: {:?}", e);
+                            break;
+                        }
+                        sent += 1;
+                    }
+                    if event.value() == 1 {
+                        for _ in 0..sent {
+                            state.replay.pop();
+                        }
+                    }
+                    sent
+                }
+                Some(QueryKey::Config(key)) => state.config.get(key),
+                Some(QueryKey::DownstreamVersion(slot)) => downstreams
+                    .get(slot as usize)
+                    .and_then(|ds| ds.version())
+                    .map_or(-1, |v| v as i16),
+                Some(QueryKey::SlotStat(slot, stat)) => match downstreams.get(slot as usize) {
+                    Some(ds) => {
+                        let stats = ds.stats();
+                        let count = match stat {
+                            SlotStat::Polls => stats.polls,
+                            SlotStat::CrcErrors => stats.crc_errors as u32,
+                            SlotStat::DeviceErrors => stats.device_errors as u32,
+                            SlotStat::Events => stats.events,
+                        };
+                        count.min(i16::MAX as u32) as i16
+                    }
+                    None => -1,
+                },
+                Some(QueryKey::DownstreamDiagnostic(slot, field)) => {
+                    match downstreams
+                        .get(slot as usize)
+                        .and_then(|ds| ds.diagnostics())
+                    {
+                        Some(diag) => match field {
+                            DiagnosticField::Flags => diag.flags() as i16,
+                            DiagnosticField::Min => diag.min.map_or(-1, |v| v as i16),
+                            DiagnosticField::Max => diag.max.map_or(-1, |v| v as i16),
+                            DiagnosticField::FieldStrength => {
+                                diag.field_strength.map_or(-1, |v| v as i16)
+                            }
+                        },
+                        None => -1,
+                    }
+                }
+                None => {
+                    warn!("Unknown query key {}", event.id());
+                    return;
+                }
+            };
+            let reply = NegiconEvent::new(NegiconEventType::Query, event.id(), answer, 0, 0);
+            if let Err(e) = up.enqueue(reply) {
+                warn!("Error while enqueueing query answer: {:?}", e);
+            }
+        }
+    }
+}
+
+/// One iteration of the main loop. Services the upstreams and dispatches
+/// host events on every call, and polls all downstreams once the poll
+/// interval has elapsed.
+///
+/// `spi` is the downstream bus. An SPI upstream owns its own peripheral, so
+/// a downstream poll and an upstream relay can't end up on the same bus.
+pub(crate) fn tick<S>(
+    state: &mut LoopState,
+    downstreams: &mut [SpiDownstream<'_, S>],
+    spi: &mut S,
+    upstreams: &mut [Upstream],
+    delay: &mut dyn DownstreamDelay,
+    board: &mut impl Board,
+) where
+    S: NegiconProtocol + SpiClock,
+{
+    for up in upstreams.iter_mut() {
+        up.set_flush_on_disconnect(state.config.flush_on_disconnect);
+        up.set_board_id(state.config.board_id);
+        for _ in 0..MAX_HOST_EVENTS_PER_TICK {
+            match up.receive() {
+                Ok(Some(event)) => dispatch(event, up, state, downstreams, spi, board),
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Error while polling: {:?}", e);
+                    break;
+                }
+            }
+        }
+        // After the host events, so a new mask already covers this round
+        up.set_blocked_events(state.config.blocked_events);
+    }
+    if !state.deferred_writes.is_empty() {
+        apply_deferred_writes(state, downstreams);
+    }
+
+    if board.poll_due() {
+        // Nobody to report to, e.g. the USB host hasn't configured us yet or
+        // suspended us. Keep servicing the upstreams so enumeration or a
+        // resume is noticed, but leave the sensors alone, so detection and
+        // priming only start once events can actually go out.
+        if !upstreams.iter().any(|up| up.is_ready()) {
+            board.schedule_poll(IDLE_POLL_INTERVAL);
+            return;
+        }
+        // Satellite boards may still be in their own reset after a cold boot.
+        // Probing them then only produces a wave of failed detections.
+        if board.now() < state.polling_from {
+            board.schedule_poll(state.config.poll_interval());
+            return;
+        }
+        let mut activity = false;
+        let mut traffic = false;
+        let start = board.now();
+        let budget = state.config.poll_interval() * POLL_BUDGET_PERCENT / 100;
+        for polled in 1..=downstreams.len() {
+            let slot = state.next_slot;
+            state.next_slot = (slot + 1) % downstreams.len();
+            set_trace_slot(slot as u8);
+            let failed_before = spi.failed_transfers();
+            let res = downstreams[slot].poll(delay, spi, board.now(), &mut |event| {
+                if matches!(
+                    event.event_type(),
+                    NegiconEventType::Input | NegiconEventType::NarrowInput
+                ) {
+                    activity = true;
+                }
+                if event.event_type() != NegiconEventType::RawAlpha {
+                    state.replay.push_overwrite(event);
+                }
+                traffic = true;
+                broadcast(upstreams, event)
+            });
+            if let Err(e) = res {
+                debug!("Error while polling downstream: {:?}", e);
+                traffic = true;
+                let event = e.to_event(slot as u16);
+                state.replay.push_overwrite(event);
+                broadcast(upstreams, event);
+                if let Some(event) = e.challenge_event(slot as u16) {
+                    state.replay.push_overwrite(event);
+                    broadcast(upstreams, event);
+                }
+            }
+            state.record_transfers(failed_before, spi.failed_transfers());
+            if state.next_slot == 0 {
+                state.finish_round();
+                if !state.deferred_writes.is_empty() {
+                    age_deferred_writes(state, downstreams);
+                }
+            }
+            let elapsed = board.now().checked_duration_since(start);
+            if elapsed.is_some_and(|e| e >= budget) {
+                if polled < downstreams.len() {
+                    debug!("Poll budget used up after {} slots", polled);
+                }
+                break;
+            }
+        }
+        let now = board.now();
+        if traffic {
+            state.heartbeat.record_traffic(now);
+        } else if state.heartbeat.poll(now) {
+            let detected = downstreams.iter().filter(|ds| ds.is_connected()).count();
+            let uptime = now.duration_since_epoch().to_secs() as u16 as i16;
+            broadcast(
+                upstreams,
+                NegiconEvent::new(NegiconEventType::Heartbeat, detected as u16, uptime, 0, 0),
+            );
+        }
+        match state.idle.update(board.now(), activity) {
+            PowerState::Active => board.schedule_poll(state.config.poll_interval()),
+            PowerState::Idle => board.schedule_poll(IDLE_POLL_INTERVAL),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::DEFAULT_POLL_INTERVAL_MS,
+        downstream::{
+            mlx90363::MlxOpcode,
+            mock::{MockPin, MockSpi, NoDelay},
+            spi_downstream::{
+                ButtonMapping, DownstreamDevice, DownstreamError, DownstreamKind,
+                DownstreamSettings, DownstreamState, ReportMode,
+            },
+            spi_protocol::{
+                NopMessage, NOP_CHALLENGE_SEED, NOP_REPLY_OPCODE_MLX, NOP_REPLY_OPCODE_RP,
+            },
+        },
+        negicon_event::{
+            CONFIG_QUERY_BASE, DIAGNOSTIC_QUERY_BASE, STATS_QUERY_BASE, VERSION_QUERY_BASE,
+        },
+        upstream::{mock::MockUpstream, ringbuf::BUFFER_SIZE},
+    };
+    use alloc::{boxed::Box, rc::Rc, vec::Vec};
+    use core::{cell::Cell, convert::Infallible};
+    use embedded_hal::digital::v2::OutputPin;
+
+    /// Active poll interval of the default config
+    const POLL_INTERVAL: MicrosDurationU64 =
+        MicrosDurationU64::millis(DEFAULT_POLL_INTERVAL_MS as u64);
+
+    struct MockBoard {
+        now: Instant,
+        /// Time that passes with every read of the clock, so polling takes
+        /// time
+        read_cost: MicrosDurationU64,
+        reads: Cell<u32>,
+        due: bool,
+        scheduled: Option<MicrosDurationU64>,
+        reboots: Vec<RebootKind>,
+        stored: Option<Config>,
+    }
+
+    impl MockBoard {
+        fn new() -> Self {
+            Self {
+                now: Instant::from_ticks(0),
+                read_cost: MicrosDurationU64::from_ticks(0),
+                reads: Cell::new(0),
+                due: true,
+                scheduled: None,
+                reboots: Vec::new(),
+                stored: None,
+            }
+        }
+    }
+
+    impl Board for MockBoard {
+        fn now(&self) -> Instant {
+            let reads = self.reads.get();
+            self.reads.set(reads + 1);
+            self.now + self.read_cost * reads
+        }
+
+        fn poll_due(&mut self) -> bool {
+            self.due
+        }
+
+        fn schedule_poll(&mut self, interval: MicrosDurationU64) {
+            self.scheduled = Some(interval);
+        }
+
+        fn peripheral_freq(&self) -> HertzU32 {
+            125.MHz()
+        }
+
+        fn reboot(&mut self, kind: RebootKind) {
+            self.reboots.push(kind);
+        }
+
+        fn store_config(&mut self, config: &Config) {
+            self.stored = Some(*config);
+        }
+    }
+
+    /// Device that reports each value written to it on the following poll
+    struct Knob {
+        id: u16,
+        written: Option<i16>,
+    }
+
+    impl DownstreamDevice<MockSpi> for Knob {
+        fn kind(&self) -> DownstreamKind {
+            DownstreamKind::Rp2040
+        }
+
+        fn poll(
+            &mut self,
+            _spi: &mut MockSpi,
+            _cs: &mut dyn OutputPin<Error = Infallible>,
+            _now: Instant,
+            sink: &mut dyn FnMut(NegiconEvent),
+        ) -> Result<(), DownstreamError> {
+            if let Some(value) = self.written.take() {
+                sink(NegiconEvent::new(
+                    NegiconEventType::Input,
+                    self.id,
+                    value,
+                    0,
+                    0,
+                ));
+            }
+            Ok(())
+        }
+
+        fn id(&self) -> Option<u16> {
+            Some(self.id)
+        }
+
+        fn write_memory(&mut self, cells: &[(u8, i16)]) {
+            self.written = cells.last().map(|&(_, value)| value);
+        }
+    }
+
+    fn host_event(event_type: NegiconEventType, id: u16, value: i16) -> NegiconEvent {
+        NegiconEvent::new(event_type, id, value, 0, 0x10)
+    }
+
+    #[test]
+    fn tick_applies_a_queued_write_before_polling() {
+        let mut spi = MockSpi::default();
+        let (mut cs0, mut cs1) = (MockPin::new(), MockPin::new());
+        let mut downstreams = [SpiDownstream::new(&mut cs0), SpiDownstream::new(&mut cs1)];
+        downstreams[1].device = DownstreamState::Initialized(Box::new(Knob {
+            id: 7,
+            written: None,
+        }));
+        let mut host = MockUpstream::default();
+        host.incoming
+            .push_back(Some(host_event(NegiconEventType::MemWrite, 7, 42)));
+        let mut board = MockBoard::new();
+        let mut state = LoopState::new(Config::default());
+
+        {
+            let mut upstreams = [Upstream::new(&mut host)];
+            tick(
+                &mut state,
+                &mut downstreams,
+                &mut spi,
+                &mut upstreams,
+                &mut NoDelay,
+                &mut board,
+            );
+            // Queued events go out at the start of the next tick
+            board.due = false;
+            tick(
+                &mut state,
+                &mut downstreams,
+                &mut spi,
+                &mut upstreams,
+                &mut NoDelay,
+                &mut board,
+            );
+        }
+
+        let sent: Vec<_> = host
+            .sent
+            .iter()
+            .map(|e| (e.event_type() as u8, e.id(), e.value()))
+            .collect();
+        assert_eq!(sent, [(NegiconEventType::Input as u8, 7, 42)]);
+        // The empty slot isn't due for a probe yet
+        assert!(spi.sent.is_empty());
+    }
+
+    /// MLX frame with the given opcode, answering the previous transfer
+    fn mlx_frame(opcode: MlxOpcode, data: [u8; 6]) -> [u8; 8] {
+        let [d0, d1, d2, d3, d4, d5] = data;
+        [d0, d1, d2, d3, d4, d5, 0xC0 | opcode as u8, 0]
+    }
+
+    #[test]
+    fn write_before_detection_is_applied_once_the_device_shows_up() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut downstreams = [SpiDownstream::new(&mut cs)];
+        let mut host = MockUpstream::default();
+        host.incoming
+            .push_back(Some(host_event(NegiconEventType::MemWrite, 7, 42)));
+        let mut board = MockBoard::new();
+        let mut state = LoopState::new(Config::default());
+        // A freshly powered MLX answers the first probe of the empty slot,
+        // then reads id 7 and erased limits, mounting and scaling
+        spi.reply(mlx_frame(MlxOpcode::ReadyMessage, [0x03, 0x41, 0, 0, 0, 0]));
+        for [lo, hi] in [[7, 0], [0, 0], [0xFF, 0xFF], [0xFF, 0xFF]] {
+            spi.reply(mlx_frame(MlxOpcode::NothingToTransmit, [0; 6]));
+            spi.reply(mlx_frame(
+                MlxOpcode::MemoryReadAnswer,
+                [lo, hi, lo, hi, 0, 0],
+            ));
+        }
+        let mut upstreams = [Upstream::new(&mut host)];
+        let mut tick_once = |state: &mut LoopState, downstreams: &mut [SpiDownstream<'_, _>]| {
+            board.now += POLL_INTERVAL;
+            tick(
+                state,
+                downstreams,
+                &mut spi,
+                &mut upstreams,
+                &mut NoDelay,
+                &mut board,
+            );
+        };
+
+        let mut ticks = 0;
+        while downstreams[0].id().is_none() {
+            tick_once(&mut state, &mut downstreams);
+            assert_eq!(state.deferred_writes.len(), 1);
+            ticks += 1;
+            assert!(ticks < 2 * DEFERRED_WRITE_ROUNDS);
+        }
+        tick_once(&mut state, &mut downstreams);
+        assert!(state.deferred_writes.is_empty());
+        // The write's leading NOP went out instead of a GET1
+        assert_eq!(
+            spi.sent.last().map(|frame| frame[6] & 0x3F),
+            Some(MlxOpcode::NOPChallenge as u8)
+        );
+    }
+
+    #[test]
+    fn deferred_write_for_an_absent_id_runs_out_of_rounds() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut downstreams = [SpiDownstream::new(&mut cs)];
+        downstreams[0].device = DownstreamState::Initialized(Box::new(Knob {
+            id: 7,
+            written: None,
+        }));
+        let mut host = MockUpstream::default();
+        host.incoming
+            .push_back(Some(host_event(NegiconEventType::MemWrite, 9, 42)));
+        let mut board = MockBoard::new();
+        let mut state = LoopState::new(Config::default());
+        let mut upstreams = [Upstream::new(&mut host)];
+
+        // Each tick is a round over the single slot
+        let mut left = Vec::new();
+        for _ in 0..DEFERRED_WRITE_ROUNDS {
+            board.now += POLL_INTERVAL;
+            tick(
+                &mut state,
+                &mut downstreams,
+                &mut spi,
+                &mut upstreams,
+                &mut NoDelay,
+                &mut board,
+            );
+            left.push(state.deferred_writes.len());
+        }
+        assert!(left[..left.len() - 1].iter().all(|&n| n == 1));
+        assert_eq!(left.last(), Some(&0));
+    }
+
+    #[test]
+    fn later_write_for_a_deferred_id_waits_behind_it() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut downstreams = [SpiDownstream::new(&mut cs)];
+        let mut host = MockUpstream::default();
+        host.incoming
+            .push_back(Some(host_event(NegiconEventType::MemWrite, 7, 42)));
+        host.incoming.push_back(None);
+        host.incoming
+            .push_back(Some(host_event(NegiconEventType::MemWrite, 7, 43)));
+        let mut board = MockBoard::new();
+        let mut state = LoopState::new(Config::default());
+
+        {
+            let mut upstreams = [Upstream::new(&mut host)];
+            // Nothing on the slot yet, so the probe finds no device
+            spi.reply_garbage();
+            tick(
+                &mut state,
+                &mut downstreams,
+                &mut spi,
+                &mut upstreams,
+                &mut NoDelay,
+                &mut board,
+            );
+            assert_eq!(state.deferred_writes.len(), 1);
+
+            downstreams[0].device = DownstreamState::Initialized(Box::new(Knob {
+                id: 7,
+                written: None,
+            }));
+            board.now += MicrosDurationU64::millis(1);
+            // The second write waits behind the first, then both are applied
+            // before the slot is polled
+            tick(
+                &mut state,
+                &mut downstreams,
+                &mut spi,
+                &mut upstreams,
+                &mut NoDelay,
+                &mut board,
+            );
+            assert!(state.deferred_writes.is_empty());
+            board.due = false;
+            tick(
+                &mut state,
+                &mut downstreams,
+                &mut spi,
+                &mut upstreams,
+                &mut NoDelay,
+                &mut board,
+            );
+        }
+
+        let sent: Vec<_> = host.sent.iter().map(|e| (e.id(), e.value())).collect();
+        assert_eq!(sent, [(7, 43)]);
+    }
+
+    #[test]
+    fn writes_are_not_deferred_without_device_state() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut downstreams = [SpiDownstream::new(&mut cs)];
+        downstreams[0].disable_device_state();
+        let mut host = MockUpstream::default();
+        let mut board = MockBoard::new();
+        let mut state = LoopState::new(Config::default());
+        let mut up = Upstream::new(&mut host);
+
+        for event_type in [NegiconEventType::MemWriteBatch, NegiconEventType::MemWrite] {
+            dispatch(
+                host_event(event_type, 7, 42),
+                &mut up,
+                &mut state,
+                &mut downstreams,
+                &mut spi,
+                &mut board,
+            );
+        }
+        assert_eq!(state.deferred_writes.capacity(), 0);
+    }
+
+    #[test]
+    fn deferred_writes_beyond_the_limit_drop_the_oldest() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut downstreams = [SpiDownstream::new(&mut cs)];
+        let mut host = MockUpstream::default();
+        let mut board = MockBoard::new();
+        let mut state = LoopState::new(Config::default());
+        let mut up = Upstream::new(&mut host);
+
+        for value in 0..=MAX_DEFERRED_WRITES as i16 {
+            dispatch(
+                host_event(NegiconEventType::MemWrite, 7, value),
+                &mut up,
+                &mut state,
+                &mut downstreams,
+                &mut spi,
+                &mut board,
+            );
+        }
+        assert_eq!(state.deferred_writes.len(), MAX_DEFERRED_WRITES);
+        assert_eq!(state.deferred_writes[0].event.value(), 1);
+    }
+
+    #[test]
+    fn blocked_events_config_reaches_the_upstreams() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut downstreams = [SpiDownstream::new(&mut cs)];
+        downstreams[0].device = DownstreamState::Initialized(Box::new(Knob {
+            id: 7,
+            written: None,
+        }));
+        let mut host = MockUpstream::default();
+        // ConfigKey::BlockedEvents, blocking Input
+        let mask = 1 << NegiconEventType::Input as u16;
+        host.incoming
+            .push_back(Some(host_event(NegiconEventType::Config, 17, mask)));
+        host.incoming
+            .push_back(Some(host_event(NegiconEventType::MemWrite, 7, 42)));
+        let mut board = MockBoard::new();
+        let mut state = LoopState::new(Config::default());
+
+        {
+            let mut upstreams = [Upstream::new(&mut host)];
+            for _ in 0..2 {
+                tick(
+                    &mut state,
+                    &mut downstreams,
+                    &mut spi,
+                    &mut upstreams,
+                    &mut NoDelay,
+                    &mut board,
+                );
+            }
+        }
+
+        assert_eq!(state.config.blocked_events, mask as u16);
+        assert!(host.sent.is_empty());
+        // Blocked events are still kept for replay
+        assert_eq!(state.replay.iter().count(), 1);
+    }
+
+    #[test]
+    fn replay_query_sends_the_retained_events_and_clears_on_request() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut downstreams = [SpiDownstream::new(&mut cs)];
+        downstreams[0].device = DownstreamState::Initialized(Box::new(Knob {
+            id: 7,
+            written: None,
+        }));
+        let mut host = MockUpstream::default();
+        host.incoming
+            .push_back(Some(host_event(NegiconEventType::MemWrite, 7, 42)));
+        let mut board = MockBoard::new();
+        let mut state = LoopState::new(Config::default());
+
+        {
+            let mut upstreams = [Upstream::new(&mut host)];
+            tick(
+                &mut state,
+                &mut downstreams,
+                &mut spi,
+                &mut upstreams,
+                &mut NoDelay,
+                &mut board,
+            );
+            assert!(upstreams[0].send().is_ok());
+        }
+        let retained: Vec<_> = state.replay.iter().map(|e| (e.id(), e.value())).collect();
+        assert_eq!(retained, [(7, 42)]);
+
+        for clear in [0, 1] {
+            let mut up = Upstream::new(&mut host);
+            assert!(up.receive().is_ok());
+            dispatch(
+                host_event(NegiconEventType::Query, 2, clear),
+                &mut up,
+                &mut state,
+                &mut downstreams,
+                &mut spi,
+                &mut board,
+            );
+            for _ in 0..2 {
+                assert!(up.send().is_ok());
+            }
+        }
+        assert!(state.replay.iter().next().is_none());
+
+        let sent: Vec<_> = host
+            .sent
+            .iter()
+            .map(|e| (e.event_type() as u8, e.id(), e.value(), e.sequence()))
+            .collect();
+        let input = NegiconEventType::Input as u8;
+        let replay = (NegiconEventType::Replay as u8, 7, 42, input);
+        let answer = (NegiconEventType::Query as u8, 2, 1, 0);
+        assert_eq!(sent, [(input, 7, 42, 0), replay, answer, replay, answer]);
+    }
+
+    #[test]
+    fn full_replay_ring_is_paged_through_the_upstream_queue() {
+        let mut spi = MockSpi::default();
+        let mut downstreams: [SpiDownstream<'_, MockSpi>; 0] = [];
+        let mut host = MockUpstream::default();
+        let mut board = MockBoard::new();
+        let mut state = LoopState::new(Config::default());
+        for id in 0..BUFFER_SIZE as u16 {
+            state
+                .replay
+                .push_overwrite(NegiconEvent::new(NegiconEventType::Input, id, 1, 0, 0));
+        }
+
+        let mut answers = Vec::new();
+        for _ in 0..2 {
+            let mut up = Upstream::new(&mut host);
+            assert!(up.receive().is_ok());
+            dispatch(
+                host_event(NegiconEventType::Query, 2, 1),
+                &mut up,
+                &mut state,
+                &mut downstreams,
+                &mut spi,
+                &mut board,
+            );
+            assert_eq!(up.dropped_count(), 0);
+            while up.free_slots() < BUFFER_SIZE {
+                assert!(up.send().is_ok());
+            }
+            answers.push(host.sent.pop().map(|e| (e.event_type(), e.value())));
+        }
+
+        // The answer took the last slot, the event left out came next
+        let query = NegiconEventType::Query;
+        assert!(answers == [Some((query, 99)), Some((query, 1))]);
+        let ids: Vec<_> = host.sent.iter().map(|e| e.id()).collect();
+        assert_eq!(ids, (0..BUFFER_SIZE as u16).collect::<Vec<_>>());
+        assert!(state.replay.iter().next().is_none());
+    }
+
+    /// Runs one tick with `count` queued host events and returns how many of
+    /// them are left over, along with the polls the downstream saw
+    fn tick_with_burst(count: usize) -> (usize, u32) {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut downstreams = [SpiDownstream::new(&mut cs)];
+        downstreams[0].device = DownstreamState::Initialized(Box::new(Knob {
+            id: 7,
+            written: None,
+        }));
+        let mut host = MockUpstream::default();
+        for value in 0..count as i16 {
+            host.incoming
+                .push_back(Some(host_event(NegiconEventType::MemWrite, 7, value)));
+        }
+        let mut board = MockBoard::new();
+        {
+            let mut upstreams = [Upstream::new(&mut host)];
+            tick(
+                &mut LoopState::new(Config::default()),
+                &mut downstreams,
+                &mut spi,
+                &mut upstreams,
+                &mut NoDelay,
+                &mut board,
+            );
+        }
+        (host.incoming.len(), downstreams[0].stats().polls)
+    }
+
+    #[test]
+    fn tick_handles_a_burst_of_host_events() {
+        assert_eq!(tick_with_burst(5), (0, 1));
+    }
+
+    #[test]
+    fn long_burst_waits_for_the_next_tick_without_holding_up_polling() {
+        let left = 3;
+        assert_eq!(tick_with_burst(MAX_HOST_EVENTS_PER_TICK + left), (left, 1));
+    }
+
+    #[test]
+    fn downstreams_wait_for_the_poll_interval() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut downstreams = [SpiDownstream::new(&mut cs)];
+        let mut host = MockUpstream::default();
+        let mut board = MockBoard::new();
+        board.due = false;
+
+        let mut upstreams = [Upstream::new(&mut host)];
+        tick(
+            &mut LoopState::new(Config::default()),
+            &mut downstreams,
+            &mut spi,
+            &mut upstreams,
+            &mut NoDelay,
+            &mut board,
+        );
+
+        assert!(spi.sent.is_empty());
+    }
+
+    #[test]
+    fn downstreams_are_left_alone_for_the_startup_delay() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut downstreams = [SpiDownstream::new(&mut cs)];
+        downstreams[0].device = DownstreamState::Initialized(Box::new(Knob {
+            id: 7,
+            written: None,
+        }));
+        let mut host = MockUpstream::default();
+        let mut board = MockBoard::new();
+        let mut state = LoopState::new(Config {
+            startup_delay_ms: 300,
+            ..Config::default()
+        });
+        let start = Instant::from_ticks(5_000_000);
+        state.hold_off_polling(start);
+        let mut upstreams = [Upstream::new(&mut host)];
+
+        let mut polls = Vec::new();
+        for ms in [0, 299, 300] {
+            board.now = start + MicrosDurationU64::millis(ms);
+            board.scheduled = None;
+            tick(
+                &mut state,
+                &mut downstreams,
+                &mut spi,
+                &mut upstreams,
+                &mut NoDelay,
+                &mut board,
+            );
+            assert!(board.scheduled == Some(POLL_INTERVAL));
+            polls.push(downstreams[0].stats().polls);
+        }
+        assert_eq!(polls, [0, 0, 1]);
+    }
+
+    #[test]
+    fn host_events_reach_the_board_and_the_bus() {
+        let mut spi = MockSpi::default();
+        let mut board = MockBoard::new();
+        let mut downstreams: [SpiDownstream<'_, MockSpi>; 0] = [];
+        let mut host = MockUpstream::default();
+        let mut up = Upstream::new(&mut host);
+
+        for event in [
+            host_event(NegiconEventType::Config, 0, 1000),
+            host_event(NegiconEventType::Reboot, 0, 1),
+        ] {
+            dispatch(
+                event,
+                &mut up,
+                &mut LoopState::new(Config::default()),
+                &mut downstreams,
+                &mut spi,
+                &mut board,
+            );
+        }
+
+        assert_eq!(spi.clock, Some(1.MHz()));
+        assert!(board.reboots == [RebootKind::Restart]);
+    }
+
+    #[test]
+    fn cs_settle_config_is_clamped_and_reaches_the_bus() {
+        let mut spi = MockSpi::default();
+        let mut board = MockBoard::new();
+        let mut downstreams: [SpiDownstream<'_, MockSpi>; 0] = [];
+        let mut host = MockUpstream::default();
+        let mut up = Upstream::new(&mut host);
+        let mut state = LoopState::new(Config::default());
+        // ConfigKey::CsSettle
+        let key = 8;
+
+        for (value, expected) in [(30, 30), (1000, MAX_CS_SETTLE_US), (-5, 0)] {
+            dispatch(
+                host_event(NegiconEventType::Config, key, value),
+                &mut up,
+                &mut state,
+                &mut downstreams,
+                &mut spi,
+                &mut board,
+            );
+            assert_eq!(state.config.cs_settle_us, expected);
+            assert_eq!(
+                spi.cs_settle,
+                Some(MicrosDurationU64::micros(expected as u64))
+            );
+        }
+    }
+
+    #[test]
+    fn startup_delay_config_is_clamped() {
+        let mut spi = MockSpi::default();
+        let mut board = MockBoard::new();
+        let mut downstreams: [SpiDownstream<'_, MockSpi>; 0] = [];
+        let mut host = MockUpstream::default();
+        let mut up = Upstream::new(&mut host);
+        let mut state = LoopState::new(Config::default());
+        // ConfigKey::StartupDelay
+        let key = 18;
+
+        for (value, expected) in [(300, 300), (i16::MAX, MAX_STARTUP_DELAY_MS), (-5, 0)] {
+            dispatch(
+                host_event(NegiconEventType::Config, key, value),
+                &mut up,
+                &mut state,
+                &mut downstreams,
+                &mut spi,
+                &mut board,
+            );
+            assert_eq!(state.config.startup_delay_ms, expected);
+        }
+    }
+
+    #[test]
+    fn dropped_events_query_reads_and_clears_the_counter() {
+        let mut spi = MockSpi::default();
+        let mut board = MockBoard::new();
+        let mut downstreams: [SpiDownstream<'_, MockSpi>; 0] = [];
+        let mut host = MockUpstream::default();
+        {
+            let mut up = Upstream::new(&mut host);
+            assert!(up.receive().is_ok());
+            let input = host_event(NegiconEventType::Input, 1, 1);
+            for _ in 0..BUFFER_SIZE + 3 {
+                let _ = up.enqueue(input);
+            }
+            // Make room for the answer
+            assert!(up.send().is_ok());
+
+            let query = host_event(NegiconEventType::Query, 0, 1);
+            dispatch(
+                query,
+                &mut up,
+                &mut LoopState::new(Config::default()),
+                &mut downstreams,
+                &mut spi,
+                &mut board,
+            );
+            assert_eq!(up.dropped_count(), 0);
+            for _ in 0..BUFFER_SIZE {
+                assert!(up.send().is_ok());
+            }
+        }
+
+        let answer = host.sent.last().unwrap();
+        assert!(answer.event_type() == NegiconEventType::Query);
+        assert_eq!(answer.value(), 3);
+    }
+
+    #[test]
+    fn polling_slows_down_without_input_and_recovers_on_input() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut downstreams = [SpiDownstream::new(&mut cs)];
+        downstreams[0].device = DownstreamState::Initialized(Box::new(Knob {
+            id: 7,
+            written: None,
+        }));
+        // One host event per tick: a 2 s idle timeout, two events that don't
+        // touch the downstreams, then a write the knob reports on
+        let mut host = MockUpstream::default();
+        host.incoming.extend([
+            Some(host_event(NegiconEventType::Config, 1, 2)),
+            None,
+            Some(host_event(NegiconEventType::Error, 0, 0)),
+            None,
+            Some(host_event(NegiconEventType::Error, 0, 0)),
+            None,
+            Some(host_event(NegiconEventType::MemWrite, 7, 1)),
+        ]);
+        let mut board = MockBoard::new();
+        let mut state = LoopState::new(Config::default());
+        let mut upstreams = [Upstream::new(&mut host)];
+
+        let mut scheduled = Vec::new();
+        for secs in 0..4 {
+            board.now = Instant::from_ticks(secs * 1_000_000);
+            tick(
+                &mut state,
+                &mut downstreams,
+                &mut spi,
+                &mut upstreams,
+                &mut NoDelay,
+                &mut board,
+            );
+            scheduled.push(board.scheduled.unwrap());
+        }
+
+        assert_eq!(
+            scheduled,
+            [
+                POLL_INTERVAL,
+                POLL_INTERVAL,
+                IDLE_POLL_INTERVAL,
+                POLL_INTERVAL
+            ]
+        );
+    }
+
+    #[test]
+    fn config_changes_are_queried_and_saved() {
+        let mut spi = MockSpi::default();
+        let mut board = MockBoard::new();
+        let mut downstreams: [SpiDownstream<'_, MockSpi>; 0] = [];
+        let mut host = MockUpstream::default();
+        let mut state = LoopState::new(Config::default());
+        {
+            let mut up = Upstream::new(&mut host);
+            assert!(up.receive().is_ok());
+            for event in [
+                host_event(NegiconEventType::Config, 1, 30),
+                host_event(NegiconEventType::Query, CONFIG_QUERY_BASE + 1, 0),
+                host_event(NegiconEventType::Config, 5, 0),
+                host_event(NegiconEventType::Config, 2, 0),
+            ] {
+                dispatch(
+                    event,
+                    &mut up,
+                    &mut state,
+                    &mut downstreams,
+                    &mut spi,
+                    &mut board,
+                );
+            }
+            assert!(up.send().is_ok());
+        }
+
+        let answer = host.sent[0];
+        assert_eq!((answer.id(), answer.value()), (CONFIG_QUERY_BASE + 1, 30));
+        assert!(board.stored.map(|c| c.idle_timeout_s) == Some(30));
+        assert!(board.stored.map(|c| c.flush_on_disconnect) == Some(false));
+    }
+
+    #[test]
+    fn poll_interval_is_clamped_and_scheduled_after_the_next_round() {
+        let mut spi = MockSpi::default();
+        let mut board = MockBoard::new();
+        let mut downstreams: [SpiDownstream<'_, MockSpi>; 0] = [];
+        let mut host = MockUpstream::default();
+        let mut state = LoopState::new(Config::default());
+        let mut upstreams = [Upstream::new(&mut host)];
+        let mut scheduled = Vec::new();
+        // ConfigKey::PollInterval
+        for value in [8, 1, -3, 500, MAX_POLL_INTERVAL_MS as i16] {
+            dispatch(
+                host_event(NegiconEventType::Config, 11, value),
+                &mut upstreams[0],
+                &mut state,
+                &mut downstreams,
+                &mut spi,
+                &mut board,
+            );
+            tick(
+                &mut state,
+                &mut downstreams,
+                &mut spi,
+                &mut upstreams,
+                &mut NoDelay,
+                &mut board,
+            );
+            scheduled.push(board.scheduled.unwrap().to_millis());
+        }
+
+        assert_eq!(scheduled, [8, 2, 2, 50, 50]);
+    }
+
+    #[test]
+    fn quiet_link_gets_a_heartbeat_with_the_device_count() {
+        let mut spi = MockSpi::default();
+        let (mut cs0, mut cs1) = (MockPin::new(), MockPin::new());
+        let mut downstreams = [SpiDownstream::new(&mut cs0), SpiDownstream::new(&mut cs1)];
+        downstreams[0].device = DownstreamState::Initialized(Box::new(Knob {
+            id: 7,
+            written: None,
+        }));
+        let mut host = MockUpstream::default();
+        // 500 ms interval, the input at 3 s pushes the heartbeat due then to 3.5 s
+        host.incoming.extend([
+            Some(host_event(NegiconEventType::Config, 3, 500)),
+            None,
+            Some(host_event(NegiconEventType::Error, 0, 0)),
+            None,
+            Some(host_event(NegiconEventType::Error, 0, 0)),
+            None,
+            Some(host_event(NegiconEventType::MemWrite, 7, 1)),
+        ]);
+        let mut board = MockBoard::new();
+        let mut state = LoopState::new(Config::default());
+        {
+            let mut upstreams = [Upstream::new(&mut host)];
+            for ms in [2000, 2400, 2500, 3000, 3400, 3500, 3600] {
+                board.now = Instant::from_ticks(ms * 1000);
+                tick(
+                    &mut state,
+                    &mut downstreams,
+                    &mut spi,
+                    &mut upstreams,
+                    &mut NoDelay,
+                    &mut board,
+                );
+            }
+            // Flushes the last tick
+            board.due = false;
+            tick(
+                &mut state,
+                &mut downstreams,
+                &mut spi,
+                &mut upstreams,
+                &mut NoDelay,
+                &mut board,
+            );
+        }
+
+        let sent: Vec<_> = host
+            .sent
+            .iter()
+            .map(|e| (e.event_type() as u8, e.id(), e.value()))
+            .collect();
+        let heartbeat = NegiconEventType::Heartbeat as u8;
+        assert_eq!(
+            sent,
+            [
+                (heartbeat, 1, 2),
+                (NegiconEventType::Input as u8, 7, 1),
+                (heartbeat, 1, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn suspended_host_pauses_the_downstreams() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut downstreams = [SpiDownstream::new(&mut cs)];
+        downstreams[0].device = DownstreamState::Initialized(Box::new(Knob {
+            id: 7,
+            written: None,
+        }));
+        let mut host = MockUpstream {
+            suspended: true,
+            ..MockUpstream::default()
+        };
+        host.incoming
+            .push_back(Some(host_event(NegiconEventType::MemWrite, 7, 5)));
+        let mut board = MockBoard::new();
+        let mut state = LoopState::new(Config::default());
+
+        {
+            let mut upstreams = [Upstream::new(&mut host)];
+            tick(
+                &mut state,
+                &mut downstreams,
+                &mut spi,
+                &mut upstreams,
+                &mut NoDelay,
+                &mut board,
+            );
+        }
+        assert_eq!(board.scheduled, Some(IDLE_POLL_INTERVAL));
+        assert!(host.sent.is_empty());
+
+        // The write is still pending on the knob and reported after resume
+        host.suspended = false;
+        {
+            let mut upstreams = [Upstream::new(&mut host)];
+            for _ in 0..2 {
+                tick(
+                    &mut state,
+                    &mut downstreams,
+                    &mut spi,
+                    &mut upstreams,
+                    &mut NoDelay,
+                    &mut board,
+                );
+            }
+        }
+        assert_eq!(board.scheduled, Some(POLL_INTERVAL));
+        let values: Vec<_> = host.sent.iter().map(|e| e.value()).collect();
+        assert_eq!(values, [5]);
+    }
+
+    #[test]
+    #[cfg(feature = "spi-upstream")]
+    fn relay_waiting_for_its_master_pauses_the_downstreams() {
+        use crate::{
+            negicon_event::REPORT_SIZE,
+            upstream::mock::{mock_relay, MockRelay},
+        };
+
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut downstreams = [SpiDownstream::new(&mut cs)];
+        let mut relay = mock_relay();
+        relay.set_wait_for_master(true);
+        let mut board = MockBoard::new();
+        let mut state = LoopState::new(Config::default());
+        let mut tick_relay = |relay: &mut MockRelay| {
+            let mut upstreams = [Upstream::new(relay)];
+            tick(
+                &mut state,
+                &mut downstreams,
+                &mut spi,
+                &mut upstreams,
+                &mut NoDelay,
+                &mut board,
+            );
+            board.scheduled
+        };
+
+        assert_eq!(tick_relay(&mut relay), Some(IDLE_POLL_INTERVAL));
+        relay.spi().clock([0; REPORT_SIZE]);
+        assert_eq!(tick_relay(&mut relay), Some(IDLE_POLL_INTERVAL));
+        // The master showed up during the last tick
+        assert_eq!(tick_relay(&mut relay), Some(POLL_INTERVAL));
+    }
+
+    #[test]
+    #[cfg(feature = "spi-upstream")]
+    fn corrupt_frames_query_reads_and_clears_the_relay_counter() {
+        use crate::{
+            downstream::spi_protocol::{set_crc, verify_crc},
+            negicon_event::REPORT_SIZE,
+            upstream::mock::{mock_relay, MockRelay},
+        };
+
+        let mut spi = MockSpi::default();
+        let mut downstreams: [SpiDownstream<'_, MockSpi>; 0] = [];
+        let mut relay = mock_relay();
+        let mut board = MockBoard::new();
+        board.due = false;
+        let mut state = LoopState::new(Config::default());
+        let mut tick_relay = |relay: &mut MockRelay| {
+            let mut upstreams = [Upstream::new(relay)];
+            for _ in 0..2 {
+                tick(
+                    &mut state,
+                    &mut downstreams,
+                    &mut spi,
+                    &mut upstreams,
+                    &mut NoDelay,
+                    &mut board,
+                );
+            }
+        };
+
+        let mut query = host_event(NegiconEventType::Query, 3, 1).serialize();
+        set_crc(&mut query);
+        let mut corrupted = query;
+        corrupted[3] ^= 0x10;
+        relay.spi().clock(corrupted);
+        tick_relay(&mut relay);
+        assert_eq!(relay.corrupt_count(), 1);
+
+        relay.spi().clock(query);
+        tick_relay(&mut relay);
+        let answer = relay.spi().clock([0; REPORT_SIZE]);
+        assert!(verify_crc(&answer).is_ok());
+        let answer = NegiconEvent::deserialize(answer).unwrap();
+        assert!(answer.event_type() == NegiconEventType::Query);
+        assert_eq!((answer.id(), answer.value()), (3, 1));
+        assert_eq!(relay.corrupt_count(), 0);
+    }
+
+    /// Device that only reports its revision
+    struct Revision(u16);
+
+    impl DownstreamDevice<MockSpi> for Revision {
+        fn kind(&self) -> DownstreamKind {
+            DownstreamKind::Mlx90363
+        }
+
+        fn poll(
+            &mut self,
+            _spi: &mut MockSpi,
+            _cs: &mut dyn OutputPin<Error = Infallible>,
+            _now: Instant,
+            _sink: &mut dyn FnMut(NegiconEvent),
+        ) -> Result<(), DownstreamError> {
+            Ok(())
+        }
+
+        fn version(&self) -> Option<u16> {
+            Some(self.0)
+        }
+    }
+
+    #[test]
+    fn version_query_answers_per_slot() {
+        let mut spi = MockSpi::default();
+        let mut board = MockBoard::new();
+        let (mut cs0, mut cs1) = (MockPin::new(), MockPin::new());
+        let mut downstreams = [SpiDownstream::new(&mut cs0), SpiDownstream::new(&mut cs1)];
+        downstreams[1].device = DownstreamState::Initialized(Box::new(Revision(0x0341)));
+        let mut host = MockUpstream::default();
+        {
+            let mut up = Upstream::new(&mut host);
+            assert!(up.receive().is_ok());
+            for slot in [0, 1, 2] {
+                dispatch(
+                    host_event(NegiconEventType::Query, VERSION_QUERY_BASE + slot, 0),
+                    &mut up,
+                    &mut LoopState::new(Config::default()),
+                    &mut downstreams,
+                    &mut spi,
+                    &mut board,
+                );
+            }
+            for _ in 0..3 {
+                assert!(up.send().is_ok());
+            }
+        }
+
+        let answers: Vec<_> = host.sent.iter().map(|e| e.value()).collect();
+        assert_eq!(answers, [-1, 0x0341, -1]);
+    }
+
+    #[test]
+    fn enumeration_lists_the_detected_downstreams() {
+        let mut spi = MockSpi::default();
+        let mut board = MockBoard::new();
+        let (mut cs0, mut cs1, mut cs2) = (MockPin::new(), MockPin::new(), MockPin::new());
+        let mut downstreams = [
+            SpiDownstream::new(&mut cs0),
+            SpiDownstream::new(&mut cs1),
+            SpiDownstream::new(&mut cs2),
+        ];
+        let knob = Knob {
+            id: 7,
+            written: None,
+        };
+        downstreams[0].attach(DownstreamKind::Rp2040, Box::new(knob));
+        // A sensor that hasn't reported its id yet
+        downstreams[2].attach(DownstreamKind::Mlx90363, Box::new(Revision(0x0341)));
+        let mut host = MockUpstream::default();
+        {
+            let mut up = Upstream::new(&mut host);
+            assert!(up.receive().is_ok());
+            dispatch(
+                host_event(NegiconEventType::Query, 1, 0),
+                &mut up,
+                &mut LoopState::new(Config::default()),
+                &mut downstreams,
+                &mut spi,
+                &mut board,
+            );
+            for _ in 0..3 {
+                assert!(up.send().is_ok());
+            }
+        }
+
+        let sent: Vec<_> = host
+            .sent
+            .iter()
+            .map(|e| (e.event_type() as u8, e.id(), e.value(), e.sequence()))
+            .collect();
+        let entry = NegiconEventType::Downstream as u8;
+        assert_eq!(
+            sent,
+            [
+                (entry, 7, 0, NOP_REPLY_OPCODE_RP),
+                (entry, 0xFFFF, 2, NOP_REPLY_OPCODE_MLX),
+                (NegiconEventType::Query as u8, 1, 2, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn stats_query_answers_per_slot_and_counter() {
+        let mut spi = MockSpi::default();
+        let mut board = MockBoard::new();
+        let mut cs = MockPin::new();
+        let mut downstreams = [SpiDownstream::new(&mut cs)];
+        downstreams[0].device = DownstreamState::Initialized(Box::new(Knob {
+            id: 7,
+            written: Some(1),
+        }));
+        assert!(downstreams[0]
+            .poll(&mut NoDelay, &mut spi, Instant::from_ticks(0), &mut |_| {})
+            .is_ok());
+        let mut host = MockUpstream::default();
+        {
+            let mut up = Upstream::new(&mut host);
+            assert!(up.receive().is_ok());
+            // Polls and events of slot 0, polls of the missing slot 1
+            for id in [STATS_QUERY_BASE, STATS_QUERY_BASE + 3, STATS_QUERY_BASE + 4] {
+                dispatch(
+                    host_event(NegiconEventType::Query, id, 0),
+                    &mut up,
+                    &mut LoopState::new(Config::default()),
+                    &mut downstreams,
+                    &mut spi,
+                    &mut board,
+                );
+            }
+            for _ in 0..3 {
+                assert!(up.send().is_ok());
+            }
+        }
+
+        let answers: Vec<_> = host.sent.iter().map(|e| e.value()).collect();
+        assert_eq!(answers, [1, 1, -1]);
+    }
+
+    #[test]
+    fn diagnostic_query_reports_device_state() {
+        let mut spi = MockSpi::default();
+        let mut board = MockBoard::new();
+        let (mut cs0, mut cs1) = (MockPin::new(), MockPin::new());
+        let mut downstreams = [SpiDownstream::new(&mut cs0), SpiDownstream::new(&mut cs1)];
+        downstreams[0].device = DownstreamState::Initialized(Box::new(Revision(1)));
+        let mut host = MockUpstream::default();
+        {
+            let mut up = Upstream::new(&mut host);
+            assert!(up.receive().is_ok());
+            // Flags, min and field strength of slot 0, flags of the empty
+            // slot 1
+            for id in [
+                DIAGNOSTIC_QUERY_BASE,
+                DIAGNOSTIC_QUERY_BASE + 1,
+                DIAGNOSTIC_QUERY_BASE + 3,
+                DIAGNOSTIC_QUERY_BASE + 4,
+            ] {
+                dispatch(
+                    host_event(NegiconEventType::Query, id, 0),
+                    &mut up,
+                    &mut LoopState::new(Config::default()),
+                    &mut downstreams,
+                    &mut spi,
+                    &mut board,
+                );
+            }
+            for _ in 0..4 {
+                assert!(up.send().is_ok());
+            }
+        }
+
+        let answers: Vec<_> = host.sent.iter().map(|e| e.value()).collect();
+        // Devices without limits report as initialized and relative, and
+        // only sensors have a field strength
+        assert_eq!(answers, [1, -1, -1, -1]);
+    }
+
+    /// Device that counts how often it was told to re-read its parameters
+    struct Reinits(u16, Rc<Cell<u8>>);
+
+    impl DownstreamDevice<MockSpi> for Reinits {
+        fn kind(&self) -> DownstreamKind {
+            DownstreamKind::Mlx90363
+        }
+
+        fn poll(
+            &mut self,
+            _spi: &mut MockSpi,
+            _cs: &mut dyn OutputPin<Error = Infallible>,
+            _now: Instant,
+            _sink: &mut dyn FnMut(NegiconEvent),
+        ) -> Result<(), DownstreamError> {
+            Ok(())
+        }
+
+        fn id(&self) -> Option<u16> {
+            Some(self.0)
+        }
+
+        fn reinit(&mut self) {
+            self.1.set(self.1.get() + 1);
+        }
+    }
+
+    #[test]
+    fn reinit_reaches_only_the_addressed_device() {
+        let mut spi = MockSpi::default();
+        let mut board = MockBoard::new();
+        let (mut cs0, mut cs1) = (MockPin::new(), MockPin::new());
+        let mut downstreams = [SpiDownstream::new(&mut cs0), SpiDownstream::new(&mut cs1)];
+        let (first, second) = (Rc::new(Cell::new(0)), Rc::new(Cell::new(0)));
+        downstreams[0].device = DownstreamState::Initialized(Box::new(Reinits(4, first.clone())));
+        downstreams[1].device = DownstreamState::Initialized(Box::new(Reinits(6, second.clone())));
+        let mut host = MockUpstream::default();
+        let mut up = Upstream::new(&mut host);
+        for id in [6, 9] {
+            dispatch(
+                host_event(NegiconEventType::Reinit, id, 0),
+                &mut up,
+                &mut LoopState::new(Config::default()),
+                &mut downstreams,
+                &mut spi,
+                &mut board,
+            );
+        }
+
+        assert_eq!((first.get(), second.get()), (0, 1));
+    }
+
+    /// Device that sends a NOP on every poll and drops out when it fails
+    struct Talker;
+
+    impl DownstreamDevice<MockSpi> for Talker {
+        fn kind(&self) -> DownstreamKind {
+            DownstreamKind::Mlx90363
+        }
+
+        fn poll(
+            &mut self,
+            spi: &mut MockSpi,
+            cs: &mut dyn OutputPin<Error = Infallible>,
+            _now: Instant,
+            _sink: &mut dyn FnMut(NegiconEvent),
+        ) -> Result<(), DownstreamError> {
+            let mut frame = NopMessage::new(NOP_CHALLENGE_SEED).serialize();
+            spi.verified_transmit(cs, &mut frame)
+                .map_err(DownstreamError::SpiError)
+        }
+
+        fn id(&self) -> Option<u16> {
+            None
+        }
+    }
+
+    #[test]
+    fn polling_stops_at_the_budget_and_resumes_at_the_next_slot() {
+        let mut spi = MockSpi::default();
+        let mut cs = [(); 5].map(|_| MockPin::new());
+        let mut downstreams = cs.each_mut().map(|cs| SpiDownstream::new(cs));
+        for ds in downstreams.iter_mut() {
+            ds.attach(DownstreamKind::Mlx90363, Box::new(Revision(0x0341)));
+        }
+        let mut host = MockUpstream::default();
+        let mut board = MockBoard::new();
+        // Each slot reads the clock twice, so the 3.75 ms budget of the
+        // default 5 ms interval is used up after the second slot
+        board.read_cost = MicrosDurationU64::millis(1);
+        let mut state = LoopState::new(Config::default());
+        let mut upstreams = [Upstream::new(&mut host)];
+
+        let mut rounds = Vec::new();
+        for _ in 0..3 {
+            board.now += POLL_INTERVAL;
+            tick(
+                &mut state,
+                &mut downstreams,
+                &mut spi,
+                &mut upstreams,
+                &mut NoDelay,
+                &mut board,
+            );
+            let polls: Vec<_> = downstreams.iter().map(|ds| ds.stats().polls).collect();
+            rounds.push(polls);
+        }
+        assert_eq!(rounds, [[1, 1, 0, 0, 0], [1, 1, 1, 1, 0], [2, 1, 1, 1, 1]]);
+        assert_eq!(state.next_slot, 1);
+    }
+
+    #[test]
+    fn fast_round_polls_every_slot_from_where_the_last_one_stopped() {
+        let mut spi = MockSpi::default();
+        let mut cs = [(); 3].map(|_| MockPin::new());
+        let mut downstreams = cs.each_mut().map(|cs| SpiDownstream::new(cs));
+        for ds in downstreams.iter_mut() {
+            ds.attach(DownstreamKind::Mlx90363, Box::new(Revision(0x0341)));
+        }
+        let mut host = MockUpstream::default();
+        let mut board = MockBoard::new();
+        let mut state = LoopState::new(Config::default());
+        state.next_slot = 2;
+        let mut upstreams = [Upstream::new(&mut host)];
+
+        tick(
+            &mut state,
+            &mut downstreams,
+            &mut spi,
+            &mut upstreams,
+            &mut NoDelay,
+            &mut board,
+        );
+        let polls: Vec<_> = downstreams.iter().map(|ds| ds.stats().polls).collect();
+        assert_eq!(polls, [1, 1, 1]);
+        assert_eq!(state.next_slot, 2);
+    }
+
+    #[test]
+    fn bus_failing_on_every_slot_is_stuck_after_two_rounds() {
+        let mut spi = MockSpi::default();
+        let (mut cs0, mut cs1) = (MockPin::new(), MockPin::new());
+        let mut downstreams = [SpiDownstream::new(&mut cs0), SpiDownstream::new(&mut cs1)];
+        for ds in downstreams.iter_mut() {
+            ds.attach(DownstreamKind::Rp2040, Box::new(Talker));
+        }
+        let mut host = MockUpstream::default();
+        let mut board = MockBoard::new();
+        let mut state = LoopState::new(Config::default());
+        let mut upstreams = [Upstream::new(&mut host)];
+        let mut round = |spi: &mut MockSpi, downstreams: &mut [SpiDownstream<'_, MockSpi>]| {
+            board.now += POLL_INTERVAL;
+            tick(
+                &mut state,
+                downstreams,
+                spi,
+                &mut upstreams,
+                &mut NoDelay,
+                &mut board,
+            );
+            state.take_spi_stuck()
+        };
+
+        assert!(!round(&mut spi, &mut downstreams));
+        spi.stuck = true;
+        assert!(!round(&mut spi, &mut downstreams));
+        // The dropped devices keep being probed, and those probes fail too
+        assert!(round(&mut spi, &mut downstreams));
+    }
+
+    #[test]
+    fn one_slot_getting_through_keeps_the_bus_from_being_stuck() {
+        let mut state = LoopState::new(Config::default());
+        for _ in 0..SPI_RESET_ROUNDS * 2 {
+            state.record_transfers(0, 1);
+            state.record_transfers(1, 0);
+            state.finish_round();
+        }
+
+        assert!(!state.take_spi_stuck());
+    }
+
+    #[test]
+    fn rounds_without_transfers_leave_the_failed_rounds_alone() {
+        let mut state = LoopState::new(Config::default());
+        for _ in 0..SPI_RESET_ROUNDS {
+            state.record_transfers(3, 4);
+            state.record_transfers(4, 4);
+            state.finish_round();
+            // Only empty slots between their probes
+            state.record_transfers(4, 4);
+            state.finish_round();
+        }
+
+        assert!(state.take_spi_stuck());
+        assert!(!state.take_spi_stuck());
+    }
+
+    #[test]
+    fn slot_enable_reaches_the_slot_and_the_saved_config() {
+        let mut spi = MockSpi::default();
+        let mut board = MockBoard::new();
+        let (mut cs0, mut cs1) = (MockPin::new(), MockPin::new());
+        let mut downstreams = [SpiDownstream::new(&mut cs0), SpiDownstream::new(&mut cs1)];
+        let first = Rc::new(Cell::new(0));
+        downstreams[1].device = DownstreamState::Initialized(Box::new(Reinits(4, first)));
+        let mut host = MockUpstream::default();
+        let mut up = Upstream::new(&mut host);
+        let mut state = LoopState::new(Config::default());
+        // Slot 7 doesn't exist and is ignored
+        for (slot, value) in [(1, 0), (7, 0), (0, 0), (0, 1)] {
+            dispatch(
+                host_event(NegiconEventType::SlotEnable, slot, value),
+                &mut up,
+                &mut state,
+                &mut downstreams,
+                &mut spi,
+                &mut board,
+            );
+        }
+
+        assert!(!downstreams[1].is_connected());
+        assert_eq!(state.config.disabled_slots, 1 << 1);
+    }
+
+    /// Device that keeps the last settings it was handed where the test can
+    /// see them
+    struct SettingsProbe(Rc<Cell<Option<DownstreamSettings>>>);
+
+    impl DownstreamDevice<MockSpi> for SettingsProbe {
+        fn kind(&self) -> DownstreamKind {
+            DownstreamKind::Mlx90363
+        }
+
+        fn poll(
+            &mut self,
+            _spi: &mut MockSpi,
+            _cs: &mut dyn OutputPin<Error = Infallible>,
+            _now: Instant,
+            _sink: &mut dyn FnMut(NegiconEvent),
+        ) -> Result<(), DownstreamError> {
+            Ok(())
+        }
+
+        fn apply_settings(&mut self, settings: &DownstreamSettings) {
+            self.0.set(Some(*settings));
+        }
+    }
+
+    #[test]
+    fn downstream_settings_reach_running_devices() {
+        let mut spi = MockSpi::default();
+        let mut board = MockBoard::new();
+        let mut cs = MockPin::new();
+        let mut downstreams = [SpiDownstream::new(&mut cs)];
+        let seen = Rc::new(Cell::new(None));
+        downstreams[0].device = DownstreamState::Initialized(Box::new(SettingsProbe(seen.clone())));
+        let mut host = MockUpstream::default();
+        let mut state = LoopState::new(Config::default());
+        let mut up = Upstream::new(&mut host);
+        for event in [
+            host_event(NegiconEventType::Config, 6, 1),
+            host_event(NegiconEventType::Config, 4, 7),
+            // ConfigKey::ReportPeriod
+            host_event(NegiconEventType::Config, 9, 25),
+            // ConfigKey::MinRelativeStep, negative steps are taken as 0
+            host_event(NegiconEventType::Config, 10, 30),
+            host_event(NegiconEventType::Config, 10, -4),
+            host_event(NegiconEventType::Config, 10, 12),
+            // ConfigKey::DiagnosticFailLimit
+            host_event(NegiconEventType::Config, 12, 6),
+            // ConfigKey::InitAttempts
+            host_event(NegiconEventType::Config, 13, 7),
+            // ConfigKey::ButtonPressValue, ButtonReleaseValue, ButtonIdOffset
+            host_event(NegiconEventType::Config, 14, 0),
+            host_event(NegiconEventType::Config, 15, 1),
+            host_event(NegiconEventType::Config, 16, 50),
+            // ConfigKey::FrameIds
+            host_event(NegiconEventType::Config, 19, 1),
+        ] {
+            dispatch(
+                event,
+                &mut up,
+                &mut state,
+                &mut downstreams,
+                &mut spi,
+                &mut board,
+            );
+        }
+
+        let expected = DownstreamSettings {
+            prime_readings: 7,
+            raw_alpha: true,
+            report_mode: ReportMode::Periodic(25),
+            min_relative_step: 12,
+            diagnostic_fail_limit: 6,
+            init_attempts: 7,
+            button_mapping: ButtonMapping {
+                press: 0,
+                release: 1,
+                id_offset: 50,
+            },
+            frame_ids: true,
+        };
+        assert!(seen.get() == Some(expected));
+    }
+}
@@ -0,0 +1,475 @@
+use defmt::{info, warn, Format};
+use fugit::MicrosDurationU64;
+use rp2040_hal::rom_data;
+
+use crate::{
+    downstream::{
+        spi_downstream::{
+            ButtonMapping, DownstreamSettings, ReportMode, DEFAULT_DIAGNOSTIC_FAIL_LIMIT,
+            DEFAULT_INIT_ATTEMPTS, DEFAULT_PRIME_READINGS,
+        },
+        spi_protocol::{crc8, DOWNSTREAM_SPI_FREQ_HZ, MAX_CS_SETTLE_US},
+        util::{i16_from_le, put_u16_le, put_u32_le, u16_from_le, u32_from_le},
+    },
+    negicon_event::ConfigKey,
+};
+
+/// Offset of the config sector from the start of flash. This is the last 4K
+/// sector of the 2M flash, which `memory.x` keeps out of the firmware image.
+const CONFIG_FLASH_OFFSET: u32 = 2048 * 1024 - SECTOR_SIZE;
+const XIP_BASE: u32 = 0x1000_0000;
+const SECTOR_SIZE: u32 = 4096;
+const BLOCK_SIZE: u32 = 65536;
+const SECTOR_ERASE_CMD: u8 = 0x20;
+/// Smallest unit the flash can be programmed in
+const PAGE_SIZE: usize = 256;
+/// Size of the second stage bootloader at the start of flash
+const BOOT2_SIZE: usize = 256;
+
+const CONFIG_MAGIC: [u8; 2] = *b"NC";
+const CONFIG_VERSION: u8 = 17;
+/// magic, version, spi clock, idle timeout, heartbeat interval, prime
+/// readings, flush on disconnect, raw alpha, board id, cs settle, report
+/// period, disabled slots, min relative step, poll interval, diagnostic fail
+/// limit, init attempts, button mapping, blocked events, startup delay, frame
+/// ids, crc
+const CONFIG_LEN: usize = 46;
+
+/// Default interval between two downstream polling rounds, i.e. every slot is
+/// polled at 200 Hz
+pub(crate) const DEFAULT_POLL_INTERVAL_MS: u16 = 5;
+/// Shortest poll interval, below which a round over every slot may not fit
+pub(crate) const MIN_POLL_INTERVAL_MS: u16 = 2;
+/// Longest poll interval, the idle poll rate
+pub(crate) const MAX_POLL_INTERVAL_MS: u16 = 50;
+
+/// Default time the downstreams get to power up after boot
+const DEFAULT_STARTUP_DELAY_MS: u16 = 100;
+/// Longest startup delay. The upstreams are serviced meanwhile, but the
+/// host sees no input at all until it is over.
+pub(crate) const MAX_STARTUP_DELAY_MS: u16 = 5000;
+
+/// Global settings persisted in the RP2040's own flash, so they survive
+/// without any downstream attached.
+#[derive(PartialEq, Clone, Copy, Format)]
+pub(crate) struct Config {
+    pub(crate) spi_clock_khz: u16,
+    pub(crate) idle_timeout_s: u16,
+    pub(crate) heartbeat_interval_ms: u16,
+    pub(crate) prime_readings: u16,
+    pub(crate) flush_on_disconnect: bool,
+    pub(crate) raw_alpha: bool,
+    pub(crate) board_id: u8,
+    pub(crate) cs_settle_us: u16,
+    pub(crate) report_period: u16,
+    /// Bit n set if slot n is not polled
+    pub(crate) disabled_slots: u32,
+    pub(crate) min_relative_step: u16,
+    pub(crate) poll_interval_ms: u16,
+    pub(crate) diagnostic_fail_limit: u16,
+    pub(crate) init_attempts: u16,
+    pub(crate) button_mapping: ButtonMapping,
+    /// Bit n set if events of type n aren't sent upstream
+    pub(crate) blocked_events: u16,
+    pub(crate) startup_delay_ms: u16,
+    pub(crate) frame_ids: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            spi_clock_khz: (DOWNSTREAM_SPI_FREQ_HZ / 1000) as u16,
+            idle_timeout_s: 0,
+            heartbeat_interval_ms: 1000,
+            prime_readings: DEFAULT_PRIME_READINGS,
+            flush_on_disconnect: true,
+            raw_alpha: false,
+            board_id: 0,
+            cs_settle_us: 0,
+            report_period: 0,
+            disabled_slots: 0,
+            min_relative_step: 0,
+            poll_interval_ms: DEFAULT_POLL_INTERVAL_MS,
+            diagnostic_fail_limit: DEFAULT_DIAGNOSTIC_FAIL_LIMIT,
+            init_attempts: DEFAULT_INIT_ATTEMPTS,
+            button_mapping: ButtonMapping::default(),
+            blocked_events: 0,
+            startup_delay_ms: DEFAULT_STARTUP_DELAY_MS,
+            frame_ids: false,
+        }
+    }
+}
+
+impl Config {
+    /// Value of `key` as reported to the host
+    pub(crate) fn get(&self, key: ConfigKey) -> i16 {
+        match key {
+            ConfigKey::DownstreamSpiClock => self.spi_clock_khz as i16,
+            ConfigKey::IdleTimeout => self.idle_timeout_s as i16,
+            ConfigKey::HeartbeatInterval => self.heartbeat_interval_ms as i16,
+            ConfigKey::PrimeReadings => self.prime_readings as i16,
+            ConfigKey::FlushOnDisconnect => self.flush_on_disconnect as i16,
+            ConfigKey::RawAlpha => self.raw_alpha as i16,
+            ConfigKey::BoardId => self.board_id as i16,
+            ConfigKey::CsSettle => self.cs_settle_us as i16,
+            ConfigKey::ReportPeriod => self.report_period as i16,
+            ConfigKey::MinRelativeStep => self.min_relative_step as i16,
+            ConfigKey::PollInterval => self.poll_interval_ms as i16,
+            ConfigKey::DiagnosticFailLimit => self.diagnostic_fail_limit as i16,
+            ConfigKey::InitAttempts => self.init_attempts as i16,
+            ConfigKey::ButtonPressValue => self.button_mapping.press,
+            ConfigKey::ButtonReleaseValue => self.button_mapping.release,
+            ConfigKey::ButtonIdOffset => self.button_mapping.id_offset as i16,
+            ConfigKey::BlockedEvents => self.blocked_events as i16,
+            ConfigKey::StartupDelay => self.startup_delay_ms as i16,
+            ConfigKey::FrameIds => self.frame_ids as i16,
+            ConfigKey::Save => 0,
+        }
+    }
+
+    pub(crate) fn report_mode(&self) -> ReportMode {
+        ReportMode::from_period(self.report_period)
+    }
+
+    pub(crate) fn slot_enabled(&self, slot: usize) -> bool {
+        self.disabled_slots & (1 << slot) == 0
+    }
+
+    pub(crate) fn set_slot_enabled(&mut self, slot: usize, enabled: bool) {
+        if enabled {
+            self.disabled_slots &= !(1 << slot);
+        } else {
+            self.disabled_slots |= 1 << slot;
+        }
+    }
+
+    /// Active poll interval, clamped in case the stored one is out of range
+    pub(crate) fn poll_interval(&self) -> MicrosDurationU64 {
+        MicrosDurationU64::millis(
+            self.poll_interval_ms
+                .clamp(MIN_POLL_INTERVAL_MS, MAX_POLL_INTERVAL_MS) as u64,
+        )
+    }
+
+    pub(crate) fn idle_timeout(&self) -> Option<MicrosDurationU64> {
+        match self.idle_timeout_s {
+            0 => None,
+            secs => Some(MicrosDurationU64::secs(secs as u64)),
+        }
+    }
+
+    /// Time since boot before the downstreams are polled
+    pub(crate) fn startup_delay(&self) -> MicrosDurationU64 {
+        MicrosDurationU64::millis(self.startup_delay_ms as u64)
+    }
+
+    pub(crate) fn heartbeat_interval(&self) -> Option<MicrosDurationU64> {
+        match self.heartbeat_interval_ms {
+            0 => None,
+            ms => Some(MicrosDurationU64::millis(ms as u64)),
+        }
+    }
+
+    pub(crate) fn cs_settle(&self) -> MicrosDurationU64 {
+        MicrosDurationU64::micros(self.cs_settle_us as u64)
+    }
+
+    pub(crate) fn downstream_settings(&self) -> DownstreamSettings {
+        DownstreamSettings {
+            prime_readings: self.prime_readings,
+            raw_alpha: self.raw_alpha,
+            report_mode: self.report_mode(),
+            min_relative_step: self.min_relative_step,
+            diagnostic_fail_limit: self.diagnostic_fail_limit,
+            init_attempts: self.init_attempts,
+            button_mapping: self.button_mapping,
+            frame_ids: self.frame_ids,
+        }
+    }
+
+    pub(crate) fn serialize(&self) -> [u8; CONFIG_LEN] {
+        let mut data = [0u8; CONFIG_LEN];
+        data[..2].copy_from_slice(&CONFIG_MAGIC);
+        data[2] = CONFIG_VERSION;
+        put_u16_le(&mut data[3..5], self.spi_clock_khz);
+        put_u16_le(&mut data[5..7], self.idle_timeout_s);
+        put_u16_le(&mut data[7..9], self.heartbeat_interval_ms);
+        put_u16_le(&mut data[9..11], self.prime_readings);
+        put_u16_le(&mut data[11..13], self.flush_on_disconnect as u16);
+        put_u16_le(&mut data[13..15], self.raw_alpha as u16);
+        put_u16_le(&mut data[15..17], self.board_id as u16);
+        put_u16_le(&mut data[17..19], self.cs_settle_us);
+        put_u16_le(&mut data[19..21], self.report_period);
+        put_u32_le(&mut data[21..25], self.disabled_slots);
+        put_u16_le(&mut data[25..27], self.min_relative_step);
+        put_u16_le(&mut data[27..29], self.poll_interval_ms);
+        put_u16_le(&mut data[29..31], self.diagnostic_fail_limit);
+        put_u16_le(&mut data[31..33], self.init_attempts);
+        put_u16_le(&mut data[33..35], self.button_mapping.press as u16);
+        put_u16_le(&mut data[35..37], self.button_mapping.release as u16);
+        put_u16_le(&mut data[37..39], self.button_mapping.id_offset);
+        put_u16_le(&mut data[39..41], self.blocked_events);
+        put_u16_le(&mut data[41..43], self.startup_delay_ms);
+        put_u16_le(&mut data[43..45], self.frame_ids as u16);
+        data[CONFIG_LEN - 1] = crc8(&data[..CONFIG_LEN - 1]);
+        data
+    }
+
+    /// Returns `None` for erased flash, a foreign version or a bad checksum.
+    /// Values outside the range the firmware accepts are clamped into it.
+    pub(crate) fn deserialize(data: &[u8; CONFIG_LEN]) -> Option<Self> {
+        if data[..2] != CONFIG_MAGIC || data[2] != CONFIG_VERSION {
+            return None;
+        }
+        if crc8(&data[..CONFIG_LEN - 1]) != data[CONFIG_LEN - 1] {
+            return None;
+        }
+        Some(Self {
+            spi_clock_khz: u16_from_le(&data[3..5]),
+            idle_timeout_s: u16_from_le(&data[5..7]),
+            heartbeat_interval_ms: u16_from_le(&data[7..9]),
+            prime_readings: u16_from_le(&data[9..11]),
+            flush_on_disconnect: u16_from_le(&data[11..13]) != 0,
+            raw_alpha: u16_from_le(&data[13..15]) != 0,
+            board_id: u16_from_le(&data[15..17]) as u8,
+            cs_settle_us: u16_from_le(&data[17..19]).min(MAX_CS_SETTLE_US),
+            report_period: u16_from_le(&data[19..21]),
+            disabled_slots: u32_from_le(&data[21..25]),
+            min_relative_step: u16_from_le(&data[25..27]),
+            poll_interval_ms: u16_from_le(&data[27..29]),
+            diagnostic_fail_limit: u16_from_le(&data[29..31]),
+            init_attempts: u16_from_le(&data[31..33]),
+            button_mapping: ButtonMapping {
+                press: i16_from_le(&data[33..35]),
+                release: i16_from_le(&data[35..37]),
+                id_offset: u16_from_le(&data[37..39]),
+            },
+            blocked_events: u16_from_le(&data[39..41]),
+            startup_delay_ms: u16_from_le(&data[41..43]).min(MAX_STARTUP_DELAY_MS),
+            frame_ids: u16_from_le(&data[43..45]) != 0,
+        })
+    }
+
+    /// Reads the config from flash, falling back to the defaults if there is
+    /// no valid one.
+    pub(crate) fn load() -> Self {
+        let data = unsafe {
+            core::ptr::read_volatile((XIP_BASE + CONFIG_FLASH_OFFSET) as *const [u8; CONFIG_LEN])
+        };
+        match Self::deserialize(&data) {
+            Some(config) => {
+                info!("Loaded config {}", config);
+                config
+            }
+            None => {
+                warn!("No valid config in flash, using defaults");
+                Self::default()
+            }
+        }
+    }
+
+    /// Erases the config sector and writes the config to it. Interrupts are
+    /// off and XIP is unavailable while this runs, which takes tens of ms.
+    /// Core 1 is never started, otherwise it would have to be parked too.
+    pub(crate) fn store(&self) {
+        let mut page = [0xFFu8; PAGE_SIZE];
+        page[..CONFIG_LEN].copy_from_slice(&self.serialize());
+        // Copied while XIP still works, word aligned so it can run from RAM
+        let mut boot2 = [0u32; BOOT2_SIZE / 4];
+        unsafe {
+            core::ptr::copy_nonoverlapping(XIP_BASE as *const u32, boot2.as_mut_ptr(), boot2.len())
+        };
+        let rom = FlashRom {
+            connect_internal_flash: rom_data::connect_internal_flash::ptr(),
+            flash_exit_xip: rom_data::flash_exit_xip::ptr(),
+            flash_range_erase: rom_data::flash_range_erase::ptr(),
+            flash_range_program: rom_data::flash_range_program::ptr(),
+            flash_flush_cache: rom_data::flash_flush_cache::ptr(),
+        };
+        cortex_m::interrupt::free(|_| unsafe { write_config_sector(&rom, &page, &boot2) });
+        info!("Stored config {}", self);
+    }
+}
+
+/// ROM flash routines, looked up while XIP is still available
+struct FlashRom {
+    connect_internal_flash: unsafe extern "C" fn(),
+    flash_exit_xip: unsafe extern "C" fn(),
+    flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: unsafe extern "C" fn(),
+}
+
+/// Runs from RAM since flash can't be read while it is being written.
+/// Afterwards XIP is set up again by the RAM copy of boot2, like the SDK does,
+/// since the ROM's `flash_enter_cmd_xip` would leave flash in the slow 03h
+/// read mode.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn write_config_sector(
+    rom: &FlashRom,
+    page: &[u8; PAGE_SIZE],
+    boot2: &[u32; BOOT2_SIZE / 4],
+) {
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    (rom.flash_range_erase)(
+        CONFIG_FLASH_OFFSET,
+        SECTOR_SIZE as usize,
+        BLOCK_SIZE,
+        SECTOR_ERASE_CMD,
+    );
+    (rom.flash_range_program)(CONFIG_FLASH_OFFSET, page.as_ptr(), PAGE_SIZE);
+    (rom.flash_flush_cache)();
+    // Thumb code, so bit 0 is set. Boot2 returns when called with a nonzero lr.
+    let enter_xip: unsafe extern "C" fn() =
+        core::mem::transmute((boot2.as_ptr() as *const u8).add(1));
+    enter_xip();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changed() -> Config {
+        Config {
+            spi_clock_khz: 1000,
+            idle_timeout_s: 0xFFFF,
+            heartbeat_interval_ms: 250,
+            prime_readings: 3,
+            flush_on_disconnect: false,
+            raw_alpha: true,
+            board_id: 0x5A,
+            cs_settle_us: 40,
+            report_period: 12,
+            disabled_slots: 1 << 20 | 1 << 3,
+            min_relative_step: 5,
+            poll_interval_ms: 20,
+            diagnostic_fail_limit: 1,
+            init_attempts: 2,
+            button_mapping: ButtonMapping {
+                press: -3,
+                release: 0,
+                id_offset: 8,
+            },
+            blocked_events: 1 << 13 | 1,
+            startup_delay_ms: 2500,
+            frame_ids: true,
+        }
+    }
+
+    #[test]
+    fn default_round_trips() {
+        let config = Config::default();
+        assert!(Config::deserialize(&config.serialize()) == Some(config));
+    }
+
+    #[test]
+    fn every_field_round_trips() {
+        let config = changed();
+        assert!(Config::deserialize(&config.serialize()) == Some(config));
+    }
+
+    #[test]
+    fn slots_are_enabled_and_disabled_one_by_one() {
+        let mut config = Config::default();
+        assert!((0..21).all(|slot| config.slot_enabled(slot)));
+        config.set_slot_enabled(3, false);
+        config.set_slot_enabled(20, false);
+        config.set_slot_enabled(3, true);
+        assert_eq!(config.disabled_slots, 1 << 20);
+        assert!(!config.slot_enabled(20));
+        assert!(config.slot_enabled(3));
+    }
+
+    #[test]
+    fn erased_flash_is_rejected() {
+        assert!(Config::deserialize(&[0xFF; CONFIG_LEN]).is_none());
+    }
+
+    #[test]
+    fn foreign_magic_or_version_is_rejected() {
+        let mut data = changed().serialize();
+        data[0] ^= 1;
+        assert!(Config::deserialize(&data).is_none());
+
+        let mut data = changed().serialize();
+        data[2] = CONFIG_VERSION + 1;
+        data[CONFIG_LEN - 1] = crc8(&data[..CONFIG_LEN - 1]);
+        assert!(Config::deserialize(&data).is_none());
+    }
+
+    #[test]
+    fn any_flipped_bit_is_rejected() {
+        let data = changed().serialize();
+        for byte in 0..CONFIG_LEN {
+            for bit in 0..8 {
+                let mut corrupt = data;
+                corrupt[byte] ^= 1 << bit;
+                assert!(Config::deserialize(&corrupt).is_none(), "{} {}", byte, bit);
+            }
+        }
+    }
+
+    #[test]
+    fn stored_poll_interval_out_of_range_is_clamped() {
+        for (stored, used) in [
+            (0, MIN_POLL_INTERVAL_MS),
+            (20, 20),
+            (u16::MAX, MAX_POLL_INTERVAL_MS),
+        ] {
+            let config = Config {
+                poll_interval_ms: stored,
+                ..Config::default()
+            };
+            assert_eq!(config.poll_interval().to_millis(), used as u64);
+        }
+    }
+
+    #[test]
+    fn stored_cs_settle_out_of_range_is_clamped() {
+        for (stored, used) in [(0, 0), (40, 40), (u16::MAX, MAX_CS_SETTLE_US)] {
+            let config = Config {
+                cs_settle_us: stored,
+                ..Config::default()
+            };
+            let loaded = Config::deserialize(&config.serialize()).unwrap();
+            assert_eq!(loaded.cs_settle_us, used);
+        }
+    }
+
+    #[test]
+    fn stored_startup_delay_out_of_range_is_clamped() {
+        for (stored, used) in [(0, 0), (2500, 2500), (u16::MAX, MAX_STARTUP_DELAY_MS)] {
+            let config = Config {
+                startup_delay_ms: stored,
+                ..Config::default()
+            };
+            let loaded = Config::deserialize(&config.serialize()).unwrap();
+            assert_eq!(loaded.startup_delay_ms, used);
+        }
+    }
+
+    #[test]
+    fn zero_idle_timeout_never_idles() {
+        assert!(Config::default().idle_timeout().is_none());
+        let config = Config {
+            idle_timeout_s: 30,
+            ..Config::default()
+        };
+        assert_eq!(config.idle_timeout(), Some(MicrosDurationU64::secs(30)));
+    }
+
+    #[test]
+    fn zero_heartbeat_interval_disables_it() {
+        assert_eq!(
+            Config::default().heartbeat_interval(),
+            Some(MicrosDurationU64::millis(1000))
+        );
+        let config = Config {
+            heartbeat_interval_ms: 0,
+            ..Config::default()
+        };
+        assert!(config.heartbeat_interval().is_none());
+    }
+}
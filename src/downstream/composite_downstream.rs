@@ -0,0 +1,228 @@
+use core::convert::Infallible;
+
+use defmt::{debug, Format};
+use embedded_hal::digital::v2::OutputPin;
+use rp2040_hal::timer::Instant;
+
+use crate::negicon_event::{NegiconEvent, NegiconEventType};
+
+use super::{
+    spi_downstream::{DownstreamDevice, DownstreamError, DownstreamKind, DownstreamResult},
+    spi_protocol::NegiconProtocol,
+    util::{i16_from_le, u16_from_le},
+};
+
+const AXIS_READ_OPCODE: u8 = 0b11100000;
+const AXIS_REPLY_OPCODE: u8 = 0b11100001;
+/// Maximum number of sub-axes a satellite board may expose
+const MAX_AXES: usize = 8;
+/// A retried read may be answered for the wrong axis, which `poll` catches
+/// by the sub index in the reply.
+const AXIS_READ_ATTEMPTS: u8 = 2;
+
+/// Request for the value of one sub-axis of a satellite board.
+///
+/// Layout: `[sub, 0, 0, 0, 0, 0, opcode, crc]`
+struct AxisReadRequest {
+    sub: u8,
+}
+
+impl AxisReadRequest {
+    fn serialize(&self) -> [u8; 8] {
+        [self.sub, 0, 0, 0, 0, 0, AXIS_READ_OPCODE, 0]
+    }
+}
+
+/// Answer to an `AxisReadRequest`, sent by the satellite in the frame
+/// following the request.
+///
+/// Layout: `[value lo, value hi, id lo, id hi, sub, axis count, opcode, crc]`
+#[derive(Format)]
+struct AxisReply {
+    value: i16,
+    id: u16,
+    sub: u8,
+    axes: u8,
+}
+
+impl AxisReply {
+    fn deserialize(data: &[u8; 8]) -> DownstreamResult<Self> {
+        if data[6] != AXIS_REPLY_OPCODE {
+            return Err(DownstreamError::UnexpectedReply);
+        }
+        Ok(Self {
+            value: i16_from_le(&data[0..2]),
+            id: u16_from_le(&data[2..4]),
+            sub: data[4],
+            axes: data[5],
+        })
+    }
+}
+
+/// Satellite board exposing several axes behind a single CS line. Every axis
+/// is read with its own addressed request and reports its own logical id, so
+/// one poll can produce an event per axis.
+#[derive(Format)]
+pub(crate) struct CompositeDownstream {
+    /// Which satellite MCU answered the detection NOP
+    kind: DownstreamKind,
+    axes: u8,
+    last: [i16; MAX_AXES],
+}
+
+impl CompositeDownstream {
+    pub(crate) fn new(kind: DownstreamKind) -> Self {
+        Self {
+            kind,
+            axes: 1,
+            last: [0; MAX_AXES],
+        }
+    }
+
+    fn read(
+        spi: &mut impl NegiconProtocol,
+        cs: &mut dyn OutputPin<Error = Infallible>,
+        sub: u8,
+    ) -> DownstreamResult<[u8; 8]> {
+        let mut buf = AxisReadRequest { sub }.serialize();
+        spi.verified_transmit_retry(cs, &mut buf, AXIS_READ_ATTEMPTS)?;
+        Ok(buf)
+    }
+}
+
+impl<S> DownstreamDevice<S> for CompositeDownstream
+where
+    S: NegiconProtocol,
+{
+    fn poll(
+        &mut self,
+        spi: &mut S,
+        cs: &mut dyn OutputPin<Error = Infallible>,
+        _now: Instant,
+        sink: &mut dyn FnMut(NegiconEvent),
+    ) -> DownstreamResult {
+        // Replies lag one frame behind their request, so the first reply is
+        // discarded and the last request wraps around to axis 0 again.
+        // The requests are addressed with the axis count known at the start
+        // of the poll, a changed count takes effect on the next one.
+        Self::read(spi, cs, 0)?;
+        let axes = self.axes;
+        let mut sub = 0;
+        while sub < axes {
+            let buf = Self::read(spi, cs, (sub + 1) % axes)?;
+            let reply = AxisReply::deserialize(&buf)?;
+            if reply.sub != sub {
+                debug!("Expected axis {}, got {}", sub, reply);
+                return Err(DownstreamError::UnexpectedReply);
+            }
+            self.axes = reply.axes.clamp(1, MAX_AXES as u8);
+            if reply.value != self.last[sub as usize] {
+                self.last[sub as usize] = reply.value;
+                sink(NegiconEvent::new(
+                    NegiconEventType::Input,
+                    reply.id,
+                    reply.value,
+                    0,
+                    0,
+                ));
+            }
+            sub += 1;
+        }
+        Ok(())
+    }
+
+    fn kind(&self) -> DownstreamKind {
+        self.kind
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downstream::mock::{MockPin, MockSpi};
+    use alloc::vec::Vec;
+
+    fn axis_reply(sub: u8, axes: u8, id: u16, value: i16) -> [u8; 8] {
+        let [value_lo, value_hi] = value.to_le_bytes();
+        let [id_lo, id_hi] = id.to_le_bytes();
+        [
+            value_lo,
+            value_hi,
+            id_lo,
+            id_hi,
+            sub,
+            axes,
+            AXIS_REPLY_OPCODE,
+            0,
+        ]
+    }
+
+    #[test]
+    fn one_poll_emits_an_event_per_axis() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut dev = CompositeDownstream::new(DownstreamKind::Rp2040);
+        // The first poll only learns the axis count
+        spi.reply(axis_reply(0, 1, 0, 0));
+        spi.reply(axis_reply(0, 2, 10, 0));
+        assert!(dev
+            .poll(&mut spi, &mut cs, Instant::from_ticks(0), &mut |_| {})
+            .is_ok());
+        // Answer to the priming request, then one reply per axis
+        spi.reply(axis_reply(0, 2, 0, 0));
+        spi.reply(axis_reply(0, 2, 10, 100));
+        spi.reply(axis_reply(1, 2, 11, -5));
+
+        let mut events = Vec::new();
+        assert!(dev
+            .poll(&mut spi, &mut cs, Instant::from_ticks(0), &mut |e| events
+                .push(e))
+            .is_ok());
+
+        let got: Vec<_> = events.iter().map(|e| (e.id(), e.value())).collect();
+        assert_eq!(got, [(10, 100), (11, -5)]);
+        let subs: Vec<_> = spi.sent[2..].iter().map(|f| f[0]).collect();
+        assert_eq!(subs, [0, 1, 0]);
+    }
+
+    #[test]
+    fn unchanged_axes_stay_silent() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut dev = CompositeDownstream::new(DownstreamKind::Rp2040);
+        for _ in 0..2 {
+            spi.reply(axis_reply(0, 1, 0, 0));
+            spi.reply(axis_reply(0, 1, 10, 42));
+        }
+
+        let mut events = Vec::new();
+        assert!(dev
+            .poll(&mut spi, &mut cs, Instant::from_ticks(0), &mut |e| events
+                .push(e))
+            .is_ok());
+        assert!(dev
+            .poll(&mut spi, &mut cs, Instant::from_ticks(0), &mut |e| events
+                .push(e))
+            .is_ok());
+
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn out_of_order_reply_is_rejected() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut dev = CompositeDownstream {
+            kind: DownstreamKind::Rp2040,
+            axes: 2,
+            last: [0; MAX_AXES],
+        };
+        spi.reply(axis_reply(0, 2, 0, 0));
+        spi.reply(axis_reply(1, 2, 11, 7));
+
+        let res = dev.poll(&mut spi, &mut cs, Instant::from_ticks(0), &mut |_| {
+            panic!("no event expected")
+        });
+        assert!(matches!(res, Err(DownstreamError::UnexpectedReply)));
+    }
+}
@@ -1,21 +1,72 @@
-use core::{
-    convert::Infallible,
-    ops::{BitXor, Shl, Shr},
-};
+extern crate alloc;
+use core::convert::Infallible;
 
-use cortex_m::delay::Delay;
-use defmt::{debug, error, info, warn, Format};
+use alloc::vec::Vec;
+use defmt::{debug, error, info, warn, Format, Formatter};
 use embedded_hal::digital::v2::OutputPin;
-use rp2040_hal::{
-    spi::{Enabled, SpiDevice, ValidSpiPinout},
-    Spi,
-};
+use rp2040_hal::timer::Instant;
 
 use super::{
     spi_protocol::{NegiconProtocol, NopError, NopMessage, SpiError},
-    util::make_u16,
+    util::{put_u16_le, u16_from_be, u16_from_le},
 };
 
+/// Gap kept between consecutive frames of a multi-frame command sequence.
+pub(crate) const MLX_FRAME_GAP_US: u32 = 200;
+/// Time the MLX needs to erase and write an EEPROM cell. Frames in between
+/// would interrupt the write, so `MlxWrite` stays silent for this long.
+pub(crate) const MLX_EEPROM_WRITE_MS: u32 = 330;
+/// Key of the NOPs that only clock out the answer to the previous command.
+/// Their reply is that answer rather than the NOP echo, so the key is never
+/// checked.
+const FILLER_NOP_KEY: u16 = 0x3939;
+/// Frames per transfer when CRC or transfer errors occur. A write step
+/// answered out of turn after a retry fails the write like any other
+/// unexpected reply.
+const MLX_TRANSFER_ATTEMPTS: u8 = 2;
+/// NOPs sent waiting for the `Get3Ready` of a GET3 before giving up
+const GET3_READY_ATTEMPTS: u8 = 8;
+
+/// Number of distinct values of the 14-bit alpha angle (`0..=ALPHA_MAX`)
+pub(crate) const ALPHA_RANGE: i32 = 1 << 14;
+pub(crate) const ALPHA_MAX: i32 = ALPHA_RANGE - 1;
+/// Largest step between two readings that is taken at face value. Bigger
+/// steps are assumed to have wrapped around 0.
+pub(crate) const ALPHA_HALF: i32 = ALPHA_RANGE / 2;
+
+/// A 14-bit alpha angle, in `0..=ALPHA_MAX` by construction
+#[derive(PartialEq, Eq, Clone, Copy, Default, Format)]
+pub(crate) struct Angle14(u16);
+
+impl Angle14 {
+    /// Keeps the low 14 bits of `raw`, for angles taken off the wire.
+    pub(crate) fn from_bits(raw: u16) -> Self {
+        Self(raw & ALPHA_MAX as u16)
+    }
+
+    pub(crate) fn get(self) -> u16 {
+        self.0
+    }
+
+    /// Signed step from `from` to `self` the short way round, i.e. assuming
+    /// it wrapped around 0 if it is longer than half a turn. A step of
+    /// exactly half a turn is ambiguous and kept as is.
+    pub(crate) fn sub(self, from: Self) -> i16 {
+        let mut step = self.0 as i32 - from.0 as i32;
+        if step > ALPHA_HALF {
+            step -= ALPHA_RANGE;
+        } else if step < -ALPHA_HALF {
+            step += ALPHA_RANGE;
+        }
+        step as i16
+    }
+
+    /// Distance between `self` and `other` the short way round
+    pub(crate) fn diff(self, other: Self) -> u16 {
+        self.sub(other).unsigned_abs()
+    }
+}
+
 const MEM_WRITE_KEYS: [u16; 32] = [
     17485, 31053, 57190, 57724, 7899, 53543, 26763, 12528, 38105, 51302, 16209, 24847, 13134,
     52339, 14530, 18350, 55636, 64477, 40905, 45498, 24411, 36677, 4213, 48843, 6368, 5907, 31384,
@@ -33,15 +84,15 @@ pub(crate) enum MlxMemWriteStatus {
 }
 
 impl MlxMemWriteStatus {
-    fn from_number(number: u8) -> Self {
+    fn from_number(number: u8) -> Result<Self, MlxError> {
         match number {
-            1 => Self::Success,
-            2 => Self::EraseWriteFail,
-            4 => Self::EepromCrcEraseWriteFail,
-            6 => Self::KeyInvalid,
-            7 => Self::ChallengeFail,
-            8 => Self::OddAddress,
-            _ => panic!("Invalid status"),
+            1 => Ok(Self::Success),
+            2 => Ok(Self::EraseWriteFail),
+            4 => Ok(Self::EepromCrcEraseWriteFail),
+            6 => Ok(Self::KeyInvalid),
+            7 => Ok(Self::ChallengeFail),
+            8 => Ok(Self::OddAddress),
+            _ => Err(MlxError::FormatError),
         }
     }
 }
@@ -74,27 +125,63 @@ pub(crate) enum MlxReply {
     MlxAlpha(MlxAlpha),
     MlxMemReadResponse(MlxMemReadResponse),
     MlxMemWriteChallengeReply(u16),
+    /// Answer to the challenge solution of an EEPROM write. Despite the
+    /// `EEReadChallenge`/`EEReadAnswer` opcode names this handshake only
+    /// exists for writes; every EEPROM cell can be read with a plain
+    /// `MemoryRead`, see `Mlx90363::read_memory`.
     MlxMemWriteReadAnswerReply(),
+    /// Frame with the `EEChallengeAns` opcode. That is our own solution
+    /// request, so receiving it means the write handshake is out of step.
+    MlxMemWriteChallengeAnsReply(MlxMemWriteStatus),
+    /// Result of an EEPROM write, collected after the erase/write cycle
     MlxMemWriteStatusReply(MlxMemWriteStatus),
-    XReply(),
+    MlxOscCounterStartReply(),
+    /// Oscillator counter value, captured between counter start and stop
+    MlxOscCounterStopReply(u16),
+    /// Sent once after power-on or reset, carries the device revision
+    Ready(MlxStatus),
+    /// The answer to the previous request isn't ready yet, e.g. a GET whose
+    /// measurement is still running
+    NothingToTransmit(),
+    /// Answer to `Mlx90363::diagnostic_details`
+    Diagnostics(MlxDiagnostics),
+    /// The measurement of a GET3 is done, the next frame carries it
+    Get3Ready(),
+    MlxXYZ(MlxXYZ),
 }
 
 impl MlxReply {
+    /// Whether this is one of the replies of the EEPROM write handshake
+    fn is_write_handshake(&self) -> bool {
+        matches!(
+            self,
+            MlxReply::MlxMemWriteChallengeReply(_)
+                | MlxReply::MlxMemWriteReadAnswerReply()
+                | MlxReply::MlxMemWriteChallengeAnsReply(_)
+                | MlxReply::MlxMemWriteStatusReply(_)
+        )
+    }
+
     pub(crate) fn deserialize(data: [u8; 8]) -> Result<Self, MlxError> {
         let frame = MlxFrame::from_message(&data);
         let opcode = frame.opcode;
         match frame.marker {
             MlxMarker::Alpha => MlxAlpha::from_message(&data).map(|a| MlxReply::MlxAlpha(a)),
-            MlxMarker::AlphaBeta => todo!(),
-            MlxMarker::XYZ => todo!(),
+            // Only sent in answer to GET2, which is never issued
+            MlxMarker::AlphaBeta => {
+                warn!("Unexpected alpha/beta frame");
+                Err(MlxError::FormatError)
+            }
+            MlxMarker::XYZ => MlxXYZ::from_message(&data).map(MlxReply::MlxXYZ),
             MlxMarker::Irregular => match opcode {
-                MlxOpcode::ReadyMessage => Ok(MlxReply::XReply()),
+                MlxOpcode::ReadyMessage => MlxStatus::from_message(&data).map(MlxReply::Ready),
                 MlxOpcode::ErrorFrame => {
                     Err(MlxError::DeviceError(DeviceError::from_number(data[0])))
                 }
+                MlxOpcode::Get3Ready => Ok(MlxReply::Get3Ready()),
                 MlxOpcode::NothingToTransmit => {
-                    info!("Nothing to transmit");
-                    Ok(MlxReply::XReply())
+                    debug!("Nothing to transmit");
+                    Ok(MlxReply::NothingToTransmit())
                 }
                 MlxOpcode::ChallengeNOPMISOPacket => {
                     match NopMessage::deserialize(&data).map(|n| MlxReply::Nop(n)) {
@@ -108,16 +195,22 @@ impl MlxReply {
                 MlxOpcode::MemoryReadAnswer => Ok(MlxReply::MlxMemReadResponse(
                     MlxMemReadResponse::deserialize(&data),
                 )),
-                MlxOpcode::EEWriteChallenge => Ok(MlxReply::MlxMemWriteChallengeReply(make_u16(
-                    data[3], data[2],
-                ))),
-                MlxOpcode::EEReadAnswer => Ok(MlxReply::MlxMemWriteReadAnswerReply()),
-                MlxOpcode::EEChallengeAns => Ok(MlxReply::MlxMemWriteStatusReply(
-                    MlxMemWriteStatus::from_number(data[0]),
-                )),
-                MlxOpcode::EEWriteStatus => Ok(MlxReply::MlxMemWriteStatusReply(
-                    MlxMemWriteStatus::from_number(data[0]),
+                MlxOpcode::EEWriteChallenge => Ok(MlxReply::MlxMemWriteChallengeReply(
+                    u16_from_le(&data[2..4]),
                 )),
+                MlxOpcode::EEReadAnswer => Ok(MlxReply::MlxMemWriteReadAnswerReply()),
+                MlxOpcode::EEChallengeAns => MlxMemWriteStatus::from_number(data[0])
+                    .map(MlxReply::MlxMemWriteChallengeAnsReply),
+                MlxOpcode::EEWriteStatus => {
+                    MlxMemWriteStatus::from_number(data[0]).map(MlxReply::MlxMemWriteStatusReply)
+                }
+                MlxOpcode::DiagnosticsAnswer => {
+                    Ok(MlxReply::Diagnostics(MlxDiagnostics::from_message(&data)))
+                }
+                MlxOpcode::OscCounterStartAcknowledge => Ok(MlxReply::MlxOscCounterStartReply()),
+                MlxOpcode::OscCounterStopAckCounterValue => {
+                    Ok(MlxReply::MlxOscCounterStopReply(u16_from_le(&data[0..2])))
+                }
                 _ => {
                     warn!("Unknown opcode: {:x}", opcode as u8);
                     Err(MlxError::FormatError)
@@ -128,6 +221,7 @@ impl MlxReply {
 }
 
 #[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum MlxOpcode {
     GET1 = 0x13,
     GET2 = 0x14,
@@ -159,6 +253,8 @@ pub enum MlxOpcode {
 }
 
 impl MlxOpcode {
+    /// Inverse of `op as u8` for every opcode in the table. Anything else
+    /// from the wire maps to `NotAnOpcode`.
     fn from_number(number: u8) -> Self {
         match number {
             0x13u8 => Self::GET1,
@@ -187,13 +283,12 @@ impl MlxOpcode {
             0x3Du8 => Self::ErrorFrame,
             0x3Eu8 => Self::NothingToTransmit,
             0x2Cu8 => Self::ReadyMessage,
-            0xFFu8 => Self::NotAnOpcode,
             _ => Self::NotAnOpcode,
         }
     }
 }
 
-#[derive(Format)]
+#[derive(Clone, Copy, Format)]
 pub(crate) enum MlxDiagnosticStatus {
     Pending,
     Fail,
@@ -202,17 +297,18 @@ pub(crate) enum MlxDiagnosticStatus {
 }
 
 impl MlxDiagnosticStatus {
+    /// Only the low two bits are used, so no byte from the wire can panic.
     pub(crate) fn from_number(number: u8) -> Self {
-        match number {
+        match number & 0b11 {
             0 => Self::Pending,
             1 => Self::Fail,
             2 => Self::Pass,
-            3 => Self::NewCycle,
-            _ => panic!("Invalid diagnostic status"),
+            _ => Self::NewCycle,
         }
     }
 }
 
+#[derive(PartialEq, Clone, Copy)]
 enum MlxMarker {
     Alpha,
     AlphaBeta,
@@ -221,21 +317,21 @@ enum MlxMarker {
 }
 
 impl MlxMarker {
+    /// Only the low two bits are used, so no byte from the wire can panic.
     fn from_number(number: u8) -> Self {
-        match number {
+        match number & 0b11 {
             0 => Self::Alpha,
             1 => Self::AlphaBeta,
             2 => Self::XYZ,
-            3 => Self::Irregular,
-            _ => panic!("Invalid marker"),
+            _ => Self::Irregular,
         }
     }
-    fn to_number(&self) -> u8 {
+    fn to_number(self) -> u8 {
         match self {
             Self::Alpha => 0,
-            Self::AlphaBeta => 1u8.shl(6u8),
-            Self::XYZ => 2u8.shl(6u8),
-            Self::Irregular => 3u8.shl(6u8),
+            Self::AlphaBeta => 1 << 6,
+            Self::XYZ => 2 << 6,
+            Self::Irregular => 3 << 6,
         }
     }
 }
@@ -266,11 +362,16 @@ pub(crate) enum MlxError {
     SpiError(SpiError),
     FormatError,
     NopError(NopError),
+    UnexpectedReply,
+    /// A reply of the EEPROM write handshake arrived at another step than
+    /// its own, e.g. the write status where the challenge answer belongs
+    WriteOutOfSequence,
+    WriteFailed(MlxMemWriteStatus),
 }
 #[allow(dead_code)]
 #[derive(Format)]
 pub(crate) struct MlxAlpha {
-    pub data: u16,
+    pub data: Angle14,
     pub diag: MlxDiagnosticStatus,
     pub vg: u8,
     pub counter: u8,
@@ -282,14 +383,64 @@ impl MlxAlpha {
             return Err(MlxError::FormatError);
         }
         Ok(Self {
-            data: message[0] as u16 | (message[1] as u16 & 0x3F).shl(8),
-            diag: MlxDiagnosticStatus::from_number(message[1].shr(6)),
+            data: Angle14::from_bits(u16_from_le(&message[0..2])),
+            diag: MlxDiagnosticStatus::from_number(message[1] >> 6),
             vg: message[4],
             counter: message[6] & 0x3F,
         })
     }
 }
 
+/// Magnetic field components from a GET3, signed 14-bit each
+#[derive(Format)]
+pub(crate) struct MlxXYZ {
+    pub x: i16,
+    pub y: i16,
+    pub z: i16,
+    pub diag: MlxDiagnosticStatus,
+    pub counter: u8,
+}
+
+impl MlxXYZ {
+    pub(crate) fn from_message(message: &[u8; 8]) -> Result<Self, MlxError> {
+        if message[6] & 0xC0 != MlxMarker::XYZ.to_number() {
+            return Err(MlxError::FormatError);
+        }
+        // Sign extends the low 14 bits
+        let component = |bytes: &[u8]| ((u16_from_le(bytes) << 2) as i16) >> 2;
+        Ok(Self {
+            x: component(&message[0..2]),
+            y: component(&message[2..4]),
+            z: component(&message[4..6]),
+            diag: MlxDiagnosticStatus::from_number(message[1] >> 6),
+            counter: message[6] & 0x3F,
+        })
+    }
+}
+
+/// Detailed self-diagnostic state from a `DiagnosticsAnswer` frame
+#[derive(PartialEq, Clone, Copy, Format)]
+pub(crate) struct MlxDiagnostics {
+    /// Diagnostic bits D21..D0, each set for a failed check such as a supply
+    /// monitor, the temperature range or the AGC gain limits. See the
+    /// diagnostics table of the datasheet for their order.
+    pub(crate) bits: u32,
+    /// Cause of the last fail-safe mode entry (FSMERC)
+    pub(crate) fsm_error_cause: u8,
+    /// Completed rounds of the analog diagnostics (ANADIAGCNT)
+    pub(crate) analog_rounds: u8,
+}
+
+impl MlxDiagnostics {
+    pub(crate) fn from_message(message: &[u8; 8]) -> Self {
+        Self {
+            bits: message[0] as u32 | (message[1] as u32) << 8 | ((message[2] & 0x3F) as u32) << 16,
+            fsm_error_cause: message[2] >> 6,
+            analog_rounds: message[3],
+        }
+    }
+}
+
 struct MlxFrame {
     marker: MlxMarker,
     opcode: MlxOpcode,
@@ -303,17 +454,17 @@ impl MlxFrame {
         }
     }
 }
-#[allow(dead_code)]
+/// Revision reported in the Ready message
+#[derive(Clone, Copy, Format)]
 pub(crate) struct MlxStatus {
-    fw_version: u8,
-    hw_version: u8,
+    pub(crate) fw_version: u8,
+    pub(crate) hw_version: u8,
 }
 
-#[allow(dead_code)]
 impl MlxStatus {
-    fn from_message(message: &[u8; 8]) -> Result<Self, &'static str> {
+    pub(crate) fn from_message(message: &[u8; 8]) -> Result<Self, MlxError> {
         if message[6] != (MlxMarker::Irregular.to_number() | MlxOpcode::ReadyMessage as u8) {
-            Err("Invalid Ready packed magic")
+            Err(MlxError::FormatError)
         } else {
             Ok(Self {
                 fw_version: message[1],
@@ -321,9 +472,16 @@ impl MlxStatus {
             })
         }
     }
+
+    /// `hw << 8 | fw`, as reported to the host
+    pub(crate) fn packed(&self) -> u16 {
+        u16_from_be(&[self.hw_version, self.fw_version])
+    }
 }
 
+/// GET request, `GET1` or `GET3`, which share their layout
 struct MlxGET1 {
+    opcode: MlxOpcode,
     reset_counter: bool,
     timeout: u16,
     marker: MlxMarker,
@@ -331,20 +489,41 @@ struct MlxGET1 {
 
 impl MlxGET1 {
     fn encode(&self) -> [u8; 8] {
-        let data: [u8; 8] = [
+        let mut data: [u8; 8] = [
             0,
             if self.reset_counter { 1 } else { 0 },
-            self.timeout as u8,
-            self.timeout.shr(8) as u8, //TODO check if timeout should be adjusted
             0,
             0,
-            (self.marker.to_number()) | MlxOpcode::GET1 as u8,
+            0,
+            0,
+            (self.marker.to_number()) | self.opcode as u8,
             0,
         ];
+        put_u16_le(&mut data[2..4], self.timeout); //TODO check if timeout should be adjusted
         data
     }
 }
 
+/// Irregular frame carrying nothing but an opcode
+struct MlxCommandRequest {
+    opcode: MlxOpcode,
+}
+
+impl MlxRequest for MlxCommandRequest {
+    fn serialize(&self) -> [u8; 8] {
+        [
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            MlxMarker::Irregular.to_number() | self.opcode as u8,
+            0,
+        ]
+    }
+}
+
 struct MlxMemReadRequest {
     addr0: u16,
     addr1: u16,
@@ -355,16 +534,19 @@ impl MlxMemReadRequest {
         Self { addr0, addr1 }
     }
     pub(crate) fn serialize(&self) -> [u8; 8] {
-        [
-            self.addr0 as u8,
-            self.addr0.shr(8) as u8,
-            self.addr1 as u8,
-            self.addr1.shr(8) as u8,
+        let mut data = [
+            0,
+            0,
+            0,
+            0,
             0,
             0,
             MlxMarker::Irregular.to_number() | MlxOpcode::MemoryRead as u8,
             0,
-        ]
+        ];
+        put_u16_le(&mut data[0..2], self.addr0);
+        put_u16_le(&mut data[2..4], self.addr1);
+        data
     }
 }
 
@@ -375,17 +557,20 @@ struct MlxMemWriteRequest {
 
 impl MlxRequest for MlxMemWriteRequest {
     fn serialize(&self) -> [u8; 8] {
-        let key = MEM_WRITE_KEYS[(self.addr & 0x3e).shr(1) as usize];
-        [
+        let key = MEM_WRITE_KEYS[((self.addr & 0x3e) >> 1) as usize];
+        let mut data = [
             0,
             self.addr,
-            key as u8,
-            key.shr(8) as u8,
-            self.data as u8,
-            self.data.shr(8) as u8,
+            0,
+            0,
+            0,
+            0,
             MlxMarker::Irregular.to_number() | MlxOpcode::EEWrite as u8,
             0,
-        ]
+        ];
+        put_u16_le(&mut data[2..4], key);
+        put_u16_le(&mut data[4..6], self.data);
+        data
     }
 }
 
@@ -406,53 +591,82 @@ impl MlxRequest for MlxMemWriteChallengeRequest {
     }
 }
 
+/// The key echo answering an EEPROM write challenge is the challenge XORed
+/// with this constant from the datasheet
+const EE_CHALLENGE_XOR: u16 = 0x1234;
+
+/// Key echo answering the EEPROM write challenge `challenge`
+pub(crate) fn challenge_solution(challenge: u16) -> u16 {
+    challenge ^ EE_CHALLENGE_XOR
+}
+
 struct MlxMemWriteChallengeSolutionRequest {
     value: u16,
 }
 
+impl MlxMemWriteChallengeSolutionRequest {
+    /// Key echo for the challenge, sent along with its inverse
+    fn solution(&self) -> u16 {
+        challenge_solution(self.value)
+    }
+}
+
 impl MlxRequest for MlxMemWriteChallengeSolutionRequest {
     fn serialize(&self) -> [u8; 8] {
-        [
+        let mut data = [
+            0,
+            0,
+            0,
+            0,
             0,
             0,
-            (self.value as u8).bitxor(0x34),
-            (self.value.shr(8) as u8).bitxor(0x12),
-            (!self.value as u8).bitxor(0x34),
-            !(self.value.shr(8) as u8).bitxor(0x12),
             MlxMarker::Irregular.to_number() | MlxOpcode::EEChallengeAns as u8,
             0,
-        ]
+        ];
+        put_u16_le(&mut data[2..4], self.solution());
+        put_u16_le(&mut data[4..6], !self.solution());
+        data
     }
 }
 
+/// Contents of the two cells of a `MlxMemReadRequest`
 #[derive(Format)]
 pub(crate) struct MlxMemReadResponse {
+    /// Cell at `addr0`
     pub(crate) data0: u16,
+    /// Cell at `addr1`
     pub(crate) data1: u16,
 }
 
 impl MlxMemReadResponse {
     pub(crate) fn deserialize(data: &[u8; 8]) -> Self {
         Self {
-            data0: make_u16(data[1], data[0]),
-            data1: make_u16(data[3], data[2]),
+            data0: u16_from_le(&data[0..2]),
+            data1: u16_from_le(&data[2..4]),
         }
     }
+
+    /// Both cells in request order
+    pub(crate) fn words(&self) -> [u16; 2] {
+        [self.data0, self.data1]
+    }
 }
 
 pub(crate) struct Mlx90363 {}
 
 impl Mlx90363 {
-    pub(crate) fn nop<D: SpiDevice>(
-        spi: &mut Spi<Enabled, D, impl ValidSpiPinout<D>, 8>,
+    pub(crate) fn nop(
+        spi: &mut impl NegiconProtocol,
         cs: &mut dyn OutputPin<Error = Infallible>,
         challenge: u16,
     ) -> Result<MlxReply, MlxError> {
         Self::transfer(spi, cs, &NopMessage::new(challenge))
     }
 
-    pub(crate) fn read_memory<D: SpiDevice>(
-        spi: &mut Spi<Enabled, D, impl ValidSpiPinout<D>, 8>,
+    /// Requests the cells at `addr0` and `addr1`, which need not be adjacent
+    /// or distinct. Their contents arrive in the reply to the next frame.
+    pub(crate) fn read_memory(
+        spi: &mut impl NegiconProtocol,
         cs: &mut dyn OutputPin<Error = Infallible>,
         addr0: u16,
         addr1: u16,
@@ -461,98 +675,870 @@ impl Mlx90363 {
         Self::transfer(spi, cs, &req)
     }
 
-    fn transfer<D>(
-        spi: &mut Spi<Enabled, D, impl ValidSpiPinout<D>, 8>,
+    /// Starts the internal oscillator counter. Like every command the returned
+    /// reply answers the previous frame; the acknowledge arrives with the next.
+    #[allow(dead_code)]
+    pub(crate) fn osc_counter_start(
+        spi: &mut impl NegiconProtocol,
+        cs: &mut dyn OutputPin<Error = Infallible>,
+    ) -> Result<MlxReply, MlxError> {
+        let req = MlxCommandRequest {
+            opcode: MlxOpcode::OscCounterStart,
+        };
+        Self::transfer(spi, cs, &req)
+    }
+
+    /// Stops the oscillator counter. The counter value comes with the reply
+    /// to the next frame, see `osc_counter_value`.
+    #[allow(dead_code)]
+    pub(crate) fn osc_counter_stop(
+        spi: &mut impl NegiconProtocol,
+        cs: &mut dyn OutputPin<Error = Infallible>,
+    ) -> Result<MlxReply, MlxError> {
+        let req = MlxCommandRequest {
+            opcode: MlxOpcode::OscCounterStop,
+        };
+        Self::transfer(spi, cs, &req)
+    }
+
+    /// Collects the counter value after `osc_counter_stop`. Returns `None`
+    /// while the device is still busy and has nothing to transmit yet.
+    #[allow(dead_code)]
+    pub(crate) fn osc_counter_value(
+        spi: &mut impl NegiconProtocol,
+        cs: &mut dyn OutputPin<Error = Infallible>,
+    ) -> Result<Option<u16>, MlxError> {
+        match Self::nop(spi, cs, FILLER_NOP_KEY)? {
+            MlxReply::MlxOscCounterStopReply(value) => Ok(Some(value)),
+            MlxReply::NothingToTransmit() => Ok(None),
+            res => {
+                debug!("Expected oscillator counter value, got {}", res);
+                Err(MlxError::UnexpectedReply)
+            }
+        }
+    }
+
+    fn transfer(
+        spi: &mut impl NegiconProtocol,
         cs: &mut dyn OutputPin<Error = Infallible>,
         request: &dyn MlxRequest,
-    ) -> Result<MlxReply, MlxError>
-    where
-        D: SpiDevice,
-    {
+    ) -> Result<MlxReply, MlxError> {
         let mut buf = request.serialize();
-        match spi.verified_transmit(cs, &mut buf) {
+        match spi.verified_transmit_retry(cs, &mut buf, MLX_TRANSFER_ATTEMPTS) {
             Ok(_) => MlxReply::deserialize(buf),
             Err(e) => Err(MlxError::SpiError(e)),
         }
     }
 
-    pub(crate) fn get_alpha<D>(
-        spi: &mut Spi<Enabled, D, impl ValidSpiPinout<D>, 8>,
+    /// Requests the detailed diagnostics. Like every command the returned
+    /// reply answers the previous frame; `MlxReply::Diagnostics` arrives
+    /// with the next.
+    pub(crate) fn diagnostic_details(
+        spi: &mut impl NegiconProtocol,
         cs: &mut dyn OutputPin<Error = Infallible>,
-    ) -> Result<MlxReply, MlxError>
-    where
-        D: SpiDevice,
-    {
+    ) -> Result<MlxReply, MlxError> {
+        let req = MlxCommandRequest {
+            opcode: MlxOpcode::DiagnosticDetails,
+        };
+        Self::transfer(spi, cs, &req)
+    }
+
+    /// Requests the next angle. With `reset_counter` the rolling counter of
+    /// the following replies restarts from 0.
+    pub(crate) fn get_alpha(
+        spi: &mut impl NegiconProtocol,
+        cs: &mut dyn OutputPin<Error = Infallible>,
+        reset_counter: bool,
+    ) -> Result<MlxReply, MlxError> {
         let req = MlxGET1 {
-            reset_counter: false,
+            opcode: MlxOpcode::GET1,
+            reset_counter,
             timeout: 0xffff,
             marker: MlxMarker::Alpha,
         };
         Self::transfer(spi, cs, &req)
     }
 
-    pub(crate) fn write_memory<D, T>(
-        spi: &mut Spi<Enabled, D, T, 8>,
-        cs: &mut (dyn OutputPin<Error = Infallible>),
-        delay: &mut Delay,
-        value: i16,
-        addr: u8,
-    ) where
-        D: SpiDevice,
-        T: ValidSpiPinout<D>,
-    {
-        delay.delay_us(200);
-        let _ = Self::nop(spi, cs, 0x3939);
-        delay.delay_us(200);
-        let _ = Self::transfer(
-            spi,
-            cs,
-            &MlxMemWriteRequest {
-                addr: addr,
-                data: value as u16,
-            },
-        );
-        delay.delay_us(200);
-        let challenge = Self::transfer(spi, cs, &MlxMemWriteChallengeRequest {});
-
-        let chal_answer = match challenge {
-            Ok(res) => match res {
-                MlxReply::MlxMemWriteChallengeReply(chal) => {
-                    let solution = MlxMemWriteChallengeSolutionRequest { value: chal };
-                    delay.delay_us(150);
-                    Self::transfer(spi, cs, &solution)
+    /// Reads the magnetic field components. Unlike a GET1 the result of a
+    /// GET3 is only valid after the sensor signalled `Get3Ready`, which is
+    /// waited for with at most `GET3_READY_ATTEMPTS` NOPs. Returns `None` if
+    /// it doesn't come in time.
+    pub(crate) fn get_xyz(
+        spi: &mut impl NegiconProtocol,
+        cs: &mut dyn OutputPin<Error = Infallible>,
+    ) -> Result<Option<MlxXYZ>, MlxError> {
+        let req = MlxGET1 {
+            opcode: MlxOpcode::GET3,
+            reset_counter: false,
+            timeout: 0xffff,
+            marker: MlxMarker::XYZ,
+        };
+        // Answers whatever came before
+        Self::transfer(spi, cs, &req)?;
+        for _ in 0..GET3_READY_ATTEMPTS {
+            match Self::nop(spi, cs, FILLER_NOP_KEY)? {
+                MlxReply::Get3Ready() => {
+                    return match Self::nop(spi, cs, FILLER_NOP_KEY)? {
+                        MlxReply::MlxXYZ(xyz) => Ok(Some(xyz)),
+                        res => {
+                            debug!("Expected XYZ reading, got {}", res);
+                            Err(MlxError::UnexpectedReply)
+                        }
+                    };
                 }
-                _ => {
-                    return error!(
-                        "Did not receive mem write challenge, got {}. Aborting write",
-                        res
-                    )
+                MlxReply::NothingToTransmit() => {}
+                res => {
+                    debug!("Expected Get3Ready, got {}", res);
+                    return Err(MlxError::UnexpectedReply);
                 }
-            },
-            Err(e) => return error!("Got error {}. Aborting write", e),
+            }
+        }
+        warn!("No Get3Ready after {} NOPs", GET3_READY_ATTEMPTS);
+        Ok(None)
+    }
+}
+
+/// Step of an EEPROM write, see `MlxWrite`
+#[derive(Clone, Copy, Format)]
+pub(crate) enum WriteState {
+    /// Leading NOP so the first write request isn't answered with a stale reply
+    Nop,
+    SendWrite,
+    /// Requests the challenge, which answers the write request
+    Challenge,
+    /// Answers the challenge received in the previous step
+    Solution(u16),
+    /// Waits for the erase/write cycle that started at the given timer tick
+    /// (µs). The challenge is kept for the failure log.
+    WaitErase(u64, u16),
+    /// Collects the write status with a NOP
+    Status(u16),
+}
+
+/// EEPROM write of one or more cells, advanced by one frame per `step` so a
+/// configuration write doesn't stall the poll loop for the erase time of
+/// every cell. Stops at the first failing cell.
+pub(crate) struct MlxWrite {
+    cells: Vec<(u8, i16)>,
+    next: usize,
+    state: WriteState,
+}
+
+impl MlxWrite {
+    pub(crate) fn new(cells: &[(u8, i16)]) -> Self {
+        Self {
+            cells: cells.to_vec(),
+            next: 0,
+            state: WriteState::Nop,
+        }
+    }
+
+    /// Cells not written yet, including the one in progress
+    pub(crate) fn remaining(&self) -> usize {
+        self.cells.len() - self.next
+    }
+
+    /// Challenge the device sent for the cell in progress, once received
+    pub(crate) fn challenge(&self) -> Option<u16> {
+        match self.state {
+            WriteState::Solution(chal)
+            | WriteState::WaitErase(_, chal)
+            | WriteState::Status(chal) => Some(chal),
+            _ => None,
+        }
+    }
+
+    /// Appends cells requested while this write is still running.
+    pub(crate) fn queue(&mut self, cells: &[(u8, i16)]) {
+        self.cells.extend_from_slice(cells);
+    }
+
+    /// Runs the next step of the sequence. Returns `Ok(true)` once the status
+    /// of the last cell has been read back.
+    pub(crate) fn step(
+        &mut self,
+        spi: &mut impl NegiconProtocol,
+        cs: &mut dyn OutputPin<Error = Infallible>,
+        now: Instant,
+    ) -> Result<bool, MlxError> {
+        let (addr, value) = match self.cells.get(self.next) {
+            Some(&cell) => cell,
+            None => return Ok(true),
         };
-        match chal_answer {
-            Ok(res) => match res {
-                MlxReply::MlxMemWriteReadAnswerReply() => {
-                    debug!("waiting");
-                    delay.delay_ms(330)
+        self.state = match self.state {
+            // The replies to these two answer earlier frames and don't
+            // matter, but a failed transfer means the device is gone.
+            WriteState::Nop => {
+                if let Err(MlxError::SpiError(e)) = Mlx90363::nop(spi, cs, FILLER_NOP_KEY) {
+                    return Err(MlxError::SpiError(e));
+                }
+                WriteState::SendWrite
+            }
+            WriteState::SendWrite => {
+                let req = MlxMemWriteRequest {
+                    addr,
+                    data: value as u16,
+                };
+                if let Err(MlxError::SpiError(e)) = Mlx90363::transfer(spi, cs, &req) {
+                    return Err(MlxError::SpiError(e));
+                }
+                WriteState::Challenge
+            }
+            WriteState::Challenge => {
+                match Mlx90363::transfer(spi, cs, &MlxMemWriteChallengeRequest {})? {
+                    MlxReply::MlxMemWriteChallengeReply(chal) => WriteState::Solution(chal),
+                    res if res.is_write_handshake() => {
+                        error!("Expected mem write challenge, got {}", res);
+                        return Err(MlxError::WriteOutOfSequence);
+                    }
+                    res => {
+                        error!(
+                            "Did not receive mem write challenge, got {}. Aborting write",
+                            res
+                        );
+                        return Err(MlxError::UnexpectedReply);
+                    }
+                }
+            }
+            WriteState::Solution(chal) => {
+                let solution = MlxMemWriteChallengeSolutionRequest { value: chal };
+                debug!(
+                    "Mem write challenge {:x}, solution {:x}",
+                    chal,
+                    solution.solution()
+                );
+                match Mlx90363::transfer(spi, cs, &solution)? {
+                    MlxReply::MlxMemWriteReadAnswerReply() => {
+                        WriteState::WaitErase(now.ticks(), chal)
+                    }
+                    res if res.is_write_handshake() => {
+                        error!("Expected mem write challenge answer, got {}", res);
+                        return Err(MlxError::WriteOutOfSequence);
+                    }
+                    res => {
+                        error!(
+                            "Did not receive mem write challenge answer (challenge {:x}, solution {:x}), got {}. Aborting write",
+                            chal,
+                            solution.solution(),
+                            res
+                        );
+                        return Err(MlxError::UnexpectedReply);
+                    }
+                }
+            }
+            WriteState::WaitErase(since, chal) => {
+                if now.ticks().saturating_sub(since) >= MLX_EEPROM_WRITE_MS as u64 * 1000 {
+                    WriteState::Status(chal)
+                } else {
+                    WriteState::WaitErase(since, chal)
+                }
+            }
+            WriteState::Status(chal) => match Mlx90363::nop(spi, cs, FILLER_NOP_KEY)? {
+                MlxReply::MlxMemWriteStatusReply(MlxMemWriteStatus::Success) => {
+                    info!("Memory write to {:x} completed", addr);
+                    self.next += 1;
+                    if self.next == self.cells.len() {
+                        return Ok(true);
+                    }
+                    WriteState::SendWrite
                 }
-                _ => return error!("Did not receive mem write challenge answer. Aborting write"),
-            },
-            Err(_) => return error!("Did not receive mem write challenge answer. Aborting write"),
-        };
-        let status = Self::nop(spi, cs, 0x3939);
-        delay.delay_us(200);
-        match status {
-            Ok(s) => match s {
                 MlxReply::MlxMemWriteStatusReply(status) => {
-                    info!("Memory write completed with status: {:?}", status);
+                    error!(
+                        "Memory write to {:x} failed with status: {:?} (challenge {:x}, solution {:x})",
+                        addr,
+                        status,
+                        chal,
+                        challenge_solution(chal)
+                    );
+                    return Err(MlxError::WriteFailed(status));
+                }
+                res if res.is_write_handshake() => {
+                    error!("Expected mem write status, got {}", res);
+                    return Err(MlxError::WriteOutOfSequence);
+                }
+                res => {
+                    error!(
+                        "Did not receive mem write status, got {}. Aborting write",
+                        res
+                    );
+                    return Err(MlxError::UnexpectedReply);
                 }
-                _ => error!("Failed to read status after mem write"),
             },
-            Err(_) => error!("Failed to read status after mem write"),
-        }
+        };
+        Ok(false)
     }
 }
 
-//impl<NopMessage> Mlx90363<NopMessage> {}
+/// Logs the progress instead of the cells, which would need defmt's `alloc`
+impl Format for MlxWrite {
+    fn format(&self, f: Formatter) {
+        defmt::write!(
+            f,
+            "MlxWrite {{ cell {} of {}, {} }}",
+            self.next,
+            self.cells.len(),
+            self.state
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downstream::mock::{MockPin, MockSpi};
+    use alloc::vec::Vec;
+
+    fn irregular(opcode: MlxOpcode, data: [u8; 6]) -> [u8; 8] {
+        let [d0, d1, d2, d3, d4, d5] = data;
+        [
+            d0,
+            d1,
+            d2,
+            d3,
+            d4,
+            d5,
+            MlxMarker::Irregular.to_number() | opcode as u8,
+            0,
+        ]
+    }
+
+    /// Answer of a sensor with nothing to say, e.g. to the leading NOP
+    fn nothing() -> [u8; 8] {
+        irregular(MlxOpcode::NothingToTransmit, [0; 6])
+    }
+
+    /// Queues the device side of one cell write, ending in `status`
+    fn script_cell(spi: &mut MockSpi, status: u8) {
+        // Answer to the EEWrite request itself isn't looked at
+        spi.reply(nothing());
+        spi.reply(irregular(
+            MlxOpcode::EEWriteChallenge,
+            [0, 0, 0x78, 0x56, 0, 0],
+        ));
+        spi.reply(irregular(MlxOpcode::EEReadAnswer, [0; 6]));
+        spi.reply(irregular(MlxOpcode::EEWriteStatus, [status, 0, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn challenge_solution_is_the_keyed_challenge_and_its_inverse() {
+        let solution = MlxMemWriteChallengeSolutionRequest { value: 0x5678 };
+        assert_eq!(solution.solution(), 0x444C);
+        assert_eq!(
+            solution.serialize(),
+            irregular(MlxOpcode::EEChallengeAns, [0, 0, 0x4C, 0x44, 0xB3, 0xBB])
+        );
+    }
+
+    #[test]
+    fn write_answers_the_challenge_it_received() {
+        let mut spi = MockSpi::default();
+        spi.reply(nothing());
+        script_cell(&mut spi, MlxMemWriteStatus::Success as u8);
+        let mut write = MlxWrite::new(&[(0x2A, 0x1234)]);
+        let _ = drive(&mut write, &mut spi);
+
+        let answer = spi
+            .sent
+            .iter()
+            .find(|f| f[6] == MlxMarker::Irregular.to_number() | MlxOpcode::EEChallengeAns as u8)
+            .unwrap();
+        assert_eq!(u16_from_le(&answer[2..4]), 0x5678 ^ EE_CHALLENGE_XOR);
+        assert_eq!(u16_from_le(&answer[4..6]), !(0x5678 ^ EE_CHALLENGE_XOR));
+    }
+
+    /// Addresses of the EEWrite requests that went out, in order
+    fn written_addresses(spi: &MockSpi) -> Vec<u8> {
+        spi.sent
+            .iter()
+            .filter(|f| f[6] == MlxMarker::Irregular.to_number() | MlxOpcode::EEWrite as u8)
+            .map(|f| f[1])
+            .collect()
+    }
+
+    fn ms(ms: u64) -> Instant {
+        Instant::from_ticks(ms * 1000)
+    }
+
+    /// Steps `write` every 10 ms until it finishes, returning the outcome and
+    /// the time it took
+    fn drive(write: &mut MlxWrite, spi: &mut MockSpi) -> (Result<(), MlxError>, u64) {
+        let mut cs = MockPin::new();
+        for t in (0..10_000).step_by(10) {
+            match write.step(spi, &mut cs, ms(t)) {
+                Ok(false) => {}
+                Ok(true) => return (Ok(()), t),
+                Err(e) => return (Err(e), t),
+            }
+        }
+        panic!("write never finished");
+    }
+
+    #[test]
+    fn write_goes_out_one_frame_per_step() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        spi.reply(nothing());
+        script_cell(&mut spi, MlxMemWriteStatus::Success as u8);
+        let mut write = MlxWrite::new(&[(0x20, 1)]);
+
+        // NOP, EEWrite, challenge request and answer
+        for step in 1..=4 {
+            assert!(matches!(write.step(&mut spi, &mut cs, ms(0)), Ok(false)));
+            assert_eq!(spi.sent.len(), step);
+        }
+        // Silent until the erase/write cycle is over
+        assert!(matches!(write.step(&mut spi, &mut cs, ms(329)), Ok(false)));
+        assert!(matches!(write.step(&mut spi, &mut cs, ms(330)), Ok(false)));
+        assert_eq!(spi.sent.len(), 4);
+        // Status NOP
+        assert!(matches!(write.step(&mut spi, &mut cs, ms(331)), Ok(true)));
+        assert_eq!(spi.sent.len(), 5);
+        assert_eq!(written_addresses(&spi), [0x20]);
+    }
+
+    #[test]
+    fn write_covers_cells_in_order() {
+        let mut spi = MockSpi::default();
+        spi.reply(nothing());
+        for _ in 0..3 {
+            script_cell(&mut spi, MlxMemWriteStatus::Success as u8);
+        }
+        let mut write = MlxWrite::new(&[(0x20, 1), (0x22, 2)]);
+        write.queue(&[(0x24, 3)]);
+
+        let (res, took) = drive(&mut write, &mut spi);
+        assert!(res.is_ok());
+        assert_eq!(written_addresses(&spi), [0x20, 0x22, 0x24]);
+        // Leading NOP, then EEWrite, challenge request, answer and status NOP
+        // per cell
+        assert_eq!(spi.sent.len(), 1 + 3 * 4);
+        assert!(took >= 3 * MLX_EEPROM_WRITE_MS as u64);
+    }
+
+    #[test]
+    fn write_stops_at_the_first_failed_cell() {
+        let mut spi = MockSpi::default();
+        spi.reply(nothing());
+        script_cell(&mut spi, MlxMemWriteStatus::Success as u8);
+        script_cell(&mut spi, MlxMemWriteStatus::EraseWriteFail as u8);
+        script_cell(&mut spi, MlxMemWriteStatus::Success as u8);
+        let mut write = MlxWrite::new(&[(0x20, 1), (0x22, 2), (0x24, 3)]);
+
+        let (res, _) = drive(&mut write, &mut spi);
+        assert!(matches!(
+            res,
+            Err(MlxError::WriteFailed(MlxMemWriteStatus::EraseWriteFail))
+        ));
+        assert_eq!(written_addresses(&spi), [0x20, 0x22]);
+        // Kept for the error report
+        assert_eq!(write.challenge(), Some(0x5678));
+    }
+
+    #[test]
+    fn failed_transfer_at_any_stage_aborts_the_write() {
+        // Leading NOP, EEWrite, challenge request and answer, and the status
+        // NOP once the erase/write cycle is over
+        for stage in [0, 1, 2, 3, 5] {
+            let mut spi = MockSpi::default();
+            let mut cs = MockPin::new();
+            spi.reply(nothing());
+            script_cell(&mut spi, MlxMemWriteStatus::Success as u8);
+            let mut write = MlxWrite::new(&[(0x20, 1), (0x22, 2)]);
+            for step in 0..stage {
+                let now = if step < 4 {
+                    0
+                } else {
+                    MLX_EEPROM_WRITE_MS as u64
+                };
+                assert!(matches!(write.step(&mut spi, &mut cs, ms(now)), Ok(false)));
+            }
+
+            spi.stuck = true;
+            let res = write.step(&mut spi, &mut cs, ms(MLX_EEPROM_WRITE_MS as u64 + 1));
+            assert!(
+                matches!(res, Err(MlxError::SpiError(SpiError::Timeout))),
+                "stage {}",
+                stage
+            );
+            assert_eq!(write.remaining(), 2);
+        }
+    }
+
+    #[test]
+    fn write_stops_when_the_challenge_is_missing() {
+        let mut spi = MockSpi::default();
+        spi.reply(nothing());
+        spi.reply(nothing());
+        spi.reply(irregular(MlxOpcode::NothingToTransmit, [0; 6]));
+        let mut write = MlxWrite::new(&[(0x20, 1), (0x22, 2)]);
+
+        let (res, _) = drive(&mut write, &mut spi);
+        assert!(matches!(res, Err(MlxError::UnexpectedReply)));
+        assert_eq!(written_addresses(&spi), [0x20]);
+    }
+
+    #[test]
+    fn challenge_answer_and_write_status_decode_apart() {
+        let status = [MlxMemWriteStatus::Success as u8, 0, 0, 0, 0, 0];
+        assert!(matches!(
+            MlxReply::deserialize(irregular(MlxOpcode::EEChallengeAns, status)),
+            Ok(MlxReply::MlxMemWriteChallengeAnsReply(
+                MlxMemWriteStatus::Success
+            ))
+        ));
+        assert!(matches!(
+            MlxReply::deserialize(irregular(MlxOpcode::EEWriteStatus, status)),
+            Ok(MlxReply::MlxMemWriteStatusReply(MlxMemWriteStatus::Success))
+        ));
+    }
+
+    #[test]
+    fn status_in_place_of_the_challenge_answer_aborts_the_write() {
+        let mut spi = MockSpi::default();
+        spi.reply(nothing());
+        spi.reply(nothing());
+        spi.reply(irregular(
+            MlxOpcode::EEWriteChallenge,
+            [0, 0, 0x78, 0x56, 0, 0],
+        ));
+        spi.reply(irregular(
+            MlxOpcode::EEWriteStatus,
+            [MlxMemWriteStatus::Success as u8, 0, 0, 0, 0, 0],
+        ));
+        let mut write = MlxWrite::new(&[(0x20, 1)]);
+
+        let (res, _) = drive(&mut write, &mut spi);
+        assert!(matches!(res, Err(MlxError::WriteOutOfSequence)));
+        // Never waited for an erase that wasn't confirmed
+        assert_eq!(spi.sent.len(), 4);
+    }
+
+    #[test]
+    fn challenge_answer_in_place_of_the_status_aborts_the_write() {
+        let mut spi = MockSpi::default();
+        spi.reply(nothing());
+        spi.reply(nothing());
+        spi.reply(irregular(
+            MlxOpcode::EEWriteChallenge,
+            [0, 0, 0x78, 0x56, 0, 0],
+        ));
+        spi.reply(irregular(MlxOpcode::EEReadAnswer, [0; 6]));
+        spi.reply(irregular(
+            MlxOpcode::EEChallengeAns,
+            [MlxMemWriteStatus::Success as u8, 0, 0, 0, 0, 0],
+        ));
+        let mut write = MlxWrite::new(&[(0x20, 1), (0x22, 2)]);
+
+        let (res, _) = drive(&mut write, &mut spi);
+        assert!(matches!(res, Err(MlxError::WriteOutOfSequence)));
+        assert_eq!(written_addresses(&spi), [0x20]);
+    }
+
+    #[test]
+    fn early_status_in_place_of_the_challenge_aborts_the_write() {
+        let mut spi = MockSpi::default();
+        spi.reply(nothing());
+        spi.reply(nothing());
+        spi.reply(irregular(
+            MlxOpcode::EEWriteStatus,
+            [MlxMemWriteStatus::Success as u8, 0, 0, 0, 0, 0],
+        ));
+        let mut write = MlxWrite::new(&[(0x20, 1)]);
+
+        let (res, _) = drive(&mut write, &mut spi);
+        assert!(matches!(res, Err(MlxError::WriteOutOfSequence)));
+    }
+
+    #[test]
+    fn osc_counter_acks_are_decoded() {
+        let start = irregular(MlxOpcode::OscCounterStartAcknowledge, [0; 6]);
+        assert!(matches!(
+            MlxReply::deserialize(start),
+            Ok(MlxReply::MlxOscCounterStartReply())
+        ));
+        let stop = irregular(
+            MlxOpcode::OscCounterStopAckCounterValue,
+            [0x34, 0x12, 0, 0, 0, 0],
+        );
+        assert!(matches!(
+            MlxReply::deserialize(stop),
+            Ok(MlxReply::MlxOscCounterStopReply(0x1234))
+        ));
+    }
+
+    #[test]
+    fn osc_counter_value_follows_start_and_stop() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        spi.reply(irregular(MlxOpcode::NothingToTransmit, [0; 6]));
+        spi.reply(irregular(MlxOpcode::OscCounterStartAcknowledge, [0; 6]));
+        spi.reply(irregular(
+            MlxOpcode::OscCounterStopAckCounterValue,
+            [0x10, 0x27, 0, 0, 0, 0],
+        ));
+
+        assert!(Mlx90363::osc_counter_start(&mut spi, &mut cs).is_ok());
+        assert!(matches!(
+            Mlx90363::osc_counter_stop(&mut spi, &mut cs),
+            Ok(MlxReply::MlxOscCounterStartReply())
+        ));
+        assert!(matches!(
+            Mlx90363::osc_counter_value(&mut spi, &mut cs),
+            Ok(Some(10000))
+        ));
+        let opcodes: Vec<_> = spi.sent.iter().map(|f| f[6] & 0x3F).collect();
+        assert_eq!(
+            opcodes,
+            [
+                MlxOpcode::OscCounterStart as u8,
+                MlxOpcode::OscCounterStop as u8,
+                MlxOpcode::NOPChallenge as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn busy_osc_counter_has_no_value_yet() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        spi.reply(irregular(MlxOpcode::NothingToTransmit, [0; 6]));
+
+        assert!(matches!(
+            Mlx90363::osc_counter_value(&mut spi, &mut cs),
+            Ok(None)
+        ));
+    }
+
+    #[test]
+    fn ready_message_carries_the_revision() {
+        let ready = irregular(MlxOpcode::ReadyMessage, [0x03, 0x41, 0, 0, 0, 0]);
+        match MlxReply::deserialize(ready) {
+            Ok(MlxReply::Ready(status)) => {
+                assert_eq!((status.hw_version, status.fw_version), (0x03, 0x41));
+                assert_eq!(status.packed(), 0x0341);
+            }
+            _ => panic!("Ready message not decoded"),
+        }
+        let nop = irregular(MlxOpcode::NothingToTransmit, [0x03, 0x41, 0, 0, 0, 0]);
+        assert!(MlxStatus::from_message(&nop).is_err());
+    }
+
+    #[test]
+    fn memory_read_carries_two_distinct_addresses_and_answers_in_order() {
+        let mut spi = MockSpi::default();
+        spi.reply(nothing());
+        spi.reply(irregular(
+            MlxOpcode::MemoryReadAnswer,
+            [0xE8, 0x03, 0xB8, 0x0B, 0, 0],
+        ));
+        assert!(Mlx90363::read_memory(&mut spi, &mut MockPin::new(), 0x103A, 0x103C).is_ok());
+        let answer = Mlx90363::read_memory(&mut spi, &mut MockPin::new(), 0x103A, 0x103C);
+
+        assert_eq!(u16_from_le(&spi.sent[0][0..2]), 0x103A);
+        assert_eq!(u16_from_le(&spi.sent[0][2..4]), 0x103C);
+        assert!(matches!(
+            answer,
+            Ok(MlxReply::MlxMemReadResponse(msg)) if msg.words() == [1000, 3000]
+        ));
+    }
+
+    #[test]
+    fn ee_read_answer_is_decoded() {
+        let answer = irregular(MlxOpcode::EEReadAnswer, [0; 6]);
+        assert!(matches!(
+            MlxReply::deserialize(answer),
+            Ok(MlxReply::MlxMemWriteReadAnswerReply())
+        ));
+    }
+
+    #[test]
+    fn diagnostics_answer_splits_bits_error_cause_and_rounds() {
+        let answer = irregular(MlxOpcode::DiagnosticsAnswer, [0x34, 0x12, 0x85, 7, 0, 0]);
+        match MlxReply::deserialize(answer) {
+            Ok(MlxReply::Diagnostics(diag)) => {
+                assert_eq!(diag.bits, 0x05_1234);
+                assert_eq!(diag.fsm_error_cause, 2);
+                assert_eq!(diag.analog_rounds, 7);
+            }
+            _ => panic!("Diagnostics answer not decoded"),
+        }
+    }
+
+    const OPCODES: [MlxOpcode; 26] = [
+        MlxOpcode::GET1,
+        MlxOpcode::GET2,
+        MlxOpcode::GET3,
+        MlxOpcode::Get3Ready,
+        MlxOpcode::MemoryRead,
+        MlxOpcode::MemoryReadAnswer,
+        MlxOpcode::EEWrite,
+        MlxOpcode::EEWriteChallenge,
+        MlxOpcode::EEChallengeAns,
+        MlxOpcode::EEReadAnswer,
+        MlxOpcode::EEReadChallenge,
+        MlxOpcode::EEWriteStatus,
+        MlxOpcode::NOPChallenge,
+        MlxOpcode::ChallengeNOPMISOPacket,
+        MlxOpcode::DiagnosticDetails,
+        MlxOpcode::DiagnosticsAnswer,
+        MlxOpcode::OscCounterStart,
+        MlxOpcode::OscCounterStartAcknowledge,
+        MlxOpcode::OscCounterStop,
+        MlxOpcode::OscCounterStopAckCounterValue,
+        MlxOpcode::Reboot,
+        MlxOpcode::Standby,
+        MlxOpcode::StandbyAck,
+        MlxOpcode::ErrorFrame,
+        MlxOpcode::NothingToTransmit,
+        MlxOpcode::ReadyMessage,
+    ];
+
+    fn angle(raw: u16) -> Angle14 {
+        Angle14::from_bits(raw)
+    }
+
+    #[test]
+    fn from_bits_drops_the_top_bits() {
+        assert_eq!(angle(0xFFFF).get(), ALPHA_MAX as u16);
+        assert_eq!(angle(1 << 14).get(), 0);
+    }
+
+    #[test]
+    fn sub_wraps_around_zero() {
+        assert_eq!(angle(0).sub(angle(ALPHA_MAX as u16)), 1);
+        assert_eq!(angle(ALPHA_MAX as u16).sub(angle(0)), -1);
+        assert_eq!(angle(10).sub(angle(16380)), 14);
+        assert_eq!(angle(16380).sub(angle(10)), -14);
+        assert_eq!(angle(5).sub(angle(5)), 0);
+    }
+
+    #[test]
+    fn sub_keeps_half_a_turn() {
+        let half = ALPHA_HALF as u16;
+        assert_eq!(angle(half).sub(angle(0)), ALPHA_HALF as i16);
+        assert_eq!(angle(0).sub(angle(half)), -ALPHA_HALF as i16);
+        assert_eq!(angle(half + 1).sub(angle(0)), 1 - ALPHA_HALF as i16);
+        assert_eq!(angle(0).sub(angle(half + 1)), ALPHA_HALF as i16 - 1);
+    }
+
+    #[test]
+    fn diff_is_symmetric_and_short() {
+        for (a, b, expected) in [
+            (0, ALPHA_MAX as u16, 1),
+            (100, 200, 100),
+            (0, ALPHA_HALF as u16, ALPHA_HALF as u16),
+            (1, ALPHA_HALF as u16 + 2, ALPHA_HALF as u16 - 1),
+        ] {
+            assert_eq!(angle(a).diff(angle(b)), expected);
+            assert_eq!(angle(b).diff(angle(a)), expected);
+        }
+    }
+
+    #[test]
+    fn opcode_round_trips() {
+        for op in OPCODES {
+            assert!(MlxOpcode::from_number(op as u8) == op, "{:#x}", op as u8);
+        }
+    }
+
+    #[test]
+    fn unknown_opcode_bytes_map_to_not_an_opcode() {
+        for number in 0..=u8::MAX {
+            if OPCODES.iter().all(|op| *op as u8 != number) {
+                assert!(MlxOpcode::from_number(number) == MlxOpcode::NotAnOpcode);
+            }
+        }
+    }
+
+    #[test]
+    fn marker_round_trips() {
+        for marker in [
+            MlxMarker::Alpha,
+            MlxMarker::AlphaBeta,
+            MlxMarker::XYZ,
+            MlxMarker::Irregular,
+        ] {
+            assert!(MlxMarker::from_number(marker.to_number() >> 6) == marker);
+        }
+    }
+
+    #[test]
+    fn alpha_beta_frames_are_rejected_and_xyz_frames_decoded() {
+        let frame = [0, 0, 0, 0, 0, 0, MlxMarker::AlphaBeta.to_number(), 0];
+        assert!(matches!(
+            MlxReply::deserialize(frame),
+            Err(MlxError::FormatError)
+        ));
+
+        let frame = xyz_frame(-1, 0x1FFF, -0x2000, 5);
+        match MlxReply::deserialize(frame) {
+            Ok(MlxReply::MlxXYZ(xyz)) => {
+                assert_eq!((xyz.x, xyz.y, xyz.z), (-1, 0x1FFF, -0x2000));
+                assert_eq!(xyz.counter, 5);
+            }
+            _ => panic!("XYZ frame not decoded"),
+        }
+    }
+
+    /// XYZ frame with each component packed into 14 bits
+    fn xyz_frame(x: i16, y: i16, z: i16, counter: u8) -> [u8; 8] {
+        let mut frame = [0; 8];
+        for (i, c) in [x, y, z].into_iter().enumerate() {
+            put_u16_le(&mut frame[2 * i..2 * i + 2], c as u16 & 0x3FFF);
+        }
+        frame[6] = MlxMarker::XYZ.to_number() | counter;
+        frame
+    }
+
+    #[test]
+    fn xyz_is_read_once_the_sensor_is_ready() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        // Answer to the GET3 itself, then one NOP still measuring
+        spi.reply(nothing());
+        spi.reply(nothing());
+        spi.reply(irregular(MlxOpcode::Get3Ready, [0; 6]));
+        spi.reply(xyz_frame(100, -200, 300, 1));
+
+        match Mlx90363::get_xyz(&mut spi, &mut cs) {
+            Ok(Some(xyz)) => assert_eq!((xyz.x, xyz.y, xyz.z), (100, -200, 300)),
+            _ => panic!("XYZ not read"),
+        }
+        assert_eq!(spi.sent.len(), 4);
+        assert_eq!(
+            spi.sent[0][6],
+            MlxMarker::XYZ.to_number() | MlxOpcode::GET3 as u8
+        );
+        for nop in &spi.sent[1..] {
+            assert_eq!(*nop, NopMessage::new(FILLER_NOP_KEY).serialize());
+        }
+    }
+
+    #[test]
+    fn xyz_read_gives_up_without_get3_ready() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        for _ in 0..=GET3_READY_ATTEMPTS {
+            spi.reply(nothing());
+        }
+
+        assert!(matches!(Mlx90363::get_xyz(&mut spi, &mut cs), Ok(None)));
+        assert_eq!(spi.sent.len(), 1 + GET3_READY_ATTEMPTS as usize);
+    }
+
+    #[test]
+    fn unknown_write_status_is_a_format_error() {
+        let status = irregular(MlxOpcode::EEWriteStatus, [3, 0, 0, 0, 0, 0]);
+        assert!(matches!(
+            MlxReply::deserialize(status),
+            Err(MlxError::FormatError)
+        ));
+    }
+
+    #[test]
+    fn any_marker_and_opcode_byte_decodes_without_panic() {
+        for byte in 0..=u8::MAX {
+            let _ = MlxReply::deserialize([0, 0, 0, 0, 0, 0, byte, 0]);
+        }
+    }
+}
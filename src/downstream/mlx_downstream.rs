@@ -1,24 +1,33 @@
 use core::convert::Infallible;
 
-use cortex_m::delay;
-use defmt::{debug, info, Format};
+use defmt::{debug, info, warn, Format};
 use embedded_hal::digital::v2::OutputPin;
-use rp2040_hal::{
-    spi::{Enabled, SpiDevice, ValidSpiPinout},
-    Spi,
-};
+use rp2040_hal::timer::Instant;
 
 use crate::negicon_event::{NegiconEvent, NegiconEventType};
 
 use super::{
-    mlx90363::{Mlx90363, MlxReply},
-    spi_downstream::{DownstreamDevice, DownstreamError},
+    mlx90363::{Angle14, Mlx90363, MlxDiagnosticStatus, MlxReply, MlxStatus, MlxWrite, ALPHA_MAX},
+    spi_downstream::{
+        ButtonMapping, DeviceDiagnostics, DownstreamDevice, DownstreamError, DownstreamKind,
+        DownstreamResult, DownstreamSettings, ReportMode,
+    },
+    spi_protocol::NegiconProtocol,
 };
 
 #[derive(PartialEq, Clone, Copy, Format)]
-enum InputMode {
+pub(crate) enum InputMode {
     Absolute,
     Relative,
+    /// The 14-bit angle as read, without limits, scaling or inversion
+    RawAbsolute,
+}
+
+impl InputMode {
+    /// Whether events carry a position rather than a step
+    pub(crate) fn is_absolute(&self) -> bool {
+        matches!(self, Self::Absolute | Self::RawAbsolute)
+    }
 }
 
 #[derive(PartialEq, Copy, Clone, Format)]
@@ -36,6 +45,13 @@ impl<T: Copy + Format> ParameterState<T> {
             ParameterState::Initialized(value) => *value,
         }
     }
+
+    fn initialized(&self) -> Option<T> {
+        match self {
+            ParameterState::Initialized(value) => Some(*value),
+            _ => None,
+        }
+    }
 }
 
 #[derive(PartialEq, Copy, Clone, Format)]
@@ -44,230 +60,2140 @@ enum ButtonState {
     Down,
 }
 
+/// Axis lockout around button presses. Pushing the knob tends to turn it a
+/// little, so while the button is held and for `RELEASE_LOCKOUT_POLLS` polls
+/// after release readings only seed the baseline instead of reporting.
+#[derive(PartialEq, Copy, Clone, Format)]
+enum AxisLock {
+    Free,
+    Held,
+    /// Polls left before the axis reports again
+    Released(u16),
+}
+
+/// How the sensor is mounted, read from its EEPROM
+#[derive(PartialEq, Clone, Copy, Format)]
+struct Mounting {
+    /// Negate the axis, for sensors mounted the other way round
+    invert: bool,
+    /// Report the unprocessed angle in `InputMode::RawAbsolute`
+    raw_absolute: bool,
+    /// Report `NarrowInput` events in the `i8` range, for hosts that want
+    /// 8-bit axes. Ignored in `InputMode::RawAbsolute`.
+    narrow: bool,
+    /// Id reported instead of the one at `ADDR_ID`
+    id_override: Option<u16>,
+}
+
+impl Mounting {
+    /// `words` are the contents of `ADDR_ID_OVERRIDE` and `ADDR_FLAGS`. Erased
+    /// cells read as 0xFFFF, so 0 and 0xFFFF both mean no override.
+    fn from_words(words: [u16; 2]) -> Self {
+        Self {
+            invert: words[1] != 0xFFFF && words[1] & FLAG_INVERT != 0,
+            raw_absolute: words[1] != 0xFFFF && words[1] & FLAG_RAW_ABSOLUTE != 0,
+            narrow: words[1] != 0xFFFF && words[1] & FLAG_NARROW_OUTPUT != 0,
+            id_override: match words[0] {
+                0 | 0xFFFF => None,
+                id => Some(id),
+            },
+        }
+    }
+}
+
+/// Travel of an absolute axis, read from the sensor EEPROM
+#[derive(PartialEq, Clone, Copy, Format)]
+struct Limits {
+    min: u16,
+    max: u16,
+}
+
+impl Limits {
+    /// `words` are the contents of `ADDR_MIN` and `ADDR_MAX`.
+    fn from_words(words: [u16; 2]) -> Self {
+        Self {
+            min: words[0],
+            max: words[1],
+        }
+    }
+}
+
+/// Gain and offset applied to the axis output, read from the sensor EEPROM
+#[derive(PartialEq, Clone, Copy, Format)]
+struct Scaling {
+    /// 8.8 fixed point, `GAIN_UNITY` leaves the output unchanged
+    gain: u16,
+    offset: i16,
+}
+
+impl Scaling {
+    /// `words` are the contents of `ADDR_GAIN` and `ADDR_OFFSET`. Erased
+    /// cells read as 0xFFFF, so a gain of 0 or 0xFFFF means unity and an
+    /// offset of 0xFFFF means none.
+    fn from_words(words: [u16; 2]) -> Self {
+        Self {
+            gain: match words[0] {
+                0 | 0xFFFF => GAIN_UNITY,
+                gain => gain,
+            },
+            offset: match words[1] {
+                0xFFFF => 0,
+                offset => offset as i16,
+            },
+        }
+    }
+
+    /// Scales the position `value` and adds the offset, saturating at the
+    /// `i16` bounds.
+    fn apply_position(&self, value: i16) -> i16 {
+        let scaled = (value as i32 * self.gain as i32) >> 8;
+        (scaled + self.offset as i32).clamp(i16::MIN as i32, i16::MAX as i32) as i16
+    }
+
+    /// Scales the relative step `value`, saturating at the `i16` bounds. The
+    /// offset would make a resting axis move, so it isn't applied. The
+    /// fraction of a step the gain leaves is kept in `remainder`, in 1/256
+    /// steps, and added to the next one, so slow turns add up the same in
+    /// both directions.
+    fn apply_step(&self, value: i16, remainder: &mut i32) -> i16 {
+        let total = value as i32 * self.gain as i32 + *remainder;
+        let scaled = total / GAIN_UNITY as i32;
+        *remainder = total - scaled * GAIN_UNITY as i32;
+        scaled.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+    }
+}
+
 #[derive(Format)]
 pub(crate) struct MlxDownstream {
     id: ParameterState<u16>,
-    min: ParameterState<u16>,
-    max: ParameterState<u16>,
+    limits: ParameterState<Limits>,
+    mounting: ParameterState<Mounting>,
+    scaling: ParameterState<Scaling>,
     mode: InputMode,
-    last: u16,
+    last: Angle14,
     button_state: ButtonState,
-    lock_countdown: i16,
+    lock: AxisLock,
+    /// Readings left that only seed `last` after detection
+    prime_remaining: u16,
+    /// Report every reading as a `RawAlpha` event as well
+    raw_alpha: bool,
+    report_mode: ReportMode,
+    /// Relative steps below this are carried over instead of reported
+    min_relative_step: u16,
+    /// Relative motion held back so far by `min_relative_step`, or beyond
+    /// the bounds of the last reported step
+    step_carry: i32,
+    /// Fraction of a relative step left over by the gain, in 1/256 steps
+    scale_carry: i32,
+    /// Failed self-diagnostics in a row before `DiagnosticFail` is returned
+    diagnostic_fail_limit: u16,
+    /// Readings in a row that failed the self-diagnostic
+    diagnostic_fails: u16,
+    /// Failed parameter reads in a row before the unread parameters fall
+    /// back to their defaults
+    init_attempts: u16,
+    /// Parameter reads in a row that failed
+    init_failures: u16,
+    /// Id reported if the id can't be read from the EEPROM. Without one the
+    /// init gives up instead of falling back to defaults.
+    fallback_id: Option<u16>,
+    /// Some parameters are defaults rather than read from the EEPROM
+    defaulted: bool,
+    button_mapping: ButtonMapping,
+    /// Give the input events of a reading its frame number as sequence
+    frame_ids: bool,
+    /// Number of the last reading, wrapping
+    frame: u8,
+    /// Polls since the last periodic report
+    polls_since_report: u16,
+    /// Rolling counter of the last reading. The sensor advances it with
+    /// every reading, so a repeat means the reply was stale.
+    last_counter: Option<u8>,
+    /// Reset the rolling counter with the next request
+    resync_counter: bool,
+    /// Send `DiagnosticDetails` instead of the next GET
+    diagnostics_requested: bool,
+    /// Read the field components with a GET3 instead of the next GET
+    field_requested: bool,
+    moving: bool,
+    /// VG of the last reading, for diagnostic queries
+    field_strength: Option<u8>,
+    /// Revision from the last Ready message, if one was seen. Only sent after
+    /// a reset, so a sensor that was already running stays unknown.
+    version: Option<MlxStatus>,
+    /// EEPROM write in progress. While set, polls advance the write instead
+    /// of reading the angle.
+    write: Option<MlxWrite>,
 }
 
+/// Change from the last reported reading a resting axis has to exceed before
+/// it starts emitting events.
+const DEADZONE_ENTER: u16 = 64;
+/// Once an axis is moving, changes above this keep emitting events. Anything
+/// at or below it puts the axis back to rest.
+const DEADZONE_EXIT: u16 = 16;
+
+/// Polls the axis stays locked after the button is released. The time this
+/// takes follows the configured poll interval.
+const RELEASE_LOCKOUT_POLLS: u16 = 100;
+
 const ADDR_ID: u16 = 0x1018;
 const ADDR_MIN: u16 = 0x103A;
 const ADDR_MAX: u16 = 0x103C;
+const ADDR_ID_OVERRIDE: u16 = 0x1036;
+const ADDR_FLAGS: u16 = 0x1038;
+const FLAG_INVERT: u16 = 1 << 0;
+const FLAG_RAW_ABSOLUTE: u16 = 1 << 1;
+const FLAG_NARROW_OUTPUT: u16 = 1 << 2;
+const ADDR_GAIN: u16 = 0x1032;
+const ADDR_OFFSET: u16 = 0x1034;
+const GAIN_UNITY: u16 = 1 << 8;
+
+/// No limits, i.e. relative mode
+const DEFAULT_LIMITS: Limits = Limits { min: 0, max: 0 };
+const DEFAULT_MOUNTING: Mounting = Mounting {
+    invert: false,
+    raw_absolute: false,
+    narrow: false,
+    id_override: None,
+};
+const DEFAULT_SCALING: Scaling = Scaling {
+    gain: GAIN_UNITY,
+    offset: 0,
+};
 
 impl MlxDownstream {
-    pub(crate) fn new() -> Self {
+    /// The first `settings.prime_readings` readings only seed the baseline, so
+    /// the first event isn't measured against 0. With a `fallback_id`, a
+    /// sensor whose parameters can't be read runs on defaults and reports as
+    /// that id, without one it gives up.
+    pub(crate) fn new(
+        version: Option<MlxStatus>,
+        fallback_id: Option<u16>,
+        settings: &DownstreamSettings,
+    ) -> Self {
         Self {
-            id: ParameterState::Uninitialized(0),
-            min: ParameterState::Uninitialized(0),
-            max: ParameterState::Uninitialized(0),
+            id: ParameterState::Uninitialized(fallback_id.unwrap_or(0)),
+            limits: ParameterState::Uninitialized(DEFAULT_LIMITS),
+            mounting: ParameterState::Uninitialized(DEFAULT_MOUNTING),
+            scaling: ParameterState::Uninitialized(DEFAULT_SCALING),
             mode: InputMode::Relative,
-            last: 0,
+            last: Angle14::default(),
             button_state: ButtonState::Up,
-            lock_countdown: 100,
+            lock: AxisLock::Free,
+            prime_remaining: settings.prime_readings,
+            raw_alpha: settings.raw_alpha,
+            report_mode: settings.report_mode,
+            min_relative_step: settings.min_relative_step,
+            step_carry: 0,
+            scale_carry: 0,
+            diagnostic_fail_limit: settings.diagnostic_fail_limit,
+            diagnostic_fails: 0,
+            init_attempts: settings.init_attempts,
+            init_failures: 0,
+            fallback_id,
+            defaulted: false,
+            button_mapping: settings.button_mapping,
+            frame_ids: settings.frame_ids,
+            frame: 0,
+            polls_since_report: 0,
+            last_counter: None,
+            resync_counter: false,
+            diagnostics_requested: false,
+            field_requested: false,
+            moving: false,
+            field_strength: None,
+            version,
+            write: None,
         }
     }
-    fn init_param<R: Copy + Format, D: SpiDevice, T: ValidSpiPinout<D>>(
-        spi: &mut Spi<Enabled, D, T, 8>,
+    /// Reads the cells at `addresses` in one transaction. `transform` gets
+    /// their contents in the same order, so a parameter spanning two cells is
+    /// read at once and one stored in a single cell passes it twice.
+    fn init_param<R: Copy + Format>(
+        spi: &mut impl NegiconProtocol,
         cs: &mut dyn OutputPin<Error = Infallible>,
         param: ParameterState<R>,
         addresses: [u16; 2],
         transform: fn([u16; 2]) -> R,
-    ) -> Result<ParameterState<R>, DownstreamError> {
-        debug!("Querying param {:x}", addresses[0]);
+    ) -> DownstreamResult<ParameterState<R>> {
+        debug!("Querying param {:x} {:x}", addresses[0], addresses[1]);
         match param {
             ParameterState::Uninitialized(default) => {
-                match Mlx90363::read_memory(spi, cs, addresses[0], addresses[1]) {
-                    Ok(_) => Ok(ParameterState::Requested(default)),
-                    Err(e) => return Err(DownstreamError::MlxError(e)),
-                }
+                Mlx90363::read_memory(spi, cs, addresses[0], addresses[1])?;
+                Ok(ParameterState::Requested(default))
             }
             ParameterState::Requested(_) => {
-                match Mlx90363::read_memory(spi, cs, addresses[0], addresses[1]) {
-                    Ok(res) => match res {
-                        MlxReply::MlxMemReadResponse(msg) => {
-                            Ok(ParameterState::Initialized(transform([
-                                msg.data0, msg.data1,
-                            ])))
-                        }
-                        _ => {
-                            debug!("MLX init got {}", res);
-                            return Err(DownstreamError::UnexpectedReply);
-                        }
-                    },
-                    Err(e) => return Err(DownstreamError::MlxError(e)),
+                match Mlx90363::read_memory(spi, cs, addresses[0], addresses[1])? {
+                    MlxReply::MlxMemReadResponse(msg) => {
+                        Ok(ParameterState::Initialized(transform(msg.words())))
+                    }
+                    res => {
+                        debug!("MLX init got {}", res);
+                        Err(DownstreamError::UnexpectedReply)
+                    }
                 }
             }
             ParameterState::Initialized(_) => Ok(param),
         }
     }
-    fn check_deadzone(&mut self, input: u16) -> bool {
-        let deadzone = 64;
-        let diff = input as i32 - self.last as i32;
-        if diff.abs() > deadzone {
-            true
+
+    /// Reads the next parameter that isn't initialized yet, one frame per
+    /// call
+    fn init_step(
+        &mut self,
+        spi: &mut impl NegiconProtocol,
+        cs: &mut dyn OutputPin<Error = Infallible>,
+    ) -> DownstreamResult {
+        match self.id {
+            ParameterState::Initialized(_) => {}
+            _ => {
+                self.id =
+                    MlxDownstream::init_param(spi, cs, self.id, [ADDR_ID, ADDR_ID], |x| -> u16 {
+                        x[0]
+                    })?;
+                return Ok(());
+            }
+        }
+        match self.limits {
+            ParameterState::Initialized(_) => {}
+            _ => {
+                self.limits = MlxDownstream::init_param(
+                    spi,
+                    cs,
+                    self.limits,
+                    [ADDR_MIN, ADDR_MAX],
+                    Limits::from_words,
+                )?;
+                if let (Some(min), Some(max)) = (self.min(), self.max()) {
+                    if (min != 0 || max != 0) && max <= min {
+                        warn!(
+                            "Invalid limits min {} max {}, using relative mode",
+                            min, max
+                        );
+                        return Err(DownstreamError::InvalidLimits);
+                    }
+                }
+                return Ok(());
+            }
+        }
+        match self.mounting {
+            ParameterState::Initialized(_) => {}
+            _ => {
+                self.mounting = MlxDownstream::init_param(
+                    spi,
+                    cs,
+                    self.mounting,
+                    [ADDR_ID_OVERRIDE, ADDR_FLAGS],
+                    Mounting::from_words,
+                )?;
+                return Ok(());
+            }
+        }
+        match self.scaling {
+            ParameterState::Initialized(_) => {}
+            _ => {
+                self.scaling = MlxDownstream::init_param(
+                    spi,
+                    cs,
+                    self.scaling,
+                    [ADDR_GAIN, ADDR_OFFSET],
+                    Scaling::from_words,
+                )?;
+                if let ParameterState::Initialized(_) = self.scaling {
+                    info!("Initialized MLX Downstream {}", self)
+                }
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Initializes every parameter that couldn't be read with its default,
+    /// so a sensor with an unreadable EEPROM still works as a relative axis
+    /// reporting as `id`.
+    fn fall_back_to_defaults(&mut self, id: u16) {
+        if self.id.initialized().is_none() {
+            self.id = ParameterState::Initialized(id);
+        }
+        if self.limits.initialized().is_none() {
+            self.limits = ParameterState::Initialized(DEFAULT_LIMITS);
+        }
+        if self.mounting.initialized().is_none() {
+            self.mounting = ParameterState::Initialized(DEFAULT_MOUNTING);
+        }
+        if self.scaling.initialized().is_none() {
+            self.scaling = ParameterState::Initialized(DEFAULT_SCALING);
+        }
+        self.defaulted = true;
+    }
+
+    /// Decides whether `input` is far enough from the last reported reading to
+    /// emit an event. The threshold depends on whether the axis is resting
+    /// (`DEADZONE_ENTER`) or already moving (`DEADZONE_EXIT`), so a reading
+    /// dithering around the enter threshold only fires once. In both input
+    /// modes `last` is only advanced when an event is emitted. The distance is
+    /// taken the short way round, so crossing 0 isn't a jump of a full turn.
+    fn check_deadzone(&mut self, input: Angle14) -> bool {
+        let diff = input.diff(self.last);
+        let threshold = if self.moving {
+            DEADZONE_EXIT
         } else {
-            false
+            DEADZONE_ENTER
+        };
+        self.moving = diff > threshold;
+        self.moving
+    }
+    /// Counts a poll towards the next periodic report and returns true if it
+    /// is due. Always false in relative mode.
+    fn periodic_report_due(&mut self) -> bool {
+        match self.report_mode {
+            ReportMode::Periodic(period) if self.mode.is_absolute() => {
+                self.polls_since_report += 1;
+                if self.polls_since_report >= period {
+                    self.polls_since_report = 0;
+                    true
+                } else {
+                    false
+                }
+            }
+            _ => false,
+        }
+    }
+    /// Holds back relative steps smaller than `min_relative_step`, adding
+    /// them to the next step so no motion is lost. Steps beyond the output
+    /// bounds are reported saturated and the rest carried the same way.
+    /// Absolute positions saturate at the bounds.
+    fn filter_step(&mut self, value: i16) -> Option<i16> {
+        let (min, max) = self.output_bounds();
+        if self.mode.is_absolute() {
+            return Some(value.clamp(min, max));
         }
+        let total = self.step_carry + value as i32;
+        if total.unsigned_abs() < self.min_relative_step as u32 {
+            self.step_carry = total;
+            return None;
+        }
+        let step = total.clamp(min as i32, max as i32);
+        self.step_carry = total - step;
+        Some(step as i16)
+    }
+
+    /// Whether a saturated step left motion in `step_carry` that is reported
+    /// on the next poll even if the axis doesn't move
+    fn step_overflow_pending(&self) -> bool {
+        self.step_carry != 0 && self.step_carry.unsigned_abs() >= self.min_relative_step as u32
     }
-    fn calculate_output(&mut self, input: u16) -> i16 {
+
+    fn calculate_output(&mut self, input: Angle14) -> i16 {
         match self.mode {
+            InputMode::RawAbsolute => {
+                self.last = input;
+                input.get() as i16
+            }
             InputMode::Absolute => {
                 self.last = input;
+                let input = input.get();
+                let limits = self.limits.get_value();
+                let min = limits.min as i32;
+                let span = limits.max as i32 - min;
+                // Unset or inverted limits would underflow or divide by zero,
+                // pass the raw angle through until they are configured.
+                if span <= 0 {
+                    return input as i16;
+                }
                 let mut output = input as i32;
-                output -= self.min.get_value() as i32;
-                output *= 16383;
-                output /= (self.max.get_value() - self.min.get_value()) as i32;
-                output as i16
+                output -= min;
+                output *= ALPHA_MAX;
+                output /= span;
+                // Angles past the limits stay at the nearer end instead of
+                // wrapping around the i16 range
+                output.clamp(0, ALPHA_MAX) as i16
             }
             InputMode::Relative => {
-                let last = self.last as i32;
-                let input = input as i32;
-                self.last = input as u16;
+                let step = input.sub(self.last);
+                self.last = input;
+                step
+            }
+        }
+    }
 
-                let mut diff = input - last;
+    /// Input mode, only meaningful once `is_initialized`
+    pub(crate) fn mode(&self) -> InputMode {
+        self.mode
+    }
 
-                if diff > 16384 / 2 {
-                    diff -= 16384;
-                } else if diff < -16384 / 2 {
-                    diff += 16384;
-                }
-                diff as i16
-            }
+    /// Whether all parameters have been read from the EEPROM, i.e. the axis
+    /// is being polled
+    pub(crate) fn is_initialized(&self) -> bool {
+        self.id.initialized().is_some()
+            && self.limits.initialized().is_some()
+            && self.mounting.initialized().is_some()
+            && self.scaling.initialized().is_some()
+    }
+
+    pub(crate) fn min(&self) -> Option<u16> {
+        self.limits.initialized().map(|l| l.min)
+    }
+
+    pub(crate) fn max(&self) -> Option<u16> {
+        self.limits.initialized().map(|l| l.max)
+    }
+
+    /// Id the axis reports as, the button reports as the id after it.
+    fn reported_id(&self) -> u16 {
+        let mounting = self.mounting.get_value();
+        mounting.id_override.unwrap_or(self.id.get_value())
+    }
+
+    /// Scales `value` and applies the mounting orientation to it. Raw
+    /// absolute output is passed through untouched.
+    fn oriented(&mut self, value: i16) -> i16 {
+        let scaling = self.scaling.get_value();
+        let value = match self.mode {
+            InputMode::RawAbsolute => return value,
+            InputMode::Absolute => scaling.apply_position(value),
+            InputMode::Relative => scaling.apply_step(value, &mut self.scale_carry),
+        };
+        if self.mounting.get_value().invert {
+            value.saturating_neg()
+        } else {
+            value
+        }
+    }
+
+    /// Sequence of the input events of the current reading, so the host can
+    /// tell that an axis and a button event came from the same one
+    fn input_sequence(&self) -> u8 {
+        if self.frame_ids {
+            self.frame
+        } else {
+            0
+        }
+    }
+
+    /// Whether input is reported as `NarrowInput`. The raw angle is always
+    /// reported in full.
+    fn narrow(&self) -> bool {
+        self.mounting.get_value().narrow && self.mode != InputMode::RawAbsolute
+    }
+
+    /// Range of the reported input values
+    fn output_bounds(&self) -> (i16, i16) {
+        if self.narrow() {
+            (i8::MIN as i16, i8::MAX as i16)
+        } else {
+            (i16::MIN, i16::MAX)
+        }
+    }
+
+    /// Scales an absolute position from 14 to 7 bits on axes with 8-bit
+    /// output, before gain, offset and inversion apply. Steps are only
+    /// bounded by `filter_step`.
+    fn narrowed(&self, value: i16) -> i16 {
+        if self.narrow() && self.mode == InputMode::Absolute {
+            value >> 7
+        } else {
+            value
+        }
+    }
+
+    fn input_event(&self, value: i16) -> NegiconEvent {
+        if self.narrow() {
+            NegiconEvent::narrow_input(self.reported_id(), value as i8, self.input_sequence())
+        } else {
+            NegiconEvent::new(
+                NegiconEventType::Input,
+                self.reported_id(),
+                value,
+                0,
+                self.input_sequence(),
+            )
         }
     }
 
     fn check_button(&mut self, vg: u8) -> Option<NegiconEvent> {
         if self.button_state == ButtonState::Up && vg < 35 {
-            self.lock_countdown = -1;
+            self.lock = AxisLock::Held;
             self.button_state = ButtonState::Down;
             Some(NegiconEvent::new(
                 NegiconEventType::Input,
-                self.id.get_value() + 1 as u16,
-                1,
-                0,
+                self.reported_id()
+                    .wrapping_add(self.button_mapping.id_offset),
+                self.button_mapping.press,
                 0,
+                self.input_sequence(),
             ))
         } else if self.button_state == ButtonState::Down && vg > 35 {
             self.button_state = ButtonState::Up;
-            self.lock_countdown = 100;
+            self.lock = AxisLock::Released(RELEASE_LOCKOUT_POLLS);
             Some(NegiconEvent::new(
                 NegiconEventType::Input,
-                self.id.get_value() + 1 as u16,
-                -1,
-                0,
+                self.reported_id()
+                    .wrapping_add(self.button_mapping.id_offset),
+                self.button_mapping.release,
                 0,
+                self.input_sequence(),
             ))
         } else {
             None
         }
     }
 }
-impl<D, T> DownstreamDevice<D, T> for MlxDownstream
+impl<S> DownstreamDevice<S> for MlxDownstream
 where
-    D: SpiDevice,
-    T: ValidSpiPinout<D>,
+    S: NegiconProtocol,
 {
     fn poll(
         &mut self,
-        spi: &mut Spi<Enabled, D, T, 8>,
+        spi: &mut S,
         cs: &mut dyn OutputPin<Error = Infallible>,
-    ) -> Result<Option<NegiconEvent>, DownstreamError> {
-        match self.id {
-            ParameterState::Initialized(_) => {}
-            _ => {
-                self.id =
-                    MlxDownstream::init_param(spi, cs, self.id, [ADDR_ID, ADDR_ID], |x| -> u16 {
-                        x[1]
-                    })?;
-                return Ok(None);
-            }
-        }
-        match self.min {
-            ParameterState::Initialized(_) => {}
-            _ => {
-                self.min = MlxDownstream::init_param(
-                    spi,
-                    cs,
-                    self.min,
-                    [ADDR_MIN, ADDR_MIN],
-                    |x| -> u16 { x[1] },
-                )?;
-                return Ok(None);
+        now: Instant,
+        sink: &mut dyn FnMut(NegiconEvent),
+    ) -> DownstreamResult {
+        if let Some(write) = &mut self.write {
+            match write.step(spi, cs, now) {
+                Ok(false) => {}
+                Ok(true) => self.write = None,
+                Err(e) => {
+                    warn!(
+                        "Memory write aborted, {} cells not written",
+                        write.remaining()
+                    );
+                    let challenge = write.challenge();
+                    self.write = None;
+                    return Err(DownstreamError::WriteFailed(e, challenge));
+                }
             }
+            return Ok(());
         }
-        match self.max {
-            ParameterState::Initialized(_) => {}
-            _ => {
-                self.max = MlxDownstream::init_param(
-                    spi,
-                    cs,
-                    self.max,
-                    [ADDR_MAX, ADDR_MAX],
-                    |x| -> u16 { x[1] },
-                )?;
-                if let ParameterState::Initialized(_) = self.max {
-                    info!("Initialized MLX Downstream {}", self)
+        if !self.is_initialized() {
+            let res = self.init_step(spi, cs);
+            match res {
+                Err(DownstreamError::UnexpectedReply) => {
+                    self.init_failures = self.init_failures.saturating_add(1);
+                    if self.init_failures >= self.init_attempts.max(1) {
+                        match self.fallback_id {
+                            Some(id) => {
+                                warn!(
+                                    "MLX init failed {} times, using defaults",
+                                    self.init_failures
+                                );
+                                self.fall_back_to_defaults(id);
+                            }
+                            None => {
+                                warn!("MLX init failed {} times, giving up", self.init_failures)
+                            }
+                        }
+                        self.init_failures = 0;
+                        return Err(DownstreamError::InitFailed);
+                    }
                 }
-                return Ok(None);
+                _ => self.init_failures = 0,
             }
+            return res;
         }
-        if self.min.get_value() != 0 || self.max.get_value() != 0 {
+        // Unset limits read as 0, and invalid ones were reported when read
+        if self.mounting.get_value().raw_absolute {
+            self.mode = InputMode::RawAbsolute;
+        } else if self.limits.get_value().max > self.limits.get_value().min {
             self.mode = InputMode::Absolute;
         } else {
             self.mode = InputMode::Relative;
         }
-        match Mlx90363::get_alpha(spi, cs) {
-            Ok(res) => match res {
-                MlxReply::MlxAlpha(a) => {
-                    match self.check_button(a.vg) {
-                        Some(event) => return Ok(Some(event)),
-                        None => {}
+        if core::mem::take(&mut self.field_requested) {
+            // The reading the GET3 answers is skipped, and the next GET is
+            // answered by the last NOP of the handshake
+            if let Some(xyz) = Mlx90363::get_xyz(spi, cs)? {
+                info!("MLX field {}", xyz);
+                for (part, value) in [xyz.x, xyz.y, xyz.z].into_iter().enumerate() {
+                    sink(NegiconEvent::new(
+                        NegiconEventType::FieldReading,
+                        self.reported_id(),
+                        value,
+                        0,
+                        part as u8,
+                    ));
+                }
+            }
+            return Ok(());
+        }
+        let details = core::mem::take(&mut self.diagnostics_requested);
+        // A pending counter reset waits for the next GET
+        let reset_counter = !details && core::mem::take(&mut self.resync_counter);
+        let res = if details {
+            Mlx90363::diagnostic_details(spi, cs)
+        } else {
+            Mlx90363::get_alpha(spi, cs, reset_counter)
+        };
+        match res? {
+            MlxReply::MlxAlpha(a) => {
+                if reset_counter {
+                    // This reply was sent before the reset took effect
+                    self.last_counter = None;
+                } else if self.last_counter.replace(a.counter) == Some(a.counter) {
+                    debug!("Stale MLX reading, resetting rolling counter");
+                    self.resync_counter = true;
+                    return Ok(());
+                }
+                self.field_strength = Some(a.vg);
+                if self.raw_alpha {
+                    sink(NegiconEvent::raw_alpha(
+                        self.reported_id(),
+                        a.data.get(),
+                        a.vg,
+                        a.diag as u8,
+                    ));
+                }
+                if matches!(a.diag, MlxDiagnosticStatus::Fail) {
+                    // The angle can't be trusted, so neither axis nor
+                    // button see it. Reported once per run of failures.
+                    self.diagnostic_fails = self.diagnostic_fails.saturating_add(1);
+                    if self.diagnostic_fails == self.diagnostic_fail_limit.max(1) {
+                        warn!("MLX self-diagnostic failing");
+                        return Err(DownstreamError::DiagnosticFail);
                     }
-
-                    match self.lock_countdown {
-                        -1 => {
-                            self.last = a.data;
-                            return Ok(None);
+                    return Ok(());
+                }
+                self.diagnostic_fails = 0;
+                self.frame = self.frame.wrapping_add(1);
+                if self.prime_remaining > 0 {
+                    self.prime_remaining -= 1;
+                    self.last = a.data;
+                } else {
+                    // The axis is evaluated against the lockout from before
+                    // this sample, so a press that comes with motion reports
+                    // both, and the lockout starts with the next reading.
+                    match self.lock {
+                        AxisLock::Held => self.last = a.data,
+                        AxisLock::Free => {
+                            let moved = self.check_deadzone(a.data);
+                            if self.periodic_report_due() || moved || self.step_overflow_pending() {
+                                let value = self.calculate_output(a.data);
+                                let value = self.narrowed(value);
+                                let value = self.oriented(value);
+                                if let Some(value) = self.filter_step(value) {
+                                    sink(self.input_event(value));
+                                }
+                            }
                         }
-                        0 => {}
-                        _ => {
+                        AxisLock::Released(remaining) => {
                             self.last = a.data;
-                            self.lock_countdown -= 1;
-                            return Ok(None);
+                            self.lock = match remaining {
+                                0 | 1 => AxisLock::Free,
+                                _ => AxisLock::Released(remaining - 1),
+                            };
                         }
                     }
-                    if self.check_deadzone(a.data) {
-                        Ok(Some(NegiconEvent::new(
-                            NegiconEventType::Input,
-                            self.id.get_value() as u16,
-                            self.calculate_output(a.data),
-                            0,
-                            0,
-                        )))
-                    } else {
-                        return Ok(None);
-                    }
                 }
-                _ => Ok(None),
-            },
-            Err(e) => Err(DownstreamError::MlxError(e)),
+                if let Some(event) = self.check_button(a.vg) {
+                    sink(event);
+                }
+                Ok(())
+            }
+            MlxReply::Diagnostics(diag) => {
+                info!("MLX diagnostics {}", diag);
+                let parts = [
+                    diag.bits as u16 as i16,
+                    (diag.bits >> 16) as i16,
+                    ((diag.fsm_error_cause as u16) << 8 | diag.analog_rounds as u16) as i16,
+                ];
+                for (part, value) in parts.into_iter().enumerate() {
+                    sink(NegiconEvent::new(
+                        NegiconEventType::DiagnosticDetails,
+                        self.reported_id(),
+                        value,
+                        0,
+                        part as u8,
+                    ));
+                }
+                Ok(())
+            }
+            MlxReply::Ready(status) => {
+                info!("MLX90363 reset, revision {}", status);
+                self.version = Some(status);
+                Ok(())
+            }
+            // Only a write in flight expects the steps of the EEPROM
+            // handshake
+            MlxReply::MlxMemWriteChallengeReply(_)
+            | MlxReply::MlxMemWriteReadAnswerReply()
+            | MlxReply::MlxMemWriteStatusReply(_) => Err(DownstreamError::UnexpectedReply),
+            // The measurement wasn't ready when this frame went out. The
+            // GET sent with it is answered by the next poll, which reads
+            // the sample then, so axis and button state stay as they are.
+            MlxReply::NothingToTransmit() => {
+                debug!("MLX reading not ready, re-reading on the next poll");
+                Ok(())
+            }
+            _ => Ok(()),
         }
     }
 
-    fn write_memory(
-        &mut self,
-        spi: &mut Spi<Enabled, D, T, 8>,
-        cs: &mut dyn OutputPin<Error = Infallible>,
-        delay: &mut delay::Delay,
-        write_event: &NegiconEvent,
-    ) {
-        Mlx90363::write_memory(spi, cs, delay, write_event.value, write_event.sequence);
+    /// The id events are reported with, so the host can address memory
+    /// writes with the id it sees.
+    fn id(&self) -> Option<u16> {
+        match (self.id, self.mounting) {
+            (ParameterState::Initialized(_), ParameterState::Initialized(_)) => {
+                Some(self.reported_id())
+            }
+            _ => None,
+        }
+    }
+
+    fn kind(&self) -> DownstreamKind {
+        DownstreamKind::Mlx90363
+    }
+
+    fn version(&self) -> Option<u16> {
+        self.version.map(|v| v.packed())
+    }
+
+    fn diagnostics(&self) -> DeviceDiagnostics {
+        DeviceDiagnostics {
+            initialized: self.is_initialized(),
+            absolute: self.mode().is_absolute(),
+            min: self.min(),
+            max: self.max(),
+            field_strength: self.field_strength,
+            diagnostic_fail: self.diagnostic_fails > 0,
+            defaulted: self.defaulted,
+        }
+    }
+
+    /// Parameters keep their old values until re-read, which don't matter
+    /// since no input is reported before all of them are initialized.
+    fn reinit(&mut self) {
+        self.id = ParameterState::Uninitialized(self.id.get_value());
+        self.limits = ParameterState::Uninitialized(self.limits.get_value());
+        self.mounting = ParameterState::Uninitialized(self.mounting.get_value());
+        self.scaling = ParameterState::Uninitialized(self.scaling.get_value());
+        self.defaulted = false;
+    }
+
+    fn request_diagnostic_details(&mut self) {
+        self.diagnostics_requested = true;
+    }
+
+    fn request_field_reading(&mut self) {
+        self.field_requested = true;
+    }
+
+    fn apply_settings(&mut self, settings: &DownstreamSettings) {
+        self.raw_alpha = settings.raw_alpha;
+        self.min_relative_step = settings.min_relative_step;
+        self.diagnostic_fail_limit = settings.diagnostic_fail_limit;
+        self.init_attempts = settings.init_attempts;
+        self.button_mapping = settings.button_mapping;
+        self.frame_ids = settings.frame_ids;
+        if self.report_mode != settings.report_mode {
+            self.report_mode = settings.report_mode;
+            self.polls_since_report = 0;
+        }
+    }
+
+    fn write_memory(&mut self, cells: &[(u8, i16)]) {
+        match &mut self.write {
+            Some(write) => write.queue(cells),
+            None => self.write = Some(MlxWrite::new(cells)),
+        }
     }
 }
 
 //impl<R: MlxReply> MlxDownstream<R> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downstream::{
+        mlx90363::{
+            MlxDiagnosticStatus, MlxError, MlxMemWriteStatus, MlxOpcode, ALPHA_HALF,
+            MLX_EEPROM_WRITE_MS,
+        },
+        mock::{MockPin, MockSpi, NoDelay, RecordingDelay},
+        spi_downstream::{DownstreamKind, SpiDownstream},
+        util::u16_from_le,
+    };
+    use crate::negicon_event::RAW_ALPHA_DIAG_FLIP;
+    use alloc::{boxed::Box, vec::Vec};
+
+    /// Mirrors `poll` once the parameters are initialized: an event is
+    /// emitted, and `last` advanced, only past the deadzone.
+    fn feed(ds: &mut MlxDownstream, input: u16) -> Option<i16> {
+        let input = Angle14::from_bits(input);
+        if ds.check_deadzone(input) {
+            Some(ds.calculate_output(input))
+        } else {
+            None
+        }
+    }
+
+    fn resting_at(last: u16) -> MlxDownstream {
+        let mut ds = MlxDownstream::new(
+            None,
+            None,
+            &DownstreamSettings {
+                prime_readings: 0,
+                ..Default::default()
+            },
+        );
+        ds.last = Angle14::from_bits(last);
+        ds
+    }
+
+    #[test]
+    fn resting_axis_ignores_changes_up_to_the_enter_threshold() {
+        let mut ds = resting_at(1000);
+        for input in [1000 + 64, 1000 - 64, 1000 + 17, 1000] {
+            assert_eq!(feed(&mut ds, input), None);
+        }
+        assert_eq!(feed(&mut ds, 1000 + 65), Some(65));
+    }
+
+    #[test]
+    fn dithering_at_the_enter_threshold_fires_once() {
+        let mut ds = resting_at(1000);
+        let events = [1063u16, 1065, 1064, 1065, 1063, 1066, 1064, 1065, 1063]
+            .iter()
+            .filter_map(|&input| feed(&mut ds, input))
+            .count();
+        assert_eq!(events, 1);
+        assert!(!ds.moving);
+    }
+
+    #[test]
+    fn moving_axis_keeps_reporting_steps_above_the_exit_threshold() {
+        let mut ds = resting_at(1000);
+        assert_eq!(feed(&mut ds, 1100), Some(100));
+        assert_eq!(feed(&mut ds, 1117), Some(17));
+        assert_eq!(feed(&mut ds, 1100), Some(-17));
+        // Back to rest, so small steps need the enter threshold again
+        assert_eq!(feed(&mut ds, 1116), None);
+        assert_eq!(feed(&mut ds, 1140), None);
+        assert_eq!(feed(&mut ds, 1165), Some(65));
+    }
+
+    #[test]
+    fn deadzone_is_measured_the_short_way_across_zero() {
+        let mut ds = resting_at(16380);
+        // 34 and 64 counts past the wrap, not a full turn back
+        assert_eq!(feed(&mut ds, 30), None);
+        assert_eq!(feed(&mut ds, 60), None);
+        assert_eq!(feed(&mut ds, 61), Some(65));
+        assert_eq!(feed(&mut ds, 16370), Some(-75));
+    }
+
+    #[test]
+    fn absolute_mode_uses_the_same_hysteresis() {
+        let mut ds = resting_at(1000);
+        ds.mode = InputMode::Absolute;
+        ds.limits = ParameterState::Initialized(Limits { min: 0, max: 16383 });
+        assert_eq!(feed(&mut ds, 1064), None);
+        assert_eq!(feed(&mut ds, 1065), Some(1065));
+        assert_eq!(feed(&mut ds, 1082), Some(1082));
+        assert_eq!(feed(&mut ds, 1066), None);
+        assert_eq!(ds.last.get(), 1082);
+    }
+
+    /// Device with all parameters read back, resting at `last` in relative
+    /// mode with the startup lockout over
+    fn running_at(id: u16, last: u16) -> MlxDownstream {
+        let mut ds = resting_at(last);
+        ds.id = ParameterState::Initialized(id);
+        ds.limits = ParameterState::Initialized(Limits { min: 0, max: 0 });
+        ds.mounting = ParameterState::Initialized(Mounting::from_words([0xFFFF, 0xFFFF]));
+        ds.scaling = ParameterState::Initialized(Scaling::from_words([0xFFFF, 0xFFFF]));
+        ds.lock = AxisLock::Free;
+        ds
+    }
+
+    fn mounted(id: u16, last: u16, words: [u16; 2]) -> MlxDownstream {
+        let mut ds = running_at(id, last);
+        ds.mounting = ParameterState::Initialized(Mounting::from_words(words));
+        ds
+    }
+
+    fn poll_events(ds: &mut MlxDownstream, spi: &mut MockSpi) -> Vec<(u16, i16)> {
+        let mut events = Vec::new();
+        let res = ds.poll(spi, &mut MockPin::new(), Instant::from_ticks(0), &mut |e| {
+            events.push((e.id(), e.value()))
+        });
+        assert!(res.is_ok());
+        events
+    }
+
+    /// GET1 answer; the sensor advances `counter` with every reading
+    fn alpha_frame(data: u16, vg: u8, counter: u8) -> [u8; 8] {
+        [
+            data as u8,
+            (data >> 8) as u8 & 0x3F,
+            0,
+            0,
+            vg,
+            0,
+            counter,
+            0,
+        ]
+    }
+
+    #[test]
+    fn reading_not_ready_is_read_on_the_next_poll() {
+        let mut spi = MockSpi::default();
+        let mut ds = running_at(20, 1000);
+        spi.reply(alpha_frame(1000, 200, 1));
+        assert!(poll_events(&mut ds, &mut spi).is_empty());
+
+        spi.reply([
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0xC0 | MlxOpcode::NothingToTransmit as u8,
+            0,
+        ]);
+        assert!(poll_events(&mut ds, &mut spi).is_empty());
+        // The same GET goes out again, no counter reset that would drop the
+        // sample it answers
+        assert_eq!(spi.sent[1], spi.sent[0]);
+
+        spi.reply(alpha_frame(1200, 200, 2));
+        assert_eq!(poll_events(&mut ds, &mut spi), [(20, 200)]);
+    }
+
+    fn failed_frame(data: u16, vg: u8, counter: u8) -> [u8; 8] {
+        let mut frame = alpha_frame(data, vg, counter);
+        frame[1] |= (MlxDiagnosticStatus::Fail as u8) << 6;
+        frame
+    }
+
+    #[test]
+    fn failing_self_diagnostic_drops_readings_and_errors_at_the_limit() {
+        let mut spi = MockSpi::default();
+        let mut ds = running_at(20, 1000);
+        let mut results = Vec::new();
+        // Moved and pressed, but none of it can be trusted
+        for counter in 1..=4 {
+            spi.reply(failed_frame(2000, 20, counter));
+            let mut events = Vec::new();
+            let res = ds.poll(
+                &mut spi,
+                &mut MockPin::new(),
+                Instant::from_ticks(0),
+                &mut |e| events.push(e),
+            );
+            assert!(events.is_empty());
+            results.push(res);
+        }
+
+        // Reported once per run of failures, with the default limit of 3
+        assert!(matches!(
+            results[..],
+            [Ok(()), Ok(()), Err(DownstreamError::DiagnosticFail), Ok(())]
+        ));
+        let event = DownstreamError::DiagnosticFail.to_event(2);
+        assert!(event.event_type() == NegiconEventType::Error);
+        assert_eq!((event.id(), event.value()), (2, 8));
+        assert!(DownstreamDevice::<MockSpi>::diagnostics(&ds).diagnostic_fail);
+
+        spi.reply(alpha_frame(2000, 200, 5));
+        assert_eq!(poll_events(&mut ds, &mut spi), [(20, 1000)]);
+        assert!(!DownstreamDevice::<MockSpi>::diagnostics(&ds).diagnostic_fail);
+    }
+
+    #[test]
+    fn diagnostic_fail_limit_comes_from_the_settings() {
+        let mut spi = MockSpi::default();
+        let mut ds = running_at(20, 1000);
+        DownstreamDevice::<MockSpi>::apply_settings(
+            &mut ds,
+            &DownstreamSettings {
+                diagnostic_fail_limit: 1,
+                ..Default::default()
+            },
+        );
+        spi.reply(failed_frame(1000, 200, 1));
+        let res = ds.poll(
+            &mut spi,
+            &mut MockPin::new(),
+            Instant::from_ticks(0),
+            &mut |_| {},
+        );
+        assert!(matches!(res, Err(DownstreamError::DiagnosticFail)));
+    }
+
+    #[test]
+    fn press_with_motion_reports_axis_and_button_from_one_poll() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut ds = running_at(20, 1000);
+        spi.reply(alpha_frame(1200, 20, 0));
+
+        let mut events = Vec::new();
+        assert!(ds
+            .poll(&mut spi, &mut cs, Instant::from_ticks(0), &mut |e| events
+                .push(e))
+            .is_ok());
+
+        let got: Vec<_> = events.iter().map(|e| (e.id(), e.value())).collect();
+        assert_eq!(got, [(20, 200), (21, 1)]);
+    }
+
+    fn poll_sequences(ds: &mut MlxDownstream, spi: &mut MockSpi) -> Vec<(u16, u8)> {
+        let mut events = Vec::new();
+        let res = ds.poll(spi, &mut MockPin::new(), Instant::from_ticks(0), &mut |e| {
+            events.push((e.id(), e.sequence()))
+        });
+        assert!(res.is_ok());
+        events
+    }
+
+    #[test]
+    fn events_of_one_reading_share_a_frame_id() {
+        let mut spi = MockSpi::default();
+        let mut ds = running_at(20, 1000);
+        DownstreamDevice::<MockSpi>::apply_settings(
+            &mut ds,
+            &DownstreamSettings {
+                frame_ids: true,
+                ..Default::default()
+            },
+        );
+        spi.reply(alpha_frame(1200, 20, 0));
+        spi.reply(alpha_frame(1200, 200, 1));
+        spi.reply(alpha_frame(1200, 20, 2));
+
+        let pressed = poll_sequences(&mut ds, &mut spi);
+        let frame = pressed[0].1;
+        assert!(frame != 0);
+        assert_eq!(pressed, [(20, frame), (21, frame)]);
+        assert_eq!(poll_sequences(&mut ds, &mut spi), [(21, frame + 1)]);
+
+        // Without frame ids the sequence stays 0
+        DownstreamDevice::<MockSpi>::apply_settings(&mut ds, &DownstreamSettings::default());
+        assert_eq!(poll_sequences(&mut ds, &mut spi), [(21, 0)]);
+    }
+
+    #[test]
+    fn button_edges_follow_the_mapping() {
+        let mut spi = MockSpi::default();
+        let mut ds = running_at(20, 1000);
+        DownstreamDevice::<MockSpi>::apply_settings(
+            &mut ds,
+            &DownstreamSettings {
+                button_mapping: ButtonMapping {
+                    press: 0,
+                    release: 1,
+                    id_offset: 100,
+                },
+                ..Default::default()
+            },
+        );
+        spi.reply(alpha_frame(1000, 20, 0));
+        spi.reply(alpha_frame(1000, 200, 1));
+
+        let mut events = poll_events(&mut ds, &mut spi);
+        events.extend(poll_events(&mut ds, &mut spi));
+        assert_eq!(events, [(120, 0), (120, 1)]);
+    }
+
+    #[test]
+    fn button_id_offset_wraps_around() {
+        let mut spi = MockSpi::default();
+        let mut ds = running_at(20, 1000);
+        DownstreamDevice::<MockSpi>::apply_settings(
+            &mut ds,
+            &DownstreamSettings {
+                button_mapping: ButtonMapping {
+                    id_offset: u16::MAX,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        spi.reply(alpha_frame(1000, 20, 0));
+
+        assert_eq!(poll_events(&mut ds, &mut spi), [(19, 1)]);
+    }
+
+    #[test]
+    fn held_button_locks_the_axis() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut ds = running_at(20, 1000);
+        spi.reply(alpha_frame(1000, 20, 0));
+        spi.reply(alpha_frame(1500, 20, 1));
+
+        let mut events = Vec::new();
+        assert!(ds
+            .poll(&mut spi, &mut cs, Instant::from_ticks(0), &mut |e| events
+                .push(e))
+            .is_ok());
+        assert!(ds
+            .poll(&mut spi, &mut cs, Instant::from_ticks(0), &mut |e| events
+                .push(e))
+            .is_ok());
+
+        let got: Vec<_> = events.iter().map(|e| (e.id(), e.value())).collect();
+        assert_eq!(got, [(21, 1)]);
+    }
+
+    #[test]
+    fn axis_stays_locked_for_the_lockout_after_release() {
+        let mut spi = MockSpi::default();
+        let mut ds = running_at(20, 1000);
+        ds.button_state = ButtonState::Down;
+        ds.lock = AxisLock::Held;
+        // Release, with the knob wobbling while it comes back up
+        spi.reply(alpha_frame(1000, 100, 0));
+        assert_eq!(poll_events(&mut ds, &mut spi), [(21, -1)]);
+        assert!(ds.lock == AxisLock::Released(RELEASE_LOCKOUT_POLLS));
+
+        for i in 0..RELEASE_LOCKOUT_POLLS {
+            spi.reply(alpha_frame(1100 + i, 100, (1 + i) as u8 & 0x3F));
+            assert!(poll_events(&mut ds, &mut spi).is_empty());
+        }
+        assert!(ds.lock == AxisLock::Free);
+        // Turns are measured from the last reading of the lockout
+        let settled = 1100 + RELEASE_LOCKOUT_POLLS - 1;
+        spi.reply(alpha_frame(settled + 100, 100, 0x3F));
+        assert_eq!(poll_events(&mut ds, &mut spi), [(20, 100)]);
+    }
+
+    #[test]
+    fn half_turn_steps_are_kept_and_bigger_ones_wrap() {
+        let half = ALPHA_HALF as u16;
+        assert_eq!(
+            resting_at(0).calculate_output(Angle14::from_bits(half)),
+            ALPHA_HALF as i16
+        );
+        assert_eq!(
+            resting_at(half).calculate_output(Angle14::from_bits(0)),
+            -ALPHA_HALF as i16
+        );
+        assert_eq!(
+            resting_at(0).calculate_output(Angle14::from_bits(half + 1)),
+            -(ALPHA_HALF as i16 - 1)
+        );
+        assert_eq!(
+            resting_at(half + 1).calculate_output(Angle14::from_bits(0)),
+            ALPHA_HALF as i16 - 1
+        );
+    }
+
+    #[test]
+    fn crossing_zero_reports_the_short_way_round() {
+        let max = ALPHA_MAX as u16;
+        assert_eq!(resting_at(max).calculate_output(Angle14::from_bits(0)), 1);
+        assert_eq!(resting_at(0).calculate_output(Angle14::from_bits(max)), -1);
+    }
+
+    fn absolute_with_limits(min: u16, max: u16) -> MlxDownstream {
+        let mut ds = resting_at(0);
+        ds.mode = InputMode::Absolute;
+        ds.limits = ParameterState::Initialized(Limits { min, max });
+        ds
+    }
+
+    #[test]
+    fn absolute_output_scales_between_the_limits() {
+        let mut ds = absolute_with_limits(1000, 3000);
+        assert_eq!(ds.calculate_output(Angle14::from_bits(1000)), 0);
+        assert_eq!(
+            ds.calculate_output(Angle14::from_bits(2000)),
+            (ALPHA_MAX / 2) as i16
+        );
+        assert_eq!(
+            ds.calculate_output(Angle14::from_bits(3000)),
+            ALPHA_MAX as i16
+        );
+    }
+
+    #[test]
+    fn angles_past_the_limits_saturate_with_and_without_scaling() {
+        let mut ds = absolute_with_limits(1000, 2000);
+        for (angle, expected) in [(5000, ALPHA_MAX as i16), (500, 0)] {
+            let output = ds.calculate_output(Angle14::from_bits(angle));
+            assert_eq!(output, expected);
+            assert_eq!(ds.oriented(output), expected);
+        }
+
+        ds.scaling = ParameterState::Initialized(Scaling::from_words([2 * GAIN_UNITY, 1000]));
+        for (angle, expected) in [(5000, i16::MAX), (500, 1000)] {
+            let output = ds.calculate_output(Angle14::from_bits(angle));
+            assert_eq!(ds.oriented(output), expected);
+        }
+    }
+
+    #[test]
+    fn unset_limits_pass_the_raw_angle_through() {
+        let mut ds = absolute_with_limits(0, 0);
+        assert_eq!(ds.calculate_output(Angle14::from_bits(1234)), 1234);
+        assert_eq!(ds.last.get(), 1234);
+    }
+
+    #[test]
+    fn inverted_limits_pass_the_raw_angle_through() {
+        let mut ds = absolute_with_limits(3000, 1000);
+        assert_eq!(ds.calculate_output(Angle14::from_bits(0)), 0);
+        assert_eq!(
+            ds.calculate_output(Angle14::from_bits(ALPHA_MAX as u16)),
+            ALPHA_MAX as i16
+        );
+    }
+
+    #[test]
+    fn equal_limits_pass_the_raw_angle_through() {
+        let mut ds = absolute_with_limits(2000, 2000);
+        assert_eq!(ds.calculate_output(Angle14::from_bits(2000)), 2000);
+        assert_eq!(ds.calculate_output(Angle14::from_bits(5)), 5);
+    }
+
+    #[test]
+    fn diagnostics_report_the_field_strength_of_the_last_reading() {
+        let mut spi = MockSpi::default();
+        let mut ds = running_at(20, 1000);
+        assert!(DownstreamDevice::<MockSpi>::diagnostics(&ds)
+            .field_strength
+            .is_none());
+        spi.reply(alpha_frame(1000, 87, 1));
+        poll_events(&mut ds, &mut spi);
+        spi.reply(alpha_frame(1000, 203, 2));
+        poll_events(&mut ds, &mut spi);
+        let diag = DownstreamDevice::<MockSpi>::diagnostics(&ds);
+        assert_eq!(diag.field_strength, Some(203));
+    }
+
+    /// Absolute axis at rest, with limits spanning the full turn so events
+    /// carry the raw angle
+    fn still_absolute(report_mode: ReportMode) -> MlxDownstream {
+        let mut ds = running_at(20, 1000);
+        ds.limits = ParameterState::Initialized(Limits {
+            min: 0,
+            max: ALPHA_MAX as u16,
+        });
+        DownstreamDevice::<MockSpi>::apply_settings(
+            &mut ds,
+            &DownstreamSettings {
+                report_mode,
+                ..Default::default()
+            },
+        );
+        ds
+    }
+
+    #[test]
+    fn periodic_mode_reports_a_still_axis_every_period() {
+        let mut spi = MockSpi::default();
+        let mut ds = still_absolute(ReportMode::Periodic(3));
+        let mut reported = Vec::new();
+        for poll in 1..=9u8 {
+            spi.reply(alpha_frame(1000, 200, poll));
+            if !poll_events(&mut ds, &mut spi).is_empty() {
+                reported.push(poll);
+            }
+        }
+        assert_eq!(reported, [3, 6, 9]);
+    }
+
+    #[test]
+    fn periodic_report_carries_the_position() {
+        let mut spi = MockSpi::default();
+        let mut ds = still_absolute(ReportMode::Periodic(1));
+        spi.reply(alpha_frame(1000, 200, 1));
+        assert_eq!(poll_events(&mut ds, &mut spi), [(20, 1000)]);
+    }
+
+    #[test]
+    fn on_change_mode_keeps_a_still_axis_quiet() {
+        let mut spi = MockSpi::default();
+        let mut ds = still_absolute(ReportMode::OnChange);
+        for poll in 1..=9u8 {
+            spi.reply(alpha_frame(1000, 200, poll));
+            assert!(poll_events(&mut ds, &mut spi).is_empty());
+        }
+        spi.reply(alpha_frame(1100, 200, 10));
+        assert_eq!(poll_events(&mut ds, &mut spi), [(20, 1100)]);
+    }
+
+    #[test]
+    fn relative_axes_ignore_the_report_period() {
+        let mut spi = MockSpi::default();
+        let mut ds = running_at(20, 1000);
+        DownstreamDevice::<MockSpi>::apply_settings(
+            &mut ds,
+            &DownstreamSettings {
+                report_mode: ReportMode::Periodic(1),
+                ..Default::default()
+            },
+        );
+        for poll in 1..=3u8 {
+            spi.reply(alpha_frame(1000, 200, poll));
+            assert!(poll_events(&mut ds, &mut spi).is_empty());
+        }
+    }
+
+    fn with_min_step(min_relative_step: u16) -> MlxDownstream {
+        let mut ds = running_at(20, 1000);
+        DownstreamDevice::<MockSpi>::apply_settings(
+            &mut ds,
+            &DownstreamSettings {
+                min_relative_step,
+                ..Default::default()
+            },
+        );
+        ds
+    }
+
+    #[test]
+    fn steps_below_the_minimum_are_carried_into_the_next_one() {
+        let mut spi = MockSpi::default();
+        let mut ds = with_min_step(100);
+        let mut reported = Vec::new();
+        for (counter, data) in [1070, 1110, 1130, 1150, 1170, 1190, 1210]
+            .into_iter()
+            .enumerate()
+        {
+            spi.reply(alpha_frame(data, 200, counter as u8 + 1));
+            reported.extend(poll_events(&mut ds, &mut spi));
+        }
+        // 70 is held back and sent along with the next 40, then four steps
+        // of 20 stay below the minimum until the fifth makes it 100
+        assert_eq!(reported, [(20, 110), (20, 100)]);
+    }
+
+    #[test]
+    fn carried_steps_in_opposite_directions_cancel_out() {
+        let mut spi = MockSpi::default();
+        let mut ds = with_min_step(100);
+        for (counter, data) in [1070, 1000, 1090].into_iter().enumerate() {
+            spi.reply(alpha_frame(data, 200, counter as u8 + 1));
+            assert!(poll_events(&mut ds, &mut spi).is_empty());
+        }
+        spi.reply(alpha_frame(1130, 200, 4));
+        assert_eq!(poll_events(&mut ds, &mut spi), [(20, 130)]);
+    }
+
+    #[test]
+    fn no_minimum_sends_every_step() {
+        let mut spi = MockSpi::default();
+        let mut ds = with_min_step(0);
+        spi.reply(alpha_frame(1070, 200, 1));
+        assert_eq!(poll_events(&mut ds, &mut spi), [(20, 70)]);
+    }
+
+    #[test]
+    fn double_gain_doubles_the_output() {
+        let scaling = Scaling::from_words([2 * GAIN_UNITY, 0]);
+        assert_eq!(scaling.apply_position(100), 200);
+        assert_eq!(scaling.apply_position(-100), -200);
+        assert_eq!(scaling.apply_position(20000), i16::MAX);
+        assert_eq!(scaling.apply_position(-20000), i16::MIN);
+    }
+
+    #[test]
+    fn fractional_gain_shrinks_the_output() {
+        let scaling = Scaling::from_words([GAIN_UNITY / 4, 0]);
+        assert_eq!(scaling.apply_position(100), 25);
+        assert_eq!(scaling.apply_position(i16::MAX), i16::MAX / 4);
+        // The shift rounds towards negative infinity
+        assert_eq!(scaling.apply_position(-3), -1);
+    }
+
+    #[test]
+    fn offset_is_added_after_the_gain_and_saturates() {
+        let scaling = Scaling::from_words([2 * GAIN_UNITY, 1000]);
+        assert_eq!(scaling.apply_position(0), 1000);
+        assert_eq!(scaling.apply_position(-600), -200);
+        assert_eq!(scaling.apply_position(16000), i16::MAX);
+        let scaling = Scaling::from_words([GAIN_UNITY, -1000i16 as u16]);
+        assert_eq!(scaling.apply_position(i16::MIN + 10), i16::MIN);
+    }
+
+    #[test]
+    fn erased_scaling_words_leave_the_output_unchanged() {
+        let scaling = Scaling::from_words([0xFFFF, 0xFFFF]);
+        assert!(scaling == Scaling::from_words([0, 0]));
+        assert_eq!(scaling.apply_position(-1234), -1234);
+    }
+
+    #[test]
+    fn scaling_composes_with_invert() {
+        let mut spi = MockSpi::default();
+        let mut ds = mounted(20, 1000, [0xFFFF, FLAG_INVERT]);
+        ds.scaling = ParameterState::Initialized(Scaling::from_words([2 * GAIN_UNITY, 50]));
+        spi.reply(alpha_frame(1100, 200, 0));
+
+        // Scaled to 200 first, then inverted. The offset only moves
+        // positions.
+        assert_eq!(poll_events(&mut ds, &mut spi), [(20, -200)]);
+    }
+
+    #[test]
+    fn fractional_gain_carries_step_remainders() {
+        let scaling = Scaling::from_words([GAIN_UNITY / 4, 1000]);
+        let turn = |step: i16, remainder: &mut i32| -> Vec<i16> {
+            (0..8)
+                .map(|_| scaling.apply_step(step, remainder))
+                .collect()
+        };
+        let mut remainder = 0;
+        // A quarter step each, a whole one every fourth, the same both ways
+        assert_eq!(turn(1, &mut remainder), [0, 0, 0, 1, 0, 0, 0, 1]);
+        assert_eq!(remainder, 0);
+        assert_eq!(turn(-1, &mut remainder), [0, 0, 0, -1, 0, 0, 0, -1]);
+        assert_eq!(remainder, 0);
+        // Back and forth cancels out instead of drifting
+        assert_eq!(scaling.apply_step(1, &mut remainder), 0);
+        assert_eq!(scaling.apply_step(-1, &mut remainder), 0);
+        assert_eq!(remainder, 0);
+        assert_eq!(scaling.apply_step(-3, &mut remainder), 0);
+        assert_eq!(scaling.apply_step(3, &mut remainder), 0);
+        assert_eq!(scaling.apply_step(0, &mut remainder), 0);
+    }
+
+    #[test]
+    fn pending_write_takes_the_place_of_the_angle_read() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut ds = running_at(20, 1000);
+        DownstreamDevice::<MockSpi>::write_memory(&mut ds, &[(0x20, 1)]);
+        spi.reply(alpha_frame(1200, 20, 0));
+
+        let mut events = Vec::new();
+        let res = ds.poll(&mut spi, &mut cs, Instant::from_ticks(0), &mut |e| {
+            events.push(e)
+        });
+        assert!(res.is_ok());
+        assert!(events.is_empty());
+        // The leading NOP of the write went out instead of a GET1
+        assert_eq!(spi.sent.len(), 1);
+        assert_eq!(spi.sent[0][6] & 0x3F, MlxOpcode::NOPChallenge as u8);
+    }
+
+    #[test]
+    fn write_waits_for_the_eeprom_across_polls_instead_of_delaying() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut slot = SpiDownstream::new(&mut cs);
+        slot.attach(DownstreamKind::Mlx90363, Box::new(running_at(20, 1000)));
+        slot.write_memory(&NegiconEvent::new(
+            NegiconEventType::MemWrite,
+            20,
+            1,
+            0,
+            0x20,
+        ));
+        let irregular = |opcode: MlxOpcode, data: [u8; 4]| {
+            let [d0, d1, d2, d3] = data;
+            [d0, d1, d2, d3, 0, 0, 0xC0 | opcode as u8, 0]
+        };
+        // Leading NOP, EEWrite, challenge request and answer, status NOP
+        spi.reply(irregular(MlxOpcode::NothingToTransmit, [0; 4]));
+        spi.reply(irregular(MlxOpcode::NothingToTransmit, [0; 4]));
+        spi.reply(irregular(MlxOpcode::EEWriteChallenge, [0, 0, 0x78, 0x56]));
+        spi.reply(irregular(MlxOpcode::EEReadAnswer, [0; 4]));
+        spi.reply(irregular(
+            MlxOpcode::EEWriteStatus,
+            [MlxMemWriteStatus::Success as u8, 0, 0, 0],
+        ));
+
+        let mut delay = RecordingDelay::default();
+        for ms in 0..=MLX_EEPROM_WRITE_MS as u64 + 4 {
+            let now = Instant::from_ticks(ms * 1000);
+            assert!(slot.poll(&mut delay, &mut spi, now, &mut |_| {}).is_ok());
+        }
+
+        assert_eq!(spi.sent.len(), 5);
+        assert_eq!(spi.sent[4][6] & 0x3F, MlxOpcode::NOPChallenge as u8);
+        assert!(delay.requested_us.is_empty());
+    }
+
+    #[test]
+    fn failed_transfer_mid_write_drops_the_device_with_a_write_failure() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut slot = SpiDownstream::new(&mut cs);
+        slot.attach(DownstreamKind::Mlx90363, Box::new(running_at(20, 1000)));
+        slot.write_memory(&NegiconEvent::new(
+            NegiconEventType::MemWrite,
+            20,
+            1,
+            0,
+            0x20,
+        ));
+        spi.reply(NOTHING);
+        let now = Instant::from_ticks(0);
+        assert!(slot.poll(&mut NoDelay, &mut spi, now, &mut |_| {}).is_ok());
+
+        // Unplugged before the EEWrite
+        spi.stuck = true;
+        let now = Instant::from_ticks(1000);
+        let res = slot.poll(&mut NoDelay, &mut spi, now, &mut |_| {});
+        assert!(matches!(
+            res,
+            Err(DownstreamError::WriteFailed(MlxError::SpiError(_), None))
+        ));
+        assert_eq!(res.err().map(|e| e.code()), Some(10));
+        assert!(!slot.is_connected());
+    }
+
+    /// Answer to the `MemoryRead` of the previous frame
+    fn mem_read_answer(words: [u16; 2]) -> [u8; 8] {
+        let [a_lo, a_hi] = words[0].to_le_bytes();
+        let [b_lo, b_hi] = words[1].to_le_bytes();
+        let opcode = 0xC0 | MlxOpcode::MemoryReadAnswer as u8;
+        [a_lo, a_hi, b_lo, b_hi, 0, 0, opcode, 0]
+    }
+
+    #[test]
+    fn accessors_follow_the_parameter_reads() {
+        let mut spi = MockSpi::default();
+        let mut ds = MlxDownstream::new(None, None, &DownstreamSettings::default());
+        // id, limits, mounting and scaling, each requested and then read
+        for words in [[20, 20], [1000, 3000], [0xFFFF, 0xFFFF], [0xFFFF, 0xFFFF]] {
+            spi.reply(NOTHING);
+            spi.reply(mem_read_answer(words));
+        }
+        spi.reply(alpha_frame(2000, 200, 0));
+
+        assert!(!ds.is_initialized());
+        poll_events(&mut ds, &mut spi);
+        poll_events(&mut ds, &mut spi);
+        assert_eq!(ds.min(), None);
+        assert_eq!(DownstreamDevice::<MockSpi>::id(&ds), None);
+        poll_events(&mut ds, &mut spi);
+        poll_events(&mut ds, &mut spi);
+        // Both limits arrive with the same read
+        assert_eq!((ds.min(), ds.max()), (Some(1000), Some(3000)));
+        for _ in 0..4 {
+            poll_events(&mut ds, &mut spi);
+        }
+        assert!(ds.is_initialized());
+        assert_eq!(DownstreamDevice::<MockSpi>::id(&ds), Some(20));
+        // The mode follows the limits from the first angle read on
+        assert!(ds.mode() == InputMode::Relative);
+        poll_events(&mut ds, &mut spi);
+        assert!(ds.mode() == InputMode::Absolute);
+
+        let diag = DownstreamDevice::<MockSpi>::diagnostics(&ds);
+        assert_eq!(diag.flags(), 0b11);
+        assert_eq!((diag.min, diag.max), (Some(1000), Some(3000)));
+    }
+
+    #[test]
+    fn initialized_sensor_reports_its_id_and_kind() {
+        let ds = running_at(20, 0);
+        assert!(ds.is_initialized());
+        assert_eq!(DownstreamDevice::<MockSpi>::id(&ds), Some(20));
+        assert!(DownstreamDevice::<MockSpi>::kind(&ds) == DownstreamKind::Mlx90363);
+    }
+
+    /// Reply to a read that hasn't been answered yet
+    const NOTHING: [u8; 8] = [
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0xC0 | MlxOpcode::NothingToTransmit as u8,
+        0,
+    ];
+
+    #[test]
+    fn requested_diagnostic_details_replace_one_get_and_are_reported_in_three_parts() {
+        let mut spi = MockSpi::default();
+        let mut ds = running_at(20, 1000);
+        DownstreamDevice::<MockSpi>::request_diagnostic_details(&mut ds);
+        spi.reply(alpha_frame(1000, 100, 1));
+        let opcode = 0xC0 | MlxOpcode::DiagnosticsAnswer as u8;
+        spi.reply([0x34, 0x12, 0x85, 7, 0, 0, opcode, 0]);
+
+        assert!(poll_events(&mut ds, &mut spi).is_empty());
+        let mut events = Vec::new();
+        let res = ds.poll(
+            &mut spi,
+            &mut MockPin::new(),
+            Instant::from_ticks(0),
+            &mut |e| events.push((e.event_type() as u8, e.id(), e.value(), e.sequence())),
+        );
+        assert!(res.is_ok());
+
+        assert_eq!(spi.sent[0][6] & 0x3F, MlxOpcode::DiagnosticDetails as u8);
+        assert_eq!(spi.sent[1][6] & 0x3F, MlxOpcode::GET1 as u8);
+        let details = NegiconEventType::DiagnosticDetails as u8;
+        assert_eq!(
+            events,
+            [
+                (details, 20, 0x1234, 0),
+                (details, 20, 0x05, 1),
+                (details, 20, 0x0207, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn requested_field_reading_replaces_one_get_and_is_reported_in_three_parts() {
+        let mut spi = MockSpi::default();
+        let mut ds = running_at(20, 1000);
+        DownstreamDevice::<MockSpi>::request_field_reading(&mut ds);
+        // Answer to the GET3, one NOP still measuring, then the result of
+        // X 100, Y -200 and Z 300
+        spi.reply(alpha_frame(1000, 100, 1));
+        spi.reply(NOTHING);
+        spi.reply([0, 0, 0, 0, 0, 0, 0xC0 | MlxOpcode::Get3Ready as u8, 0]);
+        spi.reply([100, 0, 0x38, 0x3F, 0x2C, 0x01, 0x80 | 2, 0]);
+
+        let mut events = Vec::new();
+        let res = ds.poll(
+            &mut spi,
+            &mut MockPin::new(),
+            Instant::from_ticks(0),
+            &mut |e| events.push((e.event_type() as u8, e.id(), e.value(), e.sequence())),
+        );
+        assert!(res.is_ok());
+
+        assert_eq!(spi.sent.len(), 4);
+        assert_eq!(spi.sent[0][6] & 0x3F, MlxOpcode::GET3 as u8);
+        let field = NegiconEventType::FieldReading as u8;
+        assert_eq!(
+            events,
+            [
+                (field, 20, 100, 0),
+                (field, 20, -200, 1),
+                (field, 20, 300, 2)
+            ]
+        );
+        spi.reply(NOTHING);
+        poll_events(&mut ds, &mut spi);
+        assert_eq!(spi.sent[4][6] & 0x3F, MlxOpcode::GET1 as u8);
+    }
+
+    fn poll_result(ds: &mut MlxDownstream, spi: &mut MockSpi) -> Result<(), DownstreamError> {
+        ds.poll(
+            spi,
+            &mut MockPin::new(),
+            Instant::from_ticks(0),
+            &mut |_| {},
+        )
+    }
+
+    #[test]
+    fn repeated_init_failures_fall_back_to_a_working_default_axis() {
+        let mut spi = MockSpi::default();
+        let settings = DownstreamSettings {
+            init_attempts: 3,
+            prime_readings: 0,
+            ..Default::default()
+        };
+        let mut ds = MlxDownstream::new(None, Some(7), &settings);
+        // The first poll only requests the id
+        spi.reply(NOTHING);
+        assert!(poll_result(&mut ds, &mut spi).is_ok());
+
+        let results: Vec<_> = (0..3)
+            .map(|_| {
+                spi.reply(NOTHING);
+                poll_result(&mut ds, &mut spi)
+            })
+            .collect();
+        assert!(matches!(
+            results[..],
+            [
+                Err(DownstreamError::UnexpectedReply),
+                Err(DownstreamError::UnexpectedReply),
+                Err(DownstreamError::InitFailed)
+            ]
+        ));
+        // Relative, unscaled and reporting as the slot's fallback id
+        assert!(ds.is_initialized());
+        assert_eq!(
+            DownstreamDevice::<MockSpi>::diagnostics(&ds).flags(),
+            0b1001
+        );
+        spi.reply(alpha_frame(1000, 200, 0));
+        spi.reply(alpha_frame(1200, 200, 1));
+        let mut events = poll_events(&mut ds, &mut spi);
+        events.extend(poll_events(&mut ds, &mut spi));
+        assert_eq!(events, [(7, 1000), (7, 200)]);
+        assert!(ds.mode() == InputMode::Relative);
+    }
+
+    #[test]
+    fn initialization_gives_up_after_the_configured_failed_reads() {
+        let mut spi = MockSpi::default();
+        let settings = DownstreamSettings {
+            init_attempts: 2,
+            ..Default::default()
+        };
+        let mut ds = MlxDownstream::new(None, None, &settings);
+        spi.reply(NOTHING);
+        assert!(poll_result(&mut ds, &mut spi).is_ok());
+        spi.reply(NOTHING);
+        spi.reply(NOTHING);
+        assert!(matches!(
+            poll_result(&mut ds, &mut spi),
+            Err(DownstreamError::UnexpectedReply)
+        ));
+        assert!(matches!(
+            poll_result(&mut ds, &mut spi),
+            Err(DownstreamError::InitFailed)
+        ));
+        assert!(!ds.is_initialized());
+        assert!(!DownstreamDevice::<MockSpi>::diagnostics(&ds).defaulted);
+    }
+
+    #[test]
+    fn reinit_reads_the_eeprom_again_after_falling_back() {
+        let mut spi = MockSpi::default();
+        let settings = DownstreamSettings {
+            init_attempts: 1,
+            ..Default::default()
+        };
+        let mut ds = MlxDownstream::new(None, Some(7), &settings);
+        spi.reply(NOTHING);
+        spi.reply(NOTHING);
+        assert!(poll_result(&mut ds, &mut spi).is_ok());
+        assert!(matches!(
+            poll_result(&mut ds, &mut spi),
+            Err(DownstreamError::InitFailed)
+        ));
+        assert!(DownstreamDevice::<MockSpi>::diagnostics(&ds).defaulted);
+
+        DownstreamDevice::<MockSpi>::reinit(&mut ds);
+        assert!(!ds.is_initialized());
+        assert!(!DownstreamDevice::<MockSpi>::diagnostics(&ds).defaulted);
+    }
+
+    #[test]
+    fn limits_are_read_from_two_cells_in_one_request() {
+        let mut spi = MockSpi::default();
+        let mut ds = MlxDownstream::new(None, None, &DownstreamSettings::default());
+        ds.id = ParameterState::Initialized(20);
+        spi.reply(NOTHING);
+        spi.reply(mem_read_answer([1000, 3000]));
+        poll_events(&mut ds, &mut spi);
+        poll_events(&mut ds, &mut spi);
+
+        let addresses: Vec<_> = spi
+            .sent
+            .iter()
+            .map(|f| [u16_from_le(&f[0..2]), u16_from_le(&f[2..4])])
+            .collect();
+        assert_eq!(addresses, [[ADDR_MIN, ADDR_MAX], [ADDR_MIN, ADDR_MAX]]);
+        assert!(
+            ds.limits.initialized()
+                == Some(Limits {
+                    min: 1000,
+                    max: 3000
+                })
+        );
+    }
+
+    #[test]
+    fn successful_read_restarts_the_init_attempts() {
+        let mut spi = MockSpi::default();
+        let settings = DownstreamSettings {
+            init_attempts: 2,
+            ..Default::default()
+        };
+        let mut ds = MlxDownstream::new(None, None, &settings);
+        spi.reply(NOTHING);
+        assert!(poll_result(&mut ds, &mut spi).is_ok());
+        spi.reply(NOTHING);
+        assert!(matches!(
+            poll_result(&mut ds, &mut spi),
+            Err(DownstreamError::UnexpectedReply)
+        ));
+        // The id is read, then the limits requested
+        spi.reply(mem_read_answer([20, 20]));
+        assert!(poll_result(&mut ds, &mut spi).is_ok());
+        spi.reply(NOTHING);
+        assert!(poll_result(&mut ds, &mut spi).is_ok());
+        spi.reply(NOTHING);
+        assert!(matches!(
+            poll_result(&mut ds, &mut spi),
+            Err(DownstreamError::UnexpectedReply)
+        ));
+        assert_eq!(ds.id.initialized(), Some(20));
+    }
+
+    #[test]
+    fn inverted_limits_fall_back_to_relative_mode() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut ds = MlxDownstream::new(
+            None,
+            None,
+            &DownstreamSettings {
+                prime_readings: 1,
+                ..Default::default()
+            },
+        );
+        for words in [[20, 20], [1000, 500], [0xFFFF, 0xFFFF], [0xFFFF, 0xFFFF]] {
+            spi.reply(NOTHING);
+            spi.reply(mem_read_answer(words));
+        }
+        // The first reading only seeds the baseline
+        spi.reply(alpha_frame(2000, 200, 0));
+        spi.reply(alpha_frame(2100, 200, 1));
+
+        let mut results = Vec::new();
+        let mut events = Vec::new();
+        for _ in 0..10 {
+            results.push(
+                ds.poll(&mut spi, &mut cs, Instant::from_ticks(0), &mut |e| {
+                    events.push((e.id(), e.value()))
+                }),
+            );
+        }
+
+        // Reported once, when the limits are read, and the axis keeps
+        // initializing
+        let errors: Vec<_> = results.iter().filter(|r| r.is_err()).collect();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(results[3], Err(DownstreamError::InvalidLimits)));
+        assert!(ds.is_initialized());
+        assert!(ds.mode() == InputMode::Relative);
+        assert_eq!(events, [(20, 100)]);
+    }
+
+    #[test]
+    fn reinit_reads_the_parameters_again() {
+        let mut spi = MockSpi::default();
+        let mut ds = running_at(20, 1000);
+        DownstreamDevice::<MockSpi>::reinit(&mut ds);
+        assert!(!ds.is_initialized());
+        assert_eq!(DownstreamDevice::<MockSpi>::id(&ds), None);
+
+        for words in [[30, 30], [0, 0], [0xFFFF, 0xFFFF], [0xFFFF, 0xFFFF]] {
+            spi.reply(NOTHING);
+            spi.reply(mem_read_answer(words));
+        }
+        for _ in 0..8 {
+            assert!(poll_events(&mut ds, &mut spi).is_empty());
+        }
+
+        // Every poll went to a parameter read instead of a GET1
+        assert!(spi
+            .sent
+            .iter()
+            .all(|f| f[6] & 0x3F == MlxOpcode::MemoryRead as u8));
+        assert!(ds.is_initialized());
+        assert_eq!(DownstreamDevice::<MockSpi>::id(&ds), Some(30));
+    }
+
+    #[test]
+    fn write_handshake_reply_outside_a_write_is_rejected() {
+        let mut spi = MockSpi::default();
+        let mut ds = running_at(20, 1000);
+        spi.reply([0, 0, 0, 0, 0, 0, 0xC0 | MlxOpcode::EEReadAnswer as u8, 0]);
+
+        let mut events = Vec::new();
+        let res = ds.poll(
+            &mut spi,
+            &mut MockPin::new(),
+            Instant::from_ticks(0),
+            &mut |e| events.push(e),
+        );
+        assert!(matches!(res, Err(DownstreamError::UnexpectedReply)));
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn erased_or_zero_mounting_words_leave_the_sensor_alone() {
+        for words in [[0xFFFF, 0xFFFF], [0, 0]] {
+            assert!(
+                Mounting::from_words(words)
+                    == Mounting {
+                        invert: false,
+                        raw_absolute: false,
+                        narrow: false,
+                        id_override: None,
+                    }
+            );
+        }
+        assert!(Mounting::from_words([7, FLAG_INVERT]).invert);
+        assert!(Mounting::from_words([7, FLAG_INVERT]).id_override == Some(7));
+        assert!(Mounting::from_words([0, FLAG_RAW_ABSOLUTE]).raw_absolute);
+        assert!(Mounting::from_words([0, FLAG_NARROW_OUTPUT]).narrow);
+    }
+
+    #[test]
+    fn narrow_output_scales_positions_to_seven_bits() {
+        let mut ds = mounted(20, 1000, [0xFFFF, FLAG_NARROW_OUTPUT]);
+        ds.mode = InputMode::Absolute;
+        assert_eq!(ds.narrowed(ALPHA_MAX as i16), 127);
+        assert_eq!(ds.narrowed(0), 0);
+        // Gain and offset can push the scaled position past the bounds
+        assert_eq!(ds.filter_step(i16::MAX), Some(127));
+        assert_eq!(ds.filter_step(i16::MIN), Some(-128));
+
+        ds.mode = InputMode::RawAbsolute;
+        assert_eq!(ds.narrowed(ALPHA_MAX as i16), ALPHA_MAX as i16);
+        assert_eq!(ds.filter_step(ALPHA_MAX as i16), Some(ALPHA_MAX as i16));
+
+        ds.mounting = ParameterState::Initialized(Mounting::from_words([0xFFFF, 0]));
+        ds.mode = InputMode::Absolute;
+        assert_eq!(ds.narrowed(ALPHA_MAX as i16), ALPHA_MAX as i16);
+    }
+
+    #[test]
+    fn narrow_steps_saturate_and_carry_the_rest() {
+        let mut ds = mounted(20, 1000, [0xFFFF, FLAG_NARROW_OUTPUT]);
+        assert_eq!(ds.filter_step(i16::MAX), Some(127));
+        assert!(ds.step_overflow_pending());
+        assert_eq!(ds.filter_step(-200), Some(127));
+        assert_eq!(ds.step_carry, i16::MAX as i32 - 127 - 200 - 127);
+
+        let mut ds = mounted(20, 1000, [0xFFFF, FLAG_NARROW_OUTPUT]);
+        assert_eq!(ds.filter_step(-300), Some(-128));
+        assert_eq!(ds.filter_step(0), Some(-128));
+        assert_eq!(ds.filter_step(0), Some(-44));
+        assert!(!ds.step_overflow_pending());
+    }
+
+    #[test]
+    fn narrow_sensor_reports_the_whole_step_over_several_polls() {
+        let mut spi = MockSpi::default();
+        let mut ds = mounted(20, 1000, [0xFFFF, FLAG_NARROW_OUTPUT]);
+        let mut events = Vec::new();
+        for counter in 0..3 {
+            // The axis stops after the first reading
+            spi.reply(alpha_frame(1200, 200, counter));
+            let res = ds.poll(
+                &mut spi,
+                &mut MockPin::new(),
+                Instant::from_ticks(0),
+                &mut |e| events.push((e.event_type() as u8, e.id(), e.value())),
+            );
+            assert!(res.is_ok());
+        }
+        let narrow = NegiconEventType::NarrowInput as u8;
+        assert_eq!(events, [(narrow, 20, 127), (narrow, 20, 73)]);
+    }
+
+    #[test]
+    fn narrow_absolute_axis_reports_seven_bit_positions() {
+        let mut spi = MockSpi::default();
+        spi.reply(alpha_frame(12800, 200, 0));
+        let mut ds = mounted(20, 0, [0xFFFF, FLAG_NARROW_OUTPUT]);
+        ds.mode = InputMode::Absolute;
+        ds.limits = ParameterState::Initialized(Limits {
+            min: 0,
+            max: ALPHA_MAX as u16,
+        });
+        // The offset counts in 7-bit steps on a narrow axis
+        ds.scaling =
+            ParameterState::Initialized(Scaling::from_words([GAIN_UNITY, (-27i16) as u16]));
+
+        assert_eq!(poll_events(&mut ds, &mut spi), [(20, 100 - 27)]);
+    }
+
+    #[test]
+    fn inverted_sensor_reports_the_negated_value() {
+        let mut spi = MockSpi::default();
+        spi.reply(alpha_frame(1200, 200, 0));
+        let mut ds = mounted(20, 1000, [0xFFFF, FLAG_INVERT]);
+
+        assert_eq!(poll_events(&mut ds, &mut spi), [(20, -200)]);
+    }
+
+    #[test]
+    fn raw_absolute_reports_the_angle_unmodified() {
+        let mut spi = MockSpi::default();
+        spi.reply(alpha_frame(16000, 200, 0));
+        // Limits, scaling and inversion are all ignored
+        let mut ds = mounted(20, 1000, [0xFFFF, FLAG_RAW_ABSOLUTE | FLAG_INVERT]);
+        ds.scaling = ParameterState::Initialized(Scaling::from_words([2 * GAIN_UNITY, 5]));
+
+        assert_eq!(poll_events(&mut ds, &mut spi), [(20, 16000)]);
+        assert!(ds.mode() == InputMode::RawAbsolute);
+        assert!(DownstreamDevice::<MockSpi>::diagnostics(&ds).absolute);
+    }
+
+    #[test]
+    fn remapped_sensor_reports_the_override_id() {
+        let mut spi = MockSpi::default();
+        spi.reply(alpha_frame(1200, 20, 0));
+        let mut ds = mounted(20, 1000, [40, 0]);
+
+        assert_eq!(poll_events(&mut ds, &mut spi), [(40, 200), (41, 1)]);
+        assert_eq!(DownstreamDevice::<MockSpi>::id(&ds), Some(40));
+    }
+
+    #[test]
+    fn stale_reading_resets_the_rolling_counter() {
+        let mut spi = MockSpi::default();
+        let mut ds = running_at(20, 1000);
+        spi.reply(alpha_frame(1000, 200, 7));
+        // Same counter again, the sensor hasn't taken a new reading
+        spi.reply(alpha_frame(1500, 200, 7));
+        // Answer to the resetting GET1, still from before the reset
+        spi.reply(alpha_frame(1200, 200, 8));
+        spi.reply(alpha_frame(1300, 200, 0));
+
+        let events: Vec<_> = (0..4)
+            .flat_map(|_| poll_events(&mut ds, &mut spi))
+            .collect();
+
+        // The stale reading is dropped and the next GET1 carries the reset bit
+        assert_eq!(events, [(20, 200), (20, 100)]);
+        let reset_bits: Vec<_> = spi.sent.iter().map(|f| f[1]).collect();
+        assert_eq!(reset_bits, [0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn priming_readings_only_seed_the_baseline() {
+        let mut spi = MockSpi::default();
+        let mut ds = running_at(20, 0);
+        ds.prime_remaining = 3;
+        for (n, input) in [5000, 5300, 5200].into_iter().enumerate() {
+            spi.reply(alpha_frame(input, 200, n as u8));
+        }
+        spi.reply(alpha_frame(5300, 200, 3));
+
+        for _ in 0..3 {
+            assert!(poll_events(&mut ds, &mut spi).is_empty());
+        }
+        assert_eq!(ds.last.get(), 5200);
+        assert_eq!(poll_events(&mut ds, &mut spi), [(20, 100)]);
+    }
+
+    #[test]
+    fn raw_mode_reports_the_unprocessed_angle() {
+        let mut spi = MockSpi::default();
+        let mut ds = running_at(20, 1000);
+        // Not moving, so there is no input event to go with it
+        spi.reply(alpha_frame(1000, 200, 0));
+        let mut frame = alpha_frame(1000, 200, 1);
+        frame[1] |= (MlxDiagnosticStatus::Pass as u8) << 6;
+        spi.reply(frame);
+
+        assert!(poll_events(&mut ds, &mut spi).is_empty());
+        let raw = DownstreamSettings {
+            raw_alpha: true,
+            ..Default::default()
+        };
+        DownstreamDevice::<MockSpi>::apply_settings(&mut ds, &raw);
+        let mut events = Vec::new();
+        let res = ds.poll(
+            &mut spi,
+            &mut MockPin::new(),
+            Instant::from_ticks(0),
+            &mut |e| events.push(e),
+        );
+        assert!(res.is_ok());
+        assert_eq!(events.len(), 1);
+        assert!(events[0].event_type() == NegiconEventType::RawAlpha);
+        let value = events[0].value() as u16;
+        assert_eq!((events[0].id(), value & 0x3FFF), (20, 1000));
+        assert_eq!(
+            value >> 14 ^ RAW_ALPHA_DIAG_FLIP,
+            MlxDiagnosticStatus::Pass as u16
+        );
+        assert_eq!(events[0].sequence(), 200);
+    }
+}
@@ -0,0 +1,142 @@
+//! Stand-ins for the downstream bus and CS lines in host tests
+use core::convert::Infallible;
+
+use alloc::{collections::VecDeque, vec::Vec};
+use embedded_hal::{
+    blocking::{
+        delay::{DelayMs, DelayUs},
+        spi::Transfer,
+    },
+    digital::v2::OutputPin,
+};
+use fugit::{HertzU32, MicrosDurationU64};
+
+use super::spi_protocol::{set_crc, FailedTransfers, NegiconProtocol, SpiClock, SpiError};
+
+/// CS line that remembers its level and counts how often it was driven low
+pub(crate) struct MockPin {
+    pub(crate) high: bool,
+    pub(crate) selects: u32,
+}
+
+impl MockPin {
+    pub(crate) fn new() -> Self {
+        Self {
+            high: true,
+            selects: 0,
+        }
+    }
+}
+
+impl OutputPin for MockPin {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.high = false;
+        self.selects += 1;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.high = true;
+        Ok(())
+    }
+}
+
+/// SPI bus that records every frame sent and answers with the queued
+/// replies in order, all zeros once they run out. A `stuck` bus fails every
+/// transfer, like a wedged peripheral.
+#[derive(Default)]
+pub(crate) struct MockSpi {
+    pub(crate) replies: VecDeque<[u8; 8]>,
+    pub(crate) sent: Vec<[u8; 8]>,
+    pub(crate) clock: Option<HertzU32>,
+    pub(crate) cs_settle: Option<MicrosDurationU64>,
+    pub(crate) stuck: bool,
+    pub(crate) failed: FailedTransfers,
+}
+
+impl MockSpi {
+    /// Queues `frame` as the answer to the next unanswered transfer, with a
+    /// valid CRC
+    pub(crate) fn reply(&mut self, mut frame: [u8; 8]) {
+        set_crc(&mut frame);
+        self.replies.push_back(frame);
+    }
+
+    /// Queues an answer that fails the CRC check, like a frame the device
+    /// has nothing to say in
+    pub(crate) fn reply_garbage(&mut self) {
+        self.replies.push_back([0; 8]);
+    }
+}
+
+impl Transfer<u8> for MockSpi {
+    type Error = Infallible;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+        self.sent.push(words.try_into().unwrap());
+        match self.replies.pop_front() {
+            Some(reply) => words.copy_from_slice(&reply),
+            None => words.fill(0),
+        }
+        Ok(words)
+    }
+}
+
+impl NegiconProtocol for MockSpi {
+    fn transfer_frame(&mut self, data: &mut [u8; 8]) -> Result<(), SpiError> {
+        let res = if self.stuck {
+            Err(SpiError::Timeout)
+        } else {
+            let Ok(_) = self.transfer(data);
+            Ok(())
+        };
+        self.failed.record(&res);
+        res
+    }
+
+    fn failed_transfers(&self) -> u16 {
+        self.failed.count()
+    }
+
+    fn set_cs_settle(&mut self, settle: MicrosDurationU64) {
+        self.cs_settle = Some(settle);
+    }
+}
+
+impl SpiClock for MockSpi {
+    fn set_clock(&mut self, _peripheral: HertzU32, freq: HertzU32) -> HertzU32 {
+        self.clock = Some(freq);
+        freq
+    }
+}
+
+/// Delay that returns right away
+pub(crate) struct NoDelay;
+
+/// Delay that returns right away and records every wait asked of it, in µs
+#[derive(Default)]
+pub(crate) struct RecordingDelay {
+    pub(crate) requested_us: Vec<u32>,
+}
+
+impl DelayUs<u32> for RecordingDelay {
+    fn delay_us(&mut self, us: u32) {
+        self.requested_us.push(us);
+    }
+}
+
+impl DelayMs<u32> for RecordingDelay {
+    fn delay_ms(&mut self, ms: u32) {
+        self.requested_us.push(ms.saturating_mul(1000));
+    }
+}
+
+impl DelayUs<u32> for NoDelay {
+    fn delay_us(&mut self, _us: u32) {}
+}
+
+impl DelayMs<u32> for NoDelay {
+    fn delay_ms(&mut self, _ms: u32) {}
+}
@@ -1,5 +1,8 @@
-mod mlx90363;
+mod composite_downstream;
+pub(crate) mod mlx90363;
 mod mlx_downstream;
+#[cfg(test)]
+pub(crate) mod mock;
 pub mod spi_downstream;
-mod spi_protocol;
+pub(crate) mod spi_protocol;
 pub(crate) mod util;
@@ -1,24 +1,39 @@
 extern crate alloc;
 use core::convert::Infallible;
 
-use alloc::boxed::Box;
-use cortex_m::delay::Delay;
+use alloc::{boxed::Box, vec::Vec};
 use defmt::{error, info, warn, Format};
-use embedded_hal::digital::v2::OutputPin;
-use rp2040_hal::{
-    spi::{Enabled, SpiDevice as HalSpiDevice, ValidSpiPinout},
-    Spi,
+use embedded_hal::{
+    blocking::delay::{DelayMs, DelayUs},
+    digital::v2::OutputPin,
 };
+use fugit::MicrosDurationU64;
+use rp2040_hal::timer::Instant;
 
-use crate::{downstream::mlx_downstream::MlxDownstream, negicon_event::NegiconEvent};
+use crate::{
+    downstream::{composite_downstream::CompositeDownstream, mlx_downstream::MlxDownstream},
+    negicon_event::{NegiconEvent, NegiconEventType},
+    throttle::Throttle,
+};
 
 use super::{
-    mlx90363::MlxError,
+    mlx90363::{challenge_solution, MlxError, MlxStatus, MLX_FRAME_GAP_US},
     spi_protocol::{
-        NegiconProtocol, NopError, NopMessage, SpiError, NOP_REPLY_OPCODE_MLX, NOP_REPLY_OPCODE_RP,
-        NOP_REPLY_OPCODE_STM,
+        next_challenge, NegiconProtocol, NopError, NopMessage, SpiError, NOP_CHALLENGE_SEED,
+        NOP_REPLY_OPCODE_MLX, NOP_REPLY_OPCODE_RP, NOP_REPLY_OPCODE_STM,
     },
 };
+/// Detection and removal messages repeat on every re-detection of a flaky
+/// slot, so they only go out at info level with the `verbose-detect` feature.
+macro_rules! detect_log {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "verbose-detect")]
+        defmt::info!($($arg)*);
+        #[cfg(not(feature = "verbose-detect"))]
+        defmt::debug!($($arg)*);
+    };
+}
+
 #[derive(Format)]
 pub(crate) enum DownstreamError {
     SpiError(SpiError),
@@ -26,155 +41,1402 @@ pub(crate) enum DownstreamError {
     NopError(NopError),
     MlxError(MlxError),
     UnexpectedReply,
+    /// The EEPROM limits of an axis are set but max isn't above min. The
+    /// axis keeps working in relative mode.
+    InvalidLimits,
+    /// The sensor kept reporting a failed self-diagnostic. Its readings are
+    /// dropped until it passes again.
+    DiagnosticFail,
+    /// The device kept failing to read its parameters. The first device on a
+    /// slot to do so is dropped and detected afresh, in case it was only
+    /// seated badly. If the next one fails as well, it runs on defaults.
+    InitFailed,
+    /// An EEPROM write was aborted, the failing cell and those after it
+    /// weren't written. The device is dropped and detected afresh. Carries the
+    /// write challenge of the failing cell if the device sent one.
+    WriteFailed(MlxError, Option<u16>),
+}
+
+impl DownstreamError {
+    /// Wire code reported to the host in `Error` events
+    pub(crate) fn code(&self) -> u8 {
+        match self {
+            DownstreamError::SpiError(_) => 1,
+            DownstreamError::UnknownDevice(_) => 2,
+            DownstreamError::NopError(_) => 3,
+            DownstreamError::MlxError(_) => 4,
+            DownstreamError::UnexpectedReply => 5,
+            // 6 was sent for devices in another SPI mode, which can't be
+            // told apart at detection
+            DownstreamError::InvalidLimits => 7,
+            DownstreamError::DiagnosticFail => 8,
+            DownstreamError::InitFailed => 9,
+            DownstreamError::WriteFailed(..) => 10,
+        }
+    }
+
+    pub(crate) fn to_event(&self, slot: u16) -> NegiconEvent {
+        NegiconEvent::new(NegiconEventType::Error, slot, self.code() as i16, 0, 0)
+    }
+
+    /// `WriteChallenge` event following the `Error` event of a write that
+    /// failed after the device sent its challenge
+    pub(crate) fn challenge_event(&self, slot: u16) -> Option<NegiconEvent> {
+        match self {
+            DownstreamError::WriteFailed(_, Some(challenge)) => Some(NegiconEvent::new(
+                NegiconEventType::WriteChallenge,
+                *challenge,
+                challenge_solution(*challenge) as i16,
+                0,
+                slot as u8,
+            )),
+            _ => None,
+        }
+    }
+}
+
+impl From<SpiError> for DownstreamError {
+    fn from(e: SpiError) -> Self {
+        Self::SpiError(e)
+    }
+}
+
+impl From<MlxError> for DownstreamError {
+    fn from(e: MlxError) -> Self {
+        Self::MlxError(e)
+    }
+}
+
+impl From<NopError> for DownstreamError {
+    fn from(e: NopError) -> Self {
+        Self::NopError(e)
+    }
 }
 
-pub(crate) struct SpiDownstream<'a, D, T>
+/// Result of the operations on a downstream device
+pub(crate) type DownstreamResult<T = ()> = Result<T, DownstreamError>;
+
+pub(crate) struct SpiDownstream<'a, S>
 where
-    D: HalSpiDevice,
-    T: ValidSpiPinout<D>,
+    S: NegiconProtocol,
 {
     cs: &'a mut dyn OutputPin<Error = Infallible>,
-    pub(crate) device: DownstreamState<D, T>,
+    pub(crate) device: DownstreamState<S>,
+    frame_gap: FrameGap,
+    /// (address, value) cells staged by `MemWriteBatch` events, written
+    /// together with the next `MemWrite`
+    pending_writes: Vec<(u8, i16)>,
+    /// Kind of the last device detected on this slot, kept after it drops out
+    /// so the slot keeps being probed on every poll.
+    last_seen: Option<DownstreamKind>,
+    empty_polls: u8,
+    /// Handed to the current device and every one detected later
+    settings: DownstreamSettings,
+    /// Id a device on this slot reports as if it can't read its own
+    fallback_id: u16,
+    /// The last device on this slot gave up on reading its parameters, so
+    /// the next one falls back to defaults instead
+    init_gave_up: bool,
+    /// Disabled slots are skipped by `poll`, to isolate a misbehaving device
+    enabled: bool,
+    /// Cleared when the heap is unusable. Detected devices are then only
+    /// logged instead of kept, and nothing on the slot allocates.
+    device_state: bool,
+    /// Challenge of the last detection NOP. Replies lag one frame, so this
+    /// is what the next reply has to echo.
+    last_challenge: Option<u16>,
+    challenge_seed: u16,
+    /// Limits invalid challenge warnings from a noisy slot
+    challenge_warnings: Throttle,
+    stats: SlotStats,
+}
+
+/// Counters for telling flaky slots apart in the field. They saturate
+/// instead of wrapping and survive the device dropping out.
+#[derive(Default, Clone, Copy, Format)]
+pub(crate) struct SlotStats {
+    /// Polls and detection probes that went out on the bus
+    pub(crate) polls: u32,
+    pub(crate) crc_errors: u16,
+    /// Any other error reported by the device or its protocol
+    pub(crate) device_errors: u16,
+    pub(crate) events: u32,
+}
+
+impl SlotStats {
+    fn record_error(&mut self, error: &DownstreamError) {
+        match error {
+            DownstreamError::SpiError(SpiError::CrcError)
+            | DownstreamError::MlxError(MlxError::SpiError(SpiError::CrcError))
+            | DownstreamError::WriteFailed(MlxError::SpiError(SpiError::CrcError), _) => {
+                self.crc_errors = self.crc_errors.saturating_add(1)
+            }
+            _ => self.device_errors = self.device_errors.saturating_add(1),
+        }
+    }
 }
 
-pub(crate) enum DownstreamState<D, T>
+#[derive(PartialEq, Clone, Copy, Format)]
+pub(crate) enum DownstreamKind {
+    Mlx90363,
+    Rp2040,
+    Stm32,
+}
+
+impl DownstreamKind {
+    /// Opcode the device kind answers detection NOPs with
+    pub(crate) fn opcode(&self) -> u8 {
+        match self {
+            DownstreamKind::Mlx90363 => NOP_REPLY_OPCODE_MLX,
+            DownstreamKind::Rp2040 => NOP_REPLY_OPCODE_RP,
+            DownstreamKind::Stm32 => NOP_REPLY_OPCODE_STM,
+        }
+    }
+}
+
+/// How the button of an axis is reported. Both edges are `Input` events on
+/// the axis id plus `id_offset`.
+#[derive(PartialEq, Clone, Copy, Format)]
+pub(crate) struct ButtonMapping {
+    pub(crate) press: i16,
+    pub(crate) release: i16,
+    pub(crate) id_offset: u16,
+}
+
+impl Default for ButtonMapping {
+    fn default() -> Self {
+        Self {
+            press: 1,
+            release: -1,
+            id_offset: 1,
+        }
+    }
+}
+
+/// When absolute axes send their position. Relative axes only ever report
+/// changes.
+#[derive(PartialEq, Clone, Copy, Format)]
+pub(crate) enum ReportMode {
+    /// Only when the position left the deadzone
+    OnChange,
+    /// Additionally every this many polls, for hosts expecting a steady
+    /// stream
+    Periodic(u16),
+}
+
+impl ReportMode {
+    /// A period of 0 polls means on change only.
+    pub(crate) fn from_period(polls: u16) -> Self {
+        match polls {
+            0 => Self::OnChange,
+            polls => Self::Periodic(polls),
+        }
+    }
+}
+
+/// Device state reported by diagnostic queries
+#[derive(Clone, Copy, Format)]
+pub(crate) struct DeviceDiagnostics {
+    /// Done reading its parameters and producing input
+    pub(crate) initialized: bool,
+    pub(crate) absolute: bool,
+    pub(crate) min: Option<u16>,
+    pub(crate) max: Option<u16>,
+    /// Magnetic field strength (VG) of the last reading, lower when the
+    /// magnet is further away
+    pub(crate) field_strength: Option<u8>,
+    /// The last reading failed the sensor's self-diagnostic
+    pub(crate) diagnostic_fail: bool,
+    /// Runs on default parameters as its EEPROM couldn't be read
+    pub(crate) defaulted: bool,
+}
+
+impl DeviceDiagnostics {
+    /// `initialized` in bit 0, `absolute` in bit 1, `diagnostic_fail` in bit
+    /// 2, `defaulted` in bit 3
+    pub(crate) fn flags(&self) -> u16 {
+        self.initialized as u16
+            | (self.absolute as u16) << 1
+            | (self.diagnostic_fail as u16) << 2
+            | (self.defaulted as u16) << 3
+    }
+}
+
+/// Readings used to seed the baseline of a newly detected sensor, 0.5 s at
+/// the active poll rate.
+pub(crate) const DEFAULT_PRIME_READINGS: u16 = 100;
+
+/// Host controlled settings shared by all downstreams. Devices pick out the
+/// ones that apply to them.
+#[derive(Clone, Copy, PartialEq, Format)]
+pub(crate) struct DownstreamSettings {
+    /// Readings a newly detected sensor only uses to seed its baseline. Only
+    /// read at detection, a running sensor keeps its baseline.
+    pub(crate) prime_readings: u16,
+    /// Also report every sensor reading unprocessed as a `RawAlpha` event
+    pub(crate) raw_alpha: bool,
+    /// When absolute axes report their position
+    pub(crate) report_mode: ReportMode,
+    /// Relative steps smaller than this are held back and added to the next
+    /// one, 0 or 1 sends every step
+    pub(crate) min_relative_step: u16,
+    /// Readings in a row a sensor has to fail its self-diagnostic before
+    /// the slot reports an error. Failed readings are dropped either way.
+    pub(crate) diagnostic_fail_limit: u16,
+    /// Parameter reads in a row an initializing sensor may fail before it
+    /// gives up, see `DownstreamError::InitFailed`
+    pub(crate) init_attempts: u16,
+    /// Values and id of the events a button press and release send
+    pub(crate) button_mapping: ButtonMapping,
+    /// Give the input events of one reading, e.g. an axis step and a button
+    /// press, the reading's frame number as sequence instead of 0
+    pub(crate) frame_ids: bool,
+}
+
+impl Default for DownstreamSettings {
+    fn default() -> Self {
+        Self {
+            prime_readings: DEFAULT_PRIME_READINGS,
+            raw_alpha: false,
+            report_mode: ReportMode::OnChange,
+            min_relative_step: 0,
+            diagnostic_fail_limit: DEFAULT_DIAGNOSTIC_FAIL_LIMIT,
+            init_attempts: DEFAULT_INIT_ATTEMPTS,
+            button_mapping: ButtonMapping::default(),
+            frame_ids: false,
+        }
+    }
+}
+
+/// Slots that never had a device attached are only probed every this many
+/// polls, so re-detection of previously populated slots isn't held up by
+/// empty ones.
+pub(crate) const EMPTY_SLOT_PROBE_INTERVAL: u8 = 10;
+
+/// Failed MLX self-diagnostics in a row before the slot reports an error,
+/// so a single glitch doesn't reach the host
+pub(crate) const DEFAULT_DIAGNOSTIC_FAIL_LIMIT: u16 = 3;
+
+/// Failed parameter reads in a row before an initializing MLX gives up
+pub(crate) const DEFAULT_INIT_ATTEMPTS: u16 = 10;
+
+/// Minimum time between two detection warnings from the same slot
+const DETECT_WARNING_INTERVAL: MicrosDurationU64 = MicrosDurationU64::secs(5);
+
+/// Maximum number of cells a single batch write may stage
+const MAX_BATCH_CELLS: usize = 16;
+
+/// Keeps consecutive transfers to one slot a minimum time apart
+struct FrameGap {
+    /// `None` to allow a transfer on every poll
+    min: Option<MicrosDurationU64>,
+    last: Option<Instant>,
+}
+
+impl FrameGap {
+    /// Returns true if enough time has passed since the last transfer, and
+    /// records `now` as the time of the next one.
+    fn elapsed(&mut self, now: Instant) -> bool {
+        if let (Some(gap), Some(last)) = (self.min, self.last) {
+            match now.checked_duration_since(last) {
+                Some(elapsed) if elapsed < gap => return false,
+                _ => {}
+            }
+        }
+        self.last = Some(now);
+        true
+    }
+}
+
+/// Blocking delay handed to the downstream drivers. A trait object, so that
+/// `DownstreamDevice` stays object safe.
+pub(crate) trait DownstreamDelay: DelayUs<u32> + DelayMs<u32> {}
+
+impl<T> DownstreamDelay for T where T: DelayUs<u32> + DelayMs<u32> {}
+
+pub(crate) enum DownstreamState<S>
 where
-    D: HalSpiDevice,
-    T: ValidSpiPinout<D>,
+    S: NegiconProtocol,
 {
     Uninitialized,
-    Initialized(Box<dyn DownstreamDevice<D, T>>),
+    Initialized(Box<dyn DownstreamDevice<S>>),
 }
-pub(crate) trait DownstreamDevice<D, T>
+pub(crate) trait DownstreamDevice<S>
 where
-    D: HalSpiDevice,
-    T: ValidSpiPinout<D>,
+    S: NegiconProtocol,
 {
+    /// Polls the device once, handing every resulting event to `sink`.
     fn poll(
         &mut self,
-        spi: &mut Spi<Enabled, D, T, 8>,
+        spi: &mut S,
         cs: &mut dyn OutputPin<Error = Infallible>,
-    ) -> Result<Option<NegiconEvent>, DownstreamError>;
+        now: Instant,
+        sink: &mut dyn FnMut(NegiconEvent),
+    ) -> DownstreamResult;
 
-    fn write_memory(
-        &mut self,
-        _spi: &mut Spi<Enabled, D, T, 8>,
-        _cs: &mut dyn OutputPin<Error = Infallible>,
-        _delay: &mut Delay,
-        _write_event: &NegiconEvent,
-    ) {
+    /// Logical id of the device, once it has been read from the device
+    fn id(&self) -> Option<u16> {
+        None
+    }
+
+    /// What answered the detection NOP
+    fn kind(&self) -> DownstreamKind;
+
+    /// Hardware and firmware revision, packed as `hw << 8 | fw`
+    fn version(&self) -> Option<u16> {
+        None
+    }
+
+    /// State for diagnostic queries. Devices without an init phase or limits
+    /// report as initialized in relative mode.
+    fn diagnostics(&self) -> DeviceDiagnostics {
+        DeviceDiagnostics {
+            initialized: true,
+            absolute: false,
+            min: None,
+            max: None,
+            field_strength: None,
+            diagnostic_fail: false,
+            defaulted: false,
+        }
+    }
+
+    /// Drops cached parameters so the following polls read them from the
+    /// device again
+    fn reinit(&mut self) {}
+
+    /// Reads the detailed diagnostics with the next poll and reports them as
+    /// `DiagnosticDetails` events, for devices that have them
+    fn request_diagnostic_details(&mut self) {}
+
+    /// Measures the magnetic field with the next poll and reports it as
+    /// `FieldReading` events, for devices that have a field sensor
+    fn request_field_reading(&mut self) {}
+
+    /// Takes over changed settings. Devices are also created with them, this
+    /// is only called on devices that are already running.
+    fn apply_settings(&mut self, _settings: &DownstreamSettings) {}
+
+    /// Starts writing `cells` to the device. The write is carried out by the
+    /// following polls, so it doesn't block the loop.
+    fn write_memory(&mut self, _cells: &[(u8, i16)]) {
         error!("Memory write target not implemented");
     }
 }
 
-impl<'a, D, T> SpiDownstream<'a, D, T>
+impl<'a, S> SpiDownstream<'a, S>
 where
-    D: HalSpiDevice,
-    T: ValidSpiPinout<D>,
+    S: NegiconProtocol,
 {
     pub(crate) fn new(cs: &'a mut dyn OutputPin<Error = Infallible>) -> Self {
         Self {
             cs,
             device: DownstreamState::Uninitialized,
+            frame_gap: FrameGap {
+                min: Some(MicrosDurationU64::from_ticks(MLX_FRAME_GAP_US as u64)),
+                last: None,
+            },
+            pending_writes: Vec::new(),
+            last_seen: None,
+            empty_polls: 0,
+            settings: DownstreamSettings::default(),
+            fallback_id: 0,
+            init_gave_up: false,
+            enabled: true,
+            device_state: true,
+            last_challenge: None,
+            challenge_seed: NOP_CHALLENGE_SEED,
+            challenge_warnings: Throttle::new(DETECT_WARNING_INTERVAL),
+            stats: SlotStats::default(),
         }
     }
 
     pub fn poll(
         &mut self,
-        delay: &mut Delay,
-        spi: &mut Spi<Enabled, D, T, 8>,
-    ) -> Result<Option<NegiconEvent>, DownstreamError> {
-        match &mut self.device {
-            DownstreamState::Uninitialized => self.detect(delay, spi),
-            DownstreamState::Initialized(dev) => match dev.as_mut().poll(spi, self.cs) {
-                Ok(event) => Ok(event),
-                Err(e) => match e {
-                    DownstreamError::SpiError(_) => {
-                        self.device = DownstreamState::Uninitialized;
-                        info!("SPI Error, removing downstream");
-                        Ok(None)
+        delay: &mut dyn DownstreamDelay,
+        spi: &mut S,
+        now: Instant,
+        sink: &mut dyn FnMut(NegiconEvent),
+    ) -> DownstreamResult {
+        if !self.enabled || !self.frame_gap.elapsed(now) {
+            return Ok(());
+        }
+        let res = match &mut self.device {
+            DownstreamState::Uninitialized => {
+                if self.last_seen.is_none() {
+                    self.empty_polls = (self.empty_polls + 1) % EMPTY_SLOT_PROBE_INTERVAL;
+                    if self.empty_polls != 0 {
+                        return Ok(());
                     }
-                    DownstreamError::MlxError(_) => {
-                        self.device = DownstreamState::Uninitialized;
-                        info!("MLX Error, removing downstream");
-                        Ok(None)
+                }
+                self.stats.polls = self.stats.polls.saturating_add(1);
+                self.detect(delay, spi, now)
+            }
+            DownstreamState::Initialized(dev) => {
+                self.stats.polls = self.stats.polls.saturating_add(1);
+                let events = &mut self.stats.events;
+                match dev.as_mut().poll(spi, self.cs, now, &mut |event| {
+                    *events = events.saturating_add(1);
+                    sink(event)
+                }) {
+                    Ok(()) => Ok(()),
+                    Err(e) => {
+                        match e {
+                            DownstreamError::SpiError(_) => {
+                                self.device = DownstreamState::Uninitialized;
+                                detect_log!("SPI Error, removing downstream");
+                            }
+                            DownstreamError::MlxError(_) => {
+                                self.device = DownstreamState::Uninitialized;
+                                detect_log!("MLX Error, removing downstream");
+                            }
+                            DownstreamError::WriteFailed(..) => {
+                                self.device = DownstreamState::Uninitialized;
+                                detect_log!("Memory write failed, removing downstream");
+                            }
+                            // A device running on defaults stays
+                            DownstreamError::InitFailed if !dev.diagnostics().initialized => {
+                                self.device = DownstreamState::Uninitialized;
+                                self.init_gave_up = true;
+                                detect_log!("Init failed, removing downstream");
+                            }
+                            _ => {}
+                        }
+                        Err(e)
                     }
-                    _ => Err(e),
-                },
-            },
+                }
+            }
+        };
+        if let Err(e) = &res {
+            self.stats.record_error(e);
         }
+        res
     }
 
-    pub(crate) fn write_memory(
-        &mut self,
-        write_event: &NegiconEvent,
-        spi: &mut Spi<Enabled, D, T, 8>,
-        delay: &mut Delay,
-    ) {
+    /// Passes `settings` to the current device and keeps them for the ones
+    /// detected later.
+    pub(crate) fn apply_settings(&mut self, settings: &DownstreamSettings) {
+        self.settings = *settings;
+        if let DownstreamState::Initialized(dev) = &mut self.device {
+            dev.apply_settings(settings);
+        }
+    }
+
+    /// Switches the slot to detecting devices without keeping their state,
+    /// for running without a heap. Any device already kept is dropped.
+    pub(crate) fn disable_device_state(&mut self) {
+        self.device = DownstreamState::Uninitialized;
+        self.pending_writes = Vec::new();
+        self.device_state = false;
+    }
+
+    /// Whether detected devices are kept, see `disable_device_state`
+    pub(crate) fn keeps_device_state(&self) -> bool {
+        self.device_state
+    }
+
+    /// Takes effect with the next detection on this slot.
+    pub(crate) fn set_fallback_id(&mut self, id: u16) {
+        self.fallback_id = id;
+    }
+
+    /// Disabling drops the device, so it is detected afresh once the slot is
+    /// enabled again.
+    pub(crate) fn set_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.device = DownstreamState::Uninitialized;
+            self.last_challenge = None;
+        }
+        self.enabled = enabled;
+    }
+
+    pub(crate) fn is_connected(&self) -> bool {
+        match self.device {
+            DownstreamState::Uninitialized => false,
+            DownstreamState::Initialized(_) => true,
+        }
+    }
+
+    /// Whether a device is attached but still reading its parameters, so its
+    /// id isn't known yet
+    pub(crate) fn is_initializing(&self) -> bool {
+        self.diagnostics().is_some_and(|diag| !diag.initialized)
+    }
+
+    /// Kind of the device currently in the slot
+    pub(crate) fn kind(&self) -> Option<DownstreamKind> {
+        match &self.device {
+            DownstreamState::Uninitialized => None,
+            DownstreamState::Initialized(dev) => Some(dev.kind()),
+        }
+    }
+
+    pub(crate) fn id(&self) -> Option<u16> {
+        match &self.device {
+            DownstreamState::Uninitialized => None,
+            DownstreamState::Initialized(dev) => dev.id(),
+        }
+    }
+
+    pub(crate) fn version(&self) -> Option<u16> {
+        match &self.device {
+            DownstreamState::Uninitialized => None,
+            DownstreamState::Initialized(dev) => dev.version(),
+        }
+    }
+
+    pub(crate) fn diagnostics(&self) -> Option<DeviceDiagnostics> {
+        match &self.device {
+            DownstreamState::Uninitialized => None,
+            DownstreamState::Initialized(dev) => Some(dev.diagnostics()),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> &SlotStats {
+        &self.stats
+    }
+
+    pub(crate) fn request_diagnostic_details(&mut self) {
+        match &mut self.device {
+            DownstreamState::Uninitialized => warn!("Diagnostics target not initialized"),
+            DownstreamState::Initialized(dev) => dev.request_diagnostic_details(),
+        }
+    }
+
+    pub(crate) fn request_field_reading(&mut self) {
+        match &mut self.device {
+            DownstreamState::Uninitialized => warn!("Field reading target not initialized"),
+            DownstreamState::Initialized(dev) => dev.request_field_reading(),
+        }
+    }
+
+    /// Re-reads the device parameters without going through detection.
+    pub(crate) fn reinit(&mut self) {
+        match &mut self.device {
+            DownstreamState::Uninitialized => warn!("Reinit target not initialized"),
+            DownstreamState::Initialized(dev) => {
+                info!("Reinitializing downstream {}", dev.id());
+                dev.reinit()
+            }
+        }
+    }
+
+    /// Stages a cell to be written together with the next `write_memory`.
+    pub(crate) fn stage_write(&mut self, write_event: &NegiconEvent) {
+        if !self.device_state {
+            warn!(
+                "No device state, dropping cell {:x}",
+                write_event.sequence()
+            );
+            return;
+        }
+        if self.pending_writes.len() >= MAX_BATCH_CELLS {
+            warn!(
+                "Batch write full, dropping cell {:x}",
+                write_event.sequence()
+            );
+            return;
+        }
+        self.pending_writes
+            .push((write_event.sequence(), write_event.value()));
+    }
+
+    /// Starts writing `write_event` to the device, preceded by any cells
+    /// staged with `stage_write`.
+    pub(crate) fn write_memory(&mut self, write_event: &NegiconEvent) {
         info!(
             "Downstream memory write request. Id: {}, Address: {:x}, Value: {:x}",
-            write_event.id, write_event.sequence, write_event.value
+            write_event.id(),
+            write_event.sequence(),
+            write_event.value()
         );
         match &mut self.device {
             DownstreamState::Uninitialized => {
+                self.pending_writes.clear();
                 error!("Memory write target not inialized")
             }
             DownstreamState::Initialized(dev) => {
-                dev.as_mut().write_memory(spi, self.cs, delay, write_event);
+                let mut cells = core::mem::take(&mut self.pending_writes);
+                cells.push((write_event.sequence(), write_event.value()));
+                dev.as_mut().write_memory(&cells)
             }
         }
     }
 
+    fn warn_invalid_challenge(&mut self, now: Instant, e: NopError) {
+        if let Some(suppressed) = self.challenge_warnings.poll(now) {
+            warn!(
+                "Invalid challenge response: {:?}, {} more suppressed",
+                e, suppressed
+            );
+        }
+    }
+
+    /// Takes a freshly detected device into the slot
+    pub(crate) fn attach(&mut self, kind: DownstreamKind, dev: Box<dyn DownstreamDevice<S>>) {
+        self.last_seen = Some(kind);
+        self.device = DownstreamState::Initialized(dev);
+        // The device talks over the next frames, so the first probe after it
+        // drops out has nothing to compare against
+        self.last_challenge = None;
+    }
+
+    /// Id an MLX detected now falls back to if it can't read its parameters,
+    /// only given after the last one gave up
+    fn take_fallback_id(&mut self) -> Option<u16> {
+        core::mem::take(&mut self.init_gave_up).then_some(self.fallback_id)
+    }
+
+    /// Attaches the device `make` builds, or only records what was detected
+    /// when the slot runs without device state.
+    fn adopt(
+        &mut self,
+        kind: DownstreamKind,
+        make: impl FnOnce(&DownstreamSettings) -> Box<dyn DownstreamDevice<S>>,
+    ) -> DownstreamResult {
+        if !self.device_state {
+            self.last_seen = Some(kind);
+            return Ok(());
+        }
+        let dev = make(&self.settings);
+        self.attach(kind, dev);
+        Ok(())
+    }
+
     fn detect(
         &mut self,
-        _delay: &mut Delay,
-        spi: &mut Spi<Enabled, D, T, 8>,
-    ) -> Result<Option<NegiconEvent>, DownstreamError>
-    where
-        D: HalSpiDevice,
-        T: ValidSpiPinout<D>,
-    {
-        let challenge = 0x3939;
+        _delay: &mut dyn DownstreamDelay,
+        spi: &mut S,
+        now: Instant,
+    ) -> DownstreamResult {
+        self.challenge_seed = next_challenge(self.challenge_seed);
+        let challenge = self.challenge_seed;
+        let expected = self.last_challenge.replace(challenge);
         let mut buf = NopMessage::new(challenge).serialize();
+        // Not retried: empty slots fail every probe, and a reply to a resent
+        // NOP would echo the new challenge rather than `expected`.
         let res = spi.verified_transmit(self.cs, &mut buf);
         match res {
             Ok(_) => {}
             Err(_) => {
-                return Ok(None);
+                return Ok(());
             }
         };
+        // A freshly powered MLX answers its first frame with a Ready message
+        // instead of the NOP reply, which is the only chance to see its
+        // revision.
+        if let Ok(status) = MlxStatus::from_message(&buf) {
+            detect_log!("MLX90363 detected, revision {}", status);
+            let fallback_id = self.take_fallback_id();
+            return self.adopt(DownstreamKind::Mlx90363, |settings| {
+                Box::new(MlxDownstream::new(Some(status), fallback_id, settings))
+            });
+        }
+        let expected = match expected {
+            Some(expected) => expected,
+            // Nothing to compare the first reply against
+            None => return Ok(()),
+        };
         let response = NopMessage::deserialize(&buf);
 
         match response {
-            Ok(nop) => match nop.verify(challenge) {
+            Ok(nop) => match nop.verify(expected) {
                 Ok(_) => match nop.opcode {
                     NOP_REPLY_OPCODE_MLX => {
-                        info!("MLX90363 detected");
-                        self.device = DownstreamState::Initialized(Box::new(MlxDownstream::new()));
-                        Ok(None)
+                        detect_log!("MLX90363 detected");
+                        let fallback_id = self.take_fallback_id();
+                        self.adopt(DownstreamKind::Mlx90363, |settings| {
+                            Box::new(MlxDownstream::new(None, fallback_id, settings))
+                        })
                     }
                     NOP_REPLY_OPCODE_RP => {
-                        info!("RP2040 detected");
-                        Ok(None)
+                        detect_log!("RP2040 detected");
+                        self.adopt(DownstreamKind::Rp2040, |_| {
+                            Box::new(CompositeDownstream::new(DownstreamKind::Rp2040))
+                        })
                     }
                     NOP_REPLY_OPCODE_STM => {
-                        info!("STM32 detected");
-                        Ok(None)
+                        detect_log!("STM32 detected");
+                        self.adopt(DownstreamKind::Stm32, |_| {
+                            Box::new(CompositeDownstream::new(DownstreamKind::Stm32))
+                        })
                     }
                     _ => Err(DownstreamError::UnknownDevice(nop.opcode)),
                 },
                 Err(e) => {
-                    warn!("Invalid challenge response: {:?}", e);
-                    return Ok(None);
+                    self.warn_invalid_challenge(now, e);
+                    Ok(())
                 }
             },
             Err(e) => match e {
-                NopError::InvalidOpcode(_m) => Err(DownstreamError::NopError(e)),
-                NopError::InvalidChallenge(m) => {
-                    warn!("Weird downstream behavior {}", m);
-                    Err(DownstreamError::NopError(e))
+                NopError::InvalidOpcode(_m) => Err(e.into()),
+                // Same failure as a bad echo above: no valid device yet, try
+                // again on the next probe.
+                NopError::InvalidChallenge(_m) => {
+                    self.warn_invalid_challenge(now, e);
+                    Ok(())
                 }
             },
         }
     }
 }
+
+/// Builds one downstream slot per CS line, in the order of `cs`
+pub(crate) fn downstream_slots<S, P, const N: usize>(cs: &mut [P; N]) -> [SpiDownstream<'_, S>; N]
+where
+    S: NegiconProtocol,
+    P: OutputPin<Error = Infallible>,
+{
+    cs.each_mut().map(|cs| SpiDownstream::new(cs))
+}
+
+/// Finds the slot of the downstream whose logical id is `id`. The logical id
+/// is stored on the device itself, so it is independent of which CS line the
+/// device happens to be plugged into.
+pub(crate) fn slot_for_id<S>(downstreams: &[SpiDownstream<'_, S>], id: u16) -> Option<usize>
+where
+    S: NegiconProtocol,
+{
+    downstreams.iter().position(|ds| ds.id() == Some(id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downstream::{
+        mlx90363::{MlxOpcode, MlxReply},
+        mock::{MockPin, MockSpi, NoDelay},
+        spi_protocol::{verify_crc, NopError},
+    };
+    use alloc::{collections::VecDeque, vec::Vec};
+
+    /// Device that has read its logical id and otherwise stays silent
+    struct IdOnly(u16);
+
+    impl<S: NegiconProtocol> DownstreamDevice<S> for IdOnly {
+        fn kind(&self) -> DownstreamKind {
+            DownstreamKind::Mlx90363
+        }
+
+        fn poll(
+            &mut self,
+            _spi: &mut S,
+            _cs: &mut dyn OutputPin<Error = Infallible>,
+            _now: Instant,
+            _sink: &mut dyn FnMut(NegiconEvent),
+        ) -> Result<(), DownstreamError> {
+            Ok(())
+        }
+
+        fn id(&self) -> Option<u16> {
+            Some(self.0)
+        }
+    }
+
+    #[test]
+    fn slot_for_id_looks_up_logical_ids() {
+        let mut pins = [
+            MockPin::new(),
+            MockPin::new(),
+            MockPin::new(),
+            MockPin::new(),
+        ];
+        let mut downstreams: Vec<SpiDownstream<'_, MockSpi>> =
+            pins.iter_mut().map(|cs| SpiDownstream::new(cs)).collect();
+        // Slot 1 stays empty, the others are plugged in out of id order
+        for (slot, id) in [(0, 30), (2, 0), (3, 2)] {
+            downstreams[slot].device = DownstreamState::Initialized(Box::new(IdOnly(id)));
+        }
+        assert_eq!(slot_for_id(&downstreams, 30), Some(0));
+        assert_eq!(slot_for_id(&downstreams, 0), Some(2));
+        assert_eq!(slot_for_id(&downstreams, 2), Some(3));
+        assert_eq!(slot_for_id(&downstreams, 1), None);
+        assert_eq!(slot_for_id(&downstreams, 3), None);
+    }
+
+    #[test]
+    fn each_downstream_error_has_its_own_wire_code() {
+        let cases = [
+            (DownstreamError::SpiError(SpiError::CrcError), 1),
+            (DownstreamError::SpiError(SpiError::TxError), 1),
+            (DownstreamError::UnknownDevice(0xAB), 2),
+            (DownstreamError::NopError(NopError::InvalidOpcode("")), 3),
+            (DownstreamError::NopError(NopError::InvalidChallenge("")), 3),
+            (DownstreamError::MlxError(MlxError::FormatError), 4),
+            (DownstreamError::UnexpectedReply, 5),
+            (DownstreamError::InvalidLimits, 7),
+            (DownstreamError::DiagnosticFail, 8),
+            (DownstreamError::InitFailed, 9),
+            (
+                DownstreamError::WriteFailed(MlxError::FormatError, None),
+                10,
+            ),
+        ];
+        for (error, code) in &cases {
+            assert_eq!(error.code(), *code);
+        }
+        for (a, _) in &cases {
+            for (b, _) in &cases {
+                let same_variant = core::mem::discriminant(a) == core::mem::discriminant(b);
+                assert_eq!(a.code() == b.code(), same_variant);
+            }
+        }
+    }
+
+    #[test]
+    fn source_errors_convert_into_their_downstream_variant() {
+        fn propagate<E>(e: E) -> DownstreamResult
+        where
+            DownstreamError: From<E>,
+        {
+            Err(e)?;
+            Ok(())
+        }
+        assert!(matches!(
+            propagate(SpiError::Timeout),
+            Err(DownstreamError::SpiError(SpiError::Timeout))
+        ));
+        assert!(matches!(
+            propagate(MlxError::UnexpectedReply),
+            Err(DownstreamError::MlxError(MlxError::UnexpectedReply))
+        ));
+        assert!(matches!(
+            propagate(NopError::InvalidOpcode("")),
+            Err(DownstreamError::NopError(NopError::InvalidOpcode(_)))
+        ));
+        assert!(matches!(
+            propagate(MlxError::SpiError(SpiError::CrcError)),
+            Err(DownstreamError::MlxError(MlxError::SpiError(
+                SpiError::CrcError
+            )))
+        ));
+    }
+
+    #[test]
+    fn failed_write_reports_its_challenge_and_solution() {
+        let error = DownstreamError::WriteFailed(MlxError::UnexpectedReply, Some(0x5678));
+        let event = error.challenge_event(3).unwrap();
+        let wire = NegiconEvent::deserialize(event.serialize()).unwrap();
+        assert!(wire.event_type() == NegiconEventType::WriteChallenge);
+        assert_eq!(wire.id(), 0x5678);
+        assert_eq!(wire.value() as u16, 0x444C);
+        assert_eq!(wire.sequence(), 3);
+
+        let error = DownstreamError::WriteFailed(MlxError::UnexpectedReply, None);
+        assert!(error.challenge_event(3).is_none());
+        assert!(DownstreamError::UnexpectedReply
+            .challenge_event(3)
+            .is_none());
+    }
+
+    #[test]
+    fn error_event_carries_slot_and_code() {
+        let event = DownstreamError::UnexpectedReply.to_event(17);
+        let wire = NegiconEvent::deserialize(event.serialize()).unwrap();
+        assert!(wire.event_type() == NegiconEventType::Error);
+        assert_eq!(wire.id(), 17);
+        assert_eq!(wire.value(), 5);
+    }
+
+    fn at(us: u64) -> Instant {
+        Instant::from_ticks(us)
+    }
+
+    fn mlx_gap() -> FrameGap {
+        FrameGap {
+            min: Some(MicrosDurationU64::from_ticks(MLX_FRAME_GAP_US as u64)),
+            last: None,
+        }
+    }
+
+    #[test]
+    fn first_transfer_is_never_held_back() {
+        assert!(mlx_gap().elapsed(at(0)));
+    }
+
+    #[test]
+    fn transfers_closer_than_the_gap_are_skipped() {
+        let gap = MLX_FRAME_GAP_US as u64;
+        let mut frame_gap = mlx_gap();
+        assert!(frame_gap.elapsed(at(1000)));
+        assert!(!frame_gap.elapsed(at(1000)));
+        assert!(!frame_gap.elapsed(at(1000 + gap - 1)));
+        assert!(frame_gap.elapsed(at(1000 + gap)));
+        // A skipped poll doesn't push the next transfer out
+        assert!(!frame_gap.elapsed(at(1000 + 2 * gap - 1)));
+        assert!(frame_gap.elapsed(at(1000 + 2 * gap)));
+    }
+
+    #[test]
+    fn clock_going_backwards_does_not_stall_the_slot() {
+        let mut frame_gap = mlx_gap();
+        assert!(frame_gap.elapsed(at(5000)));
+        assert!(frame_gap.elapsed(at(10)));
+    }
+
+    #[test]
+    fn no_gap_allows_every_transfer() {
+        let mut frame_gap = FrameGap {
+            min: None,
+            last: None,
+        };
+        assert!(frame_gap.elapsed(at(7)));
+        assert!(frame_gap.elapsed(at(7)));
+    }
+
+    /// Polls `ds` once per millisecond on an empty bus and returns the
+    /// number of the first poll that probed it
+    fn first_probe(ds: &mut SpiDownstream<'_, MockSpi>, polls: u64) -> Option<u64> {
+        let mut spi = MockSpi::default();
+        (1..=polls).find(|&i| {
+            assert!(ds
+                .poll(&mut NoDelay, &mut spi, at(i * 1000), &mut |_| {})
+                .is_ok());
+            !spi.sent.is_empty()
+        })
+    }
+
+    #[test]
+    fn previously_populated_slot_is_probed_before_empty_ones() {
+        let (mut cs0, mut cs1) = (MockPin::new(), MockPin::new());
+        let mut empty = SpiDownstream::new(&mut cs0);
+        let mut dropped = SpiDownstream::new(&mut cs1);
+        dropped.last_seen = Some(DownstreamKind::Mlx90363);
+
+        assert_eq!(first_probe(&mut dropped, 20), Some(1));
+        assert_eq!(
+            first_probe(&mut empty, 20),
+            Some(EMPTY_SLOT_PROBE_INTERVAL as u64)
+        );
+    }
+
+    /// Challenges of the first detection probes of a fresh slot
+    fn challenges() -> impl Iterator<Item = u16> {
+        core::iter::successors(Some(NOP_CHALLENGE_SEED), |&c| Some(next_challenge(c))).skip(1)
+    }
+
+    fn nop_reply(echo: u16, inv: u16) -> [u8; 8] {
+        let [lo, hi] = echo.to_le_bytes();
+        let [inv_lo, inv_hi] = inv.to_le_bytes();
+        [0, 0, lo, hi, inv_lo, inv_hi, NOP_REPLY_OPCODE_MLX, 0]
+    }
+
+    /// Polls the slot `n` times, one frame gap apart
+    fn probe(ds: &mut SpiDownstream<'_, MockSpi>, spi: &mut MockSpi, n: u64) {
+        for i in 0..n {
+            let now = at(i * MLX_FRAME_GAP_US as u64);
+            assert!(ds.poll(&mut NoDelay, spi, now, &mut |_| {}).is_ok());
+        }
+    }
+
+    #[test]
+    fn detection_remembers_the_device_kind() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut ds = SpiDownstream::new(&mut cs);
+        ds.last_seen = Some(DownstreamKind::Stm32);
+        let first = challenges().next().unwrap();
+        spi.reply_garbage();
+        spi.reply(nop_reply(first, !first));
+
+        probe(&mut ds, &mut spi, 2);
+        assert!(ds.last_seen == Some(DownstreamKind::Mlx90363));
+    }
+
+    #[test]
+    fn composite_device_reports_the_kind_it_was_detected_as() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut ds = SpiDownstream::new(&mut cs);
+        ds.last_seen = Some(DownstreamKind::Mlx90363);
+        let first = challenges().next().unwrap();
+        let mut reply = nop_reply(first, !first);
+        reply[6] = NOP_REPLY_OPCODE_STM;
+        spi.reply_garbage();
+        spi.reply(reply);
+
+        probe(&mut ds, &mut spi, 2);
+        assert!(ds.kind() == Some(DownstreamKind::Stm32));
+    }
+
+    #[test]
+    fn mlx_nop_reply_in_the_datasheet_layout_is_detected() {
+        // ChallengeNOPMISOPacket answering the first challenge, 0x0806, byte
+        // for byte with the CRC the sensor computes
+        const MLX_REPLY: [u8; 8] = [0x00, 0x00, 0x06, 0x08, 0xF9, 0xF7, 0xD1, 0x3A];
+        assert_eq!(challenges().next(), Some(0x0806));
+        assert!(verify_crc(&MLX_REPLY).is_ok());
+        // The sensor driver reads the same frame as its own NOP answer
+        assert!(matches!(
+            MlxReply::deserialize(MLX_REPLY),
+            Ok(MlxReply::Nop(nop)) if nop.challenge == 0x0806
+        ));
+
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut ds = SpiDownstream::new(&mut cs);
+        // Probed on every poll
+        ds.last_seen = Some(DownstreamKind::Stm32);
+        spi.reply_garbage();
+        spi.replies.push_back(MLX_REPLY);
+
+        probe(&mut ds, &mut spi, 2);
+        assert!(ds.kind() == Some(DownstreamKind::Mlx90363));
+        // The request the sensor answered is the MLX's own NOPChallenge
+        assert_eq!(spi.sent[0][6], 0xC0 | MlxOpcode::NOPChallenge as u8);
+    }
+
+    #[test]
+    fn every_probe_sends_a_new_challenge() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut ds = SpiDownstream::new(&mut cs);
+        ds.last_seen = Some(DownstreamKind::Mlx90363);
+
+        probe(&mut ds, &mut spi, 3);
+        let sent: Vec<_> = spi
+            .sent
+            .iter()
+            .map(|f| u16::from_le_bytes([f[2], f[3]]))
+            .collect();
+        assert_eq!(sent, challenges().take(3).collect::<Vec<_>>());
+        assert!(sent[0] != sent[1] && sent[1] != sent[2]);
+    }
+
+    #[test]
+    fn wrong_echo_is_no_device_yet() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut ds = SpiDownstream::new(&mut cs);
+        ds.last_seen = Some(DownstreamKind::Mlx90363);
+        spi.reply_garbage();
+        spi.reply(nop_reply(0x1234, !0x1234));
+
+        probe(&mut ds, &mut spi, 2);
+        assert!(!ds.is_connected());
+    }
+
+    #[test]
+    fn noisy_slot_warns_once_per_interval() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut ds = SpiDownstream::new(&mut cs);
+        ds.last_seen = Some(DownstreamKind::Mlx90363);
+        spi.reply_garbage();
+        for _ in 0..5 {
+            spi.reply(nop_reply(0x1234, !0x1234));
+        }
+
+        probe(&mut ds, &mut spi, 6);
+        // The first bad echo was logged, the other four held back
+        let later = at(2 * DETECT_WARNING_INTERVAL.to_micros());
+        assert_eq!(ds.challenge_warnings.poll(later), Some(4));
+    }
+
+    #[test]
+    fn garbled_inv_is_no_device_yet() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut ds = SpiDownstream::new(&mut cs);
+        ds.last_seen = Some(DownstreamKind::Mlx90363);
+        let first = challenges().next().unwrap();
+        spi.reply_garbage();
+        spi.reply(nop_reply(first, first));
+
+        probe(&mut ds, &mut spi, 2);
+        assert!(!ds.is_connected());
+    }
+
+    #[test]
+    fn stale_echo_is_no_device() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut ds = SpiDownstream::new(&mut cs);
+        ds.last_seen = Some(DownstreamKind::Mlx90363);
+        // Stuck on the answer to the fixed challenge every probe used to send
+        for _ in 0..4 {
+            spi.reply(nop_reply(NOP_CHALLENGE_SEED, !NOP_CHALLENGE_SEED));
+        }
+
+        probe(&mut ds, &mut spi, 4);
+        assert!(!ds.is_connected());
+    }
+
+    #[test]
+    fn miso_looped_back_to_mosi_is_no_device() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut ds = SpiDownstream::new(&mut cs);
+        ds.last_seen = Some(DownstreamKind::Mlx90363);
+        // Each reply echoes the frame it was clocked in with
+        for c in challenges().take(3) {
+            spi.reply(nop_reply(c, !c));
+        }
+
+        probe(&mut ds, &mut spi, 3);
+        assert!(!ds.is_connected());
+    }
+
+    /// Device that plays back one outcome per poll: a number of events or
+    /// an error
+    struct Scripted(VecDeque<Result<u8, DownstreamError>>);
+
+    impl<S: NegiconProtocol> DownstreamDevice<S> for Scripted {
+        fn kind(&self) -> DownstreamKind {
+            DownstreamKind::Mlx90363
+        }
+
+        fn poll(
+            &mut self,
+            _spi: &mut S,
+            _cs: &mut dyn OutputPin<Error = Infallible>,
+            _now: Instant,
+            sink: &mut dyn FnMut(NegiconEvent),
+        ) -> Result<(), DownstreamError> {
+            for _ in 0..self.0.pop_front().unwrap_or(Ok(0))? {
+                sink(NegiconEvent::new(NegiconEventType::Input, 1, 1, 0, 0));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn disabled_slot_is_not_polled_until_enabled_again() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut ds = SpiDownstream::new(&mut cs);
+        ds.device = DownstreamState::Initialized(Box::new(Scripted(VecDeque::from([Ok(1)]))));
+        ds.last_seen = Some(DownstreamKind::Mlx90363);
+
+        ds.set_enabled(false);
+        assert!(!ds.is_connected());
+        let mut sunk = 0;
+        for i in 0..3 {
+            let now = at(i * MLX_FRAME_GAP_US as u64);
+            assert!(ds
+                .poll(&mut NoDelay, &mut spi, now, &mut |_| sunk += 1)
+                .is_ok());
+        }
+        assert!(spi.sent.is_empty());
+        assert_eq!((ds.stats().polls, sunk), (0, 0));
+
+        // The device was dropped, so polling resumes with a detection probe
+        ds.set_enabled(true);
+        let now = at(3 * MLX_FRAME_GAP_US as u64);
+        let _ = ds.poll(&mut NoDelay, &mut spi, now, &mut |_| sunk += 1);
+        assert_eq!(spi.sent.len(), 1);
+        assert_eq!(ds.stats().polls, 1);
+    }
+
+    #[test]
+    fn device_giving_up_on_init_is_dropped() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut ds = SpiDownstream::new(&mut cs);
+        ds.apply_settings(&DownstreamSettings {
+            init_attempts: 1,
+            ..Default::default()
+        });
+        ds.set_fallback_id(7);
+        ds.last_seen = Some(DownstreamKind::Mlx90363);
+        let ready = [
+            0x03,
+            0x41,
+            0,
+            0,
+            0,
+            0,
+            0xC0 | MlxOpcode::ReadyMessage as u8,
+            0,
+        ];
+        let nothing = [
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0xC0 | MlxOpcode::NothingToTransmit as u8,
+            0,
+        ];
+        let mut now = 0;
+        let mut poll = |ds: &mut SpiDownstream<'_, MockSpi>, spi: &mut MockSpi| {
+            now += MLX_FRAME_GAP_US as u64;
+            ds.poll(&mut NoDelay, spi, at(now), &mut |_| {})
+        };
+        // Detected, then its id is requested and never answered
+        for reply in [ready, nothing, nothing] {
+            spi.reply(reply);
+        }
+        assert!(poll(&mut ds, &mut spi).is_ok());
+        assert!(poll(&mut ds, &mut spi).is_ok());
+        let res = poll(&mut ds, &mut spi);
+        assert!(matches!(res, Err(DownstreamError::InitFailed)));
+        assert!(!ds.is_connected());
+
+        // Giving up again once detected afresh, it runs on defaults
+        for reply in [ready, nothing, nothing] {
+            spi.reply(reply);
+        }
+        assert!(poll(&mut ds, &mut spi).is_ok());
+        assert!(poll(&mut ds, &mut spi).is_ok());
+        let res = poll(&mut ds, &mut spi);
+        assert!(matches!(res, Err(DownstreamError::InitFailed)));
+        assert!(ds.is_connected());
+        assert!(ds.diagnostics().is_some_and(|diag| diag.defaulted));
+        assert_eq!(ds.id(), Some(7));
+    }
+
+    #[test]
+    fn device_running_on_defaults_stays_attached() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut ds = SpiDownstream::new(&mut cs);
+        ds.device = DownstreamState::Initialized(Box::new(Scripted(VecDeque::from([Err(
+            DownstreamError::InitFailed,
+        )]))));
+        ds.last_seen = Some(DownstreamKind::Mlx90363);
+
+        let res = ds.poll(&mut NoDelay, &mut spi, at(0), &mut |_| {});
+        assert!(matches!(res, Err(DownstreamError::InitFailed)));
+        assert!(ds.is_connected());
+    }
+
+    #[test]
+    fn stats_count_polls_errors_and_events() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut ds = SpiDownstream::new(&mut cs);
+        ds.device = DownstreamState::Initialized(Box::new(Scripted(VecDeque::from([
+            Ok(2),
+            Err(DownstreamError::UnexpectedReply),
+            Ok(1),
+            Err(DownstreamError::MlxError(MlxError::SpiError(
+                SpiError::CrcError,
+            ))),
+        ]))));
+        ds.last_seen = Some(DownstreamKind::Mlx90363);
+
+        let mut sunk = 0;
+        for i in 0..5 {
+            let now = at(i * MLX_FRAME_GAP_US as u64);
+            let _ = ds.poll(&mut NoDelay, &mut spi, now, &mut |_| sunk += 1);
+        }
+        // The CRC error dropped the device, the last poll was a probe
+        assert!(!ds.is_connected());
+        assert_eq!(spi.sent.len(), 1);
+        let stats = ds.stats();
+        assert_eq!(
+            (stats.polls, stats.crc_errors, stats.device_errors),
+            (5, 1, 1)
+        );
+        assert_eq!((stats.events, sunk), (3, 3));
+    }
+
+    #[test]
+    fn settings_are_kept_for_later_detections() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut ds = SpiDownstream::new(&mut cs);
+        let settings = DownstreamSettings {
+            prime_readings: 3,
+            raw_alpha: true,
+            report_mode: ReportMode::Periodic(4),
+            min_relative_step: 8,
+            diagnostic_fail_limit: 5,
+            init_attempts: 4,
+            button_mapping: ButtonMapping {
+                press: 0,
+                release: 1,
+                id_offset: 100,
+            },
+            frame_ids: true,
+        };
+        ds.apply_settings(&settings);
+        ds.last_seen = Some(DownstreamKind::Mlx90363);
+        spi.reply([
+            0x03,
+            0x41,
+            0,
+            0,
+            0,
+            0,
+            0xC0 | MlxOpcode::ReadyMessage as u8,
+            0,
+        ]);
+
+        assert!(ds.poll(&mut NoDelay, &mut spi, at(0), &mut |_| {}).is_ok());
+        assert!(ds.is_connected());
+        assert!(ds.settings == settings);
+    }
+
+    #[test]
+    fn slot_without_device_state_detects_but_keeps_nothing() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut ds = SpiDownstream::new(&mut cs);
+        ds.disable_device_state();
+        // Seen before, so the slot is probed on the first poll
+        ds.last_seen = Some(DownstreamKind::Rp2040);
+        spi.reply([
+            0x03,
+            0x41,
+            0,
+            0,
+            0,
+            0,
+            0xC0 | MlxOpcode::ReadyMessage as u8,
+            0,
+        ]);
+
+        assert!(ds.poll(&mut NoDelay, &mut spi, at(0), &mut |_| {}).is_ok());
+        assert!(ds.last_seen == Some(DownstreamKind::Mlx90363));
+        assert!(!ds.is_connected());
+
+        let cell = NegiconEvent::new(NegiconEventType::MemWriteBatch, 0, 1, 0, 0x20);
+        ds.stage_write(&cell);
+        ds.write_memory(&cell);
+        assert_eq!(ds.pending_writes.capacity(), 0);
+    }
+
+    #[test]
+    fn freshly_powered_mlx_is_detected_with_its_revision() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        let mut ds = SpiDownstream::new(&mut cs);
+        ds.last_seen = Some(DownstreamKind::Mlx90363);
+        spi.reply([
+            0x03,
+            0x41,
+            0,
+            0,
+            0,
+            0,
+            0xC0 | MlxOpcode::ReadyMessage as u8,
+            0,
+        ]);
+
+        assert!(ds.poll(&mut NoDelay, &mut spi, at(0), &mut |_| {}).is_ok());
+        assert!(ds.is_connected());
+        assert_eq!(ds.version(), Some(0x0341));
+    }
+
+    #[test]
+    fn each_slot_drives_its_own_cs_line() {
+        let mut cs = [MockPin::new(), MockPin::new(), MockPin::new()];
+        let mut spi = MockSpi::default();
+        {
+            let mut downstreams = downstream_slots(&mut cs);
+            for ds in downstreams.iter_mut() {
+                ds.last_seen = Some(DownstreamKind::Mlx90363);
+            }
+            assert!(downstreams[1]
+                .poll(&mut NoDelay, &mut spi, at(0), &mut |_| {})
+                .is_ok());
+        }
+        assert_eq!(spi.sent.len(), 1);
+        let selects: Vec<_> = cs.iter().map(|pin| pin.selects).collect();
+        assert_eq!(selects, [0, 1, 0]);
+        assert!(cs.iter().all(|pin| pin.high));
+    }
+}
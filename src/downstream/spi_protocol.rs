@@ -1,15 +1,92 @@
-use core::{convert::Infallible, ops::Shr};
+use core::convert::Infallible;
+#[cfg(feature = "spi-trace")]
+use core::sync::atomic::{AtomicU8, Ordering};
 
-use defmt::{Format};
-use embedded_hal::{blocking, digital::v2::OutputPin};
+use defmt::Format;
+use embedded_hal::{
+    digital::v2::OutputPin,
+    spi::{FullDuplex, Mode, MODE_1},
+};
+use fugit::{HertzU32, MicrosDurationU64};
 use rp2040_hal::{
     spi::{Enabled, SpiDevice, ValidSpiPinout},
-    Spi,
+    timer::Instant,
+    Spi, Timer,
+};
+
+use super::{
+    mlx90363::MlxOpcode,
+    util::{put_u16_le, u16_from_le},
 };
 
-use super::util::make_u16;
+/// Fastest SCLK accepted for the downstream bus. It is the rate the bus ran
+/// at before the clock became configurable, known to work with MLX90363
+/// sensors and the satellites. Any slot can get an MLX90363 hotplugged, so
+/// the bus is never run faster than that.
+pub(crate) const MAX_DOWNSTREAM_SPI_FREQ_HZ: u32 = 2_500_000;
+/// Default SCLK of the downstream bus, the same fixed rate as before. Long
+/// daisy chains may need to go lower.
+pub(crate) const DOWNSTREAM_SPI_FREQ_HZ: u32 = 2_500_000;
+/// Mode of the downstream bus. The MLX90363 only talks CPOL=0/CPHA=1, and
+/// the satellites are built to answer in the same mode. The HAL can only
+/// change the mode by re-initializing the peripheral, so the bus is never
+/// switched. A device in another mode can't be told apart from an empty slot,
+/// as its replies fail the CRC.
+pub(crate) const DOWNSTREAM_SPI_MODE: Mode = MODE_1;
+/// Longest a single frame may take before the transfer is abandoned. A frame
+/// takes 33 ms at the slowest clock `validate_spi_freq` accepts.
+const SPI_FRAME_TIMEOUT: MicrosDurationU64 = MicrosDurationU64::millis(50);
+/// Upper bound for the CS settle time. Every frame waits twice as long, so
+/// more would noticeably slow down a full polling round.
+pub(crate) const MAX_CS_SETTLE_US: u16 = 100;
+
+/// Transfers in a row that failed, on any slot. Replies failing their CRC
+/// don't count, those point at a device or its wiring rather than the
+/// peripheral.
+#[derive(Default, Clone, Copy)]
+pub(crate) struct FailedTransfers(u16);
+
+impl FailedTransfers {
+    pub(crate) fn record(&mut self, res: &Result<(), SpiError>) {
+        self.0 = match res {
+            Ok(_) => 0,
+            Err(_) => self.0.saturating_add(1),
+        };
+    }
+
+    pub(crate) fn count(&self) -> u16 {
+        self.0
+    }
+}
+
+/// Slot the traced frames are going out to
+#[cfg(feature = "spi-trace")]
+static TRACE_SLOT: AtomicU8 = AtomicU8::new(0);
+
+/// Tags the frames logged with the `spi-trace` feature with the slot whose CS
+/// line they are sent on. Does nothing without the feature.
+#[inline(always)]
+pub(crate) fn set_trace_slot(_slot: u8) {
+    #[cfg(feature = "spi-trace")]
+    TRACE_SLOT.store(_slot, Ordering::Relaxed);
+}
+
+#[cfg(feature = "spi-trace")]
+fn trace_frame(tx: &[u8; 8], rx: &[u8; 8], res: &Result<(), SpiError>) {
+    defmt::debug!(
+        "SPI slot {}: tx {:x} rx {:x} {}",
+        TRACE_SLOT.load(Ordering::Relaxed),
+        tx,
+        rx,
+        res
+    );
+}
 
-const NOP_COMMAND_OPCODE: u8 = 0b11010000u8;
+/// Marker bits of the opcode byte of NOP frames, the MLX90363's irregular
+/// marker. The MLX opcode goes in the low six bits.
+const NOP_MARKER: u8 = 0b11 << 6;
+/// The MLX90363's `NOPChallenge`, which every downstream kind answers
+const NOP_COMMAND_OPCODE: u8 = NOP_MARKER | MlxOpcode::NOPChallenge as u8;
 const CBA_256_TAB: [u8; 256] = [
     0x00, 0x2f, 0x5e, 0x71, 0xbc, 0x93, 0xe2, 0xcd, 0x57, 0x78, 0x09, 0x26, 0xeb, 0xc4, 0xb5, 0x9a,
     0xae, 0x81, 0xf0, 0xdf, 0x12, 0x3d, 0x4c, 0x63, 0xf9, 0xd6, 0xa7, 0x88, 0x45, 0x6a, 0x1b, 0x34,
@@ -32,6 +109,8 @@ const CBA_256_TAB: [u8; 256] = [
 pub(crate) enum SpiError {
     CrcError,
     TxError,
+    /// The frame didn't complete within `SPI_FRAME_TIMEOUT`
+    Timeout,
 }
 
 #[derive(Format)]
@@ -40,28 +119,44 @@ pub(crate) enum NopError {
     InvalidChallenge(&'static str),
 }
 
-pub(crate) const NOP_REPLY_OPCODE_MLX: u8 = 0b11010001;
-pub(crate) const NOP_REPLY_OPCODE_STM: u8 = 0b11110011;
-pub(crate) const NOP_REPLY_OPCODE_RP: u8 = 0b11000010;
+/// The MLX90363's own `ChallengeNOPMISOPacket`, as `MlxReply::deserialize`
+/// decodes it
+pub(crate) const NOP_REPLY_OPCODE_MLX: u8 = NOP_MARKER | MlxOpcode::ChallengeNOPMISOPacket as u8;
+/// Replies of our own downstream firmwares, same marker but an opcode the
+/// MLX90363 never answers a NOP with
+pub(crate) const NOP_REPLY_OPCODE_STM: u8 = NOP_MARKER | 0x33;
+pub(crate) const NOP_REPLY_OPCODE_RP: u8 = NOP_MARKER | 0x02;
 
-fn crc(data: &[u8]) -> u8 {
+/// Checks that `requested` can be generated from the peripheral clock and
+/// doesn't exceed `MAX_DOWNSTREAM_SPI_FREQ_HZ`. The PL022 divides `clk_peri` by
+/// an even prescaler (2..=254) and a post divider (1..=256), so anything
+/// outside that range is rejected. The lower bound rounds up, as the slowest
+/// clock the dividers produce may be a fraction of a Hz above the quotient.
+pub(crate) fn validate_spi_freq(requested: u32, peripheral: u32) -> Option<u32> {
+    let max = (peripheral / 2).min(MAX_DOWNSTREAM_SPI_FREQ_HZ);
+    let min = peripheral.div_ceil(254 * 256);
+    if requested >= min && requested <= max {
+        Some(requested)
+    } else {
+        None
+    }
+}
+
+/// CRC-8 used on the downstream bus, computed over all of `data`
+pub(crate) fn crc8(data: &[u8]) -> u8 {
     let mut crc: u8 = 0xFF;
-    crc = CBA_256_TAB[(crc ^ data[0]) as usize];
-    crc = CBA_256_TAB[(crc ^ data[1]) as usize];
-    crc = CBA_256_TAB[(crc ^ data[2]) as usize];
-    crc = CBA_256_TAB[(crc ^ data[3]) as usize];
-    crc = CBA_256_TAB[(crc ^ data[4]) as usize];
-    crc = CBA_256_TAB[(crc ^ data[5]) as usize];
-    crc = CBA_256_TAB[(crc ^ data[6]) as usize];
+    for byte in data {
+        crc = CBA_256_TAB[(crc ^ byte) as usize];
+    }
     !crc
 }
-fn set_crc(data: &mut [u8]) {
+fn crc(data: &[u8; 8]) -> u8 {
+    crc8(&data[..7])
+}
+pub(crate) fn set_crc(data: &mut [u8; 8]) {
     data[7] = crc(data);
 }
-fn verify_crc(data: &[u8]) -> Result<(), SpiError> {
-    if data.len() != 8 {
-        panic!("data.len must be 8");
-    }
+pub(crate) fn verify_crc(data: &[u8; 8]) -> Result<(), SpiError> {
     let checksum = crc(data);
     if data[7] == checksum {
         Ok(())
@@ -70,6 +165,14 @@ fn verify_crc(data: &[u8]) -> Result<(), SpiError> {
     }
 }
 
+/// Challenge NOP used to detect and identify downstreams. The reply echoes
+/// the challenge and its inverse and tells the device kind by its opcode.
+///
+/// Request: `[0, 0, chal lo, chal hi, 0, 0, NOP_COMMAND_OPCODE, crc]`
+/// Reply: `[_, _, chal lo, chal hi, !chal lo, !chal hi, NOP_REPLY_OPCODE_*, crc]`
+///
+/// `deserialize` only accepts replies whose two echoes agree, `verify` then
+/// checks them against the challenge that was sent.
 #[derive(Format)]
 pub(crate) struct NopMessage {
     pub(crate) challenge: u16,
@@ -77,32 +180,193 @@ pub(crate) struct NopMessage {
     pub(crate) inv: u16,
 }
 
-pub(crate) trait NegiconProtocol: blocking::spi::Transfer<u8> {
+/// Challenge the first detection NOP of a slot is derived from
+pub(crate) const NOP_CHALLENGE_SEED: u16 = 0x3939;
+
+/// Challenge for the NOP after one carrying `prev`. A 16-bit LCG, so
+/// consecutive probes never repeat and a MISO line that just echoes an old
+/// frame fails verification.
+pub(crate) fn next_challenge(prev: u16) -> u16 {
+    prev.wrapping_mul(25173).wrapping_add(13849)
+}
+
+/// Retries `op` until it stops blocking, giving up once `deadline` has passed.
+fn complete_by<R, E>(
+    now: &impl Fn() -> Instant,
+    deadline: Instant,
+    mut op: impl FnMut() -> nb::Result<R, E>,
+) -> Result<R, SpiError> {
+    loop {
+        match op() {
+            Ok(res) => return Ok(res),
+            Err(nb::Error::Other(_)) => return Err(SpiError::TxError),
+            Err(nb::Error::WouldBlock) if now() > deadline => return Err(SpiError::Timeout),
+            Err(nb::Error::WouldBlock) => {}
+        }
+    }
+}
+
+/// Exchanges `data` byte by byte, giving up once `deadline` has passed. On
+/// failure whatever is left in the RX FIFO is dropped, so the next frame
+/// starts aligned.
+pub(crate) fn transfer_by<S: FullDuplex<u8>>(
+    spi: &mut S,
+    data: &mut [u8; 8],
+    now: impl Fn() -> Instant,
+    deadline: Instant,
+) -> Result<(), SpiError> {
+    let mut res = Ok(());
+    for byte in data.iter_mut() {
+        res = complete_by(&now, deadline, || spi.send(*byte))
+            .and_then(|_| complete_by(&now, deadline, || spi.read()))
+            .map(|read| *byte = read);
+        if res.is_err() {
+            while spi.read().is_ok() {}
+            break;
+        }
+    }
+    res
+}
+
+/// Busy-waits for `settle`, returning right away if it is zero
+pub(crate) fn settle_for(now: impl Fn() -> Instant, settle: MicrosDurationU64) {
+    if settle.ticks() == 0 {
+        return;
+    }
+    let until = now() + settle;
+    while now() < until {}
+}
+
+pub(crate) trait NegiconProtocol {
+    /// Exchanges one frame as is
+    fn transfer_frame(&mut self, data: &mut [u8; 8]) -> Result<(), SpiError>;
+
+    /// Sets how long CS is held active before the first and after the last
+    /// clock of a frame, for long harnesses where the first clock edge would
+    /// otherwise arrive before CS settled. Zero, the default, skips the wait.
+    fn set_cs_settle(&mut self, _settle: MicrosDurationU64) {}
+
+    /// Waits out the CS settle time
+    fn cs_settle(&mut self) {}
+
+    /// Transfers that failed in a row since the bus was set up
+    fn failed_transfers(&self) -> u16 {
+        0
+    }
+
     fn verified_transmit(
         &mut self,
         cs: &mut dyn OutputPin<Error = Infallible>,
-        data: &mut [u8],
+        data: &mut [u8; 8],
     ) -> Result<(), SpiError> {
         cs.set_low().unwrap();
         set_crc(data);
-        //debug!("Sending {:?}", data);
-        let res = self.transfer(data);
+        #[cfg(feature = "spi-trace")]
+        let tx = *data;
+        self.cs_settle();
+        let res = self.transfer_frame(data);
+        self.cs_settle();
         cs.set_high().unwrap();
-        match res {
-            Ok(_) => {
-                //                debug!("Received {:?}", data);
-                verify_crc(data)
+        let res = res.and_then(|_| verify_crc(data));
+        #[cfg(feature = "spi-trace")]
+        trace_frame(&tx, data, &res);
+        res
+    }
+
+    /// `verified_transmit` that resends the frame on CRC and transfer errors,
+    /// up to `attempts` frames in total. A timeout is returned right away,
+    /// retrying a device that holds the bus would only stall the loop longer.
+    ///
+    /// Replies lag one frame, so a retry is answered either like the original
+    /// or, if the device did take the failed frame, as an answer to that same
+    /// request. Only use it where both are acceptable.
+    fn verified_transmit_retry(
+        &mut self,
+        cs: &mut dyn OutputPin<Error = Infallible>,
+        data: &mut [u8; 8],
+        attempts: u8,
+    ) -> Result<(), SpiError> {
+        let request = *data;
+        let mut remaining = attempts.max(1);
+        loop {
+            *data = request;
+            remaining -= 1;
+            match self.verified_transmit(cs, data) {
+                Err(SpiError::CrcError | SpiError::TxError) if remaining > 0 => {}
+                res => return res,
             }
-            Err(_) => Err(SpiError::TxError),
         }
     }
 }
 
-impl<D, V> NegiconProtocol for Spi<Enabled, D, V, 8>
+/// Downstream bus whose frames are bounded by `SPI_FRAME_TIMEOUT`, so a
+/// device holding the bus can't stall the poll loop
+pub(crate) struct TimedSpi<S> {
+    spi: S,
+    timer: Timer,
+    cs_settle: MicrosDurationU64,
+    failed: FailedTransfers,
+}
+
+impl<S> TimedSpi<S> {
+    pub(crate) fn new(spi: S, timer: Timer) -> Self {
+        Self {
+            spi,
+            timer,
+            cs_settle: MicrosDurationU64::from_ticks(0),
+            failed: FailedTransfers::default(),
+        }
+    }
+
+    /// Hands the peripheral to `init` to be set up again, e.g. after it got
+    /// stuck, and starts counting failed transfers from scratch
+    pub(crate) fn reinit(self, init: impl FnOnce(S) -> S) -> Self {
+        Self {
+            spi: init(self.spi),
+            failed: FailedTransfers::default(),
+            ..self
+        }
+    }
+}
+
+impl<S: FullDuplex<u8>> NegiconProtocol for TimedSpi<S> {
+    fn transfer_frame(&mut self, data: &mut [u8; 8]) -> Result<(), SpiError> {
+        let timer = self.timer;
+        let deadline = timer.get_counter() + SPI_FRAME_TIMEOUT;
+        let res = transfer_by(&mut self.spi, data, || timer.get_counter(), deadline);
+        self.failed.record(&res);
+        res
+    }
+
+    fn set_cs_settle(&mut self, settle: MicrosDurationU64) {
+        self.cs_settle = settle;
+    }
+
+    fn failed_transfers(&self) -> u16 {
+        self.failed.count()
+    }
+
+    fn cs_settle(&mut self) {
+        let timer = self.timer;
+        settle_for(|| timer.get_counter(), self.cs_settle);
+    }
+}
+
+/// Bus whose SCLK can be changed at runtime
+pub(crate) trait SpiClock {
+    /// Sets SCLK as close to `freq` as the divider allows and returns the rate
+    /// actually reached.
+    fn set_clock(&mut self, peripheral: HertzU32, freq: HertzU32) -> HertzU32;
+}
+
+impl<D, T> SpiClock for TimedSpi<Spi<Enabled, D, T, 8>>
 where
     D: SpiDevice,
-    V: ValidSpiPinout<D>,
+    T: ValidSpiPinout<D>,
 {
+    fn set_clock(&mut self, peripheral: HertzU32, freq: HertzU32) -> HertzU32 {
+        self.spi.set_baudrate(peripheral, freq)
+    }
 }
 
 //TODO use 16-bit SPI
@@ -116,26 +380,18 @@ impl NopMessage {
     }
 
     pub(crate) fn serialize(&self) -> [u8; 8] {
-        let mut buf = [
-            0u8,
-            0u8,
-            self.challenge as u8,
-            self.challenge.shr(8) as u8,
-            0u8,
-            0u8,
-            self.opcode,
-            0u8,
-        ];
+        let mut buf = [0u8, 0u8, 0u8, 0u8, 0u8, 0u8, self.opcode, 0u8];
+        put_u16_le(&mut buf[2..4], self.challenge);
         set_crc(&mut buf);
         buf
     }
 
     pub(crate) fn deserialize(data: &[u8; 8]) -> Result<NopMessage, NopError> {
-        let echo = make_u16(data[3], data[2]);
-        let inv = make_u16(data[5], data[4]);
-        /*if challenge != echo && challenge != !inv {
+        let echo = u16_from_le(&data[2..4]);
+        let inv = u16_from_le(&data[4..6]);
+        if echo != !inv {
             return Err(NopError::InvalidChallenge("Invalid echo"));
-        }*/
+        }
         match data[6] {
             NOP_REPLY_OPCODE_MLX => Ok(NopMessage {
                 challenge: echo,
@@ -166,3 +422,390 @@ impl NopMessage {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downstream::mock::{MockPin, MockSpi};
+    use alloc::vec::Vec;
+    use core::cell::Cell;
+
+    const CLK_PERI_HZ: u32 = 125_000_000;
+
+    #[test]
+    fn spi_freq_capped_at_the_bus_limit() {
+        assert_eq!(
+            validate_spi_freq(MAX_DOWNSTREAM_SPI_FREQ_HZ, CLK_PERI_HZ),
+            Some(MAX_DOWNSTREAM_SPI_FREQ_HZ)
+        );
+        assert_eq!(
+            validate_spi_freq(MAX_DOWNSTREAM_SPI_FREQ_HZ + 1, CLK_PERI_HZ),
+            None
+        );
+    }
+
+    #[test]
+    fn default_spi_freq_is_the_former_fixed_clock() {
+        assert_eq!(DOWNSTREAM_SPI_FREQ_HZ, 2_500_000);
+        assert_eq!(
+            validate_spi_freq(2_500_000, CLK_PERI_HZ),
+            Some(DOWNSTREAM_SPI_FREQ_HZ)
+        );
+    }
+
+    #[test]
+    fn failed_transfers_count_in_a_row_but_not_crc_errors() {
+        let mut spi = MockSpi {
+            stuck: true,
+            ..Default::default()
+        };
+        for _ in 0..3 {
+            let res = spi.verified_transmit(&mut MockPin::new(), &mut [0; 8]);
+            assert!(matches!(res, Err(SpiError::Timeout)));
+        }
+        assert_eq!(spi.failed_transfers(), 3);
+
+        spi.stuck = false;
+        spi.reply_garbage();
+        let res = spi.verified_transmit(&mut MockPin::new(), &mut [0; 8]);
+        assert!(matches!(res, Err(SpiError::CrcError)));
+        assert_eq!(spi.failed_transfers(), 0);
+    }
+
+    #[test]
+    fn spi_freq_capped_at_half_peripheral_clock() {
+        assert_eq!(validate_spi_freq(1_500_000, 3_000_000), Some(1_500_000));
+        assert_eq!(validate_spi_freq(1_500_001, 3_000_000), None);
+    }
+
+    #[test]
+    fn spi_freq_lower_bound_rounds_up() {
+        // 125 MHz / (254 * 256) is 1922.4 Hz
+        assert_eq!(validate_spi_freq(1923, CLK_PERI_HZ), Some(1923));
+        assert_eq!(validate_spi_freq(1922, CLK_PERI_HZ), None);
+        assert_eq!(validate_spi_freq(0, CLK_PERI_HZ), None);
+    }
+
+    #[test]
+    fn crc_covers_the_first_seven_bytes() {
+        let mut frame = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0];
+        set_crc(&mut frame);
+        assert_eq!(frame[7], crc8(&frame[..7]));
+        assert!(verify_crc(&frame).is_ok());
+        for byte in 0..8 {
+            let mut corrupt = frame;
+            corrupt[byte] ^= 0x10;
+            assert!(matches!(verify_crc(&corrupt), Err(SpiError::CrcError)));
+        }
+    }
+
+    #[test]
+    fn verified_transmit_signs_the_request_and_checks_the_reply() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        spi.reply([1, 2, 3, 4, 5, 6, 7, 0]);
+        spi.reply_garbage();
+
+        let mut frame = [9, 8, 7, 6, 5, 4, 3, 0];
+        assert!(spi.verified_transmit(&mut cs, &mut frame).is_ok());
+        assert!(verify_crc(&spi.sent[0]).is_ok());
+        assert_eq!(frame[..7], [1, 2, 3, 4, 5, 6, 7]);
+        assert!(cs.high);
+
+        let mut frame = [0; 8];
+        assert!(matches!(
+            spi.verified_transmit(&mut cs, &mut frame),
+            Err(SpiError::CrcError)
+        ));
+    }
+
+    #[test]
+    fn retry_recovers_from_two_failed_frames() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        spi.reply_garbage();
+        spi.reply_garbage();
+        spi.reply([1, 2, 3, 4, 5, 6, 7, 0]);
+
+        let mut frame = [9, 8, 7, 6, 5, 4, 3, 0];
+        assert!(spi.verified_transmit_retry(&mut cs, &mut frame, 3).is_ok());
+        assert_eq!(frame[..7], [1, 2, 3, 4, 5, 6, 7]);
+        // The same request went out every time, not the failed reply
+        assert_eq!(spi.sent.len(), 3);
+        assert!(spi.sent.iter().all(|f| f[..7] == [9, 8, 7, 6, 5, 4, 3]));
+    }
+
+    #[test]
+    fn retry_gives_up_after_the_attempts() {
+        let mut spi = MockSpi::default();
+        let mut cs = MockPin::new();
+        for _ in 0..3 {
+            spi.reply_garbage();
+        }
+
+        let mut frame = [0; 8];
+        assert!(matches!(
+            spi.verified_transmit_retry(&mut cs, &mut frame, 2),
+            Err(SpiError::CrcError)
+        ));
+        assert_eq!(spi.sent.len(), 2);
+    }
+
+    /// Bus with a device that holds it on every frame
+    struct Held {
+        frames: usize,
+    }
+
+    impl NegiconProtocol for Held {
+        fn transfer_frame(&mut self, _data: &mut [u8; 8]) -> Result<(), SpiError> {
+            self.frames += 1;
+            Err(SpiError::Timeout)
+        }
+    }
+
+    #[test]
+    fn timeouts_are_not_retried() {
+        let mut spi = Held { frames: 0 };
+        let mut frame = [0; 8];
+        assert!(matches!(
+            spi.verified_transmit_retry(&mut MockPin::new(), &mut frame, 3),
+            Err(SpiError::Timeout)
+        ));
+        assert_eq!(spi.frames, 1);
+    }
+
+    /// Reply frame as a downstream sends it, with a valid CRC
+    fn nop_reply(opcode: u8, challenge: u16, inv: u16) -> [u8; 8] {
+        let mut frame = [0u8; 8];
+        put_u16_le(&mut frame[2..4], challenge);
+        put_u16_le(&mut frame[4..6], inv);
+        frame[6] = opcode;
+        set_crc(&mut frame);
+        frame
+    }
+
+    /// Answers `request` the way a downstream does: echoes the key and its
+    /// inverse under the device's reply opcode
+    fn answer(request: &[u8; 8], opcode: u8) -> [u8; 8] {
+        let key = u16_from_le(&request[2..4]);
+        nop_reply(opcode, key, !key)
+    }
+
+    fn decode(frame: &[u8; 8]) -> NopMessage {
+        match NopMessage::deserialize(frame) {
+            Ok(msg) => msg,
+            Err(_) => panic!("NOP reply {:x?} rejected", frame),
+        }
+    }
+
+    const REPLY_OPCODES: [u8; 3] = [
+        NOP_REPLY_OPCODE_MLX,
+        NOP_REPLY_OPCODE_STM,
+        NOP_REPLY_OPCODE_RP,
+    ];
+
+    #[test]
+    fn nop_request_layout() {
+        let frame = NopMessage::new(0x1234).serialize();
+        assert_eq!(frame[..7], [0, 0, 0x34, 0x12, 0, 0, NOP_COMMAND_OPCODE]);
+        assert!(verify_crc(&frame).is_ok());
+    }
+
+    #[test]
+    fn nop_round_trips_challenge() {
+        for opcode in REPLY_OPCODES {
+            for challenge in [0, 1, 0x1234, 0x8000, u16::MAX] {
+                let request = NopMessage::new(challenge).serialize();
+                let decoded = decode(&answer(&request, opcode));
+                assert_eq!(decoded.challenge, challenge);
+                assert_eq!(decoded.opcode, opcode);
+                assert!(decoded.verify(challenge).is_ok());
+            }
+        }
+    }
+
+    #[test]
+    fn nop_verify_rejects_wrong_challenge() {
+        let reply = decode(&nop_reply(NOP_REPLY_OPCODE_MLX, 0xBEEF, !0xBEEF));
+        assert!(matches!(
+            reply.verify(0xBEEE),
+            Err(NopError::InvalidChallenge(_))
+        ));
+    }
+
+    #[test]
+    fn nop_verify_rejects_non_inverted_inv() {
+        let reply = NopMessage {
+            challenge: 0xBEEF,
+            opcode: NOP_REPLY_OPCODE_MLX,
+            inv: 0xBEEF,
+        };
+        assert!(matches!(
+            reply.verify(0xBEEF),
+            Err(NopError::InvalidChallenge(_))
+        ));
+    }
+
+    #[test]
+    fn nop_rejects_echoes_that_disagree() {
+        let frame = nop_reply(NOP_REPLY_OPCODE_MLX, 0xBEEF, 0xBEEF);
+        assert!(matches!(
+            NopMessage::deserialize(&frame),
+            Err(NopError::InvalidChallenge(_))
+        ));
+    }
+
+    #[test]
+    fn nop_rejects_unknown_opcode() {
+        for opcode in [0, NOP_COMMAND_OPCODE, 0xFF] {
+            let frame = nop_reply(opcode, 0xBEEF, !0xBEEF);
+            assert!(matches!(
+                NopMessage::deserialize(&frame),
+                Err(NopError::InvalidOpcode(_))
+            ));
+        }
+    }
+
+    /// Byte-wise bus with `ready` bytes in flight before it stops answering.
+    /// MISO reads back MOSI + 1.
+    struct Stalling {
+        ready: usize,
+        rx: Option<u8>,
+    }
+
+    impl FullDuplex<u8> for Stalling {
+        type Error = Infallible;
+
+        fn read(&mut self) -> nb::Result<u8, Infallible> {
+            self.rx.take().ok_or(nb::Error::WouldBlock)
+        }
+
+        fn send(&mut self, byte: u8) -> nb::Result<(), Infallible> {
+            if self.ready == 0 || self.rx.is_some() {
+                return Err(nb::Error::WouldBlock);
+            }
+            self.ready -= 1;
+            self.rx = Some(byte.wrapping_add(1));
+            Ok(())
+        }
+    }
+
+    /// Clock that moves on by 1 ms every time it is read
+    fn ticking(time: &Cell<u64>) -> impl Fn() -> Instant + '_ {
+        || {
+            time.set(time.get() + 1000);
+            Instant::from_ticks(time.get())
+        }
+    }
+
+    #[test]
+    fn frame_completes_before_the_deadline() {
+        let mut spi = Stalling { ready: 8, rx: None };
+        let time = Cell::new(0);
+        let mut data = [0, 1, 2, 3, 4, 5, 6, 7];
+        let deadline = Instant::from_ticks(0) + SPI_FRAME_TIMEOUT;
+
+        assert!(transfer_by(&mut spi, &mut data, ticking(&time), deadline).is_ok());
+        assert_eq!(data, [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn stalled_bus_times_out() {
+        for ready in [0, 3] {
+            let mut spi = Stalling { ready, rx: None };
+            let time = Cell::new(0);
+            let mut data = [0; 8];
+            let deadline = Instant::from_ticks(0) + SPI_FRAME_TIMEOUT;
+
+            let res = transfer_by(&mut spi, &mut data, ticking(&time), deadline);
+            assert!(matches!(res, Err(SpiError::Timeout)));
+            // Gave up right after the deadline, with the RX FIFO drained
+            assert_eq!(time.get(), SPI_FRAME_TIMEOUT.to_micros() + 1000);
+            assert!(spi.rx.is_none());
+        }
+    }
+
+    #[test]
+    fn settle_waits_until_the_time_is_up() {
+        let time = Cell::new(0);
+        let clock = || {
+            time.set(time.get() + 1);
+            Instant::from_ticks(time.get())
+        };
+        settle_for(clock, MicrosDurationU64::micros(40));
+        // One read to start from, then until 40 µs later
+        assert_eq!(time.get(), 41);
+
+        settle_for(clock, MicrosDurationU64::from_ticks(0));
+        assert_eq!(time.get(), 41);
+    }
+
+    /// Bus on a fake µs clock that exchanges a frame in 1 µs and records the
+    /// time of each CS edge
+    struct Clocked<'a> {
+        now: &'a Cell<u64>,
+        settle: MicrosDurationU64,
+    }
+
+    impl NegiconProtocol for Clocked<'_> {
+        fn transfer_frame(&mut self, _data: &mut [u8; 8]) -> Result<(), SpiError> {
+            self.now.set(self.now.get() + 1);
+            Ok(())
+        }
+
+        fn set_cs_settle(&mut self, settle: MicrosDurationU64) {
+            self.settle = settle;
+        }
+
+        fn cs_settle(&mut self) {
+            self.now.set(self.now.get() + self.settle.ticks());
+        }
+    }
+
+    struct EdgePin<'a> {
+        now: &'a Cell<u64>,
+        edges: Vec<(bool, u64)>,
+    }
+
+    impl OutputPin for EdgePin<'_> {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.edges.push((false, self.now.get()));
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.edges.push((true, self.now.get()));
+            Ok(())
+        }
+    }
+
+    fn cs_edges(settle_us: u64) -> Vec<(bool, u64)> {
+        let now = Cell::new(0);
+        let mut bus = Clocked {
+            now: &now,
+            settle: MicrosDurationU64::from_ticks(0),
+        };
+        bus.set_cs_settle(MicrosDurationU64::micros(settle_us));
+        let mut cs = EdgePin {
+            now: &now,
+            edges: Vec::new(),
+        };
+        let mut frame = [0; 8];
+        set_crc(&mut frame);
+        assert!(bus.verified_transmit(&mut cs, &mut frame).is_ok());
+        cs.edges
+    }
+
+    #[test]
+    fn cs_is_held_for_the_settle_time_around_the_frame() {
+        // 20 µs before the first and after the last clock of the 1 µs frame
+        assert_eq!(cs_edges(20), [(false, 0), (true, 41)]);
+    }
+
+    #[test]
+    fn cs_frames_only_the_transfer_by_default() {
+        assert_eq!(cs_edges(0), [(false, 0), (true, 1)]);
+    }
+}
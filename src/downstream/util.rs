@@ -1,34 +1,68 @@
-use core::ops::Shl;
+//! Byte order helpers. Downstream frames are little-endian, host reports are
+//! big-endian; every 16-bit field goes through one of these or a named
+//! `to_*_bytes` so the order is spelled out at the call site.
 
-trait ConvertToU16 {
-    fn to_u16_big_endian(self) -> [u16; 4];
-    fn to_u16_little_endian(self) -> [u16; 4];
+/// Reads a little-endian `u16` from the first two bytes of `data`
+pub(crate) fn u16_from_le(data: &[u8]) -> u16 {
+    u16::from_le_bytes([data[0], data[1]])
 }
 
-impl ConvertToU16 for [u8; 8] {
-    fn to_u16_big_endian(self) -> [u16; 4] {
-        [
-            ((self[0] as u16) << 8) | self[1] as u16,
-            ((self[2] as u16) << 8) | self[3] as u16,
-            ((self[4] as u16) << 8) | self[5] as u16,
-            ((self[6] as u16) << 8) | self[7] as u16,
-        ]
-    }
+/// Reads a big-endian `u16` from the first two bytes of `data`
+pub(crate) fn u16_from_be(data: &[u8]) -> u16 {
+    u16::from_be_bytes([data[0], data[1]])
+}
 
-    fn to_u16_little_endian(self) -> [u16; 4] {
-        [
-            ((self[1] as u16) << 8) | self[0] as u16,
-            ((self[3] as u16) << 8) | self[2] as u16,
-            ((self[5] as u16) << 8) | self[4] as u16,
-            ((self[7] as u16) << 8) | self[6] as u16,
-        ]
-    }
+/// Reads a little-endian two's complement `i16` from the first two bytes of
+/// `data`
+pub(crate) fn i16_from_le(data: &[u8]) -> i16 {
+    i16::from_le_bytes([data[0], data[1]])
+}
+
+/// Reads a little-endian `u32` from the first four bytes of `data`
+pub(crate) fn u32_from_le(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+/// Writes `value` little-endian into the first four bytes of `buf`
+pub(crate) fn put_u32_le(buf: &mut [u8], value: u32) {
+    buf[..4].copy_from_slice(&value.to_le_bytes());
 }
 
-pub(crate) fn make_u16(upper: u8, lower: u8) -> u16 {
-    lower as u16 | (upper as u16).shl(8)
+/// Writes `value` little-endian into the first two bytes of `buf`
+pub(crate) fn put_u16_le(buf: &mut [u8], value: u16) {
+    buf[..2].copy_from_slice(&value.to_le_bytes());
 }
 
-pub(crate) fn make_i16(upper: u8, lower: u8) -> i16 {
-    make_u16(upper, lower) as i16
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_take_the_named_byte_order() {
+        let data = [0x34, 0x12, 0xff];
+        assert_eq!(u16_from_le(&data), 0x1234);
+        assert_eq!(u16_from_be(&data), 0x3412);
+    }
+
+    #[test]
+    fn signed_reads_keep_the_sign() {
+        assert_eq!(i16_from_le(&[0xfe, 0xff]), -2);
+        assert_eq!(i16_from_le(&[0x00, 0x80]), i16::MIN);
+    }
+
+    #[test]
+    fn writes_leave_the_rest_of_the_buffer_alone() {
+        let mut buf = [0xaa; 3];
+        put_u16_le(&mut buf, 0x1234);
+        assert_eq!(buf, [0x34, 0x12, 0xaa]);
+    }
+
+    #[test]
+    fn writes_round_trip_through_reads() {
+        for value in [0, 1, 0x00ff, 0xff00, 0x8000, 0xffff] {
+            let mut buf = [0; 2];
+            put_u16_le(&mut buf, value);
+            assert_eq!(u16_from_le(&buf), value);
+        }
+    }
 }
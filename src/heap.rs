@@ -0,0 +1,152 @@
+use core::alloc::{GlobalAlloc, Layout};
+
+use defmt::error;
+
+/// Allocators that can tell how much of them is taken, for the out of memory
+/// log
+pub(crate) trait HeapUsage {
+    fn used(&self) -> usize;
+}
+
+impl HeapUsage for embedded_alloc::Heap {
+    fn used(&self) -> usize {
+        embedded_alloc::Heap::used(self)
+    }
+}
+
+/// Heap that resets the chip when it runs out instead of halting in the
+/// allocation error panic, so a unit with too many devices attached comes
+/// back by itself.
+pub(crate) struct ResettingHeap<A> {
+    pub(crate) heap: A,
+    reset: fn() -> !,
+}
+
+impl<A> ResettingHeap<A> {
+    pub(crate) const fn new(heap: A, reset: fn() -> !) -> Self {
+        Self { heap, reset }
+    }
+}
+
+impl<A: GlobalAlloc> ResettingHeap<A> {
+    /// Allocates `probe_bytes` from the heap directly, bypassing the reset on
+    /// failure, to tell a misconfigured heap region apart before anything
+    /// depends on it.
+    pub(crate) fn usable(&self, probe_bytes: usize) -> bool {
+        let layout = match Layout::from_size_align(probe_bytes, 8) {
+            Ok(layout) => layout,
+            Err(_) => return false,
+        };
+        unsafe {
+            let ptr = self.heap.alloc(layout);
+            if ptr.is_null() {
+                return false;
+            }
+            self.heap.dealloc(ptr, layout);
+        }
+        true
+    }
+}
+
+unsafe impl<A: GlobalAlloc + HeapUsage> GlobalAlloc for ResettingHeap<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.heap.alloc(layout);
+        if ptr.is_null() {
+            error!(
+                "Out of memory allocating {} bytes, {} in use, resetting",
+                layout.size(),
+                self.heap.used()
+            );
+            (self.reset)();
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.heap.dealloc(ptr, layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out a single fixed block, then runs out
+    struct OneBlock {
+        block: core::cell::UnsafeCell<[u64; 4]>,
+        taken: core::cell::Cell<bool>,
+    }
+
+    unsafe impl Sync for OneBlock {}
+
+    unsafe impl GlobalAlloc for OneBlock {
+        unsafe fn alloc(&self, _layout: Layout) -> *mut u8 {
+            if self.taken.replace(true) {
+                core::ptr::null_mut()
+            } else {
+                self.block.get() as *mut u8
+            }
+        }
+
+        unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+            self.taken.set(false);
+        }
+    }
+
+    impl HeapUsage for OneBlock {
+        fn used(&self) -> usize {
+            self.taken.get() as usize * 32
+        }
+    }
+
+    fn reset() -> ! {
+        panic!("reset")
+    }
+
+    fn heap() -> ResettingHeap<OneBlock> {
+        ResettingHeap::new(
+            OneBlock {
+                block: core::cell::UnsafeCell::new([0; 4]),
+                taken: core::cell::Cell::new(false),
+            },
+            reset,
+        )
+    }
+
+    const LAYOUT: Layout = Layout::new::<[u64; 4]>();
+
+    #[test]
+    fn allocations_that_fit_are_passed_through() {
+        let heap = heap();
+        unsafe {
+            let ptr = heap.alloc(LAYOUT);
+            assert!(!ptr.is_null());
+            heap.dealloc(ptr, LAYOUT);
+            assert!(!heap.alloc(LAYOUT).is_null());
+        }
+    }
+
+    #[test]
+    fn probing_a_free_heap_leaves_it_free() {
+        let heap = heap();
+        assert!(heap.usable(32));
+        assert!(!heap.heap.taken.get());
+    }
+
+    #[test]
+    fn probing_an_exhausted_heap_fails_without_resetting() {
+        let heap = heap();
+        unsafe { heap.alloc(LAYOUT) };
+        assert!(!heap.usable(32));
+    }
+
+    #[test]
+    #[should_panic(expected = "reset")]
+    fn running_out_resets() {
+        let heap = heap();
+        unsafe {
+            heap.alloc(LAYOUT);
+            heap.alloc(LAYOUT);
+        }
+    }
+}
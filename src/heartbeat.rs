@@ -0,0 +1,104 @@
+use fugit::MicrosDurationU64;
+use rp2040_hal::timer::Instant;
+
+/// Returns true once the link has been quiet for at least `interval`.
+pub(crate) fn heartbeat_due(
+    quiet_for: MicrosDurationU64,
+    interval: Option<MicrosDurationU64>,
+) -> bool {
+    match interval {
+        Some(interval) => quiet_for >= interval,
+        None => false,
+    }
+}
+
+/// Emits a keepalive when nothing else went upstream for a while, so the host
+/// can tell an idle device from a hung one.
+pub(crate) struct Heartbeat {
+    /// Quiet time before a heartbeat is sent, `None` disables it
+    interval: Option<MicrosDurationU64>,
+    last_traffic: Option<Instant>,
+}
+
+impl Heartbeat {
+    pub(crate) fn new(interval: Option<MicrosDurationU64>) -> Self {
+        Self {
+            interval,
+            last_traffic: None,
+        }
+    }
+
+    pub(crate) fn set_interval(&mut self, interval: Option<MicrosDurationU64>) {
+        self.interval = interval;
+    }
+
+    /// Records that an event went upstream at `now`.
+    pub(crate) fn record_traffic(&mut self, now: Instant) {
+        self.last_traffic = Some(now);
+    }
+
+    /// Returns true if a heartbeat should be sent at `now`. Sending one
+    /// counts as traffic, so the next is due one interval later.
+    pub(crate) fn poll(&mut self, now: Instant) -> bool {
+        let last = *self.last_traffic.get_or_insert(now);
+        let quiet_for = now
+            .checked_duration_since(last)
+            .unwrap_or(MicrosDurationU64::from_ticks(0));
+        if heartbeat_due(quiet_for, self.interval) {
+            self.last_traffic = Some(now);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at_ms(ms: u64) -> Instant {
+        Instant::from_ticks(ms * 1000)
+    }
+
+    #[test]
+    fn due_only_after_a_full_interval() {
+        let interval = Some(MicrosDurationU64::millis(100));
+        assert!(!heartbeat_due(MicrosDurationU64::millis(99), interval));
+        assert!(heartbeat_due(MicrosDurationU64::millis(100), interval));
+        assert!(!heartbeat_due(MicrosDurationU64::secs(60), None));
+    }
+
+    #[test]
+    fn first_poll_starts_the_quiet_period() {
+        let mut heartbeat = Heartbeat::new(Some(MicrosDurationU64::millis(100)));
+        assert!(!heartbeat.poll(at_ms(5000)));
+        assert!(!heartbeat.poll(at_ms(5099)));
+        assert!(heartbeat.poll(at_ms(5100)));
+    }
+
+    #[test]
+    fn heartbeats_repeat_while_quiet() {
+        let mut heartbeat = Heartbeat::new(Some(MicrosDurationU64::millis(100)));
+        heartbeat.record_traffic(at_ms(0));
+        let sent: usize = (1..=350).filter(|&ms| heartbeat.poll(at_ms(ms))).count();
+        assert_eq!(sent, 3);
+    }
+
+    #[test]
+    fn traffic_postpones_the_heartbeat() {
+        let mut heartbeat = Heartbeat::new(Some(MicrosDurationU64::millis(100)));
+        heartbeat.record_traffic(at_ms(0));
+        heartbeat.record_traffic(at_ms(80));
+        assert!(!heartbeat.poll(at_ms(150)));
+        assert!(heartbeat.poll(at_ms(180)));
+    }
+
+    #[test]
+    fn disabled_heartbeat_stays_silent() {
+        let mut heartbeat = Heartbeat::new(Some(MicrosDurationU64::millis(100)));
+        heartbeat.set_interval(None);
+        heartbeat.record_traffic(at_ms(0));
+        assert!(!heartbeat.poll(at_ms(10_000)));
+    }
+}
@@ -0,0 +1,105 @@
+use defmt::{info, Format};
+use fugit::MicrosDurationU64;
+use rp2040_hal::timer::Instant;
+
+#[derive(PartialEq, Clone, Copy, Format)]
+pub(crate) enum PowerState {
+    Active,
+    Idle,
+}
+
+/// Decides the power state after a polling round. Any input wakes the device,
+/// otherwise it goes idle once nothing happened for `timeout`.
+pub(crate) fn next_power_state(
+    idle_for: MicrosDurationU64,
+    timeout: Option<MicrosDurationU64>,
+    activity: bool,
+) -> PowerState {
+    match timeout {
+        Some(timeout) if !activity && idle_for >= timeout => PowerState::Idle,
+        _ => PowerState::Active,
+    }
+}
+
+/// Tracks input activity to drop into a low-power idle state after a period
+/// without input events.
+pub(crate) struct IdleTracker {
+    /// Time without input before going idle, `None` never goes idle
+    timeout: Option<MicrosDurationU64>,
+    last_activity: Option<Instant>,
+    state: PowerState,
+}
+
+impl IdleTracker {
+    pub(crate) fn new(timeout: Option<MicrosDurationU64>) -> Self {
+        Self {
+            timeout,
+            last_activity: None,
+            state: PowerState::Active,
+        }
+    }
+
+    pub(crate) fn set_timeout(&mut self, timeout: Option<MicrosDurationU64>) {
+        self.timeout = timeout;
+    }
+
+    /// Records the outcome of a polling round at `now` and returns the new
+    /// power state.
+    pub(crate) fn update(&mut self, now: Instant, activity: bool) -> PowerState {
+        if activity || self.last_activity.is_none() {
+            self.last_activity = Some(now);
+        }
+        let idle_for = self
+            .last_activity
+            .and_then(|last| now.checked_duration_since(last))
+            .unwrap_or(MicrosDurationU64::from_ticks(0));
+        let state = next_power_state(idle_for, self.timeout, activity);
+        if state != self.state {
+            info!("Power state {} -> {}", self.state, state);
+            self.state = state;
+        }
+        state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(s: u64) -> MicrosDurationU64 {
+        MicrosDurationU64::secs(s)
+    }
+
+    fn at(s: u64) -> Instant {
+        Instant::from_ticks(s * 1_000_000)
+    }
+
+    #[test]
+    fn goes_idle_once_the_timeout_passes_without_input() {
+        assert!(next_power_state(secs(9), Some(secs(10)), false) == PowerState::Active);
+        assert!(next_power_state(secs(10), Some(secs(10)), false) == PowerState::Idle);
+        assert!(next_power_state(secs(10), Some(secs(10)), true) == PowerState::Active);
+        assert!(next_power_state(secs(1000), None, false) == PowerState::Active);
+    }
+
+    #[test]
+    fn tracker_enters_idle_and_wakes_on_input() {
+        let mut idle = IdleTracker::new(Some(secs(10)));
+        assert!(idle.update(at(0), false) == PowerState::Active);
+        assert!(idle.update(at(9), false) == PowerState::Active);
+        assert!(idle.update(at(10), false) == PowerState::Idle);
+        assert!(idle.update(at(11), true) == PowerState::Active);
+        // The timeout restarts from the last input
+        assert!(idle.update(at(20), false) == PowerState::Active);
+        assert!(idle.update(at(21), false) == PowerState::Idle);
+    }
+
+    #[test]
+    fn clearing_the_timeout_wakes_the_tracker() {
+        let mut idle = IdleTracker::new(Some(secs(1)));
+        idle.update(at(0), false);
+        assert!(idle.update(at(5), false) == PowerState::Idle);
+        idle.set_timeout(None);
+        assert!(idle.update(at(6), false) == PowerState::Active);
+    }
+}
@@ -0,0 +1,44 @@
+//! Host build of the firmware modules, so their pure logic can be unit tested
+//! with `cargo test --lib --target x86_64-unknown-linux-gnu`. The firmware is
+//! the `main.rs` binary, which declares the same modules. Outside of tests
+//! this crate is empty.
+#![cfg_attr(not(test), no_std)]
+#![cfg(test)]
+// Only what the tests reach is used here, the binary catches dead code
+#![allow(dead_code)]
+
+extern crate alloc;
+
+mod app;
+mod config;
+mod downstream;
+mod heap;
+mod heartbeat;
+mod idle;
+mod negicon_event;
+#[cfg(feature = "status-led")]
+mod status_led;
+mod throttle;
+mod timestamp;
+mod upstream;
+
+/// defmt sinks for the host. The firmware logs over RTT, tests drop the
+/// output.
+mod host_log {
+    #[defmt::global_logger]
+    struct Discard;
+
+    unsafe impl defmt::Logger for Discard {
+        fn acquire() {}
+        unsafe fn flush() {}
+        unsafe fn release() {}
+        unsafe fn write(_bytes: &[u8]) {}
+    }
+
+    defmt::timestamp!("");
+
+    #[defmt::panic_handler]
+    fn panic() -> ! {
+        panic!("defmt panic")
+    }
+}
@@ -4,13 +4,20 @@
 #![no_std]
 #![no_main]
 extern crate alloc;
-use defmt::{debug, info, warn};
+use defmt::{error, info, warn};
 use defmt_rtt as _;
 
+#[cfg(feature = "spi-upstream")]
+use core::convert::Infallible;
 use embedded_alloc::Heap;
-use embedded_hal::{digital::v2::PinState, spi::MODE_1, timer::CountDown};
-use fugit::{ExtU32, RateExtU32};
+#[cfg(feature = "spi-upstream")]
+use embedded_hal::{digital::v2::InputPin, spi::MODE_1};
+use embedded_hal::{digital::v2::PinState, timer::CountDown};
+#[cfg(feature = "usb-upstream")]
+use fugit::ExtU32;
+use fugit::{HertzU32, MicrosDurationU64, RateExtU32};
 use panic_probe as _;
+#[cfg(feature = "usb-upstream")]
 use usb_device::{
     class_prelude::UsbBusAllocator,
     prelude::{UsbDeviceBuilder, UsbVidPid},
@@ -19,62 +26,169 @@ use usb_device::{
 // Uncomment the BSP you included in Cargo.toml, the rest of the code does not need to change.
 use rp2040_hal as hal;
 // use sparkfun_pro_micro_rp2040 as bsp;
+#[cfg(feature = "spi-upstream")]
+use hal::spi::FrameFormat;
+#[cfg(feature = "usb-upstream")]
+use hal::usb::UsbBus;
 use hal::{
     clocks::init_clocks_and_plls,
     clocks::Clock,
     entry,
-    gpio::{FunctionSpi, Pins},
+    gpio::{DynPinId, FunctionSioOutput, FunctionSpi, Pin, Pins, PullDown},
     pac,
     rom_data::reset_to_usb_boot,
-    spi::FrameFormat,
-    usb::UsbBus,
+    timer::Instant,
     watchdog::Watchdog,
     Sio, Timer,
 };
 
+#[cfg(feature = "usb-upstream")]
 use usbd_human_interface_device::{
-    interface::{InBytes8, InterfaceBuilder, OutBytes8, ReportSingle},
+    interface::{InterfaceBuilder, ReportSingle},
     usb_class::UsbHidClassBuilder,
 };
 
+// Log lines carry the time since boot in µs
+defmt::timestamp!("{=u64:us}", {
+    // Safety: read-only access to the free-running timer counter, which the
+    // HAL `Timer` never writes to
+    let timer = unsafe { &*pac::TIMER::ptr() };
+    timestamp::read_split_counter(
+        || timer.timerawh.read().bits(),
+        || timer.timerawl.read().bits(),
+    )
+});
+
+pub mod app;
+mod config;
 pub mod downstream;
+mod heap;
+mod heartbeat;
+mod idle;
 pub mod negicon_event;
+#[cfg(feature = "status-led")]
+mod status_led;
+mod throttle;
+mod timestamp;
 pub mod upstream;
 
+#[cfg(feature = "status-led")]
+use crate::status_led::{LinkState, StatusLed};
+#[cfg(feature = "log-upstream")]
+use crate::upstream::log::LogUpstream;
+#[cfg(feature = "spi-upstream")]
+use crate::upstream::spi::SPIUpstream;
+#[cfg(feature = "usb-upstream")]
+use crate::upstream::upstream::{ReportIn, ReportOut, UsbUpstream, USB_HID_DESCRIPTOR};
 use crate::{
-    downstream::spi_downstream::SpiDownstream,
-    upstream::{
-        spi::SPIUpstream,
-        upstream::{Upstream, UsbUpstream},
+    app::{apply_downstream_settings, tick, Board, LoopState},
+    config::Config,
+    downstream::{
+        spi_downstream::downstream_slots,
+        spi_protocol::{
+            validate_spi_freq, NegiconProtocol, TimedSpi, DOWNSTREAM_SPI_FREQ_HZ,
+            DOWNSTREAM_SPI_MODE,
+        },
     },
+    heap::ResettingHeap,
+    negicon_event::RebootKind,
+    upstream::upstream::links,
 };
+#[cfg(feature = "status-led")]
+use hal::pio::PIOExt;
+
+#[cfg(not(any(
+    feature = "usb-upstream",
+    feature = "spi-upstream",
+    feature = "log-upstream"
+)))]
+compile_error!("enable at least one of the usb-upstream, spi-upstream and log-upstream features");
+
+/// Smallest allocation the heap has to serve at boot for downstream devices to
+/// be kept, comfortably above the size of any device state
+const HEAP_PROBE_BYTES: usize = 1024;
 
 #[global_allocator]
-static HEAP: Heap = Heap::empty();
-
-const USB_HID_DESCRIPTOR: [u8; 38] = [
-    0x05, 0x01, // USAGE_PAGE (Generic Desktop)
-    0x09, 0x00, // USAGE (Undefined)
-    0xa1, 0x01, // COLLECTION (Application)
-    0x09, 0x01, //   USAGE (Pointer)
-    0xa1, 0x00, //   COLLECTION (Physical)
-    // Input report
-    0x09, 0x02, //     USAGE (Undefined)
-    0x15, 0x00, //     LOGICAL_MINIMUM (0)
-    0x26, 0xFF, 0x00, //     LOGICAL_MAXIMUM (255)
-    0x75, 0x08, //     REPORT_SIZE (8) - 8 bits
-    0x95, 0x08, //     REPORT_COUNT (8) - 8 fields
-    0x81, 0x02, //     INPUT (Data,Var,Abs)
-    // Output report
-    0x09, 0x03, //     USAGE (Undefined)
-    0x15, 0x00, //     LOGICAL_MINIMUM (0)
-    0x26, 0xFF, 0x00, //     LOGICAL_MAXIMUM (255)
-    0x75, 0x08, //     REPORT_SIZE (8)
-    0x95, 0x08, //     REPORT_COUNT (8)
-    0x91, 0x02, //     OUTPUT (Data,Var,Abs)
-    0xc0, //   END_COLLECTION
-    0xc0, // END_COLLECTION
-];
+static HEAP: ResettingHeap<Heap> =
+    ResettingHeap::new(Heap::empty(), cortex_m::peripheral::SCB::sys_reset);
+
+/// Number of populated downstream slots. Must match the CS pins listed in
+/// `main`, which the compiler checks through the array type.
+const NUM_DOWNSTREAMS: usize = 21;
+// `Config::disabled_slots` has a bit per slot
+const _: () = assert!(NUM_DOWNSTREAMS <= 32);
+
+/// One push-pull CS output per listed gpio, idle high, in slot order. Each
+/// pin is moved out of `pins`, so listing one twice doesn't compile.
+macro_rules! cs_pins {
+    ($pins:ident, $($gpio:ident),* $(,)?) => {{
+        let cs: [Pin<DynPinId, FunctionSioOutput, PullDown>; NUM_DOWNSTREAMS] = [$(
+            $pins
+                .$gpio
+                .into_push_pull_output_in_state(PinState::High)
+                .into_dyn_pin()
+        ),*];
+        cs
+    }};
+}
+
+/// The relay master's CS on gpio13, read from the pad while the pin stays
+/// muxed to SPI1
+#[cfg(feature = "spi-upstream")]
+struct RelaySelect;
+
+#[cfg(feature = "spi-upstream")]
+impl InputPin for RelaySelect {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Infallible> {
+        // Safety: read-only access to the input levels, which SIO samples
+        // whatever function drives the pin
+        let sio = unsafe { &*pac::SIO::ptr() };
+        Ok(sio.gpio_in.read().bits() & (1 << 13) != 0)
+    }
+
+    fn is_low(&self) -> Result<bool, Infallible> {
+        self.is_high().map(|high| !high)
+    }
+}
+
+/// `Board` on the RP2040
+struct Pico<'a> {
+    timer: &'a Timer,
+    tick_timer: hal::timer::CountDown<'a>,
+    peripheral_freq: HertzU32,
+}
+
+impl Board for Pico<'_> {
+    fn now(&self) -> Instant {
+        self.timer.get_counter()
+    }
+
+    fn poll_due(&mut self) -> bool {
+        self.tick_timer.wait().is_ok()
+    }
+
+    fn schedule_poll(&mut self, interval: MicrosDurationU64) {
+        self.tick_timer.start(interval);
+    }
+
+    fn peripheral_freq(&self) -> HertzU32 {
+        self.peripheral_freq
+    }
+
+    fn reboot(&mut self, kind: RebootKind) {
+        match kind {
+            RebootKind::UsbBoot => reset_to_usb_boot(0, 0),
+            RebootKind::Restart => cortex_m::peripheral::SCB::sys_reset(),
+        }
+    }
+
+    fn store_config(&mut self, config: &Config) {
+        config.store();
+    }
+}
+
 #[link_section = ".boot2"]
 #[used]
 pub static BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_W25Q080;
@@ -85,8 +199,9 @@ fn main() -> ! {
         use core::mem::MaybeUninit;
         const HEAP_SIZE: usize = 1024 * 64;
         static mut HEAP_MEM: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
-        unsafe { HEAP.init(HEAP_MEM.as_ptr() as usize, HEAP_SIZE) }
+        unsafe { HEAP.heap.init(HEAP_MEM.as_ptr() as usize, HEAP_SIZE) }
     }
+    let heap_usable = HEAP.usable(HEAP_PROBE_BYTES);
     let mut pac = pac::Peripherals::take().unwrap();
     let _core = pac::CorePeripherals::take().unwrap();
     let mut watchdog = Watchdog::new(pac.WATCHDOG);
@@ -116,6 +231,7 @@ fn main() -> ! {
 
     let timer = Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
 
+    #[cfg(feature = "usb-upstream")]
     let usb_bus = UsbBusAllocator::new(UsbBus::new(
         pac.USBCTRL_REGS,
         pac.USBCTRL_DPRAM,
@@ -124,9 +240,10 @@ fn main() -> ! {
         &mut pac.RESETS,
     ));
 
+    #[cfg(feature = "usb-upstream")]
     let hid = UsbHidClassBuilder::new()
         .add_device(
-            InterfaceBuilder::<InBytes8, OutBytes8, ReportSingle>::new(&USB_HID_DESCRIPTOR)
+            InterfaceBuilder::<ReportIn, ReportOut, ReportSingle>::new(&USB_HID_DESCRIPTOR)
                 .unwrap()
                 .description("Negicon v3")
                 .idle_default(500.millis())
@@ -139,130 +256,132 @@ fn main() -> ! {
         )
         .build(&usb_bus);
 
-    let mut tick_timer = timer.count_down();
-    tick_timer.start(1000.millis());
+    #[cfg(feature = "usb-upstream")]
+    let mut usb_upstream = {
+        let usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1209, 0x3939))
+            .manufacturer("LeekLabs International")
+            .product("Negicon v3")
+            .serial_number("3939")
+            .build();
+        UsbUpstream::new(hid, usb_dev)
+    };
 
-    let usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1209, 0x3939))
-        .manufacturer("LeekLabs International")
-        .product("Negicon v3")
-        .serial_number("3939")
-        .build();
-    let mut usb_upstream = UsbUpstream::new(hid, usb_dev);
-    let _i = 0u8;
+    #[cfg(feature = "spi-upstream")]
+    let mut spi_upstream = {
+        let spi_sclk = pins.gpio10.into_function::<FunctionSpi>();
+        let spi_mosi = pins.gpio11.into_function::<FunctionSpi>();
+        let spi_miso = pins.gpio12.into_function::<FunctionSpi>();
+        // The HAL only takes the data and clock pins, CSn is muxed to SPI1 by
+        // hand so the master's select frames our bytes. `RelaySelect` reads
+        // it to resync after a transfer cut short.
+        let _spi_cs = pins.gpio13.into_function::<FunctionSpi>();
+        let spi1 = hal::Spi::<_, _, _, 8>::new(pac.SPI1, (spi_mosi, spi_miso, spi_sclk))
+            .init_slave(&mut pac.RESETS, FrameFormat::MotorolaSpi(MODE_1));
+        let mut relay = SPIUpstream::new(spi1, RelaySelect);
+        relay.set_wait_for_master(cfg!(feature = "relay-wait-for-master"));
+        relay
+    };
 
-    let _spi_sclk = pins.gpio10.into_function::<FunctionSpi>();
-    let _spi_mosi = pins.gpio11.into_function::<FunctionSpi>();
-    let _spi_miso = pins.gpio12.into_function::<FunctionSpi>();
-    let mut _spi_cs = pins.gpio13.into_push_pull_output_in_state(PinState::High);
+    #[cfg(feature = "log-upstream")]
+    let mut log_upstream = LogUpstream::new();
 
-    let spi1 = hal::Spi::new(pac.SPI1, (_spi_mosi, _spi_miso, _spi_sclk))
-        .init_slave(&mut pac.RESETS, FrameFormat::MotorolaSpi(MODE_1));
+    let config = Config::load();
+    let mut tick_timer = timer.count_down();
+    tick_timer.start(config.poll_interval());
+    let spi_freq = validate_spi_freq(
+        config.spi_clock_khz as u32 * 1000,
+        clocks.peripheral_clock.freq().to_Hz(),
+    )
+    .unwrap_or(DOWNSTREAM_SPI_FREQ_HZ);
+
+    #[cfg(feature = "status-led")]
+    let mut status_led = {
+        let (mut pio, sm0, _, _, _) = pac.PIO0.split(&mut pac.RESETS);
+        StatusLed::new(ws2812_pio::Ws2812Direct::new(
+            pins.gpio28.into_function(),
+            &mut pio,
+            sm0,
+            clocks.peripheral_clock.freq(),
+        ))
+    };
 
     let _spi0_sclk = pins.gpio18.into_function::<FunctionSpi>();
     let _spi0_mosi = pins.gpio19.into_function::<FunctionSpi>();
     let _spi0_miso = pins.gpio20.into_function::<FunctionSpi>();
-    let mut spi0 = hal::Spi::new(pac.SPI0, (_spi0_mosi, _spi0_miso, _spi0_sclk)).init(
+    let spi0 = hal::Spi::<_, _, _, 8>::new(pac.SPI0, (_spi0_mosi, _spi0_miso, _spi0_sclk)).init(
         &mut pac.RESETS,
         clocks.peripheral_clock.freq(),
-        2_500_000u32.Hz(),
-        &embedded_hal::spi::MODE_1,
+        spi_freq.Hz(),
+        &DOWNSTREAM_SPI_MODE,
     );
+    let mut spi0 = TimedSpi::new(spi0, timer);
+    spi0.set_cs_settle(config.cs_settle());
 
-    let _spi_upstream = SPIUpstream::new(spi1);
-
-    let mut cs0 = pins.gpio0.into_push_pull_output_in_state(PinState::High);
-    let mut cs1 = pins.gpio1.into_push_pull_output_in_state(PinState::High);
-    let mut cs2 = pins.gpio2.into_push_pull_output_in_state(PinState::High);
-    let mut cs3 = pins.gpio3.into_push_pull_output_in_state(PinState::High);
-    let mut cs4 = pins.gpio4.into_push_pull_output_in_state(PinState::High);
-    let mut cs5 = pins.gpio5.into_push_pull_output_in_state(PinState::High);
-    let mut cs6 = pins.gpio6.into_push_pull_output_in_state(PinState::High);
-    let mut cs7 = pins.gpio7.into_push_pull_output_in_state(PinState::High);
-    let mut cs8 = pins.gpio8.into_push_pull_output_in_state(PinState::High);
-    let mut cs9 = pins.gpio9.into_push_pull_output_in_state(PinState::High);
-    let mut cs10 = pins.gpio14.into_push_pull_output_in_state(PinState::High);
-    let mut cs11 = pins.gpio15.into_push_pull_output_in_state(PinState::High);
-    let mut cs12 = pins.gpio16.into_push_pull_output_in_state(PinState::High);
-    let mut cs13 = pins.gpio17.into_push_pull_output_in_state(PinState::High);
-    let mut cs14 = pins.gpio21.into_push_pull_output_in_state(PinState::High);
-    let mut cs15 = pins.gpio22.into_push_pull_output_in_state(PinState::High);
-    let mut cs16 = pins.gpio23.into_push_pull_output_in_state(PinState::High);
-    let mut cs17 = pins.gpio24.into_push_pull_output_in_state(PinState::High);
-    let mut cs18 = pins.gpio25.into_push_pull_output_in_state(PinState::High);
-    let mut cs19 = pins.gpio26.into_push_pull_output_in_state(PinState::High);
-    let mut cs20 = pins.gpio27.into_push_pull_output_in_state(PinState::High);
-
-    let mut downstreams = [
-        SpiDownstream::new(&mut cs0),
-        SpiDownstream::new(&mut cs1),
-        SpiDownstream::new(&mut cs2),
-        SpiDownstream::new(&mut cs3),
-        SpiDownstream::new(&mut cs4),
-        SpiDownstream::new(&mut cs5),
-        SpiDownstream::new(&mut cs6),
-        SpiDownstream::new(&mut cs7),
-        SpiDownstream::new(&mut cs8),
-        SpiDownstream::new(&mut cs9),
-        SpiDownstream::new(&mut cs10),
-        SpiDownstream::new(&mut cs11),
-        SpiDownstream::new(&mut cs12),
-        SpiDownstream::new(&mut cs13),
-        SpiDownstream::new(&mut cs14),
-        SpiDownstream::new(&mut cs15),
-        SpiDownstream::new(&mut cs16),
-        SpiDownstream::new(&mut cs17),
-        SpiDownstream::new(&mut cs18),
-        SpiDownstream::new(&mut cs19),
-        SpiDownstream::new(&mut cs20),
-    ];
-
-    let mut upstreams = [Upstream::new(&mut usb_upstream)];
-    loop {
-        for up in upstreams.iter_mut() {
-            match up.receive() {
-                Ok(Some(event)) => {
-                    debug!("Received event from upstream {:?}", event.event_type);
-                    match event.event_type {
-                        negicon_event::NegiconEventType::Input => todo!(),
-                        negicon_event::NegiconEventType::Output => todo!(),
-                        negicon_event::NegiconEventType::MemWrite => todo!(),
-                        negicon_event::NegiconEventType::Reboot => reset_to_usb_boot(0, 0),
-                    }
-                }
-                Ok(None) => {}
-                Err(e) => {
-                    warn!("Error while polling: {:?}", e);
-                }
-            }
+    let mut cs = cs_pins!(
+        pins, gpio0, gpio1, gpio2, gpio3, gpio4, gpio5, gpio6, gpio7, gpio8, gpio9, gpio14, gpio15,
+        gpio16, gpio17, gpio21, gpio22, gpio23, gpio24, gpio25, gpio26, gpio27,
+    );
+    let mut downstreams = downstream_slots(&mut cs);
+    apply_downstream_settings(&mut downstreams, &config);
+    for (slot, ds) in downstreams.iter_mut().enumerate() {
+        ds.set_enabled(config.slot_enabled(slot));
+        ds.set_fallback_id(slot as u16);
+        if !heap_usable {
+            ds.disable_device_state();
         }
+    }
+    if !heap_usable {
+        error!("Heap unusable, downstreams are detected but not polled");
+    }
 
-        match tick_timer.wait() {
-            Ok(_) => {
-                tick_timer.start(5.millis());
-                for ds in downstreams.iter_mut() {
-                    match ds.poll(&mut delay, &mut spi0) {
-                        Ok(res) => {
-                            res.map(|event| {
-                                for up in upstreams.iter_mut() {
-                                    match up.enqueue(event) {
-                                        Ok(_) => {}
-                                        Err(e) => {
-                                            warn!(
-                                                "Error while enqueueing event for upstream: {:?}",
-                                                e
-                                            );
-                                        }
-                                    }
-                                }
-                            });
-                        }
-                        Err(_e) => {
-                            debug!("Error while polling downstream: {:?}", _e);
-                        }
-                    };
-                }
-            }
-            Err(_) => {}
+    let mut board = Pico {
+        timer: &timer,
+        tick_timer,
+        peripheral_freq: clocks.peripheral_clock.freq(),
+    };
+    let mut upstreams = links(
+        #[cfg(feature = "usb-upstream")]
+        &mut usb_upstream,
+        #[cfg(feature = "spi-upstream")]
+        &mut spi_upstream,
+        #[cfg(feature = "log-upstream")]
+        &mut log_upstream,
+    );
+    let mut state = LoopState::new(config);
+    state.hold_off_polling(timer.get_counter());
+    loop {
+        tick(
+            &mut state,
+            &mut downstreams,
+            &mut spi0,
+            &mut upstreams,
+            &mut delay,
+            &mut board,
+        );
+        // A failing bus is the one error visible without a host
+        #[cfg(feature = "status-led")]
+        status_led.update(
+            timer.get_counter(),
+            LinkState::of(&upstreams),
+            spi0.failed_transfers() > 0,
+        );
+        if state.take_spi_stuck() {
+            // Devices that dropped out meanwhile are re-detected once the bus
+            // works again
+            warn!("Downstream SPI failing on every slot, resetting the peripheral");
+            let spi_freq = validate_spi_freq(
+                state.config.spi_clock_khz as u32 * 1000,
+                clocks.peripheral_clock.freq().to_Hz(),
+            )
+            .unwrap_or(DOWNSTREAM_SPI_FREQ_HZ);
+            spi0 = spi0.reinit(|spi| {
+                spi.disable().init(
+                    &mut pac.RESETS,
+                    clocks.peripheral_clock.freq(),
+                    spi_freq.Hz(),
+                    DOWNSTREAM_SPI_MODE,
+                )
+            });
         }
     }
 }
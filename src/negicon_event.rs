@@ -1,22 +1,334 @@
 use defmt::Format;
 
-use crate::downstream::util::{make_i16, make_u16};
-use core::ops::Shr;
-#[derive(Clone, Copy)]
+/// Size of a report exchanged with the host or relayed upstream over SPI. The
+/// HID descriptor and the report types used by the USB interface follow it.
+/// USB supports 8, 16, 32 or 64 bytes.
+pub(crate) const REPORT_SIZE: usize = 8;
+pub(crate) type Report = [u8; REPORT_SIZE];
+
+/// Bytes `NegiconEvent::serialize` fills at most: type, id, value, controller
+/// id and sequence. `serialize` lists every field of the event in an array of this
+/// size, so adding a field doesn't build until this grows, and the assertion
+/// below then fails the build instead of the report silently losing it.
+pub(crate) const SERIALIZED_SIZE: usize = 7;
+const _: () = assert!(
+    SERIALIZED_SIZE <= REPORT_SIZE,
+    "NegiconEvent doesn't fit into a report"
+);
+
+#[derive(PartialEq, Clone, Copy, Format, Debug)]
 pub(crate) struct NegiconEvent {
-    pub(crate) event_type: NegiconEventType,
-    pub(crate) id: u16,
-    pub(crate) value: i16,
-    pub(crate) controller_id: u8,
-    pub(crate) sequence: u8,
+    event_type: NegiconEventType,
+    id: u16,
+    value: i16,
+    controller_id: u8,
+    sequence: u8,
 }
 
-#[derive(PartialEq, Clone, Copy, Format)]
+#[derive(PartialEq, Clone, Copy, Format, Debug)]
 pub(crate) enum NegiconEventType {
     Input,
     Output,
     MemWrite,
     Reboot,
+    Config,
+    /// Downstream failure report. The id carries the slot, the value the
+    /// error code.
+    Error,
+    /// Stages an EEPROM cell that is written together with the next
+    /// `MemWrite` to the same device.
+    MemWriteBatch,
+    /// Diagnostic request from the host. The id selects a `QueryKey`, the
+    /// answer is sent back as a `Query` event with the same id.
+    Query,
+    /// Keepalive sent when the link has been quiet for the heartbeat
+    /// interval. The id carries the number of detected downstreams, the value
+    /// the uptime in seconds, wrapping at 16 bits.
+    Heartbeat,
+    /// Unprocessed sensor reading for calibration tools, sent next to the
+    /// `Input` events while `ConfigKey::RawAlpha` is set. See `raw_alpha` for
+    /// the packing, which changed when the controller id started carrying the
+    /// board id: the field strength moved from the controller id to the
+    /// sequence, the diagnostic status from the sequence into the value.
+    RawAlpha,
+    /// A detected downstream, sent in answer to `QueryKey::Enumerate`. The id
+    /// carries the logical id, or 0xFFFF if it hasn't been read yet, the value
+    /// the slot and the sequence the NOP reply opcode of the device kind. The
+    /// opcode was carried in the controller id before that became the board
+    /// id.
+    Downstream,
+    /// Makes the downstream with the logical id in the event id re-read its
+    /// parameters, e.g. after its EEPROM was edited externally.
+    Reinit,
+    /// Stops polling the slot in the event id if the value is 0 and resumes
+    /// it otherwise. Saved with the config.
+    SlotEnable,
+    /// A retained event sent in answer to `QueryKey::Replay`, so it isn't
+    /// acted on again. The id, value and controller id are the original's,
+    /// the sequence carries its event type.
+    Replay,
+    /// From the host, makes the device in the slot in the event id read its
+    /// detailed diagnostics. Answered by three events of this type with the
+    /// device id, the sequence numbering them: 0 carries diagnostic bits
+    /// D15..D0, 1 bits D21..D16 and 2 the fail-safe error cause in the high
+    /// and the analog diagnostic rounds in the low byte.
+    DiagnosticDetails,
+    /// `Input` of an axis with 8-bit output. The value fits an `i8` and takes
+    /// a single byte on the wire, the controller id and sequence follow it
+    /// directly.
+    NarrowInput,
+    /// Sent after the `Error` event of an EEPROM write that failed once the
+    /// device sent its challenge. The id carries the challenge, the value the
+    /// solution answered with and the sequence the slot.
+    WriteChallenge,
+    /// From the host, makes the device in the slot in the event id measure
+    /// the magnetic field once in place of an angle reading. Answered by
+    /// three events of this type with the device id, the sequence numbering
+    /// the X, Y and Z components as 0, 1 and 2.
+    FieldReading,
+}
+
+impl NegiconEventType {
+    pub(crate) fn from_number(number: u8) -> Option<Self> {
+        match number {
+            0 => Some(Self::Input),
+            1 => Some(Self::Output),
+            2 => Some(Self::MemWrite),
+            3 => Some(Self::Reboot),
+            4 => Some(Self::Config),
+            5 => Some(Self::Error),
+            6 => Some(Self::MemWriteBatch),
+            7 => Some(Self::Query),
+            8 => Some(Self::Heartbeat),
+            9 => Some(Self::RawAlpha),
+            10 => Some(Self::Downstream),
+            11 => Some(Self::Reinit),
+            12 => Some(Self::SlotEnable),
+            13 => Some(Self::Replay),
+            14 => Some(Self::DiagnosticDetails),
+            15 => Some(Self::NarrowInput),
+            16 => Some(Self::WriteChallenge),
+            17 => Some(Self::FieldReading),
+            _ => None,
+        }
+    }
+}
+
+/// A report whose first byte isn't a known `NegiconEventType`
+#[derive(PartialEq, Clone, Copy, Format, Debug)]
+pub(crate) struct UnknownEventType(pub(crate) u8);
+
+#[derive(PartialEq, Clone, Copy, Format)]
+pub(crate) enum QueryKey {
+    /// Events dropped by the upstream the query arrived on, saturated to
+    /// `i16::MAX`. A query value of 1 clears the counter after reading it.
+    DroppedEvents,
+    /// Lists the detected downstreams as `Downstream` events in slot order,
+    /// followed by the answer carrying their count
+    Enumerate,
+    /// Sends the last events and errors reported by the downstreams as
+    /// `Replay` events, oldest first, followed by the answer carrying their
+    /// count. Only as many as the upstream queue has room for go out, and a
+    /// query value of 1 clears the ones sent, so repeating it pages through
+    /// the rest.
+    Replay,
+    /// Relay frames from the SPI master dropped for a bad CRC by the upstream
+    /// the query arrived on, saturated to `i16::MAX`. A query value of 1
+    /// clears the counter after reading it. Always 0 on other links.
+    CorruptFrames,
+    /// Current value of a `ConfigKey`, queried with id `CONFIG_QUERY_BASE`
+    /// plus the config key id
+    Config(ConfigKey),
+    /// Revision of the downstream in a slot, queried with id
+    /// `VERSION_QUERY_BASE` plus the slot. Answers `hw << 8 | fw`, or -1 if
+    /// the slot is empty or the device never reported it.
+    DownstreamVersion(u16),
+    /// Statistics counter of a slot, queried with id `STATS_QUERY_BASE` plus
+    /// `slot << 2 | counter`. Answers the counter saturated to `i16::MAX`.
+    SlotStat(u16, SlotStat),
+    /// State of the downstream in a slot, queried with id
+    /// `DIAGNOSTIC_QUERY_BASE` plus `slot << 2 | field`. Answers -1 if the
+    /// slot is empty or the value hasn't been read yet.
+    DownstreamDiagnostic(u16, DiagnosticField),
+}
+
+/// Fields of a downstream diagnostic query, in their query id order
+#[derive(PartialEq, Clone, Copy, Format)]
+pub(crate) enum DiagnosticField {
+    /// Bit 0 set once the device is initialized, bit 1 in absolute mode, bit
+    /// 2 while its self-diagnostic fails, bit 3 if it runs on default
+    /// parameters
+    Flags,
+    Min,
+    Max,
+    /// Magnetic field strength (VG) of the last reading, for warning about a
+    /// magnet that is too far or too close
+    FieldStrength,
+}
+
+/// Counters kept per downstream slot, in their query id order
+#[derive(PartialEq, Clone, Copy, Format)]
+pub(crate) enum SlotStat {
+    Polls,
+    CrcErrors,
+    DeviceErrors,
+    Events,
+}
+
+/// Flips the pass bit of the diagnostic status in `RawAlpha` values, so the
+/// sign tells failed readings apart
+pub(crate) const RAW_ALPHA_DIAG_FLIP: u16 = 0b10;
+
+pub(crate) const CONFIG_QUERY_BASE: u16 = 0x100;
+pub(crate) const VERSION_QUERY_BASE: u16 = 0x200;
+pub(crate) const STATS_QUERY_BASE: u16 = 0x300;
+pub(crate) const DIAGNOSTIC_QUERY_BASE: u16 = 0x400;
+
+impl QueryKey {
+    pub(crate) fn from_id(id: u16) -> Option<Self> {
+        match id {
+            0 => Some(Self::DroppedEvents),
+            1 => Some(Self::Enumerate),
+            2 => Some(Self::Replay),
+            3 => Some(Self::CorruptFrames),
+            CONFIG_QUERY_BASE..=0x1FF => {
+                ConfigKey::from_id(id - CONFIG_QUERY_BASE).map(Self::Config)
+            }
+            VERSION_QUERY_BASE..=0x2FF => Some(Self::DownstreamVersion(id - VERSION_QUERY_BASE)),
+            STATS_QUERY_BASE..=0x3FF => {
+                let offset = id - STATS_QUERY_BASE;
+                let stat = match offset & 0b11 {
+                    0 => SlotStat::Polls,
+                    1 => SlotStat::CrcErrors,
+                    2 => SlotStat::DeviceErrors,
+                    _ => SlotStat::Events,
+                };
+                Some(Self::SlotStat(offset >> 2, stat))
+            }
+            DIAGNOSTIC_QUERY_BASE..=0x4FF => {
+                let offset = id - DIAGNOSTIC_QUERY_BASE;
+                let field = match offset & 0b11 {
+                    0 => DiagnosticField::Flags,
+                    1 => DiagnosticField::Min,
+                    2 => DiagnosticField::Max,
+                    _ => DiagnosticField::FieldStrength,
+                };
+                Some(Self::DownstreamDiagnostic(offset >> 2, field))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// What a `Reboot` event restarts into, selected by the event value. Anything
+/// other than 1 enters the USB bootloader, as all reboots used to.
+#[derive(PartialEq, Clone, Copy, Format)]
+pub(crate) enum RebootKind {
+    UsbBoot,
+    Restart,
+}
+
+impl RebootKind {
+    pub(crate) fn from_value(value: i16) -> Self {
+        match value {
+            1 => Self::Restart,
+            _ => Self::UsbBoot,
+        }
+    }
+}
+
+/// Settings that can be changed at runtime with a `Config` event. The key is
+/// carried in the event id, the new value in the event value.
+#[derive(PartialEq, Clone, Copy, Format)]
+pub(crate) enum ConfigKey {
+    /// Downstream SPI clock in kHz
+    DownstreamSpiClock,
+    /// Seconds without input before polling slows down, 0 to never idle
+    IdleTimeout,
+    /// Milliseconds without upstream traffic before a heartbeat is sent, 0
+    /// disables it
+    HeartbeatInterval,
+    /// Readings a newly detected sensor only uses to seed its baseline,
+    /// applies from the next detection
+    PrimeReadings,
+    /// 1 drops events still queued when the host disconnects or suspends,
+    /// 0 keeps them, e.g. when only absolute axes are attached
+    FlushOnDisconnect,
+    /// 1 additionally reports every sensor reading as a `RawAlpha` event
+    RawAlpha,
+    /// Id of this board in a daisy chain, sent as the controller id of its
+    /// events. 0 leaves them unmarked. On the SPI upstream, requests carrying
+    /// another nonzero controller id are meant for a different board and
+    /// ignored.
+    BoardId,
+    /// Microseconds CS is held around each downstream frame before the first
+    /// and after the last clock, up to `MAX_CS_SETTLE_US`
+    CsSettle,
+    /// Polls between position reports of absolute axes even when they are
+    /// still, 0 to only report changes
+    ReportPeriod,
+    /// Smallest relative step sent to the host. Smaller steps are held back
+    /// and added to the next one, 0 or 1 sends every step.
+    MinRelativeStep,
+    /// Milliseconds between two downstream polling rounds while active,
+    /// clamped to `MIN_POLL_INTERVAL_MS..=MAX_POLL_INTERVAL_MS`
+    PollInterval,
+    /// Readings in a row an MLX has to report a failed self-diagnostic
+    /// before an `Error` event is sent. Failed readings are never reported
+    /// as input.
+    DiagnosticFailLimit,
+    /// Parameter reads in a row that may fail while an MLX initializes before
+    /// it gives up. It is then dropped and detected afresh, and if it gives
+    /// up again the unread parameters fall back to defaults.
+    InitAttempts,
+    /// Value of the input event sent when an axis button is pressed
+    ButtonPressValue,
+    /// Value of the input event sent when an axis button is released
+    ButtonReleaseValue,
+    /// Added to the axis id to form the id of its button events
+    ButtonIdOffset,
+    /// Bit n set drops events of type n instead of sending them to the host.
+    /// `Query` answers are always sent, so the mask can be read back, and
+    /// types from 16 on can't be blocked.
+    BlockedEvents,
+    /// Milliseconds after boot before the downstreams are first polled, so
+    /// satellite boards are out of their own reset by then. Clamped to
+    /// `MAX_STARTUP_DELAY_MS`, and only used from the next boot on.
+    StartupDelay,
+    /// 1 gives the input events of one sensor reading, e.g. an axis step and
+    /// a button press, the reading's frame number as sequence. 0 leaves the
+    /// sequence at 0.
+    FrameIds,
+    /// Writes the current config to flash so it is applied on the next boot
+    Save,
+}
+
+impl ConfigKey {
+    pub(crate) fn from_id(id: u16) -> Option<Self> {
+        match id {
+            0 => Some(Self::DownstreamSpiClock),
+            1 => Some(Self::IdleTimeout),
+            2 => Some(Self::Save),
+            3 => Some(Self::HeartbeatInterval),
+            4 => Some(Self::PrimeReadings),
+            5 => Some(Self::FlushOnDisconnect),
+            6 => Some(Self::RawAlpha),
+            7 => Some(Self::BoardId),
+            8 => Some(Self::CsSettle),
+            9 => Some(Self::ReportPeriod),
+            10 => Some(Self::MinRelativeStep),
+            11 => Some(Self::PollInterval),
+            12 => Some(Self::DiagnosticFailLimit),
+            13 => Some(Self::InitAttempts),
+            14 => Some(Self::ButtonPressValue),
+            15 => Some(Self::ButtonReleaseValue),
+            16 => Some(Self::ButtonIdOffset),
+            17 => Some(Self::BlockedEvents),
+            18 => Some(Self::StartupDelay),
+            19 => Some(Self::FrameIds),
+            _ => None,
+        }
+    }
 }
 
 impl NegiconEvent {
@@ -36,37 +348,358 @@ impl NegiconEvent {
         }
     }
 
-    pub(crate) fn serialize(&self) -> [u8; 8] {
-        [
-            self.event_type as u8,
-            self.id.shr(8) as u8,
-            self.id as u8,
-            self.value.shr(8) as u8,
-            self.value as u8,
+    /// A `RawAlpha` event. The id is the axis id, the value the 14-bit angle
+    /// with the diagnostic status XOR `RAW_ALPHA_DIAG_FLIP` in the top two
+    /// bits, and the sequence the magnetic field strength (VG). Readings that
+    /// passed the self-diagnostic (status 2 and 3) are never negative, failed
+    /// or unfinished ones always are.
+    pub(crate) fn raw_alpha(id: u16, alpha: u16, vg: u8, diag: u8) -> Self {
+        let status = (diag as u16 & 0b11) ^ RAW_ALPHA_DIAG_FLIP;
+        let value = (alpha & 0x3FFF) | status << 14;
+        Self::new(NegiconEventType::RawAlpha, id, value as i16, 0, vg)
+    }
+
+    /// A `NarrowInput` event
+    pub(crate) fn narrow_input(id: u16, value: i8, sequence: u8) -> Self {
+        Self::new(NegiconEventType::NarrowInput, id, value as i16, 0, sequence)
+    }
+
+    /// This event wrapped into a `Replay` event
+    pub(crate) fn replay(&self) -> Self {
+        Self::new(
+            NegiconEventType::Replay,
+            self.id,
+            self.value,
             self.controller_id,
-            self.sequence,
-            0u8,
-        ]
-    }
-
-    pub(crate) fn deserialize(data: [u8; 8]) -> Self {
-        let event_type = match data[0] {
-            0 => NegiconEventType::Input,
-            1 => NegiconEventType::Output,
-            2 => NegiconEventType::MemWrite,
-            3 => NegiconEventType::Reboot,
-            _ => NegiconEventType::Input,
-        };
-        let id = make_u16(data[1], data[2]);
-        let value = make_i16(data[3], data[4]);
-        let controller_id = data[5];
-        let sequence = data[6];
-        NegiconEvent {
+            self.event_type as u8,
+        )
+    }
+
+    pub(crate) fn event_type(&self) -> NegiconEventType {
+        self.event_type
+    }
+
+    pub(crate) fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub(crate) fn value(&self) -> i16 {
+        self.value
+    }
+
+    /// Board the event originated from, 0 if unknown
+    pub(crate) fn controller_id(&self) -> u8 {
+        self.controller_id
+    }
+
+    pub(crate) fn with_controller_id(self, controller_id: u8) -> Self {
+        Self {
+            controller_id,
+            ..self
+        }
+    }
+
+    pub(crate) fn sequence(&self) -> u8 {
+        self.sequence
+    }
+
+    /// Packs the event into the first `SERIALIZED_SIZE` bytes of a report, the
+    /// rest is zeroed. `NarrowInput` values saturate to their single byte.
+    pub(crate) fn serialize(&self) -> Report {
+        let NegiconEvent {
             event_type,
             id,
             value,
             controller_id,
             sequence,
+        } = *self;
+        let [id_hi, id_lo] = id.to_be_bytes();
+        let [value_hi, value_lo] = value.to_be_bytes();
+        let wire: [u8; SERIALIZED_SIZE] = match event_type {
+            NegiconEventType::NarrowInput => [
+                event_type as u8,
+                id_hi,
+                id_lo,
+                value.clamp(i8::MIN as i16, i8::MAX as i16) as i8 as u8,
+                controller_id,
+                sequence,
+                0,
+            ],
+            _ => [
+                event_type as u8,
+                id_hi,
+                id_lo,
+                value_hi,
+                value_lo,
+                controller_id,
+                sequence,
+            ],
+        };
+        let mut report = [0u8; REPORT_SIZE];
+        report[..SERIALIZED_SIZE].copy_from_slice(&wire);
+        report
+    }
+
+    /// Inverse of `serialize` for every event. Reports of an unknown type are
+    /// rejected rather than read as `Input`.
+    pub(crate) fn deserialize(data: Report) -> Result<Self, UnknownEventType> {
+        let wire: [u8; SERIALIZED_SIZE] = core::array::from_fn(|i| data[i]);
+        let [event_type, id_hi, id_lo, value_hi, value_lo, controller_id, sequence] = wire;
+        let event_type =
+            NegiconEventType::from_number(event_type).ok_or(UnknownEventType(event_type))?;
+        let (value, controller_id, sequence) = match event_type {
+            NegiconEventType::NarrowInput => (value_hi as i8 as i16, value_lo, controller_id),
+            _ => (
+                i16::from_be_bytes([value_hi, value_lo]),
+                controller_id,
+                sequence,
+            ),
+        };
+        Ok(NegiconEvent {
+            event_type,
+            id: u16::from_be_bytes([id_hi, id_lo]),
+            value,
+            controller_id,
+            sequence,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    #[test]
+    fn accessors_return_the_constructed_fields() {
+        let event = NegiconEvent::new(NegiconEventType::MemWrite, 0x1234, -42, 7, 0x3A);
+        assert!(event.event_type() == NegiconEventType::MemWrite);
+        assert_eq!(event.id(), 0x1234);
+        assert_eq!(event.value(), -42);
+        assert_eq!(event.controller_id(), 7);
+        assert_eq!(event.sequence(), 0x3A);
+    }
+
+    #[test]
+    fn debug_output_includes_id_and_value() {
+        let event = NegiconEvent::new(NegiconEventType::Input, 4660, -1234, 0, 0);
+        let text = format!("{:?}", event);
+        assert!(text.contains("Input"), "{}", text);
+        assert!(text.contains("id: 4660"), "{}", text);
+        assert!(text.contains("value: -1234"), "{}", text);
+    }
+
+    #[test]
+    fn round_trips_at_report_size() {
+        let event = NegiconEvent::new(NegiconEventType::MemWrite, 0xBEEF, -12345, 0xA5, 0x5A);
+        let report: Report = event.serialize();
+        assert_eq!(report.len(), REPORT_SIZE);
+        assert_eq!(report[..7], [2, 0xBE, 0xEF, 0xCF, 0xC7, 0xA5, 0x5A]);
+        assert!(report[7..].iter().all(|b| *b == 0));
+        let back = NegiconEvent::deserialize(report).unwrap();
+        assert!(back.event_type() == NegiconEventType::MemWrite);
+        assert_eq!(back.id(), 0xBEEF);
+        assert_eq!(back.value(), -12345);
+        assert_eq!(back.controller_id(), 0xA5);
+        assert_eq!(back.sequence(), 0x5A);
+    }
+
+    #[test]
+    fn serialized_fields_end_at_serialized_size() {
+        let event = NegiconEvent::new(NegiconEventType::Input, 0xFFFF, -1, 0xFF, 0xFF);
+        let report = event.serialize();
+        assert_eq!(report[SERIALIZED_SIZE - 1], 0xFF);
+        assert!(report[SERIALIZED_SIZE..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn signed_values_keep_their_sign_on_the_wire() {
+        for (value, wire) in [
+            (-1, [0xFF, 0xFF]),
+            (i16::MIN, [0x80, 0x00]),
+            (i16::MAX, [0x7F, 0xFF]),
+            (0, [0x00, 0x00]),
+        ] {
+            let report = NegiconEvent::new(NegiconEventType::Input, 1, value, 0, 0).serialize();
+            assert_eq!(report[3..5], wire);
+            assert_eq!(NegiconEvent::deserialize(report).unwrap().value(), value);
+        }
+    }
+
+    /// Every event type, in wire order
+    fn event_types() -> impl Iterator<Item = NegiconEventType> {
+        (0..=u8::MAX).filter_map(NegiconEventType::from_number)
+    }
+
+    fn round_trip(event: NegiconEvent) -> NegiconEvent {
+        match NegiconEvent::deserialize(event.serialize()) {
+            Ok(decoded) => decoded,
+            Err(UnknownEventType(number)) => panic!("type {} rejected", number),
+        }
+    }
+
+    #[test]
+    fn raw_alpha_packs_angle_field_strength_and_diagnostics() {
+        let event = NegiconEvent::raw_alpha(20, 0x3FFF, 0xA5, 2);
+        let back = round_trip(event);
+        assert!(back.event_type() == NegiconEventType::RawAlpha);
+        assert_eq!(back.id(), 20);
+        assert_eq!(back.value() as u16 & 0x3FFF, 0x3FFF);
+        assert_eq!(back.value() as u16 >> 14 ^ RAW_ALPHA_DIAG_FLIP, 2);
+        assert_eq!((back.controller_id(), back.sequence()), (0, 0xA5));
+    }
+
+    #[test]
+    fn raw_alpha_is_negative_only_without_a_passed_diagnostic() {
+        for diag in 0..4 {
+            for alpha in [0, 0x2000, 0x3FFF] {
+                let value = NegiconEvent::raw_alpha(1, alpha, 0, diag).value();
+                assert_eq!(value >= 0, diag >= 2, "{} {}", diag, alpha);
+            }
         }
     }
+
+    #[test]
+    fn replay_keeps_the_event_and_carries_its_type_in_the_sequence() {
+        let event = NegiconEvent::new(NegiconEventType::Error, 3, -7, 0xA5, 0x11);
+        let back = round_trip(event.replay());
+        assert!(back.event_type() == NegiconEventType::Replay);
+        assert_eq!((back.id(), back.value()), (3, -7));
+        assert_eq!(back.controller_id(), 0xA5);
+        assert_eq!(back.sequence(), NegiconEventType::Error as u8);
+    }
+
+    #[test]
+    fn reboot_value_selects_the_reset_kind() {
+        assert!(RebootKind::from_value(1) == RebootKind::Restart);
+        // Hosts that predate the restart option send 0
+        for value in [0, 2, -1, i16::MAX, i16::MIN] {
+            assert!(RebootKind::from_value(value) == RebootKind::UsbBoot);
+        }
+    }
+
+    #[test]
+    fn event_types_are_numbered_densely() {
+        for (number, event_type) in event_types().enumerate() {
+            assert_eq!(event_type as usize, number);
+        }
+    }
+
+    #[test]
+    fn every_type_and_id_round_trips() {
+        for event_type in event_types() {
+            for id in 0..=u16::MAX {
+                let event = NegiconEvent::new(event_type, id, -1, 0x5A, 0xA5);
+                assert!(round_trip(event) == event, "{} {}", event_type as u8, id);
+            }
+        }
+    }
+
+    #[test]
+    fn query_ids_select_their_key() {
+        assert!(QueryKey::from_id(0) == Some(QueryKey::DroppedEvents));
+        assert!(QueryKey::from_id(1) == Some(QueryKey::Enumerate));
+        assert!(
+            QueryKey::from_id(CONFIG_QUERY_BASE + 1)
+                == Some(QueryKey::Config(ConfigKey::IdleTimeout))
+        );
+        assert!(QueryKey::from_id(CONFIG_QUERY_BASE + 0xFF).is_none());
+        assert!(QueryKey::from_id(VERSION_QUERY_BASE + 3) == Some(QueryKey::DownstreamVersion(3)));
+        assert!(
+            QueryKey::from_id(STATS_QUERY_BASE + (3 << 2 | 2))
+                == Some(QueryKey::SlotStat(3, SlotStat::DeviceErrors))
+        );
+        assert!(
+            QueryKey::from_id(DIAGNOSTIC_QUERY_BASE + (2 << 2 | 1))
+                == Some(QueryKey::DownstreamDiagnostic(2, DiagnosticField::Min))
+        );
+        assert!(
+            QueryKey::from_id(DIAGNOSTIC_QUERY_BASE + (4 << 2 | 3))
+                == Some(QueryKey::DownstreamDiagnostic(
+                    4,
+                    DiagnosticField::FieldStrength
+                ))
+        );
+        assert!(QueryKey::from_id(DIAGNOSTIC_QUERY_BASE + 0x100).is_none());
+        assert!(QueryKey::from_id(2) == Some(QueryKey::Replay));
+        assert!(QueryKey::from_id(3) == Some(QueryKey::CorruptFrames));
+        assert!(QueryKey::from_id(4).is_none());
+    }
+
+    #[test]
+    fn every_value_round_trips() {
+        for value in i16::MIN..=i16::MAX {
+            let event = NegiconEvent::new(NegiconEventType::Input, 0x1234, value, 0, 0);
+            assert!(round_trip(event) == event, "{}", value);
+        }
+    }
+
+    #[test]
+    fn every_controller_id_and_sequence_round_trips() {
+        for controller_id in 0..=u8::MAX {
+            for sequence in 0..=u8::MAX {
+                let event = NegiconEvent::new(
+                    NegiconEventType::Output,
+                    0xFFFF,
+                    i16::MIN,
+                    controller_id,
+                    sequence,
+                );
+                assert!(round_trip(event) == event);
+            }
+        }
+    }
+
+    #[test]
+    fn config_and_query_keys_survive_round_trip() {
+        for id in 0..=u16::MAX {
+            let config = round_trip(NegiconEvent::new(NegiconEventType::Config, id, 0, 0, 0));
+            assert!(ConfigKey::from_id(config.id()) == ConfigKey::from_id(id));
+            let query = round_trip(NegiconEvent::new(NegiconEventType::Query, id, 0, 0, 0));
+            assert!(QueryKey::from_id(query.id()) == QueryKey::from_id(id));
+        }
+    }
+
+    #[test]
+    fn config_keys_are_numbered_densely() {
+        let keys = (0..=u16::MAX).filter_map(ConfigKey::from_id).count() as u16;
+        assert!((0..keys).all(|id| ConfigKey::from_id(id).is_some()));
+    }
+
+    #[test]
+    fn unknown_types_are_rejected() {
+        let unknown = (0..=u8::MAX).filter(|n| NegiconEventType::from_number(*n).is_none());
+        for number in unknown {
+            let mut report = [0u8; REPORT_SIZE];
+            report[0] = number;
+            assert!(matches!(
+                NegiconEvent::deserialize(report),
+                Err(UnknownEventType(n)) if n == number
+            ));
+        }
+    }
+
+    #[test]
+    fn narrow_input_takes_one_value_byte() {
+        let event = NegiconEvent::narrow_input(0x0102, -2, 4).with_controller_id(3);
+        assert_eq!(event.serialize(), [15, 0x01, 0x02, 0xFE, 3, 4, 0, 0]);
+        for value in i8::MIN..=i8::MAX {
+            let event = NegiconEvent::narrow_input(7, value, 0xA5).with_controller_id(0x5A);
+            assert!(round_trip(event) == event, "{}", value);
+        }
+    }
+
+    #[test]
+    fn narrow_input_saturates_wide_values() {
+        for (value, narrow) in [(i16::MAX, i8::MAX), (i16::MIN, i8::MIN), (128, 127)] {
+            let event = NegiconEvent::new(NegiconEventType::NarrowInput, 7, value, 0, 0);
+            assert_eq!(round_trip(event).value(), narrow as i16);
+        }
+    }
+
+    #[test]
+    fn layout_is_big_endian() {
+        let event = NegiconEvent::new(NegiconEventType::Heartbeat, 0x0102, -2, 3, 4);
+        assert_eq!(event.serialize(), [8, 0x01, 0x02, 0xFF, 0xFE, 3, 4, 0]);
+    }
 }
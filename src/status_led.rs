@@ -0,0 +1,214 @@
+use defmt::{info, Format};
+use rp2040_hal::timer::Instant;
+use smart_leds::{SmartLedsWrite, RGB8};
+
+use crate::upstream::upstream::Upstream;
+
+/// Brightness of the LED when fully on, kept low as it sits next to the user
+const MAX_BRIGHTNESS: u8 = 32;
+/// Period of the breathing pattern shown while waiting for the host
+const PULSE_PERIOD_MS: u64 = 2000;
+/// Time the LED stays on and off in the error pattern
+const BLINK_HALF_PERIOD_MS: u64 = 150;
+
+/// Host link as far as the LED is concerned
+#[derive(PartialEq, Clone, Copy, Format)]
+pub(crate) enum LinkState {
+    /// Every upstream is suspended, e.g. the host is asleep or unplugged
+    Suspended,
+    /// Powered, but no upstream has been configured yet
+    Unconfigured,
+    /// At least one upstream takes events
+    Configured,
+}
+
+impl LinkState {
+    pub(crate) fn of(upstreams: &[Upstream]) -> Self {
+        if upstreams.iter().any(|up| up.is_ready()) {
+            Self::Configured
+        } else if upstreams.iter().all(|up| up.is_suspended()) {
+            Self::Suspended
+        } else {
+            Self::Unconfigured
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Format)]
+pub(crate) enum LedPattern {
+    Off,
+    SlowPulse,
+    Solid,
+    Blink,
+}
+
+impl LedPattern {
+    /// Errors override the link patterns, except while suspended, when the
+    /// LED stays dark.
+    pub(crate) fn for_state(link: LinkState, error: bool) -> Self {
+        match (link, error) {
+            (LinkState::Suspended, _) => Self::Off,
+            (_, true) => Self::Blink,
+            (LinkState::Unconfigured, false) => Self::SlowPulse,
+            (LinkState::Configured, false) => Self::Solid,
+        }
+    }
+
+    /// Brightness `ms` milliseconds after boot
+    pub(crate) fn brightness(&self, ms: u64) -> u8 {
+        match self {
+            Self::Off => 0,
+            Self::Solid => MAX_BRIGHTNESS,
+            Self::SlowPulse => {
+                let phase = ms % PULSE_PERIOD_MS;
+                let half = PULSE_PERIOD_MS / 2;
+                let ramp = if phase < half {
+                    phase
+                } else {
+                    PULSE_PERIOD_MS - phase
+                };
+                (ramp * MAX_BRIGHTNESS as u64 / half) as u8
+            }
+            Self::Blink => {
+                if (ms / BLINK_HALF_PERIOD_MS).is_multiple_of(2) {
+                    MAX_BRIGHTNESS
+                } else {
+                    0
+                }
+            }
+        }
+    }
+
+    fn color(&self, level: u8) -> RGB8 {
+        match self {
+            Self::Blink => RGB8::new(level, 0, 0),
+            _ => RGB8::new(0, level, 0),
+        }
+    }
+}
+
+/// Single WS2812 showing the link state. Runs off the main loop next to the
+/// event path and never feeds back into it.
+pub(crate) struct StatusLed<W> {
+    led: W,
+    pattern: LedPattern,
+    /// Color last written, so an unchanged LED isn't rewritten every loop
+    shown: Option<RGB8>,
+}
+
+impl<W> StatusLed<W>
+where
+    W: SmartLedsWrite<Color = RGB8>,
+{
+    pub(crate) fn new(led: W) -> Self {
+        Self {
+            led,
+            pattern: LedPattern::Off,
+            shown: None,
+        }
+    }
+
+    pub(crate) fn update(&mut self, now: Instant, link: LinkState, error: bool) {
+        let pattern = LedPattern::for_state(link, error);
+        if pattern != self.pattern {
+            info!("Status LED {} -> {}", self.pattern, pattern);
+            self.pattern = pattern;
+        }
+        let level = pattern.brightness(now.duration_since_epoch().to_millis());
+        let color = pattern.color(level);
+        if self.shown == Some(color) {
+            return;
+        }
+        self.shown = Some(color);
+        let _ = self.led.write(core::iter::once(color));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// Records every color written to it
+    #[derive(Default)]
+    struct Recorder(Vec<RGB8>);
+
+    impl SmartLedsWrite for Recorder {
+        type Error = ();
+        type Color = RGB8;
+
+        fn write<T, I>(&mut self, iterator: T) -> Result<(), ()>
+        where
+            T: Iterator<Item = I>,
+            I: Into<RGB8>,
+        {
+            self.0.extend(iterator.map(Into::into));
+            Ok(())
+        }
+    }
+
+    fn at_ms(ms: u64) -> Instant {
+        Instant::from_ticks(ms * 1000)
+    }
+
+    #[test]
+    fn link_states_map_to_their_patterns() {
+        for (link, error, pattern) in [
+            (LinkState::Suspended, false, LedPattern::Off),
+            (LinkState::Suspended, true, LedPattern::Off),
+            (LinkState::Unconfigured, false, LedPattern::SlowPulse),
+            (LinkState::Unconfigured, true, LedPattern::Blink),
+            (LinkState::Configured, false, LedPattern::Solid),
+            (LinkState::Configured, true, LedPattern::Blink),
+        ] {
+            assert!(LedPattern::for_state(link, error) == pattern);
+        }
+    }
+
+    #[test]
+    fn steady_patterns_keep_their_brightness() {
+        for ms in [0, 150, 1000, 1999, 123_456] {
+            assert_eq!(LedPattern::Off.brightness(ms), 0);
+            assert_eq!(LedPattern::Solid.brightness(ms), MAX_BRIGHTNESS);
+        }
+    }
+
+    #[test]
+    fn pulse_ramps_up_and_down_over_its_period() {
+        let levels: Vec<_> = [0, 500, 1000, 1500, 2000]
+            .iter()
+            .map(|&ms| LedPattern::SlowPulse.brightness(ms))
+            .collect();
+        assert_eq!(
+            levels,
+            [0, MAX_BRIGHTNESS / 2, MAX_BRIGHTNESS, MAX_BRIGHTNESS / 2, 0]
+        );
+    }
+
+    #[test]
+    fn blink_toggles_every_half_period() {
+        let levels: Vec<_> = [0, 149, 150, 299, 300]
+            .iter()
+            .map(|&ms| LedPattern::Blink.brightness(ms))
+            .collect();
+        assert_eq!(
+            levels,
+            [MAX_BRIGHTNESS, MAX_BRIGHTNESS, 0, 0, MAX_BRIGHTNESS]
+        );
+    }
+
+    #[test]
+    fn unchanged_color_is_not_rewritten() {
+        let mut led = StatusLed::new(Recorder::default());
+        led.update(at_ms(0), LinkState::Configured, false);
+        led.update(at_ms(10), LinkState::Configured, false);
+        led.update(at_ms(20), LinkState::Configured, true);
+        led.update(at_ms(200), LinkState::Configured, true);
+
+        let red = RGB8::new(MAX_BRIGHTNESS, 0, 0);
+        assert_eq!(
+            led.led.0,
+            [RGB8::new(0, MAX_BRIGHTNESS, 0), red, RGB8::new(0, 0, 0)]
+        );
+    }
+}
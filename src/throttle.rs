@@ -0,0 +1,69 @@
+use fugit::MicrosDurationU64;
+use rp2040_hal::timer::Instant;
+
+/// Lets a repeating log message through at most once per interval, so noise
+/// on a slot doesn't flood the log.
+pub(crate) struct Throttle {
+    interval: MicrosDurationU64,
+    last: Option<Instant>,
+    suppressed: u16,
+}
+
+impl Throttle {
+    pub(crate) const fn new(interval: MicrosDurationU64) -> Self {
+        Self {
+            interval,
+            last: None,
+            suppressed: 0,
+        }
+    }
+
+    /// Returns the number of messages suppressed since the last one if a
+    /// message may go out at `now`, `None` if it should be dropped.
+    pub(crate) fn poll(&mut self, now: Instant) -> Option<u16> {
+        if let Some(last) = self.last {
+            match now.checked_duration_since(last) {
+                Some(elapsed) if elapsed < self.interval => {
+                    self.suppressed = self.suppressed.saturating_add(1);
+                    return None;
+                }
+                _ => {}
+            }
+        }
+        self.last = Some(now);
+        Some(core::mem::take(&mut self.suppressed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn secs(s: u64) -> Instant {
+        Instant::from_ticks(s * 1_000_000)
+    }
+
+    #[test]
+    fn first_message_goes_out() {
+        let mut throttle = Throttle::new(MicrosDurationU64::secs(5));
+        assert_eq!(throttle.poll(secs(0)), Some(0));
+    }
+
+    #[test]
+    fn storm_collapses_into_one_message_per_interval() {
+        let mut throttle = Throttle::new(MicrosDurationU64::secs(5));
+        let passed: Vec<_> = (0..12)
+            .filter_map(|s| throttle.poll(secs(s)).map(|suppressed| (s, suppressed)))
+            .collect();
+        assert_eq!(passed, [(0, 0), (5, 4), (10, 4)]);
+    }
+
+    #[test]
+    fn quiet_source_is_never_held_back() {
+        let mut throttle = Throttle::new(MicrosDurationU64::secs(5));
+        for s in [0, 7, 20] {
+            assert_eq!(throttle.poll(secs(s)), Some(0));
+        }
+    }
+}
@@ -0,0 +1,55 @@
+//! Time since boot for defmt log lines
+
+/// Combines the two 32-bit halves of a free running 64-bit counter. The high
+/// word is re-read until it is stable, so a carry between the two reads can't
+/// make time jump back.
+pub(crate) fn read_split_counter(
+    mut read_hi: impl FnMut() -> u32,
+    mut read_lo: impl FnMut() -> u32,
+) -> u64 {
+    loop {
+        let hi = read_hi();
+        let lo = read_lo();
+        if read_hi() == hi {
+            break (hi as u64) << 32 | lo as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    /// Counter that advances by `step` on every register read
+    fn read_counter(counter: &Cell<u64>, step: u64) -> u64 {
+        let tick = |shift: u32| {
+            let value = counter.get();
+            counter.set(value + step);
+            (value >> shift) as u32
+        };
+        read_split_counter(|| tick(32), || tick(0))
+    }
+
+    #[test]
+    fn carry_between_the_reads_is_retried() {
+        // The low word wraps between reading the high and the low word
+        let counter = Cell::new(0xFFFF_FFFF);
+        let time = read_counter(&counter, 1);
+        assert!(time >= 0x1_0000_0000);
+        assert!(time < counter.get());
+    }
+
+    #[test]
+    fn time_never_goes_back() {
+        for step in [1, 7, 0x4000_0000] {
+            let counter = Cell::new(0xFFFF_FF00);
+            let mut last = 0;
+            for _ in 0..1000 {
+                let time = read_counter(&counter, step);
+                assert!(time >= last, "{:#x} after {:#x}", time, last);
+                last = time;
+            }
+        }
+    }
+}
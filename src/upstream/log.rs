@@ -0,0 +1,65 @@
+use defmt::info;
+
+use crate::negicon_event::{NegiconEvent, Report};
+
+use super::upstream::{UpstreamError, UpstreamInterface};
+
+/// Upstream without a host that logs every event it is handed, for bench
+/// testing with nothing but a debug probe. It never receives anything and
+/// takes events as fast as they come.
+pub(crate) struct LogUpstream {}
+
+impl LogUpstream {
+    pub(crate) fn new() -> Self {
+        Self {}
+    }
+}
+
+impl UpstreamInterface for LogUpstream {
+    fn receive(&mut self) -> Result<Option<NegiconEvent>, UpstreamError> {
+        Ok(None)
+    }
+
+    fn send(&mut self, event: &mut Report) -> Result<(), UpstreamError> {
+        match NegiconEvent::deserialize(*event) {
+            Ok(decoded) => info!("Upstream event {}", decoded),
+            Err(_) => info!("Upstream report {=[u8]:x}", &event[..]),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        negicon_event::{NegiconEventType, REPORT_SIZE},
+        upstream::{ringbuf::BUFFER_SIZE, upstream::Upstream},
+    };
+
+    #[test]
+    fn every_send_takes_one_event_off_the_queue() {
+        let mut log = LogUpstream::new();
+        let mut up = Upstream::new(&mut log);
+        assert!(matches!(up.receive(), Ok(None)));
+        for round in 0..2 {
+            for id in 0..BUFFER_SIZE as u16 {
+                let event = NegiconEvent::new(NegiconEventType::Input, id, round, 0, 0);
+                assert!(up.enqueue(event).is_ok());
+            }
+            for _ in 0..BUFFER_SIZE {
+                assert!(up.send().is_ok());
+            }
+        }
+        // The second round only fit because the first was drained
+        assert_eq!(up.dropped_count(), 0);
+    }
+
+    #[test]
+    fn undecodable_reports_are_taken_as_well() {
+        let mut log = LogUpstream::new();
+        let mut report = [0xFF; REPORT_SIZE];
+        assert!(NegiconEvent::deserialize(report).is_err());
+        assert!(log.send(&mut report).is_ok());
+    }
+}
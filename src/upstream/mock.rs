@@ -0,0 +1,129 @@
+//! Stand-ins for the host link and the relay master in host tests
+use alloc::{collections::VecDeque, rc::Rc, vec::Vec};
+use core::{cell::Cell, convert::Infallible};
+
+use embedded_hal::{digital::v2::InputPin, spi::FullDuplex};
+
+use crate::negicon_event::{NegiconEvent, Report, REPORT_SIZE};
+
+#[cfg(feature = "spi-upstream")]
+use super::spi::SPIUpstream;
+use super::upstream::{UpstreamError, UpstreamInterface};
+
+/// Host link replaying `incoming` and recording every report sent
+#[derive(Default)]
+pub(crate) struct MockUpstream {
+    /// Events the host sends. A `None` reads as a link with nothing pending,
+    /// which ends the burst a tick takes in.
+    pub(crate) incoming: VecDeque<Option<NegiconEvent>>,
+    pub(crate) sent: Vec<NegiconEvent>,
+    /// Reports the link as not ready, like a suspended USB host
+    pub(crate) suspended: bool,
+}
+
+impl UpstreamInterface for MockUpstream {
+    fn is_ready(&self) -> bool {
+        !self.suspended
+    }
+
+    fn receive(&mut self) -> Result<Option<NegiconEvent>, UpstreamError> {
+        Ok(self.incoming.pop_front().flatten())
+    }
+
+    fn send(&mut self, event: &mut Report) -> Result<(), UpstreamError> {
+        self.sent.push(NegiconEvent::deserialize(*event)?);
+        Ok(())
+    }
+}
+
+/// SPI slave peripheral with an upstream master on the other end. The FIFOs
+/// are as deep as on the RP2040, and only move when the test clocks bytes.
+#[derive(Default)]
+pub(crate) struct MockMaster {
+    tx: VecDeque<u8>,
+    rx: VecDeque<u8>,
+    selected: Rc<Cell<bool>>,
+}
+
+impl MockMaster {
+    const FIFO_DEPTH: usize = 8;
+
+    /// The master's CS, as the slave reads it
+    pub(crate) fn select_line(&self) -> MockSelect {
+        MockSelect(self.selected.clone())
+    }
+
+    /// Clocks a whole frame from the master in one transfer and returns what
+    /// the slave sent back. An empty TX FIFO shifts out zeros.
+    pub(crate) fn clock(&mut self, frame: Report) -> Report {
+        let mut sent = [0; REPORT_SIZE];
+        for (out, byte) in sent.iter_mut().zip(frame) {
+            *out = self.clock_byte(byte);
+        }
+        self.release();
+        sent
+    }
+
+    /// Clocks only part of a frame and keeps CS asserted, like a master
+    /// interrupted mid-transfer
+    pub(crate) fn clock_partial(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.clock_byte(*byte);
+        }
+    }
+
+    /// Ends the transfer, wherever in a frame it is
+    pub(crate) fn release(&mut self) {
+        self.selected.set(false);
+    }
+
+    fn clock_byte(&mut self, byte: u8) -> u8 {
+        self.selected.set(true);
+        if self.rx.len() < Self::FIFO_DEPTH {
+            self.rx.push_back(byte);
+        }
+        self.tx.pop_front().unwrap_or(0)
+    }
+}
+
+/// CS of a `MockMaster`, low while it clocks a transfer
+pub(crate) struct MockSelect(Rc<Cell<bool>>);
+
+impl InputPin for MockSelect {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Infallible> {
+        Ok(!self.0.get())
+    }
+
+    fn is_low(&self) -> Result<bool, Infallible> {
+        Ok(self.0.get())
+    }
+}
+
+/// Relay link to a `MockMaster`
+#[cfg(feature = "spi-upstream")]
+pub(crate) type MockRelay = SPIUpstream<MockMaster, MockSelect>;
+
+#[cfg(feature = "spi-upstream")]
+pub(crate) fn mock_relay() -> MockRelay {
+    let master = MockMaster::default();
+    let select = master.select_line();
+    SPIUpstream::new(master, select)
+}
+
+impl FullDuplex<u8> for MockMaster {
+    type Error = Infallible;
+
+    fn read(&mut self) -> nb::Result<u8, Infallible> {
+        self.rx.pop_front().ok_or(nb::Error::WouldBlock)
+    }
+
+    fn send(&mut self, word: u8) -> nb::Result<(), Infallible> {
+        if self.tx.len() == Self::FIFO_DEPTH {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.tx.push_back(word);
+        Ok(())
+    }
+}
@@ -1,3 +1,8 @@
-mod ringbuf;
+#[cfg(feature = "log-upstream")]
+pub mod log;
+#[cfg(test)]
+pub(crate) mod mock;
+pub(crate) mod ringbuf;
+#[cfg(feature = "spi-upstream")]
 pub mod spi;
 pub mod upstream;
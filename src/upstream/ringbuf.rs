@@ -1,4 +1,4 @@
-const BUFFER_SIZE: usize = 100; // Adjust the size as needed
+pub(crate) const BUFFER_SIZE: usize = 100; // Adjust the size as needed
 
 pub(crate) struct RingBuffer<T> {
     buffer: [Option<T>; BUFFER_SIZE],
@@ -35,6 +35,14 @@ impl<T: core::marker::Copy> RingBuffer<T> {
         }
     }
 
+    // Adds an item to the buffer, discarding the oldest one if it is full
+    pub(crate) fn push_overwrite(&mut self, item: T) {
+        if self.size == BUFFER_SIZE {
+            self.pop();
+        }
+        let _ = self.push(item);
+    }
+
     // Peeks the next item in the buffer
     pub(crate) fn peek(&mut self) -> Option<&mut T> {
         if self.size > 0 {
@@ -44,12 +52,159 @@ impl<T: core::marker::Copy> RingBuffer<T> {
         }
     }
 
-    // Discards the last item in the buffer
-    pub(crate) fn discard(&mut self) {
+    // Removes and returns the oldest item
+    pub(crate) fn pop(&mut self) -> Option<T> {
         if self.size > 0 {
-            self.buffer[self.head].take();
+            let item = self.buffer[self.head].take();
             self.head = (self.head + 1) % BUFFER_SIZE;
             self.size -= 1;
+            item
+        } else {
+            None
+        }
+    }
+
+    // Number of buffered items
+    pub(crate) fn len(&self) -> usize {
+        self.size
+    }
+
+    // Iterates over the buffered items from oldest to newest
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &T> + '_ {
+        (0..self.size).filter_map(move |i| self.buffer[(self.head + i) % BUFFER_SIZE].as_ref())
+    }
+
+    // Yields the buffered items from oldest to newest. The buffer is empty
+    // afterwards, even if the iterator is dropped early.
+    pub(crate) fn drain(&mut self) -> Drain<'_, T> {
+        Drain { buffer: self }
+    }
+
+    // Empties the buffer
+    pub(crate) fn clear(&mut self) {
+        self.buffer = [None; BUFFER_SIZE];
+        self.head = 0;
+        self.tail = 0;
+        self.size = 0;
+    }
+
+    // Discards the last item in the buffer
+    pub(crate) fn discard(&mut self) {
+        self.pop();
+    }
+}
+
+// Iterator returned by `RingBuffer::drain`
+pub(crate) struct Drain<'a, T: core::marker::Copy> {
+    buffer: &'a mut RingBuffer<T>,
+}
+
+impl<T: core::marker::Copy> Iterator for Drain<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.buffer.pop()
+    }
+}
+
+impl<T: core::marker::Copy> Drop for Drain<'_, T> {
+    fn drop(&mut self) {
+        self.buffer.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn items_come_out_in_order_across_the_wrap() {
+        let mut buf = RingBuffer::new();
+        for i in 0..BUFFER_SIZE - 1 {
+            assert!(buf.push(i as u8).is_ok());
+        }
+        assert_eq!(buf.drain().count(), BUFFER_SIZE - 1);
+        for i in 0..3 {
+            assert!(buf.push(i).is_ok());
+        }
+        assert_eq!(buf.drain().collect::<Vec<_>>(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn full_buffer_refuses_more() {
+        let mut buf = RingBuffer::new();
+        for _ in 0..BUFFER_SIZE {
+            assert!(buf.push(1u8).is_ok());
+        }
+        assert!(matches!(buf.push(2), Err(BufferError::Overflow)));
+        buf.discard();
+        assert!(buf.push(2).is_ok());
+    }
+
+    #[test]
+    fn push_overwrite_keeps_the_most_recent_items() {
+        let mut buf = RingBuffer::new();
+        for i in 0..BUFFER_SIZE as u8 + 3 {
+            buf.push_overwrite(i);
+        }
+        let expected: Vec<u8> = (3..BUFFER_SIZE as u8 + 3).collect();
+        assert_eq!(buf.iter().copied().collect::<Vec<_>>(), expected);
+        assert_eq!(buf.drain().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn clear_empties_the_buffer() {
+        let mut buf = RingBuffer::new();
+        for i in 0..5u8 {
+            assert!(buf.push(i).is_ok());
+        }
+        buf.discard();
+        buf.clear();
+        assert!(buf.peek().is_none());
+        // Full capacity is available again
+        for _ in 0..BUFFER_SIZE {
+            assert!(buf.push(9).is_ok());
+        }
+        assert_eq!(buf.drain().collect::<Vec<_>>(), [9; BUFFER_SIZE]);
+    }
+
+    /// Buffer whose items 0..count sit across the end of the storage
+    fn wrapped(count: u8) -> RingBuffer<u8> {
+        let mut buf = RingBuffer::new();
+        for _ in 0..BUFFER_SIZE - 2 {
+            assert!(buf.push(0xFF).is_ok());
+            buf.discard();
+        }
+        for i in 0..count {
+            assert!(buf.push(i).is_ok());
+        }
+        buf
+    }
+
+    #[test]
+    fn iter_follows_the_wrap_without_removing() {
+        let buf = wrapped(5);
+        let items: Vec<_> = buf.iter().copied().collect();
+        assert_eq!(items, [0, 1, 2, 3, 4]);
+        assert_eq!(buf.iter().count(), 5);
+    }
+
+    #[test]
+    fn drain_yields_in_order_and_leaves_the_buffer_empty() {
+        let mut buf = wrapped(5);
+        assert_eq!(buf.drain().collect::<Vec<_>>(), [0, 1, 2, 3, 4]);
+        assert!(buf.peek().is_none());
+        assert_eq!(buf.iter().count(), 0);
+    }
+
+    #[test]
+    fn dropping_a_partial_drain_empties_the_buffer() {
+        let mut buf = wrapped(5);
+        assert_eq!(buf.drain().take(2).collect::<Vec<_>>(), [0, 1]);
+        assert!(buf.peek().is_none());
+        for _ in 0..BUFFER_SIZE {
+            assert!(buf.push(7).is_ok());
         }
     }
 }
@@ -1,32 +1,470 @@
-use embedded_hal::prelude::_embedded_hal_blocking_spi_Transfer;
-use rp2040_hal::{
-    spi::{Enabled, SpiDevice, ValidSpiPinout},
-    Spi,
-};
+use defmt::warn;
+use embedded_hal::{digital::v2::InputPin, spi::FullDuplex};
 
+use crate::{
+    downstream::spi_protocol::{set_crc, verify_crc},
+    negicon_event::{NegiconEvent, Report, REPORT_SIZE, SERIALIZED_SIZE},
+};
 
+use super::upstream::UpstreamError;
 
-pub(crate) struct SPIUpstream<D, P>
+/// Relay link to the next board up the chain, as an SPI slave. Nothing here
+/// waits for the master: frames go into the TX FIFO and are picked out of the
+/// RX FIFO byte by byte, whenever the master happens to clock them.
+pub(crate) struct SPIUpstream<S, C>
 where
-    D: SpiDevice,
-    P: ValidSpiPinout<D>,
+    S: FullDuplex<u8>,
+    C: InputPin,
 {
-    spi: Spi<Enabled, D, P, 8>,
+    spi: S,
+    /// The master's CS as seen by the slave, low during a transfer
+    select: C,
+    /// Frame being clocked in from the master
+    incoming: Report,
+    /// Bytes of `incoming` received so far
+    incoming_len: usize,
+    /// Frame being loaded into the TX FIFO
+    outgoing: Report,
+    /// Bytes of `outgoing` handed to the FIFO so far
+    outgoing_len: usize,
+    /// Bytes handed to the FIFO that the master hasn't clocked out yet
+    unclocked: usize,
+    /// Last complete frame from the master, until `take_received`
+    received: Option<Report>,
+    /// Report the link as ready only once the master clocked a frame
+    wait_for_master: bool,
+    /// Whether the master clocked a complete frame yet
+    master_seen: bool,
+    /// Board id of this board, which requests from the master are matched
+    /// against
+    address: u8,
+    /// Frames from the master dropped for a bad CRC or cut short
+    corrupt: u32,
 }
 
-impl<D, P> SPIUpstream<D, P>
+/// A relay frame is a serialized `NegiconEvent` followed by a CRC-8 over the
+/// bytes before it, the same framing as the downstream bus:
+///
+/// `[type, id hi, id lo, value hi, value lo, board id, sequence, crc]`
+///
+/// The board id is the event's controller id, set to the sending board's id
+/// by `Upstream::enqueue` and to the target board by the master. The CRC
+/// covers it, so a corrupted id can't make a frame look like it came from or
+/// was meant for another board.
+const _: () = assert!(
+    SERIALIZED_SIZE == REPORT_SIZE - 1,
+    "Relay frames need the last report byte for the CRC"
+);
+
+/// Controller id of a request from the master that every board on the bus
+/// handles. Any other value only targets the board with that board id, the
+/// same id its own events are marked with.
+pub(crate) const BROADCAST_ADDRESS: u8 = 0;
+
+impl<S, C> SPIUpstream<S, C>
 where
-    D: SpiDevice,
-    P: ValidSpiPinout<D>,
+    S: FullDuplex<u8>,
+    C: InputPin,
 {
-    pub(crate) fn new(spi: Spi<Enabled, D, P, 8>) -> Self {
-        Self { spi }
+    /// Takes ownership of `spi`, so the bus can't also be handed to the
+    /// downstream poll loop. Relaying and polling on one bus would interleave
+    /// their frames, and the master's frames arrive unprompted. `select` reads
+    /// the master's CS, which marks where its frames end.
+    pub(crate) fn new(spi: S, select: C) -> Self {
+        Self {
+            spi,
+            select,
+            incoming: [0; REPORT_SIZE],
+            incoming_len: 0,
+            outgoing: [0; REPORT_SIZE],
+            outgoing_len: REPORT_SIZE,
+            unclocked: 0,
+            received: None,
+            wait_for_master: false,
+            master_seen: false,
+            address: BROADCAST_ADDRESS,
+            corrupt: 0,
+        }
+    }
+
+    pub(crate) fn set_address(&mut self, board_id: u8) {
+        self.address = board_id;
+    }
+
+    /// Whether a request from the master is meant for this board
+    pub(crate) fn is_addressed(&self, event: &NegiconEvent) -> bool {
+        let target = event.controller_id();
+        target == BROADCAST_ADDRESS || target == self.address
+    }
+
+    /// Holds back downstream polling until the master shows up, like a USB
+    /// board waits for enumeration. Off by default, so a relay polls from
+    /// boot and the master gets its queued events on its first frames.
+    pub(crate) fn set_wait_for_master(&mut self, wait: bool) {
+        self.wait_for_master = wait;
+    }
+
+    /// Whether events can go out under the `set_wait_for_master` policy
+    pub(crate) fn is_ready(&self) -> bool {
+        !self.wait_for_master || self.master_seen
+    }
+
+    /// Loads `event` for the master as a relay frame. Only one frame is in
+    /// flight at a time, and it is only loaded between two master frames so
+    /// both stay aligned. Until the master clocked out the previous one this
+    /// fails with `Busy`, and the caller keeps the event.
+    pub(crate) fn transmit_event(&mut self, event: &mut Report) -> Result<(), UpstreamError> {
+        self.service()?;
+        if self.outgoing_len < REPORT_SIZE || self.unclocked > 0 || self.incoming_len > 0 {
+            return Err(UpstreamError::Busy);
+        }
+        self.outgoing = *event;
+        set_crc(&mut self.outgoing);
+        self.outgoing_len = 0;
+        self.service()
+    }
+
+    /// Returns the next frame the master sent, if a complete one came in.
+    /// An all-zero frame means the master had nothing to send. A frame that
+    /// fails its CRC is dropped and counted, the ones after it still come
+    /// through.
+    pub(crate) fn take_received(&mut self) -> Result<Option<Report>, UpstreamError> {
+        self.service()?;
+        Ok(self.received.take())
+    }
+
+    pub(crate) fn corrupt_count(&self) -> u32 {
+        self.corrupt
     }
 
-    pub(crate) fn transmit_event(&mut self, event: &mut [u8; 8]) -> Result<(), &'static str> {
-        match self.spi.transfer(event) {
-            Ok(_) => Ok(()),
-            Err(_) => Err("SPI Upstream Error"),
+    pub(crate) fn clear_corrupt_count(&mut self) {
+        self.corrupt = 0;
+    }
+
+    #[cfg(test)]
+    pub(crate) fn spi(&mut self) -> &mut S {
+        &mut self.spi
+    }
+
+    /// Moves whatever the FIFOs can take or give right now. Stops after a
+    /// complete master frame, so it isn't overwritten before it is taken.
+    /// A frame still partial once the master released CS and the RX FIFO ran
+    /// dry was cut short, and is dropped so the next transfer starts a frame.
+    fn service(&mut self) -> Result<(), UpstreamError> {
+        self.load_outgoing()?;
+        // Sampled before draining, so every byte of a transfer that had
+        // already ended is read below
+        let released = self.select.is_high().unwrap_or(false);
+        while self.received.is_none() {
+            match self.spi.read() {
+                Ok(byte) => {
+                    self.unclocked = self.unclocked.saturating_sub(1);
+                    self.incoming[self.incoming_len] = byte;
+                    self.incoming_len += 1;
+                }
+                Err(nb::Error::WouldBlock) => {
+                    if released && self.incoming_len > 0 {
+                        self.resync();
+                        self.load_outgoing()?;
+                    }
+                    break;
+                }
+                Err(nb::Error::Other(_)) => return Err(UpstreamError::SpiError),
+            }
+            if self.incoming_len == REPORT_SIZE {
+                self.incoming_len = 0;
+                self.master_seen = true;
+                let frame = self.incoming;
+                if frame.iter().all(|b| *b == 0) {
+                    continue;
+                }
+                match verify_crc(&frame) {
+                    Ok(_) => self.received = Some(frame),
+                    Err(_) => {
+                        self.corrupt = self.corrupt.saturating_add(1);
+                        warn!("Dropping corrupt relay frame {=[u8]:x}", &frame[..]);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Hands the FIFO as much of `outgoing` as it takes
+    fn load_outgoing(&mut self) -> Result<(), UpstreamError> {
+        while self.outgoing_len < REPORT_SIZE {
+            match self.spi.send(self.outgoing[self.outgoing_len]) {
+                Ok(()) => {
+                    self.outgoing_len += 1;
+                    self.unclocked += 1;
+                }
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(_)) => return Err(UpstreamError::SpiError),
+            }
         }
+        Ok(())
+    }
+
+    /// Drops the partial frame of a transfer the master cut short. Whatever
+    /// of our own frame it didn't clock out is still in the TX FIFO and would
+    /// lead the next transfer, so it is padded with zeros to a whole frame,
+    /// which the master drops for its CRC.
+    fn resync(&mut self) {
+        warn!(
+            "Relay frame cut short after {} bytes",
+            self.incoming_len as u32
+        );
+        self.incoming_len = 0;
+        self.corrupt = self.corrupt.saturating_add(1);
+        self.outgoing = [0; REPORT_SIZE];
+        self.outgoing_len = match self.unclocked {
+            0 => REPORT_SIZE,
+            queued => queued,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        negicon_event::NegiconEventType,
+        upstream::{
+            mock::{mock_relay, MockMaster},
+            upstream::UpstreamInterface,
+        },
+    };
+
+    fn frame(id: u16, value: i16) -> Report {
+        NegiconEvent::new(NegiconEventType::Input, id, value, 0, 0).serialize()
+    }
+
+    fn with_crc(mut frame: Report) -> Report {
+        set_crc(&mut frame);
+        frame
+    }
+
+    #[test]
+    fn frames_go_out_with_a_crc_and_come_back_verified() {
+        let mut up = mock_relay();
+        assert!(up.transmit_event(&mut frame(1, 100)).is_ok());
+        // Nothing has been clocked yet, and nothing waited for it
+        assert!(matches!(up.take_received(), Ok(None)));
+
+        let sent = up.spi.clock(with_crc(frame(3, -3)));
+        assert!(verify_crc(&sent).is_ok());
+        assert_eq!(sent[..7], frame(1, 100)[..7]);
+        let received = up
+            .take_received()
+            .ok()
+            .flatten()
+            .and_then(|frame| NegiconEvent::deserialize(frame).ok());
+        assert!(matches!(up.take_received(), Ok(None)));
+        let received = received.unwrap();
+        assert_eq!((received.id(), received.value()), (3, -3));
+    }
+
+    #[test]
+    fn master_requests_are_read_without_a_local_event() {
+        let mut up = mock_relay();
+        up.spi.clock(with_crc(frame(3, -3)));
+
+        let received = up
+            .take_received()
+            .ok()
+            .flatten()
+            .and_then(|frame| NegiconEvent::deserialize(frame).ok());
+        assert_eq!(received.map(|e| e.id()), Some(3));
+    }
+
+    #[test]
+    fn next_event_waits_until_the_master_clocked_out_the_last() {
+        let mut up = mock_relay();
+        assert!(up.transmit_event(&mut frame(1, 100)).is_ok());
+        assert!(matches!(
+            up.transmit_event(&mut frame(2, 200)),
+            Err(UpstreamError::Busy)
+        ));
+        // Half a frame in, the next one would straddle two master frames
+        up.spi.clock_partial(&[0; 4]);
+        assert!(matches!(
+            up.transmit_event(&mut frame(2, 200)),
+            Err(UpstreamError::Busy)
+        ));
+        up.spi.clock_partial(&[0; 4]);
+        assert!(up.transmit_event(&mut frame(2, 200)).is_ok());
+
+        assert_eq!(up.spi.clock([0; REPORT_SIZE])[..7], frame(2, 200)[..7]);
+        assert!(matches!(up.take_received(), Ok(None)));
+    }
+
+    #[test]
+    fn corrupted_master_frame_is_dropped_and_counted() {
+        let mut up = mock_relay();
+        let mut corrupted = with_crc(frame(3, -3));
+        corrupted[4] ^= 0x01;
+        up.spi.clock(corrupted);
+
+        assert!(matches!(up.take_received(), Ok(None)));
+        assert_eq!(up.corrupt_count(), 1);
+        // A flipped bit leaves the link in step, the next frame comes through
+        up.spi.clock(with_crc(frame(4, 4)));
+        let received = up.take_received().ok().flatten();
+        assert_eq!(received.map(|frame| frame[2]), Some(4));
+        assert_eq!(up.corrupt_count(), 1);
+
+        // A lost byte makes a short transfer, dropped once CS is released
+        let full = with_crc(frame(5, 5));
+        up.spi.clock_partial(&full[..3]);
+        up.spi.clock_partial(&full[4..]);
+        up.spi.release();
+        assert!(matches!(up.take_received(), Ok(None)));
+        assert_eq!(up.corrupt_count(), 2);
+        up.spi.clock(with_crc(frame(6, 6)));
+        let received = up.take_received().ok().flatten();
+        assert_eq!(received.map(|frame| frame[2]), Some(6));
+        assert_eq!(up.corrupt_count(), 2);
+
+        up.clear_corrupt_count();
+        assert_eq!(up.corrupt_count(), 0);
+    }
+
+    #[test]
+    fn transfer_cut_short_resyncs_on_the_next_frame() {
+        let mut up = mock_relay();
+        assert!(up.transmit_event(&mut frame(1, 100)).is_ok());
+        // The master resets three bytes into a transfer
+        up.spi.clock_partial(&with_crc(frame(3, -3))[..3]);
+        up.spi.release();
+        assert!(matches!(up.take_received(), Ok(None)));
+        assert_eq!(up.corrupt_count(), 1);
+
+        // The rest of our frame leads the next transfer, padded to a whole
+        // frame, and the ones after it are in step again
+        let sent = up.spi.clock(with_crc(frame(4, 4)));
+        assert_eq!(sent[..5], with_crc(frame(1, 100))[3..]);
+        assert_eq!(sent[5..], [0; 3]);
+        assert!(verify_crc(&sent).is_err());
+        let received = up.take_received().ok().flatten();
+        assert_eq!(received.map(|frame| frame[2]), Some(4));
+        assert!(up.transmit_event(&mut frame(2, 200)).is_ok());
+        let sent = up.spi.clock(with_crc(frame(5, 5)));
+        assert!(verify_crc(&sent).is_ok());
+        assert_eq!(sent[..7], frame(2, 200)[..7]);
+        let received = up.take_received().ok().flatten();
+        assert_eq!(received.map(|frame| frame[2]), Some(5));
+        assert_eq!(up.corrupt_count(), 1);
+    }
+
+    #[test]
+    fn relay_frame_carries_the_board_id_under_its_crc() {
+        let mut up = mock_relay();
+        let event = NegiconEvent::new(NegiconEventType::Input, 0x1234, -5, 9, 0x42);
+        assert!(up.transmit_event(&mut event.serialize()).is_ok());
+
+        // What the master reads back is verified and decoded like a
+        // downstream frame
+        let sent = up.spi.clock([0; REPORT_SIZE]);
+        assert!(verify_crc(&sent).is_ok());
+        assert!(NegiconEvent::deserialize(sent) == Ok(event));
+        for byte in 0..SERIALIZED_SIZE {
+            let mut corrupted = sent;
+            corrupted[byte] ^= 0x80;
+            assert!(verify_crc(&corrupted).is_err(), "byte {}", byte);
+        }
+    }
+
+    #[test]
+    fn master_requests_reach_only_the_addressed_board() {
+        let mut up = mock_relay();
+        up.set_address(4);
+        let request = |board| {
+            with_crc(
+                NegiconEvent::new(NegiconEventType::Config, 7, 2, 0, 0)
+                    .with_controller_id(board)
+                    .serialize(),
+            )
+        };
+
+        for (board, addressed) in [(4, true), (BROADCAST_ADDRESS, true), (5, false)] {
+            up.spi.clock(request(board));
+            let received = UpstreamInterface::receive(&mut up).ok().flatten();
+            assert_eq!(
+                received.map(|e| e.controller_id()),
+                addressed.then_some(board)
+            );
+        }
+        // A board id corrupted into ours is caught by the CRC
+        let mut misrouted = request(5);
+        misrouted[5] = 4;
+        up.spi.clock(misrouted);
+        assert!(matches!(UpstreamInterface::receive(&mut up), Ok(None)));
+        assert_eq!(up.corrupt_count(), 1);
+    }
+
+    /// Bus whose every transfer fails
+    struct Broken;
+
+    impl FullDuplex<u8> for Broken {
+        type Error = ();
+
+        fn read(&mut self) -> nb::Result<u8, ()> {
+            Err(nb::Error::Other(()))
+        }
+
+        fn send(&mut self, _word: u8) -> nb::Result<(), ()> {
+            Err(nb::Error::Other(()))
+        }
+    }
+
+    #[test]
+    fn failed_transfer_is_an_spi_error() {
+        let mut up = SPIUpstream::new(Broken, MockMaster::default().select_line());
+
+        assert!(matches!(
+            up.transmit_event(&mut frame(1, 100)),
+            Err(UpstreamError::SpiError)
+        ));
+        assert!(matches!(up.take_received(), Err(UpstreamError::SpiError)));
+    }
+
+    #[test]
+    fn idle_master_sends_nothing() {
+        let mut up = mock_relay();
+        up.spi.clock([0; REPORT_SIZE]);
+
+        assert!(matches!(up.take_received(), Ok(None)));
+    }
+
+    #[test]
+    fn waiting_relay_is_ready_once_the_master_clocked_a_frame() {
+        let mut up = mock_relay();
+        assert!(up.is_ready());
+        up.set_wait_for_master(true);
+        assert!(!up.is_ready());
+
+        up.spi.clock_partial(&[0; 4]);
+        assert!(matches!(up.take_received(), Ok(None)));
+        assert!(!up.is_ready());
+        // An idle frame is enough, the master is there
+        up.spi.clock_partial(&[0; 4]);
+        assert!(matches!(up.take_received(), Ok(None)));
+        assert!(up.is_ready());
+    }
+
+    #[test]
+    fn requests_are_addressed_by_board_id_or_broadcast() {
+        let mut up = mock_relay();
+        let request = |board| {
+            NegiconEvent::new(NegiconEventType::Config, 7, 2, 0, 0).with_controller_id(board)
+        };
+        assert!(up.is_addressed(&request(BROADCAST_ADDRESS)));
+        assert!(up.is_addressed(&request(0)));
+        assert!(!up.is_addressed(&request(4)));
+
+        up.set_address(4);
+        assert!(up.is_addressed(&request(4)));
+        assert!(up.is_addressed(&request(BROADCAST_ADDRESS)));
+        assert!(!up.is_addressed(&request(5)));
     }
 }
@@ -1,20 +1,122 @@
-use super::{ringbuf::RingBuffer, spi::SPIUpstream};
-use crate::negicon_event::NegiconEvent;
+use super::ringbuf::{RingBuffer, BUFFER_SIZE};
+#[cfg(feature = "spi-upstream")]
+use super::spi::SPIUpstream;
+#[cfg(feature = "usb-upstream")]
+use crate::negicon_event::REPORT_SIZE;
+use crate::negicon_event::{NegiconEvent, NegiconEventType, Report, UnknownEventType};
 
-use defmt::{warn, Format};
+use defmt::{info, warn, Format};
+#[cfg(feature = "usb-upstream")]
 use frunk::{HCons, HNil};
 
-use rp2040_hal::spi::{SpiDevice, ValidSpiPinout};
-use usb_device::{class_prelude::UsbBus, device::UsbDevice, UsbError};
+#[cfg(feature = "spi-upstream")]
+use embedded_hal::{digital::v2::InputPin, spi::FullDuplex};
+#[cfg(feature = "usb-upstream")]
+use usb_device::{
+    class_prelude::UsbBus,
+    device::{UsbDevice, UsbDeviceState},
+    UsbError,
+};
+#[cfg(feature = "usb-upstream")]
 use usbd_human_interface_device::{
-    interface::{InBytes8, Interface, OutBytes8, ReportSingle},
+    interface::{
+        InBytes16, InBytes32, InBytes64, InBytes8, Interface, OutBytes16, OutBytes32, OutBytes64,
+        OutBytes8, ReportSingle,
+    },
     usb_class::UsbHidClass,
 };
+
+/// HID report buffers for a report. usbd-human-interface-device only has them
+/// for 8, 16, 32 and 64 bytes, any other `REPORT_SIZE` fails the build.
+#[cfg(feature = "usb-upstream")]
+pub(crate) trait HidReportBuffers {
+    type In;
+    type Out;
+}
+
+#[cfg(feature = "usb-upstream")]
+impl HidReportBuffers for [u8; 8] {
+    type In = InBytes8;
+    type Out = OutBytes8;
+}
+
+#[cfg(feature = "usb-upstream")]
+impl HidReportBuffers for [u8; 16] {
+    type In = InBytes16;
+    type Out = OutBytes16;
+}
+
+#[cfg(feature = "usb-upstream")]
+impl HidReportBuffers for [u8; 32] {
+    type In = InBytes32;
+    type Out = OutBytes32;
+}
+
+#[cfg(feature = "usb-upstream")]
+impl HidReportBuffers for [u8; 64] {
+    type In = InBytes64;
+    type Out = OutBytes64;
+}
+
+/// HID report types matching `REPORT_SIZE`
+#[cfg(feature = "usb-upstream")]
+pub(crate) type ReportIn = <Report as HidReportBuffers>::In;
+#[cfg(feature = "usb-upstream")]
+pub(crate) type ReportOut = <Report as HidReportBuffers>::Out;
+
+#[cfg(feature = "usb-upstream")]
+pub(crate) const USB_HID_DESCRIPTOR: [u8; 38] = hid_descriptor(REPORT_SIZE);
+
+/// Vendor HID descriptor with one input and one output report of
+/// `report_bytes` opaque bytes each. Only the two report counts depend on it,
+/// so the descriptor length stays fixed.
+#[cfg(feature = "usb-upstream")]
+#[rustfmt::skip]
+const fn hid_descriptor(report_bytes: usize) -> [u8; 38] {
+    assert!(report_bytes <= u8::MAX as usize, "report too long for REPORT_COUNT");
+    let count = report_bytes as u8;
+    [
+        0x05, 0x01, // USAGE_PAGE (Generic Desktop)
+        0x09, 0x00, // USAGE (Undefined)
+        0xa1, 0x01, // COLLECTION (Application)
+        0x09, 0x01, //   USAGE (Pointer)
+        0xa1, 0x00, //   COLLECTION (Physical)
+        // Input report
+        0x09, 0x02, //     USAGE (Undefined)
+        0x15, 0x00, //     LOGICAL_MINIMUM (0)
+        0x26, 0xFF, 0x00, //     LOGICAL_MAXIMUM (255)
+        0x75, 0x08, //     REPORT_SIZE (8) - 8 bits
+        0x95, count, //     REPORT_COUNT - one field per report byte
+        0x81, 0x02, //     INPUT (Data,Var,Abs)
+        // Output report
+        0x09, 0x03, //     USAGE (Undefined)
+        0x15, 0x00, //     LOGICAL_MINIMUM (0)
+        0x26, 0xFF, 0x00, //     LOGICAL_MAXIMUM (255)
+        0x75, 0x08, //     REPORT_SIZE (8)
+        0x95, count, //     REPORT_COUNT
+        0x91, 0x02, //     OUTPUT (Data,Var,Abs)
+        0xc0, //   END_COLLECTION
+        0xc0, // END_COLLECTION
+    ]
+}
+
+#[cfg(feature = "usb-upstream")]
 type HID<'a, B> =
-    UsbHidClass<'a, B, HCons<Interface<'a, B, InBytes8, OutBytes8, ReportSingle>, HNil>>;
+    UsbHidClass<'a, B, HCons<Interface<'a, B, ReportIn, ReportOut, ReportSingle>, HNil>>;
 pub(crate) struct Upstream<'a> {
-    buffer: RingBuffer<[u8; 8]>,
+    buffer: RingBuffer<Report>,
     interface: &'a mut dyn UpstreamInterface,
+    /// Events dropped because the buffer was full
+    dropped: u32,
+    /// Whether the link could take events at the last `receive`
+    ready: bool,
+    /// Drop queued events when the link goes down, so the host doesn't get
+    /// stale relative steps after reconnecting
+    flush_on_disconnect: bool,
+    /// Controller id given to events that don't carry one yet
+    board_id: u8,
+    /// Bit n set if events of type n are dropped instead of queued
+    blocked_events: u16,
 }
 
 impl<'a> Upstream<'a> {
@@ -22,24 +124,115 @@ impl<'a> Upstream<'a> {
         Self {
             buffer: RingBuffer::new(),
             interface,
+            dropped: 0,
+            ready: false,
+            flush_on_disconnect: true,
+            board_id: 0,
+            blocked_events: 0,
         }
     }
 
+    /// Flushes one buffered event, then reads from the interface
     pub(crate) fn receive(&mut self) -> Result<Option<NegiconEvent>, UpstreamError> {
-        match self.send() {
-            Ok(_) => {}
-            Err(e) => warn!("Failed to send event to upstream {:?}", e),
+        let ready = self.interface.is_ready();
+        if ready != self.ready {
+            info!("Upstream {}", if ready { "ready" } else { "suspended" });
+            self.ready = ready;
+            if !ready && self.flush_on_disconnect {
+                let flushed = self.buffer.drain().count();
+                if flushed > 0 {
+                    info!("Flushed {} queued events", flushed);
+                }
+            }
+        }
+        // Without flush on disconnect, queued events wait for the link to
+        // come back
+        if self.ready {
+            match self.send() {
+                Ok(()) => {}
+                // The relay master simply hasn't come by yet
+                #[cfg(feature = "spi-upstream")]
+                Err(UpstreamError::Busy) => {}
+                Err(e) => warn!("Failed to send event to upstream {:?}", e),
+            }
         }
         self.interface.receive()
     }
 
-    pub(crate) fn enqueue(&mut self, event: NegiconEvent) -> Result<(), UpstreamError> {
+    /// Queues `event` for sending. While the link is suspended events are
+    /// discarded instead, so the buffer doesn't fill up with stale input.
+    /// Events without a controller id are marked as coming from this board,
+    /// ones relayed from another board keep theirs. Blocked event types are
+    /// discarded as well, except for `Query` answers.
+    pub(crate) fn enqueue(&mut self, mut event: NegiconEvent) -> Result<(), UpstreamError> {
+        if !self.ready || self.is_blocked(event.event_type()) {
+            return Ok(());
+        }
+        if event.controller_id() == 0 {
+            event = event.with_controller_id(self.board_id);
+        }
         match self.buffer.push(event.serialize()) {
             Ok(_) => Ok(()),
-            Err(_) => panic!("Upstream buffer overflow"),
+            Err(_) => {
+                self.dropped = self.dropped.saturating_add(1);
+                Err(UpstreamError::BufferOverflow)
+            }
         }
     }
 
+    pub(crate) fn set_flush_on_disconnect(&mut self, flush: bool) {
+        self.flush_on_disconnect = flush;
+    }
+
+    pub(crate) fn set_blocked_events(&mut self, mask: u16) {
+        self.blocked_events = mask;
+    }
+
+    fn is_blocked(&self, event_type: NegiconEventType) -> bool {
+        event_type != NegiconEventType::Query
+            && 1u16
+                .checked_shl(event_type as u32)
+                .is_some_and(|bit| self.blocked_events & bit != 0)
+    }
+
+    pub(crate) fn set_board_id(&mut self, board_id: u8) {
+        self.board_id = board_id;
+        self.interface.set_board_id(board_id);
+    }
+
+    pub(crate) fn is_ready(&self) -> bool {
+        self.ready
+    }
+
+    #[cfg(feature = "status-led")]
+    pub(crate) fn is_suspended(&self) -> bool {
+        self.interface.is_suspended()
+    }
+
+    /// Events that can still be queued before the buffer overflows
+    pub(crate) fn free_slots(&self) -> usize {
+        BUFFER_SIZE - self.buffer.len()
+    }
+
+    pub(crate) fn dropped_count(&self) -> u32 {
+        self.dropped
+    }
+
+    pub(crate) fn clear_dropped(&mut self) {
+        self.dropped = 0;
+    }
+
+    pub(crate) fn corrupt_frames(&self) -> u32 {
+        self.interface.corrupt_frames()
+    }
+
+    pub(crate) fn clear_corrupt_frames(&mut self) {
+        self.interface.clear_corrupt_frames();
+    }
+
+    /// Sends the oldest buffered event. It is only removed from the buffer
+    /// once the interface accepted it, so a failed send is retried on the
+    /// next call.
     pub(crate) fn send(&mut self) -> Result<(), UpstreamError> {
         if let Some(event) = self.buffer.peek() {
             match self.interface.send(event) {
@@ -52,11 +245,13 @@ impl<'a> Upstream<'a> {
     }
 }
 
+#[cfg(feature = "usb-upstream")]
 pub(crate) struct UsbUpstream<'a, B: UsbBus + 'a> {
     hid: HID<'a, B>,
     dev: UsbDevice<'a, B>,
 }
 
+#[cfg(feature = "usb-upstream")]
 impl<'a, B> UsbUpstream<'a, B>
 where
     B: UsbBus,
@@ -66,15 +261,25 @@ where
     }
 }
 
+#[cfg(feature = "usb-upstream")]
 impl<B> UpstreamInterface for UsbUpstream<'_, B>
 where
     B: UsbBus,
 {
+    fn is_ready(&self) -> bool {
+        self.dev.state() == UsbDeviceState::Configured
+    }
+
+    #[cfg(feature = "status-led")]
+    fn is_suspended(&self) -> bool {
+        self.dev.state() == UsbDeviceState::Suspend
+    }
+
     fn receive(&mut self) -> Result<Option<NegiconEvent>, UpstreamError> {
         self.dev.poll(&mut [&mut self.hid]);
-        let mut data = [0u8; 8];
+        let mut data = [0u8; REPORT_SIZE];
         match self.hid.device().read_report(&mut data) {
-            Ok(_report) => Ok(Some(NegiconEvent::deserialize(data))),
+            Ok(_report) => Ok(Some(NegiconEvent::deserialize(data)?)),
             Err(e) => match e {
                 UsbError::WouldBlock => Ok(None),
                 _ => Err(UpstreamError::UsbError(e)),
@@ -82,7 +287,7 @@ where
         }
     }
 
-    fn send(&mut self, event: &mut [u8; 8]) -> Result<(), UpstreamError> {
+    fn send(&mut self, event: &mut Report) -> Result<(), UpstreamError> {
         match self.hid.device().write_report(event) {
             Ok(_) => Ok(()),
             Err(e) => Err(UpstreamError::UsbError(e)),
@@ -91,29 +296,515 @@ where
 }
 
 pub(crate) trait UpstreamInterface {
+    /// Whether the host can currently take events. Downstream polling waits
+    /// until one upstream is ready. The SPI relay only holds it back if told
+    /// to wait for its master.
+    fn is_ready(&self) -> bool {
+        true
+    }
     fn receive(&mut self) -> Result<Option<NegiconEvent>, UpstreamError>;
-    fn send(&mut self, event: &mut [u8; 8]) -> Result<(), UpstreamError>;
+    fn send(&mut self, event: &mut Report) -> Result<(), UpstreamError>;
+    /// Board id of this board, for links that address boards individually
+    fn set_board_id(&mut self, _board_id: u8) {}
+    /// Whether the host put the link to sleep or went away. Only USB knows.
+    #[cfg(feature = "status-led")]
+    fn is_suspended(&self) -> bool {
+        false
+    }
+    /// Frames from the host dropped for failing their checksum. Only the SPI
+    /// relay checks one, USB has its own.
+    fn corrupt_frames(&self) -> u32 {
+        0
+    }
+    fn clear_corrupt_frames(&mut self) {}
 }
 
+/// Errors of every upstream interface, so callers can handle them the same
+/// way regardless of the link
 #[derive(Format)]
 pub(crate) enum UpstreamError {
+    /// The SPI transfer to the upstream master failed
+    #[cfg(feature = "spi-upstream")]
     SpiError,
+    /// The master hasn't clocked out the previous event yet, the event stays
+    /// queued
+    #[cfg(feature = "spi-upstream")]
+    Busy,
+    #[cfg(feature = "usb-upstream")]
     UsbError(UsbError),
+    BufferOverflow,
+    /// The host sent a report with this unknown event type
+    UnknownEventType(u8),
 }
 
-impl<D, P> UpstreamInterface for SPIUpstream<D, P>
+impl From<UnknownEventType> for UpstreamError {
+    fn from(e: UnknownEventType) -> Self {
+        Self::UnknownEventType(e.0)
+    }
+}
+
+#[cfg(feature = "spi-upstream")]
+impl<S, C> UpstreamInterface for SPIUpstream<S, C>
 where
-    D: SpiDevice,
-    P: ValidSpiPinout<D>,
+    S: FullDuplex<u8>,
+    C: InputPin,
 {
-    fn send(&mut self, event: &mut [u8; 8]) -> Result<(), UpstreamError> {
-        match self.transmit_event(event) {
-            Ok(_) => Ok(()),
-            Err(_) => Err(UpstreamError::SpiError),
-        }
+    fn is_ready(&self) -> bool {
+        SPIUpstream::is_ready(self)
+    }
+
+    fn send(&mut self, event: &mut Report) -> Result<(), UpstreamError> {
+        self.transmit_event(event)
     }
 
     fn receive(&mut self) -> Result<Option<NegiconEvent>, UpstreamError> {
-        todo!()
+        let event = match self.take_received()? {
+            Some(frame) => NegiconEvent::deserialize(frame)?,
+            None => return Ok(None),
+        };
+        // Requests for other boards on the bus are ignored
+        Ok(Some(event).filter(|event| self.is_addressed(event)))
+    }
+
+    fn set_board_id(&mut self, board_id: u8) {
+        self.set_address(board_id);
+    }
+
+    fn corrupt_frames(&self) -> u32 {
+        self.corrupt_count()
+    }
+
+    fn clear_corrupt_frames(&mut self) {
+        self.clear_corrupt_count();
+    }
+}
+
+/// Number of upstream links the enabled features build in
+pub(crate) const NUM_LINKS: usize = cfg!(feature = "usb-upstream") as usize
+    + cfg!(feature = "spi-upstream") as usize
+    + cfg!(feature = "log-upstream") as usize;
+
+/// The upstream links the `usb-upstream`, `spi-upstream` and `log-upstream`
+/// features build in, in the order `tick` serves them. Kept off the heap so
+/// the host is still reached without one.
+pub(crate) fn links<'a>(
+    #[cfg(feature = "usb-upstream")] usb: &'a mut dyn UpstreamInterface,
+    #[cfg(feature = "spi-upstream")] spi: &'a mut dyn UpstreamInterface,
+    #[cfg(feature = "log-upstream")] log: &'a mut dyn UpstreamInterface,
+) -> [Upstream<'a>; NUM_LINKS] {
+    [
+        #[cfg(feature = "usb-upstream")]
+        Upstream::new(usb),
+        #[cfg(feature = "spi-upstream")]
+        Upstream::new(spi),
+        #[cfg(feature = "log-upstream")]
+        Upstream::new(log),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        negicon_event::NegiconEventType,
+        upstream::{mock::MockUpstream, ringbuf::BUFFER_SIZE},
+    };
+    use alloc::vec::Vec;
+    use core::cell::Cell;
+
+    #[test]
+    #[cfg(feature = "usb-upstream")]
+    fn descriptor_counts_follow_report_size() {
+        assert_eq!(USB_HID_DESCRIPTOR[20], REPORT_SIZE as u8);
+        assert_eq!(USB_HID_DESCRIPTOR[33], REPORT_SIZE as u8);
+        let descriptor = hid_descriptor(64);
+        assert_eq!(descriptor[19..23], [0x95, 64, 0x81, 0x02]);
+        assert_eq!(descriptor[32..36], [0x95, 64, 0x91, 0x02]);
+        assert_eq!(hid_descriptor(64)[..19], hid_descriptor(8)[..19]);
+        assert_eq!(hid_descriptor(64)[23..32], hid_descriptor(8)[23..32]);
+    }
+
+    #[test]
+    #[cfg(feature = "usb-upstream")]
+    #[rustfmt::skip]
+    fn descriptor_matches_the_hand_assembled_one() {
+        assert_eq!(USB_HID_DESCRIPTOR, [
+            0x05, 0x01, 0x09, 0x00, 0xa1, 0x01, 0x09, 0x01, 0xa1, 0x00,
+            0x09, 0x02, 0x15, 0x00, 0x26, 0xFF, 0x00, 0x75, 0x08, 0x95, 0x08, 0x81, 0x02,
+            0x09, 0x03, 0x15, 0x00, 0x26, 0xFF, 0x00, 0x75, 0x08, 0x95, 0x08, 0x91, 0x02,
+            0xc0, 0xc0,
+        ]);
+    }
+
+    #[test]
+    fn overflowing_events_are_counted_until_cleared() {
+        let mut host = MockUpstream::default();
+        let mut up = Upstream::new(&mut host);
+        assert!(up.receive().is_ok());
+        let event = NegiconEvent::new(NegiconEventType::Input, 1, 1, 0, 0);
+        for _ in 0..BUFFER_SIZE {
+            assert!(up.enqueue(event).is_ok());
+        }
+        assert_eq!(up.dropped_count(), 0);
+
+        assert!(matches!(
+            up.enqueue(event),
+            Err(UpstreamError::BufferOverflow)
+        ));
+        assert!(up.enqueue(event).is_err());
+        assert_eq!(up.dropped_count(), 2);
+
+        up.clear_dropped();
+        assert_eq!(up.dropped_count(), 0);
+        // Sending frees a slot again
+        assert!(up.send().is_ok());
+        assert!(up.enqueue(event).is_ok());
+        assert_eq!(up.dropped_count(), 0);
+    }
+
+    #[test]
+    fn events_are_discarded_until_the_link_is_ready() {
+        let mut host = MockUpstream {
+            suspended: true,
+            ..MockUpstream::default()
+        };
+        let input = |value| NegiconEvent::new(NegiconEventType::Input, 1, value, 0, 0);
+        {
+            let mut up = Upstream::new(&mut host);
+            // Nothing is known about the link before the first receive
+            assert!(up.enqueue(input(1)).is_ok());
+            assert!(up.receive().is_ok());
+            assert!(!up.is_ready());
+            for _ in 0..BUFFER_SIZE + 1 {
+                assert!(up.enqueue(input(2)).is_ok());
+            }
+            assert_eq!(up.dropped_count(), 0);
+            assert!(up.send().is_ok());
+        }
+        assert!(host.sent.is_empty());
+
+        host.suspended = false;
+        {
+            let mut up = Upstream::new(&mut host);
+            assert!(up.receive().is_ok());
+            assert!(up.is_ready());
+            assert!(up.enqueue(input(3)).is_ok());
+            assert!(up.send().is_ok());
+        }
+        let values: Vec<_> = host.sent.iter().map(|e| e.value()).collect();
+        assert_eq!(values, [3]);
+    }
+
+    #[test]
+    fn blocked_event_types_are_dropped_but_query_answers_pass() {
+        let mut host = MockUpstream::default();
+        {
+            let mut up = Upstream::new(&mut host);
+            assert!(up.receive().is_ok());
+            up.set_blocked_events(
+                1 << NegiconEventType::Heartbeat as u16
+                    | 1 << NegiconEventType::Error as u16
+                    | 1 << NegiconEventType::Query as u16,
+            );
+            for event_type in [
+                NegiconEventType::Heartbeat,
+                NegiconEventType::Input,
+                NegiconEventType::Error,
+                NegiconEventType::Query,
+            ] {
+                assert!(up
+                    .enqueue(NegiconEvent::new(event_type, 1, 0, 0, 0))
+                    .is_ok());
+            }
+            for _ in 0..2 {
+                assert!(up.send().is_ok());
+            }
+            assert_eq!(up.dropped_count(), 0);
+        }
+        let sent: Vec<_> = host.sent.iter().map(|e| e.event_type() as u8).collect();
+        assert_eq!(
+            sent,
+            [NegiconEventType::Input as u8, NegiconEventType::Query as u8]
+        );
+    }
+
+    #[test]
+    fn types_beyond_the_mask_are_never_blocked() {
+        let mut host = MockUpstream::default();
+        {
+            let mut up = Upstream::new(&mut host);
+            assert!(up.receive().is_ok());
+            up.set_blocked_events(u16::MAX);
+            let event = NegiconEvent::new(NegiconEventType::WriteChallenge, 1, 0, 0, 0);
+            assert!(up.enqueue(event).is_ok());
+            assert!(up.send().is_ok());
+        }
+        assert_eq!(host.sent.len(), 1);
+    }
+
+    #[test]
+    fn forwarded_events_keep_their_originating_board_id() {
+        let mut host = MockUpstream::default();
+        {
+            let mut up = Upstream::new(&mut host);
+            up.set_board_id(3);
+            assert!(up.receive().is_ok());
+            let local = NegiconEvent::new(NegiconEventType::Input, 1, 1, 0, 0);
+            let relayed = NegiconEvent::new(NegiconEventType::Input, 2, 1, 5, 0);
+            assert!(up.enqueue(local).is_ok());
+            assert!(up.enqueue(relayed).is_ok());
+            up.set_board_id(0);
+            assert!(up.enqueue(local).is_ok());
+            for _ in 0..3 {
+                assert!(up.send().is_ok());
+            }
+        }
+        let marks: Vec<_> = host
+            .sent
+            .iter()
+            .map(|e| (e.id(), e.controller_id()))
+            .collect();
+        assert_eq!(marks, [(1, 3), (2, 5), (1, 0)]);
+    }
+
+    #[test]
+    #[cfg(feature = "spi-upstream")]
+    fn relay_marks_its_events_and_takes_only_its_own_requests() {
+        use crate::{
+            downstream::spi_protocol::set_crc,
+            upstream::{mock::mock_relay, spi::BROADCAST_ADDRESS},
+        };
+
+        let request = |board| {
+            let mut frame = NegiconEvent::new(NegiconEventType::Reinit, 3, 0, 0, 0)
+                .with_controller_id(board)
+                .serialize();
+            set_crc(&mut frame);
+            frame
+        };
+        let mut relay = mock_relay();
+        {
+            let mut up = Upstream::new(&mut relay);
+            up.set_board_id(4);
+            assert!(matches!(up.receive(), Ok(None)));
+            let event = NegiconEvent::new(NegiconEventType::Input, 1, 9, 0, 0);
+            assert!(up.enqueue(event).is_ok());
+            assert!(up.send().is_ok());
+        }
+
+        let sent = NegiconEvent::deserialize(relay.spi().clock(request(5))).unwrap();
+        assert_eq!((sent.id(), sent.controller_id()), (1, 4));
+        // Meant for board 5
+        assert!(matches!(relay.receive(), Ok(None)));
+        relay.spi().clock(request(4));
+        assert!(matches!(relay.receive(), Ok(Some(e)) if e.controller_id() == 4));
+        relay.spi().clock(request(BROADCAST_ADDRESS));
+        assert!(matches!(relay.receive(), Ok(Some(e)) if e.controller_id() == 0));
+    }
+
+    #[derive(PartialEq, Debug)]
+    enum Call {
+        Send(Report),
+        Receive,
+    }
+
+    /// Records every call, failing the first `failing_sends` sends
+    #[derive(Default)]
+    struct CallLog {
+        calls: Vec<Call>,
+        failing_sends: usize,
+    }
+
+    impl UpstreamInterface for CallLog {
+        fn receive(&mut self) -> Result<Option<NegiconEvent>, UpstreamError> {
+            self.calls.push(Call::Receive);
+            Ok(None)
+        }
+
+        fn send(&mut self, event: &mut Report) -> Result<(), UpstreamError> {
+            self.calls.push(Call::Send(*event));
+            if self.failing_sends > 0 {
+                self.failing_sends -= 1;
+                return Err(UpstreamError::BufferOverflow);
+            }
+            Ok(())
+        }
+    }
+
+    fn input(id: u16) -> NegiconEvent {
+        NegiconEvent::new(NegiconEventType::Input, id, 1, 0, 0)
+    }
+
+    #[test]
+    fn flushes_pending_event_before_receiving() {
+        let mut log = CallLog::default();
+        let mut up = Upstream::new(&mut log);
+        // Picks up that the link is ready, events are dropped before that
+        up.receive().ok();
+        up.enqueue(input(1)).ok();
+        up.receive().ok();
+        assert_eq!(
+            log.calls,
+            [
+                Call::Receive,
+                Call::Send(input(1).serialize()),
+                Call::Receive
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_send_keeps_event() {
+        let mut log = CallLog {
+            failing_sends: 1,
+            ..Default::default()
+        };
+        let mut up = Upstream::new(&mut log);
+        up.receive().ok();
+        up.enqueue(input(1)).ok();
+        up.enqueue(input(2)).ok();
+        up.receive().ok();
+        up.receive().ok();
+        up.receive().ok();
+        let sent = [input(1).serialize(), input(2).serialize()];
+        assert_eq!(
+            log.calls,
+            [
+                Call::Receive,
+                Call::Send(sent[0]),
+                Call::Receive,
+                Call::Send(sent[0]),
+                Call::Receive,
+                Call::Send(sent[1]),
+                Call::Receive,
+            ]
+        );
+    }
+
+    /// Link whose readiness the test flips while an `Upstream` holds it
+    struct Link<'a> {
+        ready: &'a Cell<bool>,
+        sent: Vec<NegiconEvent>,
+    }
+
+    impl UpstreamInterface for Link<'_> {
+        fn is_ready(&self) -> bool {
+            self.ready.get()
+        }
+
+        fn receive(&mut self) -> Result<Option<NegiconEvent>, UpstreamError> {
+            Ok(None)
+        }
+
+        fn send(&mut self, event: &mut Report) -> Result<(), UpstreamError> {
+            self.sent.push(NegiconEvent::deserialize(*event)?);
+            Ok(())
+        }
+    }
+
+    /// Queues two events, drops the link and brings it back. Returns the ids
+    /// the host got after the reconnect.
+    fn reconnect(flush: bool) -> Vec<u16> {
+        let ready = Cell::new(true);
+        let mut link = Link {
+            ready: &ready,
+            sent: Vec::new(),
+        };
+        {
+            let mut up = Upstream::new(&mut link);
+            up.set_flush_on_disconnect(flush);
+            assert!(up.receive().is_ok());
+            // Two events in flight while the host goes away
+            ready.set(false);
+            assert!(up.enqueue(input(1)).is_ok());
+            assert!(up.enqueue(input(2)).is_ok());
+            assert!(up.receive().is_ok());
+            ready.set(true);
+            assert!(up.receive().is_ok());
+            assert!(up.receive().is_ok());
+        }
+        link.sent.iter().map(|e| e.id()).collect()
+    }
+
+    #[test]
+    fn disconnect_drops_queued_events() {
+        assert!(reconnect(true).is_empty());
+    }
+
+    #[test]
+    fn queued_events_survive_a_disconnect_without_flush() {
+        assert_eq!(reconnect(false), [1, 2]);
+    }
+
+    /// Sends an event with the link's index as id through every link, so
+    /// each mock shows which position it was wired into
+    fn send_through(mut links: [Upstream; NUM_LINKS]) {
+        for (i, up) in links.iter_mut().enumerate() {
+            assert!(up.receive().is_ok());
+            assert!(up.enqueue(input(i as u16)).is_ok());
+            assert!(up.receive().is_ok());
+        }
+    }
+
+    fn sent_ids(link: &MockUpstream) -> Vec<u16> {
+        link.sent.iter().map(|e| e.id()).collect()
+    }
+
+    #[test]
+    #[cfg(all(
+        feature = "usb-upstream",
+        not(feature = "spi-upstream"),
+        not(feature = "log-upstream")
+    ))]
+    fn usb_build_serves_only_the_host() {
+        let mut usb = MockUpstream::default();
+        let list = links(&mut usb);
+        assert_eq!(list.len(), 1);
+        send_through(list);
+        assert_eq!(sent_ids(&usb), [0]);
+    }
+
+    #[test]
+    #[cfg(all(
+        feature = "spi-upstream",
+        not(feature = "usb-upstream"),
+        not(feature = "log-upstream")
+    ))]
+    fn relay_build_serves_only_the_master() {
+        let mut spi = MockUpstream::default();
+        let list = links(&mut spi);
+        assert_eq!(list.len(), 1);
+        send_through(list);
+        assert_eq!(sent_ids(&spi), [0]);
+    }
+
+    #[test]
+    #[cfg(all(
+        feature = "usb-upstream",
+        feature = "spi-upstream",
+        not(feature = "log-upstream")
+    ))]
+    fn combined_build_serves_the_host_then_the_master() {
+        let mut usb = MockUpstream::default();
+        let mut spi = MockUpstream::default();
+        let list = links(&mut usb, &mut spi);
+        assert_eq!(list.len(), 2);
+        send_through(list);
+        assert_eq!((sent_ids(&usb), sent_ids(&spi)), (vec![0], vec![1]));
+    }
+
+    #[test]
+    #[cfg(all(
+        feature = "usb-upstream",
+        feature = "log-upstream",
+        not(feature = "spi-upstream")
+    ))]
+    fn bench_build_serves_the_host_then_the_log() {
+        let mut usb = MockUpstream::default();
+        let mut log = MockUpstream::default();
+        let list = links(&mut usb, &mut log);
+        assert_eq!(list.len(), 2);
+        send_through(list);
+        assert_eq!((sent_ids(&usb), sent_ids(&log)), (vec![0], vec![1]));
     }
 }